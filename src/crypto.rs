@@ -3,8 +3,9 @@ use std::io;
 use std::io::{Read, Seek, Write};
 use std::num::ParseIntError;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
-use argon2::Argon2;
+use argon2::{Argon2, Params};
 use base64::alphabet::STANDARD;
 use base64::engine::general_purpose::NO_PAD;
 use base64::engine::GeneralPurpose;
@@ -13,30 +14,47 @@ use hex::FromHexError;
 use num_format::{Locale, ToFormattedString};
 use rand_chacha::rand_core::{CryptoRng, RngCore, SeedableRng};
 use rand_chacha::ChaCha20Rng;
-use ring::aead::{AES_256_GCM, CHACHA20_POLY1305};
+use ring::aead::{AES_128_GCM, AES_256_GCM, CHACHA20_POLY1305, NONCE_LEN};
+use ring::{hkdf, hmac};
 use secrecy::{ExposeSecret, SecretString, SecretVec};
 use serde::{Deserialize, Serialize};
-use strum_macros::{Display, EnumIter, EnumString};
+use strum::IntoEnumIterator;
+use strum_macros::{Display, EnumIter};
 use thiserror::Error;
 use tracing::{debug, error, instrument};
 
-use crate::crypto::read::{CryptoRead, CryptoReadSeek, RingCryptoRead};
-use crate::crypto::write::{CryptoWrite, CryptoWriteSeek, RingCryptoWrite, RingCryptoWriteSeek};
+use crate::crypto::read::{CryptoRead, CryptoReadSeek, LegacyRingCryptoRead, RingCryptoRead};
+use crate::crypto::write::{
+    CryptoWrite, CryptoWriteSeek, RingCryptoWrite, RingCryptoWriteSeek, BLOCK_SIZE, HEADER_LEN,
+    MAX_BLOCK_SIZE,
+};
 use crate::encryptedfs::FsResult;
 use crate::{fs_util, stream_util};
 
 pub mod buf_mut;
 pub mod read;
+#[cfg(test)]
+mod test;
 pub mod write;
 
 pub static BASE64: GeneralPurpose = GeneralPurpose::new(&STANDARD, NO_PAD);
 
-#[derive(
-    Debug, Clone, Copy, EnumIter, EnumString, Display, Serialize, Deserialize, PartialEq, Eq,
-)]
+/// Argon2 iterations used by default to derive the key from the password.
+pub const KDF_ITERATIONS: u32 = Params::DEFAULT_T_COST;
+
+/// HKDF info for the key of [`hash_file_name`], so it's different from keys derived for other uses.
+const NAME_LOOKUP_KEY_INFO: &[u8] = b"rencfs name lookup";
+
+/// Ciphers the content is encrypted with.
+///
+/// There is no `XChaCha20-Poly1305`, `ring` doesn't implement it.
+#[derive(Debug, Clone, Copy, Default, EnumIter, Display, Serialize, Deserialize, PartialEq, Eq)]
 pub enum Cipher {
+    /// The recommended one, fast also on CPUs without AES acceleration
+    #[default]
     ChaCha20Poly1305,
     Aes256Gcm,
+    Aes128Gcm,
 }
 
 impl Cipher {
@@ -47,7 +65,27 @@ impl Cipher {
         match self {
             Cipher::ChaCha20Poly1305 => CHACHA20_POLY1305.key_len(),
             Cipher::Aes256Gcm => AES_256_GCM.key_len(),
+            Cipher::Aes128Gcm => AES_128_GCM.key_len(),
+        }
+    }
+
+    /// Length (in bytes) of the ciphertext for `plaintext_len` bytes written sequentially: the header
+    /// and, for each block, the nonce and the tag besides the encrypted data.
+    ///
+    /// Files extended with seeks past the end can be smaller, as the holes are not written.
+    #[must_use]
+    #[allow(clippy::use_self)]
+    pub fn ciphertext_len(&self, plaintext_len: u64) -> u64 {
+        if plaintext_len == 0 {
+            return 0;
         }
+        let tag_len = match self {
+            Cipher::ChaCha20Poly1305 => CHACHA20_POLY1305.tag_len(),
+            Cipher::Aes256Gcm => AES_256_GCM.tag_len(),
+            Cipher::Aes128Gcm => AES_128_GCM.tag_len(),
+        };
+        let blocks = plaintext_len.div_ceil(BLOCK_SIZE as u64);
+        HEADER_LEN as u64 + plaintext_len + blocks * (NONCE_LEN + tag_len) as u64
     }
 
     /// Max length (in bytes) of the plaintext that can be encrypted before becoming unsafe.
@@ -56,7 +94,33 @@ impl Cipher {
     pub const fn max_plaintext_len(&self) -> usize {
         match self {
             Cipher::ChaCha20Poly1305 => (2_usize.pow(32) - 1) * 64,
-            Cipher::Aes256Gcm => (2_usize.pow(39) - 256) / 8,
+            Cipher::Aes256Gcm | Cipher::Aes128Gcm => (2_usize.pow(39) - 256) / 8,
+        }
+    }
+}
+
+impl FromStr for Cipher {
+    type Err = Error;
+
+    /// Parse the name case-insensitively, `-` and `_` are ignored, so `ChaCha20Poly1305`,
+    /// `chacha20` and `aes-256-gcm` are all accepted.
+    fn from_str(s: &str) -> Result<Self> {
+        let name: String = s
+            .chars()
+            .filter(|c| *c != '-' && *c != '_')
+            .collect::<String>()
+            .to_lowercase();
+        match name.as_str() {
+            "chacha20poly1305" | "chacha20" => Ok(Self::ChaCha20Poly1305),
+            "aes256gcm" | "aes256" => Ok(Self::Aes256Gcm),
+            "aes128gcm" | "aes128" => Ok(Self::Aes128Gcm),
+            _ => Err(Error::GenericString(format!(
+                "unknown cipher '{s}', possible values: {}",
+                Self::iter()
+                    .map(|c| c.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ))),
         }
     }
 }
@@ -113,7 +177,7 @@ pub fn create_write<W: Write + Send + Sync>(
     cipher: Cipher,
     key: &SecretVec<u8>,
 ) -> impl CryptoWrite<W> {
-    create_ring_write(writer, cipher, key)
+    create_ring_write(writer, cipher, key, BLOCK_SIZE, 0)
 }
 
 /// Creates and encrypted writer with seek
@@ -122,55 +186,128 @@ pub fn create_write_seek<W: Write + Seek + Read + Send + Sync>(
     cipher: Cipher,
     key: &SecretVec<u8>,
 ) -> impl CryptoWriteSeek<W> {
-    create_ring_write_seek(writer, cipher, key)
+    create_ring_write_seek(writer, cipher, key, BLOCK_SIZE, 0)
+}
+
+/// Creates and encrypted writer for the content of the file `file_id`, like the inode.
+///
+/// The blocks are bound to the file, they can be read only by [`create_read_for_file`] and
+/// [`create_read_seek_for_file`] with the same `file_id`, so blocks copied from other files don't decrypt.
+pub fn create_write_for_file<W: Write + Send + Sync>(
+    writer: W,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    file_id: u64,
+) -> impl CryptoWrite<W> {
+    create_ring_write(writer, cipher, key, BLOCK_SIZE, file_id)
+}
+
+/// Creates and encrypted writer with seek for the content of the file `file_id`, see [`create_write_for_file`].
+pub fn create_write_seek_for_file<W: Write + Seek + Read + Send + Sync>(
+    writer: W,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    file_id: u64,
+) -> impl CryptoWriteSeek<W> {
+    create_ring_write_seek(writer, cipher, key, BLOCK_SIZE, file_id)
+}
+
+/// Creates and encrypted writer which encrypts in blocks of `block_size` bytes.
+///
+/// Smaller blocks use less memory per writer, larger ones have less overhead for big files.
+/// The content needs to be read with a reader using the same block size.
+#[allow(clippy::missing_errors_doc)]
+pub fn create_write_with_block_size<W: Write + Send + Sync>(
+    writer: W,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    block_size: usize,
+) -> Result<impl CryptoWrite<W>> {
+    check_block_size(block_size)?;
+    Ok(create_ring_write(writer, cipher, key, block_size, 0))
+}
+
+/// Creates and encrypted writer with seek which encrypts in blocks of `block_size` bytes.
+#[allow(clippy::missing_errors_doc)]
+pub fn create_write_seek_with_block_size<W: Write + Seek + Read + Send + Sync>(
+    writer: W,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    block_size: usize,
+) -> Result<impl CryptoWriteSeek<W>> {
+    check_block_size(block_size)?;
+    Ok(create_ring_write_seek(writer, cipher, key, block_size, 0))
+}
+
+const fn check_block_size(block_size: usize) -> Result<()> {
+    if block_size == 0 {
+        return Err(Error::Generic("block size must be greater than 0"));
+    }
+    if block_size > MAX_BLOCK_SIZE {
+        return Err(Error::Generic("block size is too large"));
+    }
+    Ok(())
 }
 
 fn create_ring_write<W: Write + Send + Sync>(
     writer: W,
     cipher: Cipher,
     key: &SecretVec<u8>,
+    block_size: usize,
+    file_id: u64,
 ) -> RingCryptoWrite<W> {
     let algorithm = match cipher {
         Cipher::ChaCha20Poly1305 => &CHACHA20_POLY1305,
         Cipher::Aes256Gcm => &AES_256_GCM,
+        Cipher::Aes128Gcm => &AES_128_GCM,
     };
-    RingCryptoWrite::new(writer, algorithm, key)
+    RingCryptoWrite::new_with_block_size(writer, algorithm, key, block_size).with_file_id(file_id)
 }
 
 fn create_ring_write_seek<W: Write + Seek + Read + Send + Sync>(
     writer: W,
     cipher: Cipher,
     key: &SecretVec<u8>,
+    block_size: usize,
+    file_id: u64,
 ) -> RingCryptoWriteSeek<W> {
     let algorithm = match cipher {
         Cipher::ChaCha20Poly1305 => &CHACHA20_POLY1305,
         Cipher::Aes256Gcm => &AES_256_GCM,
+        Cipher::Aes128Gcm => &AES_128_GCM,
     };
-    RingCryptoWriteSeek::new(writer, algorithm, key)
+    RingCryptoWriteSeek::new_with_block_size(writer, algorithm, key, block_size)
+        .with_file_id(file_id)
 }
 
 fn create_ring_read<R: Read + Send + Sync>(
     reader: R,
     cipher: Cipher,
     key: &SecretVec<u8>,
+    block_size: usize,
+    file_id: u64,
 ) -> RingCryptoRead<R> {
     let algorithm = match cipher {
         Cipher::ChaCha20Poly1305 => &CHACHA20_POLY1305,
         Cipher::Aes256Gcm => &AES_256_GCM,
+        Cipher::Aes128Gcm => &AES_128_GCM,
     };
-    RingCryptoRead::new(reader, algorithm, key)
+    RingCryptoRead::new_with_block_size(reader, algorithm, key, block_size).with_file_id(file_id)
 }
 
 fn create_ring_read_seek<R: Read + Seek + Send + Sync>(
     reader: R,
     cipher: Cipher,
     key: &SecretVec<u8>,
+    block_size: usize,
+    file_id: u64,
 ) -> RingCryptoRead<R> {
     let algorithm = match cipher {
         Cipher::ChaCha20Poly1305 => &CHACHA20_POLY1305,
         Cipher::Aes256Gcm => &AES_256_GCM,
+        Cipher::Aes128Gcm => &AES_128_GCM,
     };
-    RingCryptoRead::new(reader, algorithm, key)
+    RingCryptoRead::new_with_block_size(reader, algorithm, key, block_size).with_file_id(file_id)
 }
 
 /// Creates and encrypted reader
@@ -179,7 +316,7 @@ pub fn create_read<R: Read + Send + Sync>(
     cipher: Cipher,
     key: &SecretVec<u8>,
 ) -> impl CryptoRead<R> {
-    create_ring_read(reader, cipher, key)
+    create_ring_read(reader, cipher, key, BLOCK_SIZE, 0)
 }
 
 /// Creates and encrypted reader with seek
@@ -188,7 +325,67 @@ pub fn create_read_seek<R: Read + Seek + Send + Sync>(
     cipher: Cipher,
     key: &SecretVec<u8>,
 ) -> impl CryptoReadSeek<R> {
-    create_ring_read_seek(reader, cipher, key)
+    create_ring_read_seek(reader, cipher, key, BLOCK_SIZE, 0)
+}
+
+/// Creates and encrypted reader for content written by [`create_write_for_file`] with the same `file_id`.
+pub fn create_read_for_file<R: Read + Send + Sync>(
+    reader: R,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    file_id: u64,
+) -> impl CryptoRead<R> {
+    create_ring_read(reader, cipher, key, BLOCK_SIZE, file_id)
+}
+
+/// Creates and encrypted reader with seek for content written by [`create_write_for_file`]
+/// with the same `file_id`.
+pub fn create_read_seek_for_file<R: Read + Seek + Send + Sync>(
+    reader: R,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    file_id: u64,
+) -> impl CryptoReadSeek<R> {
+    create_ring_read_seek(reader, cipher, key, BLOCK_SIZE, file_id)
+}
+
+/// Creates and encrypted reader for content written in blocks of `block_size` bytes.
+#[allow(clippy::missing_errors_doc)]
+pub fn create_read_with_block_size<R: Read + Send + Sync>(
+    reader: R,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    block_size: usize,
+) -> Result<impl CryptoRead<R>> {
+    check_block_size(block_size)?;
+    Ok(create_ring_read(reader, cipher, key, block_size, 0))
+}
+
+/// Creates and encrypted reader with seek for content written in blocks of `block_size` bytes.
+#[allow(clippy::missing_errors_doc)]
+pub fn create_read_seek_with_block_size<R: Read + Seek + Send + Sync>(
+    reader: R,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    block_size: usize,
+) -> Result<impl CryptoReadSeek<R>> {
+    check_block_size(block_size)?;
+    Ok(create_ring_read_seek(reader, cipher, key, block_size, 0))
+}
+
+/// Creates and encrypted reader for content written before the format version was kept,
+/// see [`LegacyRingCryptoRead`].
+pub(crate) fn create_read_legacy<R: Read + Send + Sync>(
+    reader: R,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+) -> impl Read + Send + Sync {
+    let algorithm = match cipher {
+        Cipher::ChaCha20Poly1305 => &CHACHA20_POLY1305,
+        Cipher::Aes256Gcm => &AES_256_GCM,
+        Cipher::Aes128Gcm => &AES_128_GCM,
+    };
+    LegacyRingCryptoRead::new(reader, algorithm, key)
 }
 
 #[allow(clippy::missing_errors_doc)]
@@ -208,7 +405,8 @@ pub fn decrypt(s: &str, cipher: Cipher, key: &SecretVec<u8>) -> Result<SecretStr
     let cursor = io::Cursor::new(vec);
 
     let mut reader = create_read(cursor, cipher, key);
-    let mut decrypted = String::new();
+    // plaintext is shorter than ciphertext, allocate once so no copies of it are left behind by reallocation
+    let mut decrypted = String::with_capacity(s.len());
     reader.read_to_string(&mut decrypted)?;
     Ok(SecretString::new(decrypted))
 }
@@ -219,15 +417,51 @@ pub fn decrypt_file_name(name: &str, cipher: Cipher, key: &SecretVec<u8>) -> Res
     decrypt(&name, cipher, key)
 }
 
+/// Like [`decrypt_file_name`] for names encrypted before the format version was kept.
+pub(crate) fn decrypt_file_name_legacy(
+    name: &str,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+) -> Result<SecretString> {
+    let vec = BASE64.decode(name.replace('|', "/"))?;
+    let mut reader = create_read_legacy(io::Cursor::new(vec), cipher, key);
+    let mut decrypted = String::with_capacity(name.len());
+    reader.read_to_string(&mut decrypted)?;
+    Ok(SecretString::new(decrypted))
+}
+
 #[instrument(skip(password, salt))]
 #[allow(clippy::missing_errors_doc)]
 pub fn derive_key(password: &SecretString, cipher: Cipher, salt: &[u8]) -> Result<SecretVec<u8>> {
+    derive_key_with_iterations(password, cipher, salt, KDF_ITERATIONS)
+}
+
+/// Like [`derive_key`] but with `iterations` of Argon2, more makes guessing the password slower.
+#[instrument(skip(password, salt))]
+#[allow(clippy::missing_errors_doc)]
+pub fn derive_key_with_iterations(
+    password: &SecretString,
+    cipher: Cipher,
+    salt: &[u8],
+    iterations: u32,
+) -> Result<SecretVec<u8>> {
     let mut dk = vec![];
     let key_len = cipher.key_len();
     dk.resize(key_len, 0);
-    Argon2::default()
-        .hash_password_into(password.expose_secret().as_bytes(), salt, &mut dk)
-        .map_err(|err| Error::GenericString(err.to_string()))?;
+    let params = Params::new(
+        Params::DEFAULT_M_COST,
+        iterations,
+        Params::DEFAULT_P_COST,
+        None,
+    )
+    .map_err(|err| Error::GenericString(err.to_string()))?;
+    Argon2::new(
+        argon2::Algorithm::default(),
+        argon2::Version::default(),
+        params,
+    )
+    .hash_password_into(password.expose_secret().as_bytes(), salt, &mut dk)
+    .map_err(|err| Error::GenericString(err.to_string()))?;
     Ok(SecretVec::new(dk))
 }
 
@@ -242,25 +476,62 @@ pub fn encrypt_file_name(
     } else if name.expose_secret() == "." || name.expose_secret() == ".." {
         Ok(format!("${}", name.expose_secret()))
     } else {
-        let normalized_name = SecretString::new(name.expose_secret().replace(['/', '\\'], " "));
+        let normalized_name = normalize_file_name(name);
         let mut encrypted = encrypt(&normalized_name, cipher, key)?;
         encrypted = encrypted.replace('/', "|");
         Ok(encrypted)
     }
 }
 
-#[allow(clippy::missing_errors_doc)]
+/// Length of the name returned by [`encrypt_file_name`], without encrypting it.
 #[must_use]
-pub fn hash_file_name(name: &SecretString) -> String {
+#[allow(clippy::cast_possible_truncation)]
+pub fn encrypted_file_name_len(name: &SecretString, cipher: Cipher) -> usize {
+    let name = name.expose_secret();
+    if name == "$." || name == "$.." {
+        name.len()
+    } else if name == "." || name == ".." {
+        name.len() + 1
+    } else {
+        let len = cipher.ciphertext_len(name.len() as u64) as usize;
+        base64::encoded_len(len, false).unwrap_or(usize::MAX)
+    }
+}
+
+/// Deterministic name used to look up an entry in the `hash` dir.
+///
+/// It's a MAC with a key derived from the encryption key, so equal names map to the same value
+/// for lookup, but without the key one can't check if the store contains a guessed name.
+/// The names themselves are encrypted with [`encrypt_file_name`], with a random nonce,
+/// this is only for lookup.
+#[must_use]
+pub fn hash_file_name(name: &SecretString, key: &SecretVec<u8>) -> String {
     if name.expose_secret() == "$." || name.expose_secret() == "$.." {
         name.expose_secret().clone()
     } else if name.expose_secret() == "." || name.expose_secret() == ".." {
         format!("${}", name.expose_secret())
     } else {
-        hex::encode(hash_secret_string(name))
+        // hash the normalized name, so names that end up the same on disk are detected as duplicates
+        let key = name_lookup_key(key);
+        let normalized_name = normalize_file_name(name);
+        hex::encode(hmac::sign(&key, normalized_name.expose_secret().as_bytes()))
     }
 }
 
+/// Key for [`hash_file_name`], derived with HKDF so the encryption key is not used directly for the MAC.
+fn name_lookup_key(key: &SecretVec<u8>) -> hmac::Key {
+    hkdf::Salt::new(hkdf::HKDF_SHA256, &[])
+        .extract(key.expose_secret())
+        .expand(&[NAME_LOOKUP_KEY_INFO], hmac::HMAC_SHA256)
+        .expect("hmac key length is valid for hkdf")
+        .into()
+}
+
+/// Replace path separators, they can't be part of the file name.
+fn normalize_file_name(name: &SecretString) -> SecretString {
+    SecretString::new(name.expose_secret().replace(['/', '\\'], " "))
+}
+
 #[must_use]
 pub fn hash(data: &[u8]) -> [u8; 32] {
     let mut hasher = blake3::Hasher::new();
@@ -194,10 +194,70 @@ impl Drop for BufMut {
 
 #[cfg(test)]
 mod tests {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
     use std::io::SeekFrom;
 
     use super::*;
 
+    /// Checks if the memory is zeroed when it's freed, only for allocations with the watched size.
+    struct CheckZeroedAlloc;
+
+    thread_local! {
+        static WATCH_SIZE: Cell<usize> = const { Cell::new(0) };
+        static FREED_NON_ZERO: Cell<bool> = const { Cell::new(false) };
+    }
+
+    unsafe impl GlobalAlloc for CheckZeroedAlloc {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            System.alloc(layout)
+        }
+
+        unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+            System.alloc_zeroed(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            let watched = WATCH_SIZE
+                .try_with(Cell::get)
+                .is_ok_and(|size| size == layout.size());
+            if watched
+                && std::slice::from_raw_parts(ptr, layout.size())
+                    .iter()
+                    .any(|b| *b != 0)
+            {
+                let _ = FREED_NON_ZERO.try_with(|freed| freed.set(true));
+            }
+            System.dealloc(ptr, layout);
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            System.realloc(ptr, layout, new_size)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOC: CheckZeroedAlloc = CheckZeroedAlloc;
+
+    #[test]
+    fn test_zeroized_on_drop() {
+        // an odd size, so we don't catch other allocations
+        let size = 4099;
+        WATCH_SIZE.with(|s| s.set(size));
+
+        // make sure we detect it
+        drop(std::hint::black_box(vec![42_u8; size]));
+        assert!(FREED_NON_ZERO.with(Cell::get));
+        FREED_NON_ZERO.with(|f| f.set(false));
+
+        let mut buf = BufMut::new(vec![0; size]);
+        buf.write_all(&[42; 4099]).unwrap();
+        drop(buf);
+        assert!(!FREED_NON_ZERO.with(Cell::get));
+
+        WATCH_SIZE.with(|s| s.set(0));
+    }
+
     #[test]
     fn test_available() {
         let buf = BufMut::new(vec![0; 10]);
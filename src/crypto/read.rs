@@ -1,16 +1,24 @@
 use std::io;
 use std::io::{Read, Seek, SeekFrom};
-use std::sync::{Arc, Mutex};
+use std::marker::PhantomData;
+use std::sync::mpsc::Receiver;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::thread::JoinHandle;
 
 use ring::aead::{
     Aad, Algorithm, BoundKey, Nonce, NonceSequence, OpeningKey, UnboundKey, NONCE_LEN,
 };
 use ring::error;
+use secrecy::zeroize::{Zeroize, Zeroizing};
 use secrecy::{ExposeSecret, SecretVec};
 use tracing::{error, instrument, warn};
 
 use crate::crypto::buf_mut::BufMut;
-use crate::crypto::write::BLOCK_SIZE;
+use crate::crypto::write::{
+    block_aad, count_holes_before, is_hole, max_holes, open_block, read_header, BLOCK_SIZE,
+    HEADER_LEN, MAX_BLOCK_SIZE,
+};
 use crate::stream_util;
 
 mod bench;
@@ -19,62 +27,13 @@ mod test;
 /// Reads encrypted content from the wrapped Reader.
 #[allow(clippy::module_name_repetitions)]
 pub trait CryptoRead<R: Read + Send + Sync>: Read + Send + Sync {
-    #[allow(clippy::wrong_self_convention)]
-    fn into_inner(&mut self) -> R;
+    /// Takes the wrapped reader, the crypto reader can't be used after this.
+    #[allow(clippy::wrong_self_convention, clippy::missing_errors_doc)]
+    fn into_inner(&mut self) -> io::Result<R>;
 }
 
 /// ring
 
-#[macro_export]
-macro_rules! decrypt_block {
-    ($block_index:expr, $buf:expr, $input:expr, $last_nonce:expr, $opening_key:expr) => {{
-        let len = {
-            $buf.clear();
-            let buffer = $buf.as_mut_remaining();
-            let mut len = {
-                let mut pos = 0;
-                loop {
-                    match $input.read(&mut buffer[pos..]) {
-                        Ok(read) => {
-                            pos += read;
-                            if read == 0 {
-                                break;
-                            }
-                        }
-                        Err(err) => return Err(err),
-                    }
-                }
-                pos
-            };
-            if len != 0 {
-                let data = &mut buffer[..len];
-                let aad = Aad::from(($block_index).to_le_bytes());
-                // extract nonce
-                $last_nonce
-                    .lock()
-                    .unwrap()
-                    .replace(data[..NONCE_LEN].to_vec());
-                let data = &mut data[NONCE_LEN..];
-                let plaintext = $opening_key.open_within(aad, data, 0..).map_err(|err| {
-                    error!("error opening within: {}", err);
-                    io::Error::new(io::ErrorKind::Other, "error opening within")
-                })?;
-                len = plaintext.len();
-            }
-            len
-        };
-        if len != 0 {
-            $buf.seek_available(SeekFrom::Start(NONCE_LEN as u64 + len as u64))
-                .unwrap();
-            // skip nonce
-            $buf.seek_read(SeekFrom::Start(NONCE_LEN as u64)).unwrap();
-            $block_index += 1;
-        }
-    }};
-}
-
-pub(crate) use decrypt_block;
-
 #[allow(clippy::module_name_repetitions)]
 pub struct RingCryptoRead<R: Read> {
     input: Option<R>,
@@ -84,12 +43,41 @@ pub struct RingCryptoRead<R: Read> {
     ciphertext_block_size: usize,
     plaintext_block_size: usize,
     block_index: u64,
+    header_read: bool,
+    file_id: u64,
+    /// Holes right before the next block in the stream.
+    holes_before: u64,
+    /// Holes we already checked and didn't return yet.
+    pending_holes: u64,
+    /// Block after [`Self::pending_holes`], decrypted.
+    ahead: Option<BufMut>,
 }
 
 impl<R: Read> RingCryptoRead<R> {
     #[allow(clippy::missing_panics_doc)]
     pub fn new(reader: R, algorithm: &'static Algorithm, key: &SecretVec<u8>) -> Self {
-        let ciphertext_block_size = NONCE_LEN + BLOCK_SIZE + algorithm.tag_len();
+        Self::new_with_block_size(reader, algorithm, key, BLOCK_SIZE)
+    }
+
+    /// Like [`Self::new`] but with a different default block size.
+    ///
+    /// The block size the content was written with is read from the header,
+    /// so `block_size` is used only until the header is read.
+    ///
+    /// # Panics
+    ///
+    /// If `block_size` is 0 or greater than `MAX_BLOCK_SIZE`.
+    pub fn new_with_block_size(
+        reader: R,
+        algorithm: &'static Algorithm,
+        key: &SecretVec<u8>,
+        block_size: usize,
+    ) -> Self {
+        assert!(
+            block_size > 0 && block_size <= MAX_BLOCK_SIZE,
+            "block size must be greater than 0 and at most {MAX_BLOCK_SIZE}"
+        );
+        let ciphertext_block_size = NONCE_LEN + block_size + algorithm.tag_len();
         let buf = BufMut::new(vec![0; ciphertext_block_size]);
         let last_nonce = Arc::new(Mutex::new(None));
         let unbound_key = UnboundKey::new(algorithm, key.expose_secret()).unwrap();
@@ -101,9 +89,157 @@ impl<R: Read> RingCryptoRead<R> {
             buf,
             last_nonce,
             ciphertext_block_size,
-            plaintext_block_size: BLOCK_SIZE,
+            plaintext_block_size: block_size,
             block_index: 0,
+            header_read: false,
+            file_id: 0,
+            holes_before: 0,
+            pending_holes: 0,
+            ahead: None,
+        }
+    }
+
+    /// Reads content written for `file_id`, see [`crate::crypto::write::RingCryptoWrite::with_file_id`].
+    #[must_use]
+    pub const fn with_file_id(mut self, file_id: u64) -> Self {
+        self.file_id = file_id;
+        self
+    }
+
+    /// Reads the header and switches to the block size the content was written with.
+    ///
+    /// Returns `false` if the stream is empty.
+    fn read_header(&mut self) -> io::Result<bool> {
+        if self.header_read {
+            return Ok(true);
+        }
+        let mut header = [0; HEADER_LEN];
+        let len = stream_util::read(self.input.as_mut().unwrap(), &mut header)?;
+        if len == 0 {
+            return Ok(false);
+        }
+        let block_size = read_header(&mut &header[..len])?;
+        if block_size != self.plaintext_block_size {
+            self.ciphertext_block_size =
+                self.ciphertext_block_size - self.plaintext_block_size + block_size;
+            self.plaintext_block_size = block_size;
+            self.buf = BufMut::new(vec![0; self.ciphertext_block_size]);
+        }
+        self.header_read = true;
+        Ok(true)
+    }
+
+    /// Offset of the block in the ciphertext stream.
+    const fn block_offset(&self, block_index: u64) -> u64 {
+        HEADER_LEN as u64 + block_index * self.ciphertext_block_size as u64
+    }
+
+    /// Reads the next block in the buffer, decrypted, the buffer is left empty at the end of the stream.
+    ///
+    /// A hole is returned only after we decrypt the block after its run,
+    /// as that one authenticates the number of holes before it.
+    fn next_block(&mut self) -> io::Result<()> {
+        if self.pending_holes > 0 {
+            self.pending_holes -= 1;
+            self.load_hole();
+            return Ok(());
+        }
+        if let Some(block) = self.ahead.take() {
+            self.buf = block;
+            self.block_index += 1;
+            return Ok(());
+        }
+        let (pos_read, available) = (self.buf.pos_read(), self.buf.available());
+        self.buf.clear();
+        let input = self.input.as_mut().unwrap();
+        let len = stream_util::read(&mut *input, self.buf.as_mut_remaining())?;
+        if len == 0 {
+            // stay at the end of the last block, `pos()` depends on it
+            self.buf.seek_available(SeekFrom::Start(available as u64))?;
+            self.buf.seek_read(SeekFrom::Start(pos_read as u64))?;
+            return Ok(());
+        }
+        if !is_hole(
+            &self.buf.as_mut_remaining()[..len],
+            self.ciphertext_block_size,
+        ) {
+            let holes_before = std::mem::take(&mut self.holes_before);
+            let len = open_block(
+                &mut self.opening_key,
+                &self.last_nonce,
+                block_aad(
+                    self.file_id,
+                    self.plaintext_block_size,
+                    self.block_index,
+                    holes_before,
+                ),
+                &mut self.buf.as_mut_remaining()[..len],
+            )?;
+            Self::set_plaintext_len(&mut self.buf, len);
+            self.block_index += 1;
+            return Ok(());
+        }
+        // read until the block after the holes
+        let max_holes = max_holes(self.plaintext_block_size);
+        let holes_before = std::mem::take(&mut self.holes_before);
+        let mut ahead = BufMut::new(vec![0; self.ciphertext_block_size]);
+        let mut holes = 1;
+        loop {
+            if holes_before + holes > max_holes {
+                error!(block_index = self.block_index, "too many consecutive holes");
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "too many consecutive holes",
+                ));
+            }
+            let len = stream_util::read(&mut *input, ahead.as_mut_remaining())?;
+            if len == 0 {
+                error!(
+                    block_index = self.block_index,
+                    "holes at the end of the stream"
+                );
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "holes at the end of the stream",
+                ));
+            }
+            if !is_hole(&ahead.as_mut_remaining()[..len], self.ciphertext_block_size) {
+                let len = open_block(
+                    &mut self.opening_key,
+                    &self.last_nonce,
+                    block_aad(
+                        self.file_id,
+                        self.plaintext_block_size,
+                        self.block_index + holes,
+                        holes_before + holes,
+                    ),
+                    &mut ahead.as_mut_remaining()[..len],
+                )?;
+                Self::set_plaintext_len(&mut ahead, len);
+                break;
+            }
+            holes += 1;
         }
+        self.ahead = Some(ahead);
+        self.pending_holes = holes - 1;
+        self.load_hole();
+        Ok(())
+    }
+
+    /// Marks the plaintext after the nonce as available to read.
+    fn set_plaintext_len(buf: &mut BufMut, len: usize) {
+        buf.seek_available(SeekFrom::Start(NONCE_LEN as u64 + len as u64))
+            .unwrap();
+        // skip nonce
+        buf.seek_read(SeekFrom::Start(NONCE_LEN as u64)).unwrap();
+    }
+
+    /// Loads a hole in the buffer, which is a block of zeros.
+    fn load_hole(&mut self) {
+        self.buf.clear();
+        self.buf.as_mut_remaining()[NONCE_LEN..NONCE_LEN + self.plaintext_block_size].fill(0);
+        Self::set_plaintext_len(&mut self.buf, self.plaintext_block_size);
+        self.block_index += 1;
     }
 }
 
@@ -115,14 +251,11 @@ impl<R: Read> Read for RingCryptoRead<R> {
         if len != 0 {
             return Ok(len);
         }
+        if !self.read_header()? {
+            return Ok(0);
+        }
         // we read all the data from the buffer, so we need to read a new block and decrypt it
-        decrypt_block!(
-            self.block_index,
-            self.buf,
-            self.input.as_mut().unwrap(),
-            self.last_nonce,
-            self.opening_key
-        );
+        self.next_block()?;
         let len = self.buf.read(buf)?;
         Ok(len)
     }
@@ -145,8 +278,62 @@ impl NonceSequence for ExistingNonceSequence {
 }
 
 impl<R: Read + Send + Sync> CryptoRead<R> for RingCryptoRead<R> {
-    fn into_inner(&mut self) -> R {
-        self.input.take().unwrap()
+    fn into_inner(&mut self) -> io::Result<R> {
+        self.input
+            .take()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "reader already taken"))
+    }
+}
+
+/// Reads content written before the format version was kept, version `0`.
+///
+/// Those streams have no header, the blocks are [`BLOCK_SIZE`] and have only their index
+/// in the associated data. Used only to migrate them, see [`crate::encryptedfs::EncryptedFs::migrate`].
+pub(crate) struct LegacyRingCryptoRead<R: Read> {
+    input: R,
+    opening_key: OpeningKey<ExistingNonceSequence>,
+    buf: BufMut,
+    last_nonce: Arc<Mutex<Option<Vec<u8>>>>,
+    block_index: u64,
+}
+
+impl<R: Read> LegacyRingCryptoRead<R> {
+    #[allow(clippy::missing_panics_doc)]
+    pub fn new(reader: R, algorithm: &'static Algorithm, key: &SecretVec<u8>) -> Self {
+        let buf = BufMut::new(vec![0; NONCE_LEN + BLOCK_SIZE + algorithm.tag_len()]);
+        let last_nonce = Arc::new(Mutex::new(None));
+        let unbound_key = UnboundKey::new(algorithm, key.expose_secret()).unwrap();
+        let nonce_sequence = ExistingNonceSequence::new(last_nonce.clone());
+        Self {
+            input: reader,
+            opening_key: OpeningKey::new(unbound_key, nonce_sequence),
+            buf,
+            last_nonce,
+            block_index: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for LegacyRingCryptoRead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.buf.read(buf)?;
+        if len != 0 {
+            return Ok(len);
+        }
+        self.buf.clear();
+        let len = stream_util::read(&mut self.input, self.buf.as_mut_remaining())?;
+        if len == 0 {
+            return Ok(0);
+        }
+        let len = open_block(
+            &mut self.opening_key,
+            &self.last_nonce,
+            Aad::from(self.block_index.to_le_bytes()),
+            &mut self.buf.as_mut_remaining()[..len],
+        )?;
+        RingCryptoRead::<R>::set_plaintext_len(&mut self.buf, len);
+        self.block_index += 1;
+        self.buf.read(buf)
     }
 }
 
@@ -155,6 +342,9 @@ impl<R: Read + Send + Sync> CryptoRead<R> for RingCryptoRead<R> {
 pub trait CryptoReadSeek<R: Read + Seek + Send + Sync>:
     CryptoRead<R> + Read + Seek + Send + Sync
 {
+    /// Size of the plaintext blocks, the one the content was written with.
+    #[allow(clippy::missing_errors_doc)]
+    fn block_size(&mut self) -> io::Result<usize>;
 }
 
 impl<R: Read + Seek> RingCryptoRead<R> {
@@ -168,12 +358,18 @@ impl<R: Read + Seek> RingCryptoRead<R> {
     }
 
     fn get_plaintext_len(&mut self) -> io::Result<u64> {
-        let ciphertext_len = self.input.as_mut().unwrap().stream_len()?;
+        let ciphertext_len = self
+            .input
+            .as_mut()
+            .unwrap()
+            .stream_len()?
+            .saturating_sub(HEADER_LEN as u64);
         if ciphertext_len == 0 {
             return Ok(0);
         }
+        // the last block might be partial, each one has the nonce and tag overhead
         let plaintext_len = ciphertext_len
-            - ((ciphertext_len / self.ciphertext_block_size as u64) + 1)
+            - ciphertext_len.div_ceil(self.ciphertext_block_size as u64)
                 * (self.ciphertext_block_size - self.plaintext_block_size) as u64;
         Ok(plaintext_len)
     }
@@ -183,9 +379,15 @@ impl<R: Read + Seek> Seek for RingCryptoRead<R> {
     #[allow(clippy::cast_possible_wrap)]
     #[allow(clippy::cast_sign_loss)]
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        if !self.header_read {
+            // we need the block size before seeking
+            self.input.as_mut().unwrap().seek(SeekFrom::Start(0))?;
+            self.read_header()?;
+        }
         let plaintext_len = self.get_plaintext_len()?;
         let new_pos = match pos {
-            SeekFrom::Start(pos) => pos as i64,
+            // clamp before casting, so offsets past `i64::MAX` don't wrap
+            SeekFrom::Start(pos) => pos.min(plaintext_len) as i64,
             SeekFrom::End(pos) => plaintext_len as i64 + pos,
             SeekFrom::Current(pos) => self.pos() as i64 + pos,
         };
@@ -222,9 +424,16 @@ impl<R: Read + Seek> Seek for RingCryptoRead<R> {
             }
         } else {
             // change block
-            self.input.as_mut().unwrap().seek(SeekFrom::Start(
-                new_block_index * self.ciphertext_block_size as u64,
-            ))?;
+            let offset = self.block_offset(new_block_index);
+            self.pending_holes = 0;
+            self.ahead = None;
+            self.holes_before = count_holes_before(
+                self.input.as_mut().unwrap(),
+                new_block_index,
+                self.ciphertext_block_size,
+                max_holes(self.plaintext_block_size),
+            )?;
+            self.input.as_mut().unwrap().seek(SeekFrom::Start(offset))?;
             self.buf.clear();
             self.block_index = new_block_index;
             if new_pos % self.plaintext_block_size as u64 == 0 {
@@ -232,13 +441,7 @@ impl<R: Read + Seek> Seek for RingCryptoRead<R> {
                 // the block_index but the seek seek_forward from below will not decrypt anything
                 // as the offset in new block is 0. In that case the po()
                 // method is affected as it will use the wrong block_index value
-                decrypt_block!(
-                    self.block_index,
-                    self.buf,
-                    self.input.as_mut().unwrap(),
-                    self.last_nonce,
-                    self.opening_key
-                );
+                self.next_block()?;
             }
             // seek inside new block
             let plaintext_block_size = self.plaintext_block_size;
@@ -248,4 +451,223 @@ impl<R: Read + Seek> Seek for RingCryptoRead<R> {
     }
 }
 
-impl<R: Read + Seek + Send + Sync> CryptoReadSeek<R> for RingCryptoRead<R> {}
+impl<R: Read + Seek + Send + Sync> CryptoReadSeek<R> for RingCryptoRead<R> {
+    fn block_size(&mut self) -> io::Result<usize> {
+        if !self.header_read {
+            // it's in the header, at the start
+            self.input.as_mut().unwrap().seek(SeekFrom::Start(0))?;
+            self.read_header()?;
+        }
+        Ok(self.plaintext_block_size)
+    }
+}
+
+/// Decrypted blocks sent by the read ahead thread.
+type BlockReceiver = Receiver<io::Result<Zeroizing<Vec<u8>>>>;
+
+/// Decrypts the next blocks in a background thread while the consumer reads the current ones.
+///
+/// Useful for large sequential reads, keeps at most `readahead` decrypted blocks in memory.
+/// The thread is stopped on seek, when the buffered blocks are discarded, and on drop.
+#[allow(clippy::module_name_repetitions)]
+pub struct ReadAheadCryptoRead<R: Read + Seek + Send + Sync, T: CryptoReadSeek<R> + 'static> {
+    reader: Option<T>,
+    worker: Option<(JoinHandle<T>, Mutex<BlockReceiver>)>,
+    readahead: usize,
+    /// Of the reader, known after we start the thread.
+    block_size: Option<usize>,
+    /// The thread reported the end of the stream.
+    eof: bool,
+    buf: Zeroizing<Vec<u8>>,
+    buf_pos: usize,
+    pos: u64,
+    _marker: PhantomData<R>,
+}
+
+impl<R: Read + Seek + Send + Sync, T: CryptoReadSeek<R> + 'static> ReadAheadCryptoRead<R, T> {
+    /// `readahead` is the max number of blocks decrypted ahead.
+    #[must_use]
+    pub fn new(reader: T, readahead: usize) -> Self {
+        Self {
+            reader: Some(reader),
+            worker: None,
+            readahead: readahead.max(1),
+            block_size: None,
+            eof: false,
+            buf: Zeroizing::new(vec![]),
+            buf_pos: 0,
+            pos: 0,
+            _marker: PhantomData,
+        }
+    }
+
+    fn start(&mut self) -> io::Result<()> {
+        if let Some(mut reader) = self.reader.take() {
+            let block_size = match reader.block_size() {
+                Ok(block_size) => block_size,
+                Err(err) => {
+                    self.reader = Some(reader);
+                    return Err(err);
+                }
+            };
+            self.block_size = Some(block_size);
+            let (handle, rx) = spawn_read_ahead(reader, block_size, self.readahead);
+            self.worker = Some((handle, Mutex::new(rx)));
+        }
+        Ok(())
+    }
+
+    /// Stops the background thread and takes back the reader, the blocks not read yet are discarded.
+    fn stop(&mut self) -> io::Result<()> {
+        if let Some((handle, rx)) = self.worker.take() {
+            // dropping the receiver makes the thread stop on next send
+            drop(rx);
+            let reader = handle
+                .join()
+                .map_err(|_| io::Error::new(io::ErrorKind::Other, "read ahead thread panicked"))?;
+            self.reader = Some(reader);
+        }
+        self.buf.zeroize();
+        self.buf_pos = 0;
+        self.eof = false;
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek + Send + Sync, T: CryptoReadSeek<R> + 'static> Read
+    for ReadAheadCryptoRead<R, T>
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.buf_pos == self.buf.len() {
+            self.start()?;
+            let Some((_, rx)) = self.worker.as_mut() else {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "read ahead thread panicked",
+                ));
+            };
+            let rx = rx
+                .get_mut()
+                .map_err(|_| io::Error::new(io::ErrorKind::Other, "read ahead channel poisoned"))?;
+            match rx.recv() {
+                Ok(Ok(block)) if block.is_empty() => {
+                    // the thread reported the end, keep it for the next reads
+                    self.eof = true;
+                    return Ok(0);
+                }
+                Ok(block) => {
+                    self.buf = block?;
+                    self.buf_pos = 0;
+                }
+                Err(_) if self.eof => return Ok(0),
+                // the thread stopped without reporting the end or an error
+                Err(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "read ahead thread panicked",
+                    ))
+                }
+            }
+        }
+        let len = buf.len().min(self.buf.len() - self.buf_pos);
+        buf[..len].copy_from_slice(&self.buf[self.buf_pos..self.buf_pos + len]);
+        self.buf_pos += len;
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl<R: Read + Seek + Send + Sync, T: CryptoReadSeek<R> + 'static> Seek
+    for ReadAheadCryptoRead<R, T>
+{
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.stop()?;
+        // the inner reader is ahead of us, make the position absolute
+        let pos = match pos {
+            SeekFrom::Current(offset) => SeekFrom::Start(
+                self.pos
+                    .checked_add_signed(offset)
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "position < 0"))?,
+            ),
+            pos => pos,
+        };
+        let Some(reader) = self.reader.as_mut() else {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "read ahead thread panicked",
+            ));
+        };
+        self.pos = reader.seek(pos)?;
+        Ok(self.pos)
+    }
+}
+
+impl<R: Read + Seek + Send + Sync, T: CryptoReadSeek<R> + 'static> CryptoRead<R>
+    for ReadAheadCryptoRead<R, T>
+{
+    fn into_inner(&mut self) -> io::Result<R> {
+        self.stop()?;
+        self.reader
+            .as_mut()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "read ahead thread panicked"))?
+            .into_inner()
+    }
+}
+
+impl<R: Read + Seek + Send + Sync, T: CryptoReadSeek<R> + 'static> CryptoReadSeek<R>
+    for ReadAheadCryptoRead<R, T>
+{
+    fn block_size(&mut self) -> io::Result<usize> {
+        match (self.reader.as_mut(), self.block_size) {
+            (Some(reader), _) => reader.block_size(),
+            (None, Some(block_size)) => Ok(block_size),
+            (None, None) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "read ahead thread panicked",
+            )),
+        }
+    }
+}
+
+impl<R: Read + Seek + Send + Sync, T: CryptoReadSeek<R> + 'static> Drop
+    for ReadAheadCryptoRead<R, T>
+{
+    fn drop(&mut self) {
+        if let Err(err) = self.stop() {
+            error!("error stopping read ahead thread: {}", err);
+        }
+    }
+}
+
+fn spawn_read_ahead<T: Read + Send + 'static>(
+    mut reader: T,
+    block_size: usize,
+    readahead: usize,
+) -> (JoinHandle<T>, BlockReceiver) {
+    let (tx, rx) = mpsc::sync_channel(readahead);
+    let handle = thread::spawn(move || {
+        loop {
+            let mut block = Zeroizing::new(vec![0; block_size]);
+            match stream_util::read(&mut reader, &mut block) {
+                Ok(0) => {
+                    // an empty block marks the end
+                    let _ = tx.send(Ok(Zeroizing::new(vec![])));
+                    break;
+                }
+                Ok(len) => {
+                    block.truncate(len);
+                    // the receiver is dropped when we need to stop
+                    if tx.send(Ok(block)).is_err() {
+                        break;
+                    }
+                }
+                Err(err) => {
+                    let _ = tx.send(Err(err));
+                    break;
+                }
+            }
+        }
+        reader
+    });
+    (handle, rx)
+}
@@ -411,3 +411,359 @@ fn test_ring_crypto_read_seek_in_second_block() {
         BLOCK_SIZE as u64
     );
 }
+
+#[test]
+#[traced_test]
+fn test_ring_crypto_read_short_block() {
+    use std::io::{Cursor, ErrorKind, Read};
+
+    use ring::aead::CHACHA20_POLY1305;
+    use secrecy::SecretVec;
+
+    use crate::crypto::read::RingCryptoRead;
+    use crate::crypto::write::BLOCK_SIZE;
+
+    let algorithm = &CHACHA20_POLY1305;
+    let key = SecretVec::new(vec![0; algorithm.key_len()]);
+    #[allow(clippy::cast_possible_truncation)]
+    let header = (BLOCK_SIZE as u32).to_le_bytes();
+
+    // shorter than the nonce
+    let mut reader = RingCryptoRead::new(
+        Cursor::new([&header[..], &[0; 5]].concat()),
+        algorithm,
+        &key,
+    );
+    let mut buf = vec![];
+    let err = reader.read_to_end(&mut buf).unwrap_err();
+    assert_eq!(ErrorKind::InvalidData, err.kind());
+
+    // has the nonce but not the tag
+    let mut reader = RingCryptoRead::new(
+        Cursor::new([&header[..], &[0; 20]].concat()),
+        algorithm,
+        &key,
+    );
+    let err = reader.read_to_end(&mut buf).unwrap_err();
+    assert_eq!(ErrorKind::InvalidData, err.kind());
+
+    // incomplete header
+    let mut reader = RingCryptoRead::new(Cursor::new(vec![1; 3]), algorithm, &key);
+    let err = reader.read_to_end(&mut buf).unwrap_err();
+    assert_eq!(ErrorKind::InvalidData, err.kind());
+
+    // invalid block size in header
+    let mut reader = RingCryptoRead::new(Cursor::new(vec![0; 40]), algorithm, &key);
+    let err = reader.read_to_end(&mut buf).unwrap_err();
+    assert_eq!(ErrorKind::InvalidData, err.kind());
+}
+
+#[test]
+#[traced_test]
+fn test_read_block_size_from_header() {
+    use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+
+    use rand::RngCore;
+    use ring::aead::CHACHA20_POLY1305;
+    use secrecy::SecretVec;
+
+    use crate::crypto::read::RingCryptoRead;
+    use crate::crypto::write::{CryptoWrite, RingCryptoWrite, RingCryptoWriteSeek, BLOCK_SIZE};
+
+    let algorithm = &CHACHA20_POLY1305;
+    let key = SecretVec::new(vec![0; algorithm.key_len()]);
+
+    let written_block_size = 777;
+    let mut data = vec![0; written_block_size * 5 + 42];
+    rand::thread_rng().fill_bytes(&mut data);
+    let mut writer = RingCryptoWrite::new_with_block_size(
+        Cursor::new(vec![]),
+        algorithm,
+        &key,
+        written_block_size,
+    );
+    writer.write_all(&data).unwrap();
+    let mut cursor = writer.finish().unwrap();
+
+    // readers configured with the default block size use the one from the header
+    assert_ne!(BLOCK_SIZE, written_block_size);
+    cursor.seek(SeekFrom::Start(0)).unwrap();
+    let mut reader = RingCryptoRead::new(&mut cursor, algorithm, &key);
+    let mut data2 = vec![];
+    reader.read_to_end(&mut data2).unwrap();
+    assert_eq!(data, data2);
+
+    cursor.seek(SeekFrom::Start(0)).unwrap();
+    let mut reader = RingCryptoRead::new_seek(&mut cursor, algorithm, &key);
+    let mut buf = vec![0; 10];
+    reader
+        .seek(SeekFrom::Start(written_block_size as u64 * 3 + 5))
+        .unwrap();
+    reader.read_exact(&mut buf).unwrap();
+    assert_eq!(
+        &data[written_block_size * 3 + 5..written_block_size * 3 + 15],
+        &buf[..]
+    );
+    assert_eq!(data.len() as u64, reader.seek(SeekFrom::End(0)).unwrap());
+
+    // and so does the writer when changing existing content
+    let mut writer = RingCryptoWriteSeek::new_with_block_size(cursor, algorithm, &key, BLOCK_SIZE);
+    writer
+        .seek(SeekFrom::Start(written_block_size as u64 * 2 + 1))
+        .unwrap();
+    writer.write_all(b"changed").unwrap();
+    writer.seek(SeekFrom::End(0)).unwrap();
+    writer.write_all(b"appended").unwrap();
+    let mut cursor = writer.finish().unwrap();
+    data[written_block_size * 2 + 1..written_block_size * 2 + 8].copy_from_slice(b"changed");
+    data.extend_from_slice(b"appended");
+
+    cursor.seek(SeekFrom::Start(0)).unwrap();
+    let mut reader = RingCryptoRead::new(cursor, algorithm, &key);
+    let mut data2 = vec![];
+    reader.read_to_end(&mut data2).unwrap();
+    assert_eq!(data, data2);
+}
+
+#[test]
+#[traced_test]
+fn test_read_changed_block_size_in_header() {
+    use std::io::{Cursor, ErrorKind, Read, Seek, SeekFrom, Write};
+
+    use ring::aead::CHACHA20_POLY1305;
+    use secrecy::SecretVec;
+
+    use crate::crypto::read::RingCryptoRead;
+    use crate::crypto::write::{CryptoWrite, RingCryptoWrite, BLOCK_SIZE, HEADER_LEN};
+
+    let algorithm = &CHACHA20_POLY1305;
+    let key = SecretVec::new(vec![0; algorithm.key_len()]);
+
+    // one partial block, it's read the same with a bigger block size
+    let mut writer = RingCryptoWrite::new(Cursor::new(vec![]), algorithm, &key);
+    writer.write_all(b"hello").unwrap();
+    let mut data = writer.finish().unwrap().into_inner();
+    #[allow(clippy::cast_possible_truncation)]
+    let header = (BLOCK_SIZE as u32 * 2).to_le_bytes();
+    data[..HEADER_LEN].copy_from_slice(&header);
+
+    let mut reader = RingCryptoRead::new(Cursor::new(data.clone()), algorithm, &key);
+    assert_eq!(
+        ErrorKind::InvalidData,
+        reader.read_to_end(&mut vec![]).unwrap_err().kind()
+    );
+    let mut reader = RingCryptoRead::new_seek(Cursor::new(data), algorithm, &key);
+    assert_eq!(
+        ErrorKind::InvalidData,
+        reader.seek(SeekFrom::Start(2)).unwrap_err().kind()
+    );
+}
+
+#[test]
+#[traced_test]
+fn test_read_ahead() {
+    use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+
+    use rand::RngCore;
+    use ring::aead::CHACHA20_POLY1305;
+    use secrecy::SecretVec;
+
+    use crate::crypto::read::{ReadAheadCryptoRead, RingCryptoRead};
+    use crate::crypto::write::{CryptoWrite, RingCryptoWrite, BLOCK_SIZE};
+
+    let algorithm = &CHACHA20_POLY1305;
+    let key = SecretVec::new(vec![0; algorithm.key_len()]);
+
+    let mut data = vec![0; BLOCK_SIZE * 10 + 42];
+    rand::thread_rng().fill_bytes(&mut data);
+    let mut writer = RingCryptoWrite::new(Cursor::new(vec![]), algorithm, &key);
+    writer.write_all(&data).unwrap();
+    let mut cursor = writer.finish().unwrap();
+    cursor.seek(SeekFrom::Start(0)).unwrap();
+
+    let reader = RingCryptoRead::new_seek(cursor, algorithm, &key);
+    let mut reader = ReadAheadCryptoRead::new(reader, 2);
+    let mut data2 = vec![];
+    reader.read_to_end(&mut data2).unwrap();
+    assert_eq!(data, data2);
+
+    // seek discards what was decrypted ahead
+    let mut buf = vec![0; 42];
+    reader.seek(SeekFrom::Start(BLOCK_SIZE as u64 + 7)).unwrap();
+    reader.read_exact(&mut buf).unwrap();
+    assert_eq!(&data[BLOCK_SIZE + 7..BLOCK_SIZE + 7 + 42], &buf[..]);
+    assert_eq!(
+        BLOCK_SIZE as u64 + 7,
+        reader.seek(SeekFrom::Current(-42)).unwrap()
+    );
+    let mut data2 = vec![];
+    reader.read_to_end(&mut data2).unwrap();
+    assert_eq!(&data[BLOCK_SIZE + 7..], &data2[..]);
+
+    // drop in the middle of reading stops the thread
+    reader.seek(SeekFrom::Start(0)).unwrap();
+    reader.read_exact(&mut buf).unwrap();
+    assert_eq!(&data[..42], &buf[..]);
+    drop(reader);
+}
+
+#[test]
+#[traced_test]
+fn test_read_ahead_block_size_from_header() {
+    use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+
+    use rand::RngCore;
+    use ring::aead::CHACHA20_POLY1305;
+    use secrecy::SecretVec;
+
+    use crate::crypto::read::{CryptoReadSeek, ReadAheadCryptoRead, RingCryptoRead};
+    use crate::crypto::write::{CryptoWrite, RingCryptoWrite, BLOCK_SIZE};
+
+    let algorithm = &CHACHA20_POLY1305;
+    let key = SecretVec::new(vec![0; algorithm.key_len()]);
+
+    let written_block_size = 777;
+    assert_ne!(BLOCK_SIZE, written_block_size);
+    let mut data = vec![0; written_block_size * 5 + 42];
+    rand::thread_rng().fill_bytes(&mut data);
+    let mut writer = RingCryptoWrite::new_with_block_size(
+        Cursor::new(vec![]),
+        algorithm,
+        &key,
+        written_block_size,
+    );
+    writer.write_all(&data).unwrap();
+    let mut cursor = writer.finish().unwrap();
+    cursor.seek(SeekFrom::Start(0)).unwrap();
+
+    let reader = RingCryptoRead::new_seek(cursor, algorithm, &key);
+    let mut reader = ReadAheadCryptoRead::new(reader, 2);
+    assert_eq!(written_block_size, reader.block_size().unwrap());
+    let mut buf = vec![0; 10];
+    reader.read_exact(&mut buf).unwrap();
+    assert_eq!(&data[..10], &buf[..]);
+    // blocks are decrypted ahead whole
+    assert_eq!(written_block_size, reader.buf.len());
+    assert_eq!(written_block_size, reader.block_size().unwrap());
+    let mut data2 = vec![];
+    reader.read_to_end(&mut data2).unwrap();
+    assert_eq!(&data[10..], &data2[..]);
+}
+
+#[test]
+#[traced_test]
+fn test_read_ahead_thread_panic() {
+    use std::io;
+    use std::io::{Cursor, Read, Seek, SeekFrom};
+
+    use crate::crypto::read::{CryptoRead, CryptoReadSeek, ReadAheadCryptoRead};
+
+    /// With `panic` it returns one block then panics, like a bug in the decryption would.
+    struct PanicReader(Cursor<Vec<u8>>, bool);
+
+    impl Read for PanicReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            assert!(!self.1 || self.0.position() == 0, "reader failed");
+            self.0.read(buf)
+        }
+    }
+
+    impl Seek for PanicReader {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.0.seek(pos)
+        }
+    }
+
+    impl CryptoRead<Cursor<Vec<u8>>> for PanicReader {
+        fn into_inner(&mut self) -> io::Result<Cursor<Vec<u8>>> {
+            Ok(self.0.clone())
+        }
+    }
+
+    impl CryptoReadSeek<Cursor<Vec<u8>>> for PanicReader {
+        fn block_size(&mut self) -> io::Result<usize> {
+            Ok(10)
+        }
+    }
+
+    let mut reader = ReadAheadCryptoRead::new(PanicReader(Cursor::new(vec![1; 42]), true), 2);
+    let mut buf = vec![0; 10];
+    reader.read_exact(&mut buf).unwrap();
+    assert_eq!(vec![1; 10], buf);
+    // not the end of the stream
+    assert!(reader.read(&mut buf).is_err());
+    assert!(reader.into_inner().is_err());
+
+    // the end reported by the thread is not an error
+    let mut reader = ReadAheadCryptoRead::new(PanicReader(Cursor::new(vec![1; 5]), false), 2);
+    let mut data = vec![];
+    reader.read_to_end(&mut data).unwrap();
+    assert_eq!(vec![1; 5], data);
+    assert_eq!(0, reader.read(&mut buf).unwrap());
+    assert_eq!(vec![1; 5], reader.into_inner().unwrap().into_inner());
+}
+
+#[test]
+#[traced_test]
+fn test_ring_crypto_read_moved_block() {
+    use std::io;
+    use std::io::{Cursor, ErrorKind, Read, Seek, SeekFrom, Write};
+
+    use ring::aead::{CHACHA20_POLY1305, NONCE_LEN};
+    use secrecy::SecretVec;
+
+    use crate::crypto::read::RingCryptoRead;
+    use crate::crypto::write::{CryptoWrite, RingCryptoWrite, BLOCK_SIZE, HEADER_LEN};
+
+    let algorithm = &CHACHA20_POLY1305;
+    let key = SecretVec::new(vec![0; algorithm.key_len()]);
+    let ciphertext_block_size = NONCE_LEN + BLOCK_SIZE + algorithm.tag_len();
+
+    // 3 full blocks with different content
+    let mut writer = RingCryptoWrite::new(Cursor::new(vec![]), algorithm, &key).with_file_id(7);
+    for b in [b'a', b'b', b'c'] {
+        writer.write_all(&[b; BLOCK_SIZE]).unwrap();
+    }
+    let data = writer.finish().unwrap().into_inner();
+    assert_eq!(HEADER_LEN + 3 * ciphertext_block_size, data.len());
+    let block = |i: usize| {
+        let start = HEADER_LEN + i * ciphertext_block_size;
+        data[start..start + ciphertext_block_size].to_vec()
+    };
+
+    let read = |data: Vec<u8>, pos: u64| -> io::Result<Vec<u8>> {
+        let mut reader = RingCryptoRead::new(Cursor::new(data), algorithm, &key).with_file_id(7);
+        reader.seek(SeekFrom::Start(pos))?;
+        let mut buf = vec![];
+        reader.read_to_end(&mut buf).map(|_| buf)
+    };
+    let plaintext = read(data.clone(), 0).unwrap();
+    assert_eq!(3 * BLOCK_SIZE, plaintext.len());
+    assert!(plaintext[BLOCK_SIZE..2 * BLOCK_SIZE]
+        .iter()
+        .all(|b| *b == b'b'));
+
+    // swap the first two blocks
+    let swapped = [&data[..HEADER_LEN], &block(1), &block(0), &block(2)].concat();
+    assert_eq!(
+        ErrorKind::InvalidData,
+        read(swapped.clone(), 0).unwrap_err().kind()
+    );
+    assert_eq!(
+        ErrorKind::InvalidData,
+        read(swapped, BLOCK_SIZE as u64).unwrap_err().kind()
+    );
+
+    // copy the first block over the last one, the start is still fine
+    let copied = [&data[..HEADER_LEN], &block(0), &block(1), &block(0)].concat();
+    assert_eq!(
+        ErrorKind::InvalidData,
+        read(copied.clone(), 2 * BLOCK_SIZE as u64)
+            .unwrap_err()
+            .kind()
+    );
+    let mut reader = RingCryptoRead::new(Cursor::new(copied), algorithm, &key).with_file_id(7);
+    let mut buf = vec![0; 2 * BLOCK_SIZE];
+    reader.read_exact(&mut buf).unwrap();
+    assert!(buf[..BLOCK_SIZE].iter().all(|b| *b == b'a'));
+}
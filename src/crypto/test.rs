@@ -0,0 +1,182 @@
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::str::FromStr;
+
+use rand::RngCore;
+use secrecy::{ExposeSecret, SecretString, SecretVec};
+#[allow(unused_imports)]
+use tracing_test::traced_test;
+
+use crate::crypto;
+use crate::crypto::write::{CryptoWrite, BLOCK_SIZE, HEADER_LEN};
+use crate::crypto::{Cipher, Error};
+
+#[test]
+fn test_cipher_from_str() {
+    for (s, cipher) in [
+        ("ChaCha20Poly1305", Cipher::ChaCha20Poly1305),
+        ("chacha20", Cipher::ChaCha20Poly1305),
+        ("CHACHA20-POLY1305", Cipher::ChaCha20Poly1305),
+        ("chacha20_poly1305", Cipher::ChaCha20Poly1305),
+        ("Aes256Gcm", Cipher::Aes256Gcm),
+        ("aes-256-gcm", Cipher::Aes256Gcm),
+        ("AES256", Cipher::Aes256Gcm),
+        ("aes-128-gcm", Cipher::Aes128Gcm),
+        ("aes128", Cipher::Aes128Gcm),
+    ] {
+        assert_eq!(cipher, Cipher::from_str(s).unwrap(), "{s}");
+    }
+    // what we print can be parsed back
+    for cipher in [
+        Cipher::ChaCha20Poly1305,
+        Cipher::Aes256Gcm,
+        Cipher::Aes128Gcm,
+    ] {
+        assert_eq!(cipher, cipher.to_string().parse().unwrap());
+    }
+
+    let err = Cipher::from_str("des").unwrap_err();
+    assert!(matches!(err, Error::GenericString(_)));
+    assert!(err.to_string().contains("unknown cipher 'des'"));
+    assert!(Cipher::from_str("").is_err());
+}
+
+#[test]
+#[traced_test]
+fn test_cipher_default_is_authenticated() {
+    let cipher = Cipher::default();
+    assert_eq!(Cipher::ChaCha20Poly1305, cipher);
+
+    let mut key = vec![0; cipher.key_len()];
+    rand::thread_rng().fill_bytes(&mut key);
+    let key = SecretVec::new(key);
+
+    let mut writer = crypto::create_write(io::Cursor::new(vec![]), cipher, &key);
+    writer.write_all(b"authenticated").unwrap();
+    let mut cursor = writer.finish().unwrap();
+
+    // flip a bit of the ciphertext, reading it should fail instead of returning garbage
+    let data = cursor.get_mut();
+    let last = data.len() - 1;
+    assert!(last > HEADER_LEN);
+    data[last] ^= 1;
+    cursor.seek(SeekFrom::Start(0)).unwrap();
+    let mut reader = crypto::create_read(cursor, cipher, &key);
+    let mut buf = vec![];
+    assert!(reader.read_to_end(&mut buf).is_err());
+}
+
+#[test]
+#[traced_test]
+fn test_blocks_bound_to_file_id() {
+    let cipher = Cipher::ChaCha20Poly1305;
+    let mut key = vec![0; cipher.key_len()];
+    rand::thread_rng().fill_bytes(&mut key);
+    let key = SecretVec::new(key);
+
+    let mut writer = crypto::create_write_for_file(io::Cursor::new(vec![]), cipher, &key, 42);
+    writer.write_all(b"content of 42").unwrap();
+    let data = writer.finish().unwrap().into_inner();
+
+    // same id
+    let mut reader = crypto::create_read_for_file(io::Cursor::new(data.clone()), cipher, &key, 42);
+    let mut buf = String::new();
+    reader.read_to_string(&mut buf).unwrap();
+    assert_eq!("content of 42", buf);
+
+    // as if the content was moved to another file
+    let mut reader = crypto::create_read_for_file(io::Cursor::new(data.clone()), cipher, &key, 43);
+    assert!(reader.read_to_end(&mut vec![]).is_err());
+    let mut reader =
+        crypto::create_read_seek_for_file(io::Cursor::new(data.clone()), cipher, &key, 43);
+    assert!(reader.read_to_end(&mut vec![]).is_err());
+    // or read without the id
+    let mut reader = crypto::create_read(io::Cursor::new(data), cipher, &key);
+    assert!(reader.read_to_end(&mut vec![]).is_err());
+}
+
+#[test]
+fn test_ciphertext_len() {
+    for cipher in [
+        Cipher::ChaCha20Poly1305,
+        Cipher::Aes256Gcm,
+        Cipher::Aes128Gcm,
+    ] {
+        let mut key = vec![0; cipher.key_len()];
+        rand::thread_rng().fill_bytes(&mut key);
+        let key = SecretVec::new(key);
+        for len in [0, 1, BLOCK_SIZE - 1, BLOCK_SIZE, BLOCK_SIZE * 2 + 3] {
+            let mut writer = crypto::create_write(io::Cursor::new(vec![]), cipher, &key);
+            writer.write_all(&vec![1; len]).unwrap();
+            let data = writer.finish().unwrap().into_inner();
+            assert_eq!(
+                data.len() as u64,
+                cipher.ciphertext_len(len as u64),
+                "{cipher} {len}"
+            );
+        }
+    }
+}
+
+#[test]
+fn test_file_name() {
+    let cipher = Cipher::ChaCha20Poly1305;
+    let mut key = vec![0; cipher.key_len()];
+    rand::thread_rng().fill_bytes(&mut key);
+    let key = SecretVec::new(key);
+    let mut other_key = vec![0; cipher.key_len()];
+    rand::thread_rng().fill_bytes(&mut other_key);
+    let other_key = SecretVec::new(other_key);
+    let name = SecretString::from_str("file-name").unwrap();
+
+    // lookup hash is deterministic per key
+    let hash = crypto::hash_file_name(&name, &key);
+    assert_eq!(hash, crypto::hash_file_name(&name, &key));
+    assert_ne!(
+        hash,
+        crypto::hash_file_name(&SecretString::from_str("file-name2").unwrap(), &key)
+    );
+    assert_ne!(hash, crypto::hash_file_name(&name, &other_key));
+    // the MAC key is derived, it's not the encryption key
+    let raw_key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, key.expose_secret());
+    assert_ne!(
+        hash,
+        hex::encode(ring::hmac::sign(&raw_key, name.expose_secret().as_bytes()))
+    );
+
+    // encrypted name decrypts only with the same key
+    let encrypted = crypto::encrypt_file_name(&name, cipher, &key).unwrap();
+    assert_eq!(
+        "file-name",
+        crypto::decrypt_file_name(&encrypted, cipher, &key)
+            .unwrap()
+            .expose_secret()
+    );
+    assert!(crypto::decrypt_file_name(&encrypted, cipher, &other_key).is_err());
+
+    // tampering is detected
+    let mut tampered = encrypted.into_bytes();
+    let mid = tampered.len() / 2;
+    tampered[mid] = if tampered[mid] == b'A' { b'B' } else { b'A' };
+    let tampered = String::from_utf8(tampered).unwrap();
+    assert!(crypto::decrypt_file_name(&tampered, cipher, &key).is_err());
+}
+
+#[test]
+fn test_encrypted_file_name_len() {
+    for cipher in [Cipher::ChaCha20Poly1305, Cipher::Aes128Gcm] {
+        let mut key = vec![0; cipher.key_len()];
+        rand::thread_rng().fill_bytes(&mut key);
+        let key = SecretVec::new(key);
+        for len in [1, 2, 3, BLOCK_SIZE, BLOCK_SIZE + 1, 200] {
+            let name = SecretString::new("a".repeat(len));
+            assert_eq!(
+                crypto::encrypt_file_name(&name, cipher, &key)
+                    .unwrap()
+                    .len(),
+                crypto::encrypted_file_name_len(&name, cipher),
+                "{cipher} {len}"
+            );
+        }
+    }
+}
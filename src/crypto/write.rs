@@ -2,18 +2,18 @@ use std::io;
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::sync::{Arc, Mutex};
 
-use bytes::Buf;
 use rand_chacha::rand_core::RngCore;
 use ring::aead::{
     Aad, Algorithm, BoundKey, Nonce, NonceSequence, OpeningKey, SealingKey, UnboundKey, NONCE_LEN,
 };
 use ring::error::Unspecified;
+use secrecy::zeroize::Zeroizing;
 use secrecy::{ExposeSecret, SecretVec};
 use tracing::error;
 
 use crate::crypto::buf_mut::BufMut;
 use crate::crypto::read::ExistingNonceSequence;
-use crate::{crypto, decrypt_block, stream_util};
+use crate::{crypto, stream_util};
 
 mod bench;
 mod test;
@@ -23,6 +23,17 @@ pub(crate) const BLOCK_SIZE: usize = 100; // round value easier for debugging
 #[cfg(not(test))]
 pub(crate) const BLOCK_SIZE: usize = 16 * 1024; // 16 KB block size
 
+/// Each stream starts with a header holding the plaintext block size it was written with,
+/// as a `u32` in little endian, so readers can use it regardless of their configured block size.
+pub(crate) const HEADER_LEN: usize = 4;
+/// Max block size accepted from a header, so a corrupted one doesn't make us allocate a lot.
+pub(crate) const MAX_BLOCK_SIZE: usize = 64 * 1024 * 1024;
+/// Max length of plaintext left as consecutive holes, longer gaps get a block of zeros after each run.
+///
+/// A hole is checked with the block after its run, which has the number of holes before it
+/// in the associated data, this bounds how much we need to read for that.
+pub(crate) const MAX_HOLES_LEN: u64 = 4 * 1024 * 1024;
+
 /// Writes encrypted content to the wrapped Writer.
 #[allow(clippy::module_name_repetitions)]
 pub trait CryptoWrite<W: Write + Send + Sync>: Write + Send + Sync {
@@ -42,31 +53,94 @@ pub struct RingCryptoWrite<W: Write> {
     ciphertext_block_size: usize,
     plaintext_block_size: usize,
     block_index: u64,
+    header_written: bool,
+    file_id: u64,
+    /// Holes right before the current block, see [`block_aad`].
+    holes_before: u64,
 }
 
 impl<W: Write> RingCryptoWrite<W> {
     #[allow(clippy::missing_panics_doc)]
-    #[allow(clippy::needless_pass_by_value)]
     pub fn new(writer: W, algorithm: &'static Algorithm, key: &SecretVec<u8>) -> Self {
+        Self::new_with_block_size(writer, algorithm, key, BLOCK_SIZE)
+    }
+
+    /// Like [`Self::new`] but encrypts in blocks of `block_size` plaintext bytes instead of the default.
+    ///
+    /// Content must be read with the same block size it was written with.
+    ///
+    /// # Panics
+    ///
+    /// If `block_size` is 0 or greater than `MAX_BLOCK_SIZE`.
+    #[allow(clippy::needless_pass_by_value)]
+    pub fn new_with_block_size(
+        writer: W,
+        algorithm: &'static Algorithm,
+        key: &SecretVec<u8>,
+        block_size: usize,
+    ) -> Self {
+        assert!(
+            block_size > 0 && block_size <= MAX_BLOCK_SIZE,
+            "block size must be greater than 0 and at most {MAX_BLOCK_SIZE}"
+        );
         let unbound_key = UnboundKey::new(algorithm, key.expose_secret()).expect("unbound key");
         let nonce_sequence = Arc::new(Mutex::new(RandomNonceSequence::default()));
         let wrapping_nonce_sequence = RandomNonceSequenceWrapper::new(nonce_sequence.clone());
         let sealing_key = SealingKey::new(unbound_key, wrapping_nonce_sequence);
-        let buf = BufMut::new(vec![0; BLOCK_SIZE]);
+        let buf = BufMut::new(vec![0; block_size]);
         Self {
             out: Some(writer),
             sealing_key,
             buf,
             nonce_sequence,
-            ciphertext_block_size: NONCE_LEN + BLOCK_SIZE + algorithm.tag_len(),
-            plaintext_block_size: BLOCK_SIZE,
+            ciphertext_block_size: NONCE_LEN + block_size + algorithm.tag_len(),
+            plaintext_block_size: block_size,
             block_index: 0,
+            header_written: false,
+            file_id: 0,
+            holes_before: 0,
         }
     }
 
+    /// Binds the blocks to `file_id`, like the inode, the content can be read only by a reader
+    /// with the same id, so blocks moved from another file fail to decrypt.
+    #[must_use]
+    pub const fn with_file_id(mut self, file_id: u64) -> Self {
+        self.file_id = file_id;
+        self
+    }
+
+    /// Offset of the block in the ciphertext stream.
+    const fn block_offset(&self, block_index: u64) -> u64 {
+        HEADER_LEN as u64 + block_index * self.ciphertext_block_size as u64
+    }
+
+    fn set_block_size(&mut self, block_size: usize) {
+        self.ciphertext_block_size =
+            self.ciphertext_block_size - self.plaintext_block_size + block_size;
+        self.plaintext_block_size = block_size;
+        self.buf = BufMut::new(vec![0; block_size]);
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        #[allow(clippy::cast_possible_truncation)]
+        let header = (self.plaintext_block_size as u32).to_le_bytes();
+        self.out.as_mut().unwrap().write_all(&header)?;
+        self.header_written = true;
+        Ok(())
+    }
+
     fn encrypt_and_write(&mut self) -> io::Result<()> {
+        if !self.header_written {
+            self.write_header()?;
+        }
         let data = self.buf.as_mut();
-        let aad = Aad::from(self.block_index.to_le_bytes());
+        let aad = block_aad(
+            self.file_id,
+            self.plaintext_block_size,
+            self.block_index,
+            self.holes_before,
+        );
         let tag = self
             .sealing_key
             .seal_in_place_separate_tag(aad, data)
@@ -83,8 +157,10 @@ impl<W: Write> RingCryptoWrite<W> {
         self.out.as_mut().unwrap().write_all(data)?;
         self.buf.clear();
         self.out.as_mut().unwrap().write_all(tag.as_ref())?;
-        self.out.as_mut().unwrap().flush()?;
+        // we don't flush the inner writer here, that's done on `flush` and `finish`,
+        // so buffered writers can batch more blocks in one syscall
         self.block_index += 1;
+        self.holes_before = 0;
         Ok(())
     }
 }
@@ -98,7 +174,7 @@ impl<W: Write> Write for RingCryptoWrite<W> {
             ));
         }
         if self.buf.is_dirty() && self.buf.remaining() == 0 {
-            self.flush()?;
+            self.encrypt_and_write()?;
         }
         let len = self.buf.write(buf)?;
         Ok(len)
@@ -111,15 +187,12 @@ impl<W: Write> Write for RingCryptoWrite<W> {
                 "flush called on already finished writer",
             ));
         }
-        if !self.buf.is_dirty() {
-            return Ok(());
-        }
-        // encrypt and write when we have a full buffer
-        if self.buf.remaining() == 0 {
+        // encrypt and write when we have a full buffer,
+        // a partial block is written only on `finish`
+        if self.buf.is_dirty() && self.buf.remaining() == 0 {
             self.encrypt_and_write()?;
         }
-
-        Ok(())
+        self.out.as_mut().unwrap().flush()
     }
 }
 
@@ -183,87 +256,462 @@ impl NonceSequence for RandomNonceSequenceWrapper {
     }
 }
 
+/// Associated data of a block, binds it to the file and to its position in the file.
+///
+/// It has also the number of holes right before the block, that's how the holes are authenticated,
+/// as they are not encrypted, the underlying file reads them back as zeros.
+/// The block size from the header is included too, so changing it there fails the decryption.
+#[allow(clippy::cast_possible_truncation)]
+pub(crate) fn block_aad(
+    file_id: u64,
+    block_size: usize,
+    block_index: u64,
+    holes_before: u64,
+) -> Aad<[u8; 28]> {
+    let mut aad = [0; 28];
+    aad[..8].copy_from_slice(&file_id.to_le_bytes());
+    aad[8..16].copy_from_slice(&block_index.to_le_bytes());
+    aad[16..24].copy_from_slice(&holes_before.to_le_bytes());
+    // it fits, it's checked against `MAX_BLOCK_SIZE`
+    aad[24..].copy_from_slice(&(block_size as u32).to_le_bytes());
+    Aad::from(aad)
+}
+
+/// Max number of consecutive holes for `block_size`, see [`MAX_HOLES_LEN`].
+pub(crate) fn max_holes(block_size: usize) -> u64 {
+    (MAX_HOLES_LEN / block_size as u64).max(1)
+}
+
+/// If the block read from the stream is a hole, a whole block that was never written.
+pub(crate) fn is_hole(block: &[u8], ciphertext_block_size: usize) -> bool {
+    block.len() == ciphertext_block_size && block.iter().all(|b| *b == 0)
+}
+
+/// Counts the holes right before `block_index`, but not more than `max + 1`.
+///
+/// The position of the stream is changed.
+pub(crate) fn count_holes_before<R: Read + Seek>(
+    input: &mut R,
+    block_index: u64,
+    ciphertext_block_size: usize,
+    max: u64,
+) -> io::Result<u64> {
+    let mut block = vec![0; ciphertext_block_size];
+    let mut holes = 0;
+    while holes < block_index && holes <= max {
+        let offset = HEADER_LEN as u64 + (block_index - holes - 1) * ciphertext_block_size as u64;
+        input.seek(SeekFrom::Start(offset))?;
+        let len = stream_util::read(&mut *input, &mut block)?;
+        if !is_hole(&block[..len], ciphertext_block_size) {
+            break;
+        }
+        holes += 1;
+    }
+    Ok(holes)
+}
+
+/// Decrypts in place a block read from the stream, the plaintext is after the nonce.
+///
+/// Returns the length of the plaintext.
+pub(crate) fn open_block<A: AsRef<[u8]>>(
+    opening_key: &mut OpeningKey<ExistingNonceSequence>,
+    last_nonce: &Mutex<Option<Vec<u8>>>,
+    aad: Aad<A>,
+    data: &mut [u8],
+) -> io::Result<usize> {
+    if data.len() < NONCE_LEN + opening_key.algorithm().tag_len() {
+        error!(
+            len = data.len(),
+            "block is too short to hold the nonce and tag"
+        );
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "block is too short to hold the nonce and tag",
+        ));
+    }
+    last_nonce
+        .lock()
+        .unwrap()
+        .replace(data[..NONCE_LEN].to_vec());
+    let plaintext = opening_key
+        .open_within(aad, &mut data[NONCE_LEN..], 0..)
+        .map_err(|err| {
+            error!("error opening within: {}", err);
+            io::Error::new(io::ErrorKind::InvalidData, "error opening within")
+        })?;
+    Ok(plaintext.len())
+}
+
+/// Reads the block size from the header at the current position of the stream.
+pub(crate) fn read_header<R: Read>(input: &mut R) -> io::Result<usize> {
+    let mut header = [0; HEADER_LEN];
+    input.read_exact(&mut header).map_err(|err| {
+        error!("cannot read header: {err}");
+        io::Error::new(io::ErrorKind::InvalidData, "cannot read header")
+    })?;
+    let block_size = u32::from_le_bytes(header) as usize;
+    if block_size == 0 || block_size > MAX_BLOCK_SIZE {
+        error!(block_size, "invalid block size in header");
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid block size in header",
+        ));
+    }
+    Ok(block_size)
+}
+
 /// Write with Seek
 
-pub trait CryptoWriteSeek<W: Write + Seek + Send + Sync>: CryptoWrite<W> + Seek {}
+pub trait CryptoWriteSeek<W: Write + Seek + Send + Sync>: CryptoWrite<W> + Seek {
+    /// Write to the wrapped writer what we have in the buffer, also if it's a partial block,
+    /// so it can be read from there. Unlike [`CryptoWrite::finish`] we can keep writing after, from the
+    /// same position.
+    ///
+    /// The wrapped writer is flushed, but not synced.
+    #[allow(clippy::missing_errors_doc)]
+    fn flush_pending(&mut self) -> io::Result<()>;
+}
 
 pub struct RingCryptoWriteSeek<W: Write + Seek + Read> {
     inner: RingCryptoWrite<W>,
     opening_key: OpeningKey<ExistingNonceSequence>,
     last_nonce: Arc<Mutex<Option<Vec<u8>>>>,
     decrypt_buf: BufMut,
+    /// Last run of holes we checked, the start and the index of the block after it.
+    hole_run: Option<(u64, u64)>,
+    /// If the holes before the current block are known, they are not after we seek to another block,
+    /// then we count them from the stream.
+    holes_before_known: bool,
 }
 
 impl<W: Write + Seek + Read> RingCryptoWriteSeek<W> {
-    pub(crate) fn new(writer: W, algorithm: &'static Algorithm, key: &SecretVec<u8>) -> Self {
+    /// See [`RingCryptoWrite::new_with_block_size`].
+    pub(crate) fn new_with_block_size(
+        writer: W,
+        algorithm: &'static Algorithm,
+        key: &SecretVec<u8>,
+        block_size: usize,
+    ) -> Self {
+        let inner = RingCryptoWrite::new_with_block_size(writer, algorithm, key, block_size);
         let last_nonce = Arc::new(Mutex::new(None));
         let unbound_key = UnboundKey::new(algorithm, key.expose_secret()).unwrap();
         let nonce_sequence = ExistingNonceSequence::new(last_nonce.clone());
         let opening_key = OpeningKey::new(unbound_key, nonce_sequence);
-        let ciphertext_block_size = NONCE_LEN + BLOCK_SIZE + algorithm.tag_len();
-        let decrypt_buf = BufMut::new(vec![0; ciphertext_block_size]);
+        let decrypt_buf = BufMut::new(vec![0; inner.ciphertext_block_size]);
         Self {
-            inner: RingCryptoWrite::new(writer, algorithm, key),
+            inner,
             opening_key,
             last_nonce,
             decrypt_buf,
+            hole_run: None,
+            holes_before_known: true,
         }
     }
 
+    /// See [`RingCryptoWrite::with_file_id`].
+    #[must_use]
+    pub(crate) const fn with_file_id(mut self, file_id: u64) -> Self {
+        self.inner.file_id = file_id;
+        self
+    }
+
     const fn pos(&self) -> u64 {
         self.inner.block_index * self.inner.plaintext_block_size as u64
             + self.inner.buf.pos_write() as u64
     }
 
-    fn decrypt_block(&mut self) -> io::Result<bool> {
-        let old_block_index = self.inner.block_index;
-        decrypt_block!(
+    /// Reads the header of an existing stream and switches to the block size it was written with.
+    fn read_header(&mut self) -> io::Result<()> {
+        if self.inner.header_written {
+            return Ok(());
+        }
+        let out = self.inner.out.as_mut().unwrap();
+        if out.stream_len()? == 0 {
+            // a new stream, the header is written with the first block
+            return Ok(());
+        }
+        out.seek(SeekFrom::Start(0))?;
+        let block_size = read_header(out)?;
+        if block_size != self.inner.plaintext_block_size {
+            self.inner.set_block_size(block_size);
+            self.decrypt_buf = BufMut::new(vec![0; self.inner.ciphertext_block_size]);
+        }
+        self.inner.header_written = true;
+        Ok(())
+    }
+
+    /// Makes sure the header is written before we write any block,
+    /// as we might not write the first block first.
+    fn ensure_header(&mut self) -> io::Result<()> {
+        self.read_header()?;
+        if !self.inner.header_written {
+            self.inner.out.as_mut().unwrap().seek(SeekFrom::Start(0))?;
+            self.inner.write_header()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the current block.
+    ///
+    /// If it was a hole, the block after the run is sealed again, as it has fewer holes before it now.
+    fn encrypt_and_write(&mut self) -> io::Result<()> {
+        let block_index = self.inner.block_index;
+        self.inner.encrypt_and_write()?;
+        if let Some((start, end)) = self.hole_run {
+            if (start..end).contains(&block_index) {
+                self.reseal_block(end, end - start, end - block_index - 1)?;
+                self.hole_run = (block_index + 1 < end).then_some((block_index + 1, end));
+            }
+        }
+        Ok(())
+    }
+
+    /// Seals again the block at `block_index` with a different number of holes before it.
+    fn reseal_block(
+        &mut self,
+        block_index: u64,
+        holes_before: u64,
+        new_holes_before: u64,
+    ) -> io::Result<()> {
+        let offset = self.inner.block_offset(block_index);
+        let file_id = self.inner.file_id;
+        let block_size = self.inner.plaintext_block_size;
+        let out = self.inner.out.as_mut().unwrap();
+        let pos = out.stream_position()?;
+        out.seek(SeekFrom::Start(offset))?;
+        let mut block = Zeroizing::new(vec![0; self.inner.ciphertext_block_size]);
+        let len = stream_util::read(&mut *out, &mut block)?;
+        let plaintext_len = open_block(
+            &mut self.opening_key,
+            &self.last_nonce,
+            block_aad(file_id, block_size, block_index, holes_before),
+            &mut block[..len],
+        )?;
+        let data = &mut block[NONCE_LEN..NONCE_LEN + plaintext_len];
+        let tag = self
+            .inner
+            .sealing_key
+            .seal_in_place_separate_tag(
+                block_aad(file_id, block_size, block_index, new_holes_before),
+                data,
+            )
+            .map_err(|err| {
+                error!("error sealing in place: {}", err);
+                io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("error sealing in place: {err}"),
+                )
+            })?;
+        let nonce = self.inner.nonce_sequence.lock().unwrap().last_nonce.clone();
+        out.seek(SeekFrom::Start(offset))?;
+        out.write_all(nonce.as_ref().unwrap())?;
+        out.write_all(data)?;
+        out.write_all(tag.as_ref())?;
+        out.seek(SeekFrom::Start(pos))?;
+        Ok(())
+    }
+
+    /// Finds the run of holes `block_index` is in, it's checked with the block after it.
+    ///
+    /// Returns the start of the run and the index of the block after it.
+    fn find_hole_run(&mut self, block_index: u64) -> io::Result<(u64, u64)> {
+        let ciphertext_block_size = self.inner.ciphertext_block_size;
+        let max_holes = max_holes(self.inner.plaintext_block_size);
+        let file_id = self.inner.file_id;
+        let block_size = self.inner.plaintext_block_size;
+        let holes_before = self.known_holes_before()?;
+        let out = self.inner.out.as_mut().unwrap();
+        let start = block_index - holes_before;
+        let mut block = Zeroizing::new(vec![0; ciphertext_block_size]);
+        let mut end = block_index + 1;
+        loop {
+            if end - start > max_holes {
+                error!(start, "too many consecutive holes");
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "too many consecutive holes",
+                ));
+            }
+            let offset = HEADER_LEN as u64 + end * ciphertext_block_size as u64;
+            out.seek(SeekFrom::Start(offset))?;
+            let len = stream_util::read(&mut *out, &mut block)?;
+            if len == 0 {
+                error!(start, "holes at the end of the stream");
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "holes at the end of the stream",
+                ));
+            }
+            if !is_hole(&block[..len], ciphertext_block_size) {
+                open_block(
+                    &mut self.opening_key,
+                    &self.last_nonce,
+                    block_aad(file_id, block_size, end, end - start),
+                    &mut block[..len],
+                )?;
+                return Ok((start, end));
+            }
+            end += 1;
+        }
+    }
+
+    /// Holes before the current block.
+    ///
+    /// While we move sequentially we know them, after we seek to another block we count them
+    /// back from the stream. The position of the stream is changed.
+    fn known_holes_before(&mut self) -> io::Result<u64> {
+        if self.holes_before_known {
+            return Ok(self.inner.holes_before);
+        }
+        count_holes_before(
+            self.inner.out.as_mut().unwrap(),
             self.inner.block_index,
-            self.decrypt_buf,
+            self.inner.ciphertext_block_size,
+            max_holes(self.inner.plaintext_block_size),
+        )
+    }
+
+    /// Reads the block at the current position in the buffer, decrypted.
+    ///
+    /// Returns `false` if there is no block.
+    fn decrypt_block(&mut self) -> io::Result<bool> {
+        let block_index = self.inner.block_index;
+        let ciphertext_block_size = self.inner.ciphertext_block_size;
+        self.decrypt_buf.clear();
+        let len = stream_util::read(
             self.inner.out.as_mut().unwrap(),
-            self.last_nonce,
-            self.opening_key
-        );
-        if old_block_index == self.inner.block_index {
-            // no decryption happened
-            Ok(false)
+            self.decrypt_buf.as_mut_remaining(),
+        )?;
+        if len == 0 {
+            // we are at the end, the last block is never a hole
+            self.inner.holes_before = 0;
+            self.holes_before_known = true;
+            return Ok(false);
+        }
+        let plaintext_len = if is_hole(
+            &self.decrypt_buf.as_mut_remaining()[..len],
+            ciphertext_block_size,
+        ) {
+            let (start, end) = match self.hole_run {
+                Some((start, end)) if (start..end).contains(&block_index) => (start, end),
+                _ => self.find_hole_run(block_index)?,
+            };
+            self.hole_run = Some((start, end));
+            self.inner.holes_before = block_index - start;
+            // the plaintext is all zeros, like the hole
+            self.inner.plaintext_block_size
         } else {
-            // a decryption happened
+            let holes_before = self.known_holes_before()?;
+            let plaintext_len = open_block(
+                &mut self.opening_key,
+                &self.last_nonce,
+                block_aad(
+                    self.inner.file_id,
+                    self.inner.plaintext_block_size,
+                    block_index,
+                    holes_before,
+                ),
+                &mut self.decrypt_buf.as_mut_remaining()[..len],
+            )?;
+            self.inner.holes_before = holes_before;
+            plaintext_len
+        };
+        self.holes_before_known = true;
+        // bring back file pos so the next writing will write to the same block
+        let offset = self.inner.block_offset(block_index);
+        self.inner
+            .out
+            .as_mut()
+            .unwrap()
+            .seek(SeekFrom::Start(offset))?;
+        // copy plaintext
+        self.inner.buf.clear();
+        self.inner
+            .buf
+            .seek_available(SeekFrom::Start(plaintext_len as u64))?;
+        self.inner.buf.as_mut()[..plaintext_len].copy_from_slice(
+            &self.decrypt_buf.as_mut_remaining()[NONCE_LEN..NONCE_LEN + plaintext_len],
+        );
+        Ok(true)
+    }
+
+    /// Extends the content with zeros until `new_pos`, which is past the end.
+    ///
+    /// The current block is completed with zeros, but whole blocks in between are not written,
+    /// we just seek over them and leave a hole in the underlying file, which reads back as zeros.
+    /// The block after the holes has their number in the associated data, to authenticate them,
+    /// and after each [`MAX_HOLES_LEN`] we write a block of zeros, so the runs are not too long.
+    fn fill_sparse(&mut self, new_pos: u64) -> io::Result<()> {
+        self.ensure_header()?;
+        let plaintext_block_size = self.inner.plaintext_block_size as u64;
+        // complete current block
+        let pos = self.pos();
+        let block_end = pos.next_multiple_of(plaintext_block_size);
+        stream_util::fill_zeros(&mut self.inner, new_pos.min(block_end) - pos)?;
+        if self.pos() == new_pos {
+            return Ok(());
+        }
+        if self.inner.buf.is_dirty() {
+            self.encrypt_and_write()?;
+        } else if self.inner.buf.available() > 0 {
+            // unchanged full block, just move past it
             self.inner.buf.clear();
-            // bring back block index to current block, it's incremented by decrypt_block if it can decrypt something
-            self.inner.block_index -= 1;
-            // bring back file pos also so the next writing will write to the same block
-            self.inner.out.as_mut().unwrap().seek(SeekFrom::Start(
-                self.inner.block_index * self.inner.ciphertext_block_size as u64,
-            ))?;
-            // copy plaintext
-            self.inner
-                .buf
-                .seek_available(SeekFrom::Start(self.decrypt_buf.available_read() as u64))?;
-            self.decrypt_buf
-                .as_ref_read_available()
-                .copy_to_slice(&mut self.inner.buf.as_mut()[..self.decrypt_buf.available_read()]);
-            Ok(true)
+            self.inner.block_index += 1;
         }
+        // skip whole blocks, but if the new position is at a block boundary we write the last one,
+        // so the underlying file is extended until there
+        let mut holes = new_pos / plaintext_block_size - self.inner.block_index;
+        if new_pos % plaintext_block_size == 0 {
+            holes -= 1;
+        }
+        let max_holes = max_holes(self.inner.plaintext_block_size);
+        while holes > max_holes {
+            self.skip_holes(max_holes)?;
+            stream_util::fill_zeros(&mut self.inner, plaintext_block_size)?;
+            self.encrypt_and_write()?;
+            holes -= max_holes + 1;
+        }
+        self.skip_holes(holes)?;
+        let len = new_pos - self.pos();
+        stream_util::fill_zeros(&mut self.inner, len)
+    }
+
+    /// Moves to the block after `holes` blocks, which are left unwritten.
+    fn skip_holes(&mut self, holes: u64) -> io::Result<()> {
+        self.inner.block_index += holes;
+        self.inner.holes_before = holes;
+        let offset = self.inner.block_offset(self.inner.block_index);
+        self.inner
+            .out
+            .as_mut()
+            .unwrap()
+            .seek(SeekFrom::Start(offset))?;
+        Ok(())
     }
 
     fn get_plaintext_len(&mut self) -> io::Result<u64> {
-        let ciphertext_len = self.inner.out.as_mut().unwrap().stream_len()?;
+        let ciphertext_len = self
+            .inner
+            .out
+            .as_mut()
+            .unwrap()
+            .stream_len()?
+            .saturating_sub(HEADER_LEN as u64);
         if ciphertext_len == 0 && self.inner.buf.available() == 0 {
             return Ok(0);
         }
-        let stream_last_block_index = self.inner.out.as_mut().unwrap().stream_len()?
-            / self.inner.ciphertext_block_size as u64;
-        let plaintext_len = if self.inner.block_index == stream_last_block_index
+        let stream_last_block_index = ciphertext_len / self.inner.ciphertext_block_size as u64;
+        let plaintext_len = if self.inner.block_index >= stream_last_block_index
             && self.inner.buf.is_dirty()
         {
-            // we are at the last block, we consider what we have in buffer,
-            // as we might have additional content that is not written yet
+            // we are at the last block, or after the holes past it, we consider what we have in
+            // buffer, as we might have additional content that is not written yet
             self.inner.block_index * self.inner.plaintext_block_size as u64
                 + self.inner.buf.available() as u64
         } else {
+            // the last block might be partial, each one has the nonce and tag overhead
             ciphertext_len
-                - ((ciphertext_len / self.inner.ciphertext_block_size as u64) + 1)
+                - ciphertext_len.div_ceil(self.inner.ciphertext_block_size as u64)
                     * (self.inner.ciphertext_block_size - self.inner.plaintext_block_size) as u64
         };
         Ok(plaintext_len)
@@ -274,6 +722,7 @@ impl<W: Write + Seek + Read> Seek for RingCryptoWriteSeek<W> {
     #[allow(clippy::cast_possible_wrap)]
     #[allow(clippy::cast_sign_loss)]
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.read_header()?;
         let new_pos = match pos {
             SeekFrom::Start(pos) => pos as i64,
             SeekFrom::End(pos) => self.get_plaintext_len()? as i64 + pos,
@@ -291,7 +740,12 @@ impl<W: Write + Seek + Read> Seek for RingCryptoWriteSeek<W> {
         if current_block_index == new_block_index {
             if self.pos() == 0 && self.inner.buf.available() == 0 {
                 // first write since we opened the writer, try to load the first block
-                self.inner.out.as_mut().unwrap().seek(SeekFrom::Start(0))?;
+                let offset = self.inner.block_offset(0);
+                self.inner
+                    .out
+                    .as_mut()
+                    .unwrap()
+                    .seek(SeekFrom::Start(offset))?;
                 self.inner.block_index = 0;
                 self.decrypt_block()?;
             }
@@ -305,7 +759,7 @@ impl<W: Write + Seek + Read> Seek for RingCryptoWriteSeek<W> {
             {
                 // write current block
                 if self.inner.buf.is_dirty() {
-                    self.inner.encrypt_and_write()?;
+                    self.encrypt_and_write()?;
                 }
                 // decrypt the next block
                 self.decrypt_block()?;
@@ -320,17 +774,29 @@ impl<W: Write + Seek + Read> Seek for RingCryptoWriteSeek<W> {
 
             // write current block
             if self.inner.buf.is_dirty() {
-                self.inner.encrypt_and_write()?;
+                self.encrypt_and_write()?;
             }
 
             // seek to new block, or until the last block in stream
-            let last_block_index = self.inner.out.as_mut().unwrap().stream_len()?
+            let last_block_index = self
+                .inner
+                .out
+                .as_mut()
+                .unwrap()
+                .stream_len()?
+                .saturating_sub(HEADER_LEN as u64)
                 / self.inner.ciphertext_block_size as u64;
             let target_block_index = new_block_index.min(last_block_index);
-            self.inner.out.as_mut().unwrap().seek(SeekFrom::Start(
-                target_block_index * self.inner.ciphertext_block_size as u64,
-            ))?;
+            let offset = self.inner.block_offset(target_block_index);
+            self.inner
+                .out
+                .as_mut()
+                .unwrap()
+                .seek(SeekFrom::Start(offset))?;
             // try to decrypt target block
+            if target_block_index != self.inner.block_index {
+                self.holes_before_known = false;
+            }
             self.inner.block_index = target_block_index;
             self.decrypt_block()?;
             if self.inner.block_index == new_block_index {
@@ -348,8 +814,7 @@ impl<W: Write + Seek + Read> Seek for RingCryptoWriteSeek<W> {
         }
         // if we couldn't seek until new pos, write zeros until new position
         if self.pos() < new_pos {
-            let len = new_pos - self.pos();
-            stream_util::fill_zeros(&mut self.inner, len)?;
+            self.fill_sparse(new_pos)?;
         }
         Ok(self.pos())
     }
@@ -357,17 +822,22 @@ impl<W: Write + Seek + Read> Seek for RingCryptoWriteSeek<W> {
 
 impl<W: Write + Seek + Read + Send + Sync> Write for RingCryptoWriteSeek<W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.ensure_header()?;
         if self.pos() == 0 && self.inner.buf.available() == 0 {
             // first write since we opened the writer, try to load the first block
-            self.inner.out.as_mut().unwrap().seek(SeekFrom::Start(0))?;
+            let offset = self.inner.block_offset(0);
+            self.inner
+                .out
+                .as_mut()
+                .unwrap()
+                .seek(SeekFrom::Start(offset))?;
             self.inner.block_index = 0;
             self.decrypt_block()?;
         } else if self.inner.buf.is_dirty() && self.inner.buf.remaining() == 0 {
-            self.flush()?;
+            self.encrypt_and_write()?;
             // try to decrypt the next block if we have any
             let block_index = self.pos() / self.inner.plaintext_block_size as u64;
-            if self.inner.out.as_mut().unwrap().stream_len()?
-                > block_index * self.inner.ciphertext_block_size as u64
+            if self.inner.out.as_mut().unwrap().stream_len()? > self.inner.block_offset(block_index)
             {
                 self.decrypt_block()?;
             }
@@ -376,14 +846,43 @@ impl<W: Write + Seek + Read + Send + Sync> Write for RingCryptoWriteSeek<W> {
     }
 
     fn flush(&mut self) -> io::Result<()> {
+        // write a full block here, so we handle if it was a hole
+        if self.inner.out.is_some() && self.inner.buf.is_dirty() && self.inner.buf.remaining() == 0
+        {
+            self.encrypt_and_write()?;
+        }
         self.inner.flush()
     }
 }
 
 impl<W: Write + Seek + Read + Send + Sync> CryptoWrite<W> for RingCryptoWriteSeek<W> {
     fn finish(&mut self) -> io::Result<W> {
+        // write the last block here, so we handle if it was a hole
+        if self.inner.out.is_some() && self.inner.buf.is_dirty() {
+            self.encrypt_and_write()?;
+        }
         self.inner.finish()
     }
 }
 
-impl<W: Write + Seek + Read + Send + Sync> CryptoWriteSeek<W> for RingCryptoWriteSeek<W> {}
+impl<W: Write + Seek + Read + Send + Sync> CryptoWriteSeek<W> for RingCryptoWriteSeek<W> {
+    fn flush_pending(&mut self) -> io::Result<()> {
+        if self.inner.out.is_some() && self.inner.buf.is_dirty() {
+            let pos = self.pos();
+            self.encrypt_and_write()?;
+            if self.pos() == pos {
+                // it was a full block, load the next one if we have it, like `write` does
+                let block_index = self.inner.block_index;
+                if self.inner.out.as_mut().unwrap().stream_len()?
+                    > self.inner.block_offset(block_index)
+                {
+                    self.decrypt_block()?;
+                }
+            } else {
+                // back in the block we just wrote
+                self.seek(SeekFrom::Start(pos))?;
+            }
+        }
+        self.inner.flush()
+    }
+}
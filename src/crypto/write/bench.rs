@@ -184,3 +184,35 @@ fn bench_writer_1mb_aes256gcm_mem(b: &mut Bencher) {
         })
     });
 }
+
+#[bench]
+fn bench_writer_100mb_cha_cha20poly1305_buffered_file(b: &mut Bencher) {
+    use ::test::black_box;
+    use std::io;
+    use std::io::BufWriter;
+
+    use rand::RngCore;
+    use secrecy::SecretVec;
+
+    use crate::crypto;
+    use crate::crypto::write::CryptoWrite;
+    use crate::crypto::Cipher;
+
+    let cipher = Cipher::ChaCha20Poly1305;
+    let len = 100 * 1024 * 1024;
+
+    let mut key: Vec<u8> = vec![0; cipher.key_len()];
+    rand::thread_rng().fill_bytes(&mut key);
+    let key = SecretVec::new(key);
+
+    let rnd_reader = RandomReader::new(len);
+    b.iter(|| {
+        black_box({
+            let mut reader = rnd_reader.clone();
+            let file = BufWriter::new(tempfile::tempfile().unwrap());
+            let mut writer = crypto::create_write(file, cipher, &key);
+            io::copy(&mut reader, &mut writer).unwrap();
+            writer.finish().unwrap()
+        })
+    });
+}
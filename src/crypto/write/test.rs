@@ -171,6 +171,53 @@ fn test_reader_writer_1mb_aes() {
     assert_eq!(hash1, hash2);
 }
 
+#[test]
+#[traced_test]
+fn test_reader_writer_aes128() {
+    use std::io;
+    use std::io::{Read, Seek};
+    use std::io::{SeekFrom, Write};
+
+    use rand::RngCore;
+    use secrecy::SecretVec;
+
+    use crate::crypto;
+    use crate::crypto::write::{CryptoWrite, BLOCK_SIZE};
+    use crate::crypto::Cipher;
+
+    let cipher = Cipher::Aes128Gcm;
+    assert_eq!(16, cipher.key_len());
+    let mut key: Vec<u8> = vec![0; cipher.key_len()];
+    rand::thread_rng().fill_bytes(&mut key);
+    let key = SecretVec::new(key);
+
+    // simple text
+    let mut cursor = io::Cursor::new(vec![0; 0]);
+    let mut writer = crypto::create_write(cursor, cipher, &key);
+    let data = "hello, this is my secret message";
+    writer.write_all(data.as_bytes()).unwrap();
+    cursor = writer.finish().unwrap();
+    cursor.seek(SeekFrom::Start(0)).unwrap();
+    let mut reader = crypto::create_read(cursor, cipher, &key);
+    let mut s = String::new();
+    reader.read_to_string(&mut s).unwrap();
+    assert_eq!(data, s);
+
+    // larger data
+    let mut cursor = io::Cursor::new(vec![]);
+    let mut writer = crypto::create_write(cursor, cipher, &key);
+    let mut data: [u8; BLOCK_SIZE + 42] = [0; BLOCK_SIZE + 42];
+    rand::thread_rng().fill_bytes(&mut data);
+    writer.write_all(&data).unwrap();
+    cursor = writer.finish().unwrap();
+    cursor.seek(SeekFrom::Start(0)).unwrap();
+    let mut reader = crypto::create_read(cursor, cipher, &key);
+    let mut data2 = vec![];
+    reader.read_to_end(&mut data2).unwrap();
+    assert_eq!(data.len(), data2.len());
+    assert_eq!(crypto::hash(&data), crypto::hash(&data2));
+}
+
 #[test]
 #[traced_test]
 #[allow(clippy::too_many_lines)]
@@ -206,7 +253,7 @@ fn test_writer_seek_text_chacha() {
     let mut reader = crypto::create_read(cursor, cipher, &key);
     let mut s = String::new();
     reader.read_to_string(&mut s).unwrap();
-    cursor = reader.into_inner();
+    cursor = reader.into_inner().unwrap();
     assert_eq!("This IS a test message for THE seek capability", s.as_str());
 
     // open existing content
@@ -219,7 +266,7 @@ fn test_writer_seek_text_chacha() {
     let mut reader = crypto::create_read(cursor, cipher, &key);
     let mut s = String::new();
     reader.read_to_string(&mut s).unwrap();
-    cursor = reader.into_inner();
+    cursor = reader.into_inner().unwrap();
     assert_eq!("This IS a TEST message for THE seek capability", s.as_str());
 
     // seek current
@@ -232,7 +279,7 @@ fn test_writer_seek_text_chacha() {
     let mut reader = crypto::create_read(cursor, cipher, &key);
     let mut s = String::new();
     reader.read_to_string(&mut s).unwrap();
-    cursor = reader.into_inner();
+    cursor = reader.into_inner().unwrap();
     assert_eq!("This IS a TEST MESSAGE for THE seek capability", s.as_str());
 
     // seek from the end
@@ -245,7 +292,7 @@ fn test_writer_seek_text_chacha() {
     let mut reader = crypto::create_read(cursor, cipher, &key);
     let mut s = String::new();
     reader.read_to_string(&mut s).unwrap();
-    cursor = reader.into_inner();
+    cursor = reader.into_inner().unwrap();
     assert_eq!("This IS a TEST MESSAGE for THE SEEK capability", s.as_str());
 
     // seek < 0
@@ -263,7 +310,7 @@ fn test_writer_seek_text_chacha() {
     let mut reader = crypto::create_read(cursor, cipher, &key);
     let mut s = String::new();
     reader.read_to_string(&mut s).unwrap();
-    reader.into_inner();
+    reader.into_inner().unwrap();
     assert_eq!(
         "This IS a TEST MESSAGE for THE SEEK capability\0",
         s.as_str()
@@ -282,7 +329,7 @@ fn test_writer_seek_text_chacha() {
     let mut reader = crypto::create_read(cursor, cipher, &key);
     let mut buf2 = [0; 10];
     reader.read_exact(&mut buf2).unwrap();
-    cursor = reader.into_inner();
+    cursor = reader.into_inner().unwrap();
     buf[5] = 1;
     buf[6] = 1;
     buf[8] = 2;
@@ -301,7 +348,7 @@ fn test_writer_seek_text_chacha() {
     buf[4] = 3;
     let mut buf2 = [0; 10];
     reader.read_exact(&mut buf2).unwrap();
-    reader.into_inner();
+    reader.into_inner().unwrap();
     assert_eq!(buf, buf2);
 }
 
@@ -340,7 +387,7 @@ fn test_writer_seek_text_aes() {
     let mut reader = crypto::create_read(cursor, cipher, &key);
     let mut s = String::new();
     reader.read_to_string(&mut s).unwrap();
-    cursor = reader.into_inner();
+    cursor = reader.into_inner().unwrap();
     assert_eq!("This IS a test message for THE seek capability", s.as_str());
 
     // open existing content
@@ -353,7 +400,7 @@ fn test_writer_seek_text_aes() {
     let mut reader = crypto::create_read(cursor, cipher, &key);
     let mut s = String::new();
     reader.read_to_string(&mut s).unwrap();
-    cursor = reader.into_inner();
+    cursor = reader.into_inner().unwrap();
     assert_eq!("This IS a TEST message for THE seek capability", s.as_str());
 
     // seek current
@@ -366,7 +413,7 @@ fn test_writer_seek_text_aes() {
     let mut reader = crypto::create_read(cursor, cipher, &key);
     let mut s = String::new();
     reader.read_to_string(&mut s).unwrap();
-    cursor = reader.into_inner();
+    cursor = reader.into_inner().unwrap();
     assert_eq!("This IS a TEST MESSAGE for THE seek capability", s.as_str());
 
     // seek from the end
@@ -379,7 +426,7 @@ fn test_writer_seek_text_aes() {
     let mut reader = crypto::create_read(cursor, cipher, &key);
     let mut s = String::new();
     reader.read_to_string(&mut s).unwrap();
-    cursor = reader.into_inner();
+    cursor = reader.into_inner().unwrap();
     assert_eq!("This IS a TEST MESSAGE for THE SEEK capability", s.as_str());
 
     // seek < 0
@@ -397,7 +444,7 @@ fn test_writer_seek_text_aes() {
     let mut reader = crypto::create_read(cursor, cipher, &key);
     let mut s = String::new();
     reader.read_to_string(&mut s).unwrap();
-    reader.into_inner();
+    reader.into_inner().unwrap();
     assert_eq!(
         "This IS a TEST MESSAGE for THE SEEK capability\0",
         s.as_str()
@@ -416,7 +463,7 @@ fn test_writer_seek_text_aes() {
     let mut reader = crypto::create_read(cursor, cipher, &key);
     let mut buf2 = [0; 10];
     reader.read_exact(&mut buf2).unwrap();
-    cursor = reader.into_inner();
+    cursor = reader.into_inner().unwrap();
     buf[5] = 1;
     buf[6] = 1;
     buf[8] = 2;
@@ -435,7 +482,7 @@ fn test_writer_seek_text_aes() {
     buf[4] = 3;
     let mut buf2 = [0; 10];
     reader.read_exact(&mut buf2).unwrap();
-    reader.into_inner();
+    reader.into_inner().unwrap();
     assert_eq!(buf, buf2);
 }
 
@@ -1031,6 +1078,440 @@ fn test_writer_seek_blocks_one_go_aes() {
     compare(&mut cursor_random, cursor, cipher, &key);
 }
 
+#[test]
+#[traced_test]
+fn test_writer_seek_end_append() {
+    use std::io::{Read, Write};
+
+    use rand::RngCore;
+
+    use crate::crypto::write::{CryptoWrite, BLOCK_SIZE};
+
+    let cipher = Cipher::ChaCha20Poly1305;
+    let mut key: Vec<u8> = vec![0; cipher.key_len()];
+    rand::thread_rng().fill_bytes(&mut key);
+    let key = SecretVec::new(key);
+
+    // a partial last block and an exact multiple of the block size
+    for len in [4096, BLOCK_SIZE * 40] {
+        let mut existing = vec![0; len];
+        rand::thread_rng().fill_bytes(&mut existing);
+        let mut writer = crypto::create_write(io::Cursor::new(vec![]), cipher, &key);
+        writer.write_all(&existing).unwrap();
+        let cursor = writer.finish().unwrap();
+
+        // reopen and append
+        let mut writer = crypto::create_write_seek(cursor, cipher, &key);
+        assert_eq!(len as u64, writer.seek(SeekFrom::End(0)).unwrap());
+        writer.write_all(b"appended").unwrap();
+        let mut cursor = writer.finish().unwrap();
+
+        cursor.seek(SeekFrom::Start(0)).unwrap();
+        let mut reader = crypto::create_read_seek(cursor, cipher, &key);
+        let mut data = vec![];
+        reader.read_to_end(&mut data).unwrap();
+        existing.extend_from_slice(b"appended");
+        assert_eq!(existing, data);
+        assert_eq!(
+            existing.len() as u64,
+            reader.seek(SeekFrom::End(0)).unwrap()
+        );
+    }
+}
+
+#[test]
+#[traced_test]
+fn test_writer_flush_pending_continues_writing() {
+    use std::io::{Read, Write};
+
+    use rand::RngCore;
+
+    use crate::crypto::write::{CryptoWrite, CryptoWriteSeek, BLOCK_SIZE};
+
+    let cipher = Cipher::ChaCha20Poly1305;
+    let mut key: Vec<u8> = vec![0; cipher.key_len()];
+    rand::thread_rng().fill_bytes(&mut key);
+    let key = SecretVec::new(key);
+
+    let mut data = vec![0; BLOCK_SIZE * 2 + 42];
+    rand::thread_rng().fill_bytes(&mut data);
+    let mut writer = crypto::create_write_seek(io::Cursor::new(vec![]), cipher, &key);
+    // a partial block, then a full one, each flushed and continued from the same position
+    writer.write_all(&data[..42]).unwrap();
+    writer.flush_pending().unwrap();
+    assert_eq!(42, writer.stream_position().unwrap());
+    writer.write_all(&data[42..BLOCK_SIZE]).unwrap();
+    writer.flush_pending().unwrap();
+    assert_eq!(BLOCK_SIZE as u64, writer.stream_position().unwrap());
+    writer.write_all(&data[BLOCK_SIZE..]).unwrap();
+    writer.flush_pending().unwrap();
+    // change something in the first block after it was flushed
+    writer.seek(SeekFrom::Start(10)).unwrap();
+    writer.write_all(b"changed").unwrap();
+    data[10..17].copy_from_slice(b"changed");
+    writer.flush_pending().unwrap();
+    let mut cursor = writer.finish().unwrap();
+
+    cursor.seek(SeekFrom::Start(0)).unwrap();
+    let mut reader = crypto::create_read_seek(cursor, cipher, &key);
+    let mut data2 = vec![];
+    reader.read_to_end(&mut data2).unwrap();
+    assert_eq!(data, data2);
+}
+
+#[test]
+#[traced_test]
+fn test_writer_seek_past_end_sparse() {
+    use std::io::{Read, Write};
+
+    use rand::RngCore;
+    use ring::aead::{CHACHA20_POLY1305, NONCE_LEN};
+
+    use crate::crypto::write::{CryptoWrite, BLOCK_SIZE, HEADER_LEN};
+
+    let cipher = Cipher::ChaCha20Poly1305;
+    let mut key: Vec<u8> = vec![0; cipher.key_len()];
+    rand::thread_rng().fill_bytes(&mut key);
+    let key = SecretVec::new(key);
+
+    // inside the last block, at a block boundary and inside a later block,
+    // with and without writing after seek
+    for (pos, append) in [
+        (42, true),
+        (BLOCK_SIZE * 10, true),
+        (BLOCK_SIZE * 10, false),
+        (BLOCK_SIZE * 10 + 7, true),
+        (BLOCK_SIZE * 10 + 7, false),
+    ] {
+        let mut existing = vec![0; 30];
+        rand::thread_rng().fill_bytes(&mut existing);
+        let mut writer = crypto::create_write_seek(io::Cursor::new(vec![]), cipher, &key);
+        writer.write_all(&existing).unwrap();
+        assert_eq!(
+            pos as u64,
+            writer.seek(SeekFrom::Start(pos as u64)).unwrap()
+        );
+        if append {
+            writer.write_all(b"x").unwrap();
+        }
+        let mut cursor = writer.finish().unwrap();
+
+        // whole blocks in between are left as holes
+        let ciphertext_block_size = NONCE_LEN + BLOCK_SIZE + CHACHA20_POLY1305.tag_len();
+        let holes = cursor.get_ref()[HEADER_LEN..]
+            .chunks(ciphertext_block_size)
+            .filter(|block| block.iter().all(|b| *b == 0))
+            .count();
+        if pos > BLOCK_SIZE * 2 {
+            assert!(holes > 0);
+        }
+
+        cursor.seek(SeekFrom::Start(0)).unwrap();
+        let mut reader = crypto::create_read_seek(cursor, cipher, &key);
+        let mut data = vec![];
+        reader.read_to_end(&mut data).unwrap();
+        existing.resize(pos, 0);
+        if append {
+            existing.push(b'x');
+        }
+        assert_eq!(existing, data);
+    }
+}
+
+#[test]
+#[traced_test]
+fn test_writer_seek_sparse_holes_authenticated() {
+    use std::io::{Read, Write};
+
+    use rand::RngCore;
+    use ring::aead::{CHACHA20_POLY1305, NONCE_LEN};
+
+    use crate::crypto::write::{CryptoWrite, BLOCK_SIZE, HEADER_LEN};
+
+    let cipher = Cipher::ChaCha20Poly1305;
+    let mut key: Vec<u8> = vec![0; cipher.key_len()];
+    rand::thread_rng().fill_bytes(&mut key);
+    let key = SecretVec::new(key);
+
+    let mut existing = vec![0; BLOCK_SIZE * 5];
+    rand::thread_rng().fill_bytes(&mut existing);
+    let mut writer = crypto::create_write_seek(io::Cursor::new(vec![]), cipher, &key);
+    writer.write_all(&existing).unwrap();
+    writer
+        .seek(SeekFrom::Start(BLOCK_SIZE as u64 * 20))
+        .unwrap();
+    writer.write_all(b"x").unwrap();
+    let cursor = writer.finish().unwrap();
+
+    // a block in the middle, the one before the holes and the last one
+    let ciphertext_block_size = NONCE_LEN + BLOCK_SIZE + CHACHA20_POLY1305.tag_len();
+    for block_index in [2, 4, 20] {
+        let mut data = cursor.get_ref().clone();
+        let offset = HEADER_LEN + block_index * ciphertext_block_size;
+        let end = (offset + ciphertext_block_size).min(data.len());
+        data[offset..end].fill(0);
+
+        let mut reader = crypto::create_read(io::Cursor::new(data.clone()), cipher, &key);
+        let err = reader.read_to_end(&mut vec![]).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+
+        // seeking to the start of a block already decrypts it
+        let mut reader = crypto::create_read_seek(io::Cursor::new(data), cipher, &key);
+        let err = reader
+            .seek(SeekFrom::Start((block_index * BLOCK_SIZE) as u64))
+            .and_then(|_| reader.read_to_end(&mut vec![]))
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+}
+
+#[test]
+#[traced_test]
+fn test_writer_seek_sparse_long_gap() {
+    use std::io::{Read, Write};
+
+    use rand::RngCore;
+    use ring::aead::{CHACHA20_POLY1305, NONCE_LEN};
+
+    use crate::crypto::write::{max_holes, CryptoWrite, BLOCK_SIZE, HEADER_LEN};
+
+    let cipher = Cipher::ChaCha20Poly1305;
+    let mut key: Vec<u8> = vec![0; cipher.key_len()];
+    rand::thread_rng().fill_bytes(&mut key);
+    let key = SecretVec::new(key);
+
+    // more than two runs of holes, so we need blocks of zeros written in between
+    #[allow(clippy::cast_possible_truncation)]
+    let max_holes = max_holes(BLOCK_SIZE) as usize;
+    let pos = BLOCK_SIZE * (max_holes * 2 + 10) + 7;
+    let mut writer = crypto::create_write_seek(io::Cursor::new(vec![]), cipher, &key);
+    writer.write_all(b"start").unwrap();
+    writer.seek(SeekFrom::Start(pos as u64)).unwrap();
+    writer.write_all(b"end").unwrap();
+    let cursor = writer.finish().unwrap();
+
+    let ciphertext_block_size = NONCE_LEN + BLOCK_SIZE + CHACHA20_POLY1305.tag_len();
+    let blocks: Vec<_> = cursor.get_ref()[HEADER_LEN..]
+        .chunks(ciphertext_block_size)
+        .collect();
+    let mut run = 0;
+    let mut holes = 0;
+    for block in &blocks {
+        if block.iter().all(|b| *b == 0) {
+            run += 1;
+            holes += 1;
+            assert!(run <= max_holes);
+        } else {
+            run = 0;
+        }
+    }
+    assert_eq!(blocks.len() - 4, holes);
+
+    let mut expected = b"start".to_vec();
+    expected.resize(pos, 0);
+    expected.extend_from_slice(b"end");
+    let mut reader = crypto::create_read(io::Cursor::new(cursor.get_ref().clone()), cipher, &key);
+    let mut data = vec![];
+    reader.read_to_end(&mut data).unwrap();
+    assert_eq!(expected, data);
+
+    // seek in each run
+    let mut reader = crypto::create_read_seek(cursor, cipher, &key);
+    for pos in [
+        BLOCK_SIZE * 3 + 1,
+        BLOCK_SIZE * (max_holes + 1),
+        BLOCK_SIZE * (max_holes + 5),
+        BLOCK_SIZE * (max_holes * 2 + 8) + 5,
+    ] {
+        reader.seek(SeekFrom::Start(pos as u64)).unwrap();
+        let mut data = vec![0; BLOCK_SIZE * 2];
+        reader.read_exact(&mut data).unwrap();
+        assert_eq!(expected[pos..pos + BLOCK_SIZE * 2], data);
+    }
+}
+
+#[test]
+#[traced_test]
+fn test_writer_seek_write_in_hole() {
+    use std::io::{Read, Write};
+
+    use rand::RngCore;
+
+    use crate::crypto::write::{CryptoWrite, BLOCK_SIZE};
+
+    let cipher = Cipher::ChaCha20Poly1305;
+    let mut key: Vec<u8> = vec![0; cipher.key_len()];
+    rand::thread_rng().fill_bytes(&mut key);
+    let key = SecretVec::new(key);
+
+    let mut writer = crypto::create_write_seek(io::Cursor::new(vec![]), cipher, &key);
+    writer.write_all(b"start").unwrap();
+    writer
+        .seek(SeekFrom::Start(BLOCK_SIZE as u64 * 20))
+        .unwrap();
+    writer.write_all(b"end").unwrap();
+    let mut cursor = writer.finish().unwrap();
+    let mut expected = b"start".to_vec();
+    expected.resize(BLOCK_SIZE * 20, 0);
+    expected.extend_from_slice(b"end");
+
+    // first, last and in the middle of the holes, then one spanning two of them
+    for (pos, len) in [
+        (BLOCK_SIZE, 3),
+        (BLOCK_SIZE * 19 + 10, 5),
+        (BLOCK_SIZE * 10 + 2, 7),
+        (BLOCK_SIZE * 5 - 3, 6),
+    ] {
+        let mut buf = vec![0; len];
+        rand::thread_rng().fill_bytes(&mut buf);
+        cursor.seek(SeekFrom::Start(0)).unwrap();
+        let mut writer = crypto::create_write_seek(cursor, cipher, &key);
+        writer.seek(SeekFrom::Start(pos as u64)).unwrap();
+        writer.write_all(&buf).unwrap();
+        cursor = writer.finish().unwrap();
+        expected[pos..pos + len].copy_from_slice(&buf);
+
+        cursor.seek(SeekFrom::Start(0)).unwrap();
+        let mut reader = crypto::create_read(cursor, cipher, &key);
+        let mut data = vec![];
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(expected, data);
+        cursor = reader.into_inner().unwrap();
+    }
+}
+
+/// Counts the bytes and flushes that reach the inner writer.
+#[allow(dead_code)]
+#[derive(Default)]
+struct CountingWriter {
+    data: Vec<u8>,
+    writes: usize,
+    flushes: usize,
+}
+
+impl io::Write for CountingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writes += 1;
+        self.data.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flushes += 1;
+        Ok(())
+    }
+}
+
+#[test]
+#[traced_test]
+fn test_writer_partial_last_block_written_once() {
+    use std::io::{Read, Write};
+
+    use rand::RngCore;
+    use ring::aead::{CHACHA20_POLY1305, NONCE_LEN};
+
+    use crate::crypto::write::{CryptoWrite, BLOCK_SIZE, HEADER_LEN};
+
+    let cipher = Cipher::ChaCha20Poly1305;
+    let mut key: Vec<u8> = vec![0; cipher.key_len()];
+    rand::thread_rng().fill_bytes(&mut key);
+    let key = SecretVec::new(key);
+
+    let mut writer = crypto::create_write(CountingWriter::default(), cipher, &key);
+    let mut data = vec![0; BLOCK_SIZE * 2 + 42];
+    rand::thread_rng().fill_bytes(&mut data);
+    writer.write_all(&data).unwrap();
+    // flush doesn't write the partial block
+    writer.flush().unwrap();
+    writer.flush().unwrap();
+    let out = writer.finish().unwrap();
+    // full blocks don't flush the inner writer, only the explicit flush and finish do
+    assert_eq!(3, out.flushes);
+    let overhead = NONCE_LEN + CHACHA20_POLY1305.tag_len();
+    assert_eq!(HEADER_LEN + 3 * overhead + data.len(), out.data.len());
+
+    let mut reader = crypto::create_read(io::Cursor::new(out.data), cipher, &key);
+    let mut data2 = vec![];
+    reader.read_to_end(&mut data2).unwrap();
+    assert_eq!(data, data2);
+}
+
+/// Counts the bytes read from the inner stream.
+#[allow(dead_code)]
+struct CountingReads {
+    inner: io::Cursor<Vec<u8>>,
+    read: usize,
+}
+
+impl io::Read for CountingReads {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.read += len;
+        Ok(len)
+    }
+}
+
+impl io::Write for CountingReads {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Seek for CountingReads {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+#[test]
+#[traced_test]
+fn test_writer_seek_reads_each_block_once() {
+    use std::io::{Read, Write};
+
+    use rand::RngCore;
+    use ring::aead::{CHACHA20_POLY1305, NONCE_LEN};
+
+    use crate::crypto::write::{CryptoWrite, BLOCK_SIZE, HEADER_LEN};
+
+    let cipher = Cipher::ChaCha20Poly1305;
+    let mut key: Vec<u8> = vec![0; cipher.key_len()];
+    rand::thread_rng().fill_bytes(&mut key);
+    let key = SecretVec::new(key);
+
+    let blocks = 10;
+    let mut data = vec![0; BLOCK_SIZE * blocks];
+    rand::thread_rng().fill_bytes(&mut data);
+    let mut writer = crypto::create_write(io::Cursor::new(vec![]), cipher, &key);
+    writer.write_all(&data).unwrap();
+    let cursor = writer.finish().unwrap();
+
+    // overwrite all of it, there are no holes, so we don't need to read the blocks before
+    rand::thread_rng().fill_bytes(&mut data);
+    let mut writer = crypto::create_write_seek(
+        CountingReads {
+            inner: cursor,
+            read: 0,
+        },
+        cipher,
+        &key,
+    );
+    writer.write_all(&data).unwrap();
+    let out = writer.finish().unwrap();
+    let ciphertext_block_size = NONCE_LEN + BLOCK_SIZE + CHACHA20_POLY1305.tag_len();
+    assert_eq!(HEADER_LEN + blocks * ciphertext_block_size, out.read);
+
+    let mut cursor = out.inner;
+    cursor.seek(SeekFrom::Start(0)).unwrap();
+    let mut reader = crypto::create_read(cursor, cipher, &key);
+    let mut data2 = vec![];
+    reader.read_to_end(&mut data2).unwrap();
+    assert_eq!(data, data2);
+}
+
 #[allow(dead_code)]
 fn compare(
     mut plaintext: &mut io::Cursor<Vec<u8>>,
@@ -1044,8 +1525,75 @@ fn compare(
     let hash1 = crypto::hash_reader(&mut plaintext).unwrap();
     let hash2 = crypto::hash_reader(&mut reader).unwrap();
     assert_eq!(hash1, hash2);
-    ciphertext = reader.into_inner();
+    ciphertext = reader.into_inner().unwrap();
     plaintext.seek(SeekFrom::Start(0)).unwrap();
     ciphertext.seek(SeekFrom::Start(0)).unwrap();
     ciphertext
 }
+
+#[test]
+#[traced_test]
+fn test_writer_custom_block_size() {
+    use std::io::{Read, Write};
+
+    use rand::RngCore;
+
+    use crate::crypto::write::{CryptoWrite, HEADER_LEN, MAX_BLOCK_SIZE};
+
+    let cipher = Cipher::ChaCha20Poly1305;
+    let mut key: Vec<u8> = vec![0; cipher.key_len()];
+    rand::thread_rng().fill_bytes(&mut key);
+    let key = SecretVec::new(key);
+
+    assert!(
+        crypto::create_write_with_block_size(io::Cursor::new(vec![]), cipher, &key, 0).is_err()
+    );
+    assert!(crypto::create_read_with_block_size(io::Cursor::new(vec![]), cipher, &key, 0).is_err());
+    let too_large = MAX_BLOCK_SIZE + 1;
+    assert!(crypto::create_write_seek_with_block_size(
+        io::Cursor::new(vec![]),
+        cipher,
+        &key,
+        too_large
+    )
+    .is_err());
+    assert!(crypto::create_read_seek_with_block_size(
+        io::Cursor::new(vec![]),
+        cipher,
+        &key,
+        too_large
+    )
+    .is_err());
+
+    let block_size = 777;
+    let mut data = vec![0; block_size * 10 + 42];
+    rand::thread_rng().fill_bytes(&mut data);
+    let mut writer =
+        crypto::create_write_with_block_size(io::Cursor::new(vec![]), cipher, &key, block_size)
+            .unwrap();
+    writer.write_all(&data).unwrap();
+    let cursor = writer.finish().unwrap();
+    // the header and 11 blocks, each with its nonce and tag
+    assert_eq!(
+        HEADER_LEN + data.len() + 11 * (12 + 16),
+        cursor.get_ref().len()
+    );
+
+    // overwrite inside the 4th block
+    let mut writer =
+        crypto::create_write_seek_with_block_size(cursor, cipher, &key, block_size).unwrap();
+    writer
+        .seek(SeekFrom::Start(block_size as u64 * 3 + 5))
+        .unwrap();
+    writer.write_all(b"overwritten").unwrap();
+    let mut cursor = writer.finish().unwrap();
+    data[block_size * 3 + 5..block_size * 3 + 16].copy_from_slice(b"overwritten");
+
+    cursor.seek(SeekFrom::Start(0)).unwrap();
+    let mut reader =
+        crypto::create_read_seek_with_block_size(cursor, cipher, &key, block_size).unwrap();
+    let mut read = vec![];
+    reader.read_to_end(&mut read).unwrap();
+    assert_eq!(data, read);
+    assert_eq!(data.len() as u64, reader.seek(SeekFrom::End(0)).unwrap());
+}
@@ -1,6 +1,7 @@
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::ffi::OsStr;
 use std::fmt::Debug;
-use std::fs::{DirEntry, File, OpenOptions, ReadDir};
+use std::io;
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::num::{NonZeroUsize, ParseIntError};
 use std::path::{Path, PathBuf};
@@ -8,28 +9,29 @@ use std::str::FromStr;
 use std::sync::atomic::AtomicU64;
 use std::sync::{Arc, LazyLock, Weak};
 use std::time::{Duration, SystemTime};
-use std::{fs, io};
 
 use argon2::password_hash::rand_core::RngCore;
 use async_trait::async_trait;
-use futures_util::TryStreamExt;
+use base64::Engine;
 use lru::LruCache;
 use num_format::{Locale, ToFormattedString};
+use ring::digest;
+use secrecy::zeroize::{Zeroize, Zeroizing};
 use secrecy::{ExposeSecret, SecretString, SecretVec};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tokio::runtime::Runtime;
 use tokio::sync::{Mutex, RwLock};
 use tokio::task::{JoinError, JoinSet};
-use tokio_stream::wrappers::ReadDirStream;
 use tracing::{debug, error, instrument, warn};
 
 use crate::arc_hashmap::ArcHashMap;
-use crate::crypto::read::{CryptoRead, CryptoReadSeek};
+use crate::crypto::read::{CryptoRead, CryptoReadSeek, ReadAheadCryptoRead};
 use crate::crypto::write::{CryptoWrite, CryptoWriteSeek};
 use crate::crypto::Cipher;
 use crate::expire_value::{ExpireValue, ValueProvider};
-use crate::{crypto, fs_util, stream_util};
+use crate::storage::{FsStorage, Storage, StorageFile};
+use crate::{async_util, crypto, stream_util};
 
 mod bench;
 #[cfg(test)]
@@ -40,12 +42,40 @@ pub(crate) const CONTENTS_DIR: &str = "contents";
 pub(crate) const SECURITY_DIR: &str = "security";
 pub(crate) const KEY_ENC_FILENAME: &str = "key.enc";
 pub(crate) const KEY_SALT_FILENAME: &str = "key.salt";
+pub(crate) const INODE_COUNTER_FILENAME: &str = "inode.counter";
+pub(crate) const METADATA_FILENAME: &str = "metadata";
+/// Directory in `SECURITY_DIR` with the multistep operations in progress, see [`JournalEntry`].
+pub(crate) const JOURNAL_DIR: &str = "journal";
+/// File in `SECURITY_DIR` locked while the data dir is open, see [`EncryptedFs::new`].
+pub(crate) const LOCK_FILENAME: &str = "lock";
+/// File in `SECURITY_DIR` with the changes, see [`EncryptedFs::set_change_log`].
+pub(crate) const CHANGES_FILENAME: &str = "changes";
+
+/// Block size reported for files and in [`FsStat`].
+pub(crate) const BLKSIZE: u32 = 4096;
+
+/// Version of the on-disk format, increase it on any incompatible change.
+pub(crate) const FORMAT_VERSION: u32 = 6;
+
+/// Max length of a file name on the host filesystem, the encrypted names must fit in it.
+pub(crate) const NAME_MAX: usize = 255;
 
 pub(crate) const LS_DIR: &str = "ls";
 pub(crate) const HASH_DIR: &str = "hash";
 
 pub(crate) const ROOT_INODE: u64 = 1;
 
+/// Directory under root where [`EncryptedFs::repair`] puts the orphan inodes.
+pub const LOST_AND_FOUND_DIR: &str = "lost+found";
+
+/// How many inode attributes we keep in memory, by default, see [`FsOptions`].
+pub const ATTR_CACHE_SIZE: usize = 2000;
+/// How many directory entries, names and metadata, we keep in memory, by default, see [`FsOptions`].
+pub const DIR_ENTRIES_CACHE_SIZE: usize = 2000;
+/// How many readers of released handles we keep to reuse when the files are opened again,
+/// by default, see [`FsOptions`].
+pub const IDLE_READERS_CACHE_SIZE: usize = 64;
+
 fn spawn_runtime() -> Runtime {
     let runtime = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
@@ -95,36 +125,291 @@ pub struct FileAttr {
 /// File types.
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
 pub enum FileType {
-    // /// Named pipe (S_IFIFO)
-    // NamedPipe,
-    // /// Character device (S_IFCHR)
-    // CharDevice,
-    // /// Block device (S_IFBLK)
-    // BlockDevice,
+    // new types need to be added at the end, they are serialized by index
     /// Directory (`S_IFDIR`)
     Directory,
     /// Regular file (`S_IFREG`)
     RegularFile,
-    // /// Symbolic link (S_IFLNK)
-    // Symlink,
+    /// Symbolic link (`S_IFLNK`)
+    Symlink,
+    /// Named pipe (`S_IFIFO`)
+    NamedPipe,
+    /// Character device (`S_IFCHR`)
+    CharDevice,
+    /// Block device (`S_IFBLK`)
+    BlockDevice,
     // /// Unix domain socket (S_IFSOCK)
     // Socket,
 }
 
+/// Filesystem statistics, like `statvfs(3)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FsStat {
+    /// Total number of blocks, in units of `frsize`
+    pub blocks: u64,
+    /// Number of free blocks
+    pub bfree: u64,
+    /// Number of free blocks available to unprivileged users
+    pub bavail: u64,
+    /// Total number of inodes
+    pub files: u64,
+    /// Number of free inodes
+    pub ffree: u64,
+    /// Block size
+    pub bsize: u32,
+    /// Max length of file names
+    pub namelen: u32,
+    /// Fragment size
+    pub frsize: u32,
+}
+
+/// How the data dir was created, see [`EncryptedFs::config`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FsConfig {
+    /// Version of the on-disk format
+    pub format_version: u32,
+    /// Cipher used to encrypt the data
+    pub cipher: Cipher,
+    /// Argon2 memory cost, in KiB, used to derive the key from the password
+    pub kdf_m_cost: u32,
+    /// Argon2 number of iterations
+    pub kdf_t_cost: u32,
+    /// Argon2 degree of parallelism
+    pub kdf_p_cost: u32,
+}
+
+/// How to open the data dir with [`EncryptedFs::new_with`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FsOptions {
+    /// Open for reading only, like [`EncryptedFs::new_read_only`]
+    pub read_only: bool,
+    /// How many inode attributes we keep in memory
+    pub attr_cache_size: usize,
+    /// How many directory entries we keep in memory, for the names and for the metadata
+    pub dir_entries_cache_size: usize,
+    /// How many readers of released handles we keep to reuse when the files are opened again
+    pub idle_readers_cache_size: usize,
+    /// Argon2 iterations to derive the key from the password, used only when the data dir is
+    /// created, then it's read from its metadata
+    pub kdf_iterations: u32,
+}
+
+impl Default for FsOptions {
+    fn default() -> Self {
+        Self {
+            read_only: false,
+            attr_cache_size: ATTR_CACHE_SIZE,
+            dir_entries_cache_size: DIR_ENTRIES_CACHE_SIZE,
+            idle_readers_cache_size: IDLE_READERS_CACHE_SIZE,
+            kdf_iterations: crypto::KDF_ITERATIONS,
+        }
+    }
+}
+
+impl FsOptions {
+    #[must_use]
+    pub const fn with_read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_attr_cache_size(mut self, attr_cache_size: usize) -> Self {
+        self.attr_cache_size = attr_cache_size;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_dir_entries_cache_size(mut self, dir_entries_cache_size: usize) -> Self {
+        self.dir_entries_cache_size = dir_entries_cache_size;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_idle_readers_cache_size(mut self, idle_readers_cache_size: usize) -> Self {
+        self.idle_readers_cache_size = idle_readers_cache_size;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_kdf_iterations(mut self, kdf_iterations: u32) -> Self {
+        self.kdf_iterations = kdf_iterations;
+        self
+    }
+}
+
+/// How to open a file with [`EncryptedFs::open_with`], like [`std::fs::OpenOptions`].
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OpenFlags {
+    /// Open for reading
+    pub read: bool,
+    /// Open for writing
+    pub write: bool,
+    /// All writes go to the end of the file, needs `write`
+    pub append: bool,
+    /// Truncate the file to 0 length, needs `write`
+    pub truncate: bool,
+}
+
+impl OpenFlags {
+    #[must_use]
+    pub const fn with_read(mut self, read: bool) -> Self {
+        self.read = read;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_write(mut self, write: bool) -> Self {
+        self.write = write;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_append(mut self, append: bool) -> Self {
+        self.append = append;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_truncate(mut self, truncate: bool) -> Self {
+        self.truncate = truncate;
+        self
+    }
+}
+
+/// How to create a file with [`EncryptedFs::create_with`], like `O_CREAT` with or without `O_EXCL`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CreateFlags {
+    /// How to open the created or the existing file, if neither `read` nor `write` no handle is opened
+    pub open: OpenFlags,
+    /// Fail with [`FsError::AlreadyExists`] if the name exists, else the existing file is opened
+    pub exclusive: bool,
+}
+
+impl CreateFlags {
+    #[must_use]
+    pub const fn with_open(mut self, open: OpenFlags) -> Self {
+        self.open = open;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_exclusive(mut self, exclusive: bool) -> Self {
+        self.exclusive = exclusive;
+        self
+    }
+}
+
+/// How to rename with [`EncryptedFs::rename_with`], like the `renameat2(2)` flags.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RenameFlags {
+    /// Fail with [`FsError::AlreadyExists`] instead of replacing an existing destination,
+    /// like `RENAME_NOREPLACE`
+    pub no_replace: bool,
+}
+
+impl RenameFlags {
+    #[must_use]
+    pub const fn with_no_replace(mut self, no_replace: bool) -> Self {
+        self.no_replace = no_replace;
+        self
+    }
+}
+
+/// When reading updates the access time, like the `atime` mount options.
+///
+/// Set it with [`EncryptedFs::set_atime_policy`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AtimePolicy {
+    /// Update it on every read.
+    Always,
+    /// Update it only if it's older than the last modification or change, or older than a day,
+    /// so most reads don't need to write the inode.
+    #[default]
+    Relatime,
+    /// Never update it on reads.
+    Noatime,
+}
+
+impl AtimePolicy {
+    /// If a read at `now` should update `atime`.
+    fn should_update(
+        self,
+        atime: SystemTime,
+        mtime: SystemTime,
+        ctime: SystemTime,
+        now: SystemTime,
+    ) -> bool {
+        match self {
+            Self::Always => true,
+            Self::Relatime => {
+                atime <= mtime
+                    || atime <= ctime
+                    || now
+                        .duration_since(atime)
+                        .is_ok_and(|elapsed| elapsed >= Duration::from_secs(24 * 60 * 60))
+            }
+            Self::Noatime => false,
+        }
+    }
+}
+
+/// How [`EncryptedFs::allocate`] changes the file, like the `fallocate` modes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AllocateMode {
+    /// Grow the file to include the range, the new content reads as zeros
+    #[default]
+    Default,
+    /// Don't change the size, like `FALLOC_FL_KEEP_SIZE`
+    KeepSize,
+}
+
+/// Time to set in [`SetFileAttr`], like the values `utimensat(2)` takes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SetTime {
+    /// Leave it unchanged, like `UTIME_OMIT`
+    #[default]
+    Omit,
+    /// Set it to the current time, like `UTIME_NOW`
+    Now,
+    Set(SystemTime),
+}
+
+impl SetTime {
+    /// The time to set, with `now` for [`SetTime::Now`], `None` for [`SetTime::Omit`].
+    #[must_use]
+    pub const fn resolve(self, now: SystemTime) -> Option<SystemTime> {
+        match self {
+            Self::Omit => None,
+            Self::Now => Some(now),
+            Self::Set(time) => Some(time),
+        }
+    }
+}
+
+impl From<SystemTime> for SetTime {
+    fn from(value: SystemTime) -> Self {
+        Self::Set(value)
+    }
+}
+
 #[derive(Debug, Clone, Copy, Default)]
 pub struct SetFileAttr {
     /// Size in bytes
     pub size: Option<u64>,
     /// Time of last access
-    pub atime: Option<SystemTime>,
+    pub atime: SetTime,
     /// Time of last modification
-    pub mtime: Option<SystemTime>,
+    pub mtime: SetTime,
     /// Time of last change
     pub ctime: Option<SystemTime>,
     /// Time of creation (macOS only)
     pub crtime: Option<SystemTime>,
     /// Permissions
     pub perm: Option<u16>,
+    /// Number of hard links
+    pub nlink: Option<u32>,
     /// User id
     pub uid: Option<u32>,
     /// Group id
@@ -143,14 +428,14 @@ impl SetFileAttr {
     }
 
     #[must_use]
-    pub const fn with_atime(mut self, atime: SystemTime) -> Self {
-        self.atime = Some(atime);
+    pub fn with_atime(mut self, atime: impl Into<SetTime>) -> Self {
+        self.atime = atime.into();
         self
     }
 
     #[must_use]
-    pub const fn with_mtime(mut self, mtime: SystemTime) -> Self {
-        self.mtime = Some(mtime);
+    pub fn with_mtime(mut self, mtime: impl Into<SetTime>) -> Self {
+        self.mtime = mtime.into();
         self
     }
 
@@ -172,6 +457,12 @@ impl SetFileAttr {
         self
     }
 
+    #[must_use]
+    pub const fn with_nlink(mut self, nlink: u32) -> Self {
+        self.nlink = Some(nlink);
+        self
+    }
+
     #[must_use]
     pub const fn with_uid(mut self, uid: u32) -> Self {
         self.uid = Some(uid);
@@ -192,7 +483,7 @@ impl SetFileAttr {
 
     #[must_use]
     pub const fn with_flags(mut self, flags: u32) -> Self {
-        self.rdev = Some(flags);
+        self.flags = Some(flags);
         self
     }
 }
@@ -234,7 +525,7 @@ impl From<CreateFileAttr> for FileAttr {
             uid: value.uid,
             gid: value.gid,
             rdev: value.rdev,
-            blksize: 0,
+            blksize: BLKSIZE,
             flags: value.flags,
         }
     }
@@ -256,8 +547,8 @@ pub enum FsError {
     },
     #[error("item not found: {0}")]
     NotFound(&'static str),
-    #[error("inode not found")]
-    InodeNotFound,
+    #[error("inode {0} not found")]
+    InodeNotFound(u64),
     #[error("invalid input")]
     InvalidInput(&'static str),
     #[error("invalid node type")]
@@ -268,14 +559,22 @@ pub enum FsError {
     AlreadyExists,
     #[error("already open for write")]
     AlreadyOpenForWrite,
-    #[error("not empty")]
-    NotEmpty,
+    #[error("directory {0} is not empty")]
+    NotEmpty(u64),
+    #[error("not a directory")]
+    NotADirectory,
+    #[error("is a directory")]
+    IsADirectory,
     #[error("other: {0}")]
     Other(&'static str),
     #[error("invalid password")]
     InvalidPassword,
     #[error("invalid structure of data directory")]
     InvalidDataDirStructure,
+    #[error("directory entry is corrupted or was tampered with")]
+    InvalidDirectoryEntry,
+    #[error("inode {0} is corrupted")]
+    CorruptedInode(u64),
     #[error("crypto error: {source}")]
     Crypto {
         #[from]
@@ -302,6 +601,22 @@ pub enum FsError {
     },
     #[error("max filesize exceeded, max allowed {0}")]
     MaxFilesizeExceeded(usize),
+    #[error("content of inode {ino} at offset {offset} is corrupted or was tampered with")]
+    IntegrityError { ino: u64, offset: u64 },
+    #[error("permission denied")]
+    PermissionDenied,
+    #[error("removing inode {ino} failed: {source}")]
+    RemoveFailed { ino: u64, source: Box<Self> },
+    #[error("file name too long")]
+    NameTooLong,
+    #[error("quota exceeded")]
+    QuotaExceeded,
+    #[error("data directory is already in use")]
+    DataDirInUse,
+    #[error("read-only filesystem")]
+    ReadOnly,
+    #[error("unsupported data directory format version {0}, only version 0 can be migrated to version {FORMAT_VERSION}")]
+    UnsupportedFormatVersion(u32),
 }
 
 #[derive(Debug, Clone)]
@@ -434,8 +749,51 @@ impl PartialEq for DirectoryEntryPlus {
     }
 }
 
+impl FsError {
+    /// The `errno` matching this error, like a FUSE adapter needs to report.
+    ///
+    /// Errors without a more specific match are reported as `EIO`.
+    #[must_use]
+    pub fn to_errno(&self) -> libc::c_int {
+        match self {
+            Self::RemoveFailed { source, .. } => source.to_errno(),
+            Self::NotFound(_) | Self::InodeNotFound(_) => libc::ENOENT,
+            Self::AlreadyExists => libc::EEXIST,
+            Self::NotEmpty(_) => libc::ENOTEMPTY,
+            Self::NotADirectory => libc::ENOTDIR,
+            Self::IsADirectory => libc::EISDIR,
+            Self::InvalidInput(_) | Self::InvalidInodeType => libc::EINVAL,
+            Self::PermissionDenied => libc::EACCES,
+            Self::NameTooLong => libc::ENAMETOOLONG,
+            Self::QuotaExceeded => libc::EDQUOT,
+            Self::DataDirInUse => libc::EBUSY,
+            Self::ReadOnly => libc::EROFS,
+            _ => libc::EIO,
+        }
+    }
+}
+
 pub type FsResult<T> = Result<T, FsError>;
 
+/// Problem found by [`EncryptedFs::verify`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Inconsistency {
+    /// Inode file can't be read.
+    CorruptedInode(u64),
+    /// Inode has no contents file or directory.
+    MissingContents(u64),
+    /// Directory entry can't be read.
+    CorruptedEntry { parent: u64 },
+    /// Directory entry points to an inode that doesn't exist.
+    DanglingEntry { parent: u64, ino: u64 },
+    /// Directory is missing the `.` or `..` entry.
+    MissingDotEntry { ino: u64, name: &'static str },
+    /// Inode is not referenced by any directory entry.
+    OrphanInode(u64),
+    /// `nlink` doesn't match the number of directory entries pointing to the inode.
+    NlinkMismatch { ino: u64, nlink: u32, entries: u32 },
+}
+
 pub struct DirectoryEntryIterator(VecDeque<FsResult<DirectoryEntry>>);
 
 impl Iterator for DirectoryEntryIterator {
@@ -448,6 +806,45 @@ impl Iterator for DirectoryEntryIterator {
 
 pub struct DirectoryEntryPlusIterator(VecDeque<FsResult<DirectoryEntryPlus>>);
 
+/// Iterator over all nodes in a tree, see [`EncryptedFs::walk`].
+pub struct WalkIterator(VecDeque<FsResult<(PathBuf, FileAttr)>>);
+
+/// What happened to an inode, see [`ChangeRecord`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChangeKind {
+    Created,
+    /// The attributes changed, which includes the size and times when the content changes.
+    Modified,
+    Deleted,
+}
+
+/// Entry of the change log, see [`EncryptedFs::set_change_log`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangeRecord {
+    pub ino: u64,
+    pub kind: ChangeKind,
+    pub time: SystemTime,
+}
+
+/// Iterator over the changes, see [`EncryptedFs::changes_since`].
+pub struct ChangeIterator(VecDeque<ChangeRecord>);
+
+impl Iterator for ChangeIterator {
+    type Item = ChangeRecord;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.pop_front()
+    }
+}
+
+impl Iterator for WalkIterator {
+    type Item = FsResult<(PathBuf, FileAttr)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.pop_front()
+    }
+}
+
 impl Iterator for DirectoryEntryPlusIterator {
     type Item = FsResult<DirectoryEntryPlus>;
 
@@ -457,10 +854,150 @@ impl Iterator for DirectoryEntryPlusIterator {
     }
 }
 
+/// A file opened with [`EncryptedFs::open_file`], the handle is released when it's dropped.
+///
+/// It keeps the position, so it can be used with the [`Read`], [`Write`] and [`Seek`] traits
+/// instead of the offset and handle API. These block on the async API, so they need to be called
+/// from a multi-threaded Tokio runtime.
+pub struct EncryptedFile {
+    fs: Arc<EncryptedFs>,
+    ino: u64,
+    handle: u64,
+    pos: u64,
+    released: bool,
+}
+
+impl EncryptedFile {
+    #[must_use]
+    pub const fn ino(&self) -> u64 {
+        self.ino
+    }
+
+    #[must_use]
+    pub const fn handle(&self) -> u64 {
+        self.handle
+    }
+
+    /// Release the handle, like dropping it but reporting the errors.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn close(mut self) -> FsResult<()> {
+        self.released = true;
+        self.fs.release(self.handle).await
+    }
+}
+
+fn to_io_error(err: FsError) -> io::Error {
+    match err {
+        FsError::Io { source } => source,
+        err => io::Error::other(err),
+    }
+}
+
+impl Read for EncryptedFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = async_util::call_async(self.fs.read(self.ino, self.pos, buf, self.handle))
+            .map_err(to_io_error)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for EncryptedFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = async_util::call_async(self.fs.write(self.ino, self.pos, buf, self.handle))
+            .map_err(to_io_error)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        async_util::call_async(self.fs.flush(self.handle)).map_err(to_io_error)
+    }
+}
+
+impl Seek for EncryptedFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(pos) => {
+                let size = async_util::call_async(self.fs.get_attr(self.ino))
+                    .map_err(to_io_error)?
+                    .size;
+                size.checked_add_signed(pos)
+            }
+            SeekFrom::Current(pos) => self.pos.checked_add_signed(pos),
+        };
+        self.pos = new_pos.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+
+impl Drop for EncryptedFile {
+    fn drop(&mut self) {
+        if self.released {
+            return;
+        }
+        if let Err(err) = async_util::call_async(self.fs.release(self.handle)) {
+            error!(err = %err, handle = self.handle, "releasing handle");
+        }
+    }
+}
+
+/// Directory attributes changes are kept in memory while this is alive, and written once when
+/// it's committed, see [`EncryptedFs::begin_batch`].
+///
+/// Dropping it commits, blocking on the async API, so that needs a multi-threaded Tokio runtime.
+/// Use [`Batch::commit`] to do it async and get the errors.
+pub struct Batch {
+    fs: Arc<EncryptedFs>,
+    committed: bool,
+}
+
+impl Batch {
+    /// End the batch, writing the pending changes if it's the outermost one.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn commit(mut self) -> FsResult<()> {
+        self.committed = true;
+        self.fs.end_batch().await
+    }
+}
+
+impl Drop for Batch {
+    fn drop(&mut self) {
+        if self.committed {
+            return;
+        }
+        if let Err(err) = async_util::call_async(self.fs.end_batch()) {
+            error!(err = %err, "committing batch");
+        }
+    }
+}
+
+#[derive(Default)]
+struct BatchState {
+    // nested batches, we write when the outermost ends
+    depth: usize,
+    pending: HashMap<u64, FileAttr>,
+}
+
+#[derive(Default)]
+struct QuotaState {
+    limit: Option<u64>,
+    // total size of the files, `None` until it's computed when a quota is first set
+    used: Option<u64>,
+}
+
 struct ReadHandleContext {
     ino: u64,
     attr: TimesFileAttr,
-    reader: Option<Box<dyn CryptoReadSeek<File>>>,
+    // if reads changed `atime`, so we need to save it on release
+    atime_updated: bool,
+    reader: Option<Box<dyn CryptoReadSeek<Box<dyn StorageFile>>>>,
 }
 
 enum ReadHandleContextOperation {
@@ -476,7 +1013,7 @@ impl ReadHandleContextOperation {
 }
 
 enum WriteHandleContextOperation {
-    Create { ino: u64 },
+    Create { ino: u64, append: bool },
 }
 
 impl WriteHandleContextOperation {
@@ -490,14 +1027,18 @@ impl WriteHandleContextOperation {
 struct WriteHandleContext {
     ino: u64,
     attr: TimesAndSizeFileAttr,
-    writer: Option<Box<dyn CryptoWriteSeek<File>>>,
+    writer: Option<Box<dyn CryptoWriteSeek<Box<dyn StorageFile>>>>,
+    /// Writes ignore the offset and go to the end of the file
+    append: bool,
+    /// We wrote since the reader of this handle was last opened, it might not see the changes
+    written: bool,
 }
 
 struct KeyProvider {
-    key_path: PathBuf,
-    salt_path: PathBuf,
+    storage: Arc<dyn Storage>,
     password_provider: Box<dyn PasswordProvider>,
     cipher: Cipher,
+    kdf_iterations: u32,
 }
 
 #[async_trait]
@@ -507,7 +1048,7 @@ impl ValueProvider<SecretVec<u8>, FsError> for KeyProvider {
             .password_provider
             .get_password()
             .ok_or(FsError::InvalidPassword)?;
-        read_or_create_key(&self.key_path, &self.salt_path, &password, self.cipher)
+        read_or_create_key(&*self.storage, &password, self.cipher, self.kdf_iterations)
     }
 }
 
@@ -515,39 +1056,54 @@ pub trait PasswordProvider: Send + Sync + 'static {
     fn get_password(&self) -> Option<SecretString>;
 }
 
-struct DirEntryNameCacheProvider {}
+struct DirEntryNameCacheProvider {
+    size: NonZeroUsize,
+}
 #[async_trait]
 impl ValueProvider<Mutex<LruCache<String, SecretString>>, FsError> for DirEntryNameCacheProvider {
     async fn provide(&self) -> Result<Mutex<LruCache<String, SecretString>>, FsError> {
-        Ok(Mutex::new(LruCache::new(NonZeroUsize::new(2000).unwrap())))
+        Ok(Mutex::new(LruCache::new(self.size)))
     }
 }
 
-struct DirEntryMetaCacheProvider {}
+struct DirEntryMetaCacheProvider {
+    size: NonZeroUsize,
+}
 #[async_trait]
 impl ValueProvider<Mutex<DirEntryMetaCache>, FsError> for DirEntryMetaCacheProvider {
     async fn provide(&self) -> Result<Mutex<DirEntryMetaCache>, FsError> {
-        Ok(Mutex::new(LruCache::new(NonZeroUsize::new(2000).unwrap())))
+        Ok(Mutex::new(LruCache::new(self.size)))
     }
 }
 
-struct AttrCacheProvider {}
+struct AttrCacheProvider {
+    size: NonZeroUsize,
+}
 #[async_trait]
 impl ValueProvider<RwLock<LruCache<u64, FileAttr>>, FsError> for AttrCacheProvider {
     async fn provide(&self) -> Result<RwLock<LruCache<u64, FileAttr>>, FsError> {
-        Ok(RwLock::new(LruCache::new(NonZeroUsize::new(2000).unwrap())))
+        Ok(RwLock::new(LruCache::new(self.size)))
     }
 }
 
 type DirEntryMetaCache = LruCache<String, (u64, FileType)>;
+type IdleReadersCache = LruCache<u64, Box<dyn CryptoReadSeek<Box<dyn StorageFile>>>>;
 
 /// Encrypted FS that stores encrypted files in a dedicated directory with a specific structure based on `inode`.
 pub struct EncryptedFs {
-    pub(crate) data_dir: PathBuf,
+    storage: Arc<dyn Storage>,
     write_handles: RwLock<HashMap<u64, Mutex<WriteHandleContext>>>,
     read_handles: RwLock<HashMap<u64, Mutex<ReadHandleContext>>>,
+    // entries of the directories as they were when opened with `opendir`
+    dir_handles: RwLock<HashMap<u64, Vec<DirectoryEntry>>>,
     current_handle: AtomicU64,
+    // next inode to be allocated, persisted in `SECURITY_DIR` to survive remount
+    current_ino: AtomicU64,
+    // serialize allocation and persisting of the inode counter
+    current_ino_lock: std::sync::Mutex<()>,
     cipher: Cipher,
+    // read from the metadata of the data dir
+    config: FsConfig,
     // (ino, fh)
     opened_files_for_read: RwLock<HashMap<u64, HashSet<u64>>>,
     opened_files_for_write: RwLock<HashMap<u64, u64>>,
@@ -567,9 +1123,36 @@ pub struct EncryptedFs {
         ExpireValue<Mutex<LruCache<String, SecretString>>, FsError, DirEntryNameCacheProvider>,
     dir_entries_meta_cache:
         ExpireValue<Mutex<DirEntryMetaCache>, FsError, DirEntryMetaCacheProvider>,
+    // readers of released handles, reused by the next open of the same inode as they seek anyway
+    // on read, they are dropped when the content changes
+    idle_readers: std::sync::Mutex<IdleReadersCache>,
+    // (uid, gid) to check permissions for, `None` if we don't check them
+    enforce_permissions: std::sync::RwLock<Option<(u32, u32)>>,
+    atime_policy: std::sync::RwLock<AtimePolicy>,
+    // if we record the changes, also serializes the access to the change log
+    change_log: std::sync::Mutex<bool>,
+    batch: std::sync::Mutex<BatchState>,
+    quota: std::sync::Mutex<QuotaState>,
+    // number of inodes, `None` until it's computed when `statfs` is first called
+    inode_count: std::sync::Mutex<Option<u64>>,
+    // how many times each inode was written to storage
+    #[cfg(test)]
+    inode_writes: std::sync::Mutex<HashMap<u64, usize>>,
+    // makes the operations fail at this point, to simulate a crash
+    #[cfg(test)]
+    fail_point: std::sync::Mutex<Option<&'static str>>,
+    read_only: bool,
+    // holds the lock on the data dir while we're open
+    _lock: Box<dyn Send + Sync>,
 }
 
 impl EncryptedFs {
+    /// Open the data dir, creating it if it doesn't exist.
+    ///
+    /// Only one instance can have it open at a time, it fails with [`FsError::DataDirInUse`] if
+    /// another one, also from other processes, has it open.
+    /// Data dirs written with another format fail with [`FsError::UnsupportedFormatVersion`],
+    /// the ones created before the format version was kept need [`EncryptedFs::migrate`] first.
     #[allow(clippy::missing_panics_doc)]
     #[allow(clippy::missing_errors_doc)]
     pub async fn new(
@@ -577,23 +1160,116 @@ impl EncryptedFs {
         password_provider: Box<dyn PasswordProvider>,
         cipher: Cipher,
     ) -> FsResult<Arc<Self>> {
+        Self::new_with(data_dir, password_provider, cipher, FsOptions::default()).await
+    }
+
+    /// Open an existing data dir for reading only, changes fail with [`FsError::ReadOnly`].
+    ///
+    /// Many read-only instances can have it open at the same time, but not together with one
+    /// opened with [`EncryptedFs::new`]. Access times are not updated.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn new_read_only(
+        data_dir: PathBuf,
+        password_provider: Box<dyn PasswordProvider>,
+        cipher: Cipher,
+    ) -> FsResult<Arc<Self>> {
+        Self::new_with(
+            data_dir,
+            password_provider,
+            cipher,
+            FsOptions::default().with_read_only(true),
+        )
+        .await
+    }
+
+    /// Like [`EncryptedFs::new`] or [`EncryptedFs::new_read_only`], with the [`FsOptions`].
+    ///
+    /// The cache sizes and the key derivation iterations must be greater than 0.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn new_with(
+        data_dir: PathBuf,
+        password_provider: Box<dyn PasswordProvider>,
+        cipher: Cipher,
+        options: FsOptions,
+    ) -> FsResult<Arc<Self>> {
+        Self::new_with_storage(
+            Arc::new(FsStorage::new(data_dir)),
+            password_provider,
+            cipher,
+            options,
+        )
+        .await
+    }
+
+    /// Like [`EncryptedFs::new_with`], with the files kept in `storage` instead of the data dir.
+    ///
+    /// With [`crate::storage::MemoryStorage`] nothing is written to disk and everything is lost
+    /// when it's dropped.
+    #[allow(clippy::missing_panics_doc)]
+    #[allow(clippy::missing_errors_doc)]
+    #[allow(clippy::too_many_lines)]
+    pub async fn new_with_storage(
+        storage: Arc<dyn Storage>,
+        password_provider: Box<dyn PasswordProvider>,
+        cipher: Cipher,
+        options: FsOptions,
+    ) -> FsResult<Arc<Self>> {
+        let (Some(attr_cache_size), Some(dir_entries_cache_size), Some(idle_readers_cache_size)) = (
+            NonZeroUsize::new(options.attr_cache_size),
+            NonZeroUsize::new(options.dir_entries_cache_size),
+            NonZeroUsize::new(options.idle_readers_cache_size),
+        ) else {
+            return Err(FsError::InvalidInput("cache size must be greater than 0"));
+        };
+        if options.kdf_iterations < argon2::Params::MIN_T_COST {
+            return Err(FsError::InvalidInput(
+                "key derivation needs at least one iteration",
+            ));
+        }
+        let read_only = options.read_only;
+
+        if read_only {
+            check_structure(&*storage, false)?;
+        } else {
+            ensure_structure_created(&*storage)?;
+        }
+        // lock before changing anything, it's released when the file is closed, also on crash
+        let lock = lock_data_dir(&*storage, read_only)?;
+        if !read_only {
+            storage.create_dir_all(&Path::new(SECURITY_DIR).join(JOURNAL_DIR))?;
+        }
+        let metadata = check_metadata(&*storage, cipher)?;
+        if metadata.is_none() && has_inodes(&*storage)? {
+            // created before we kept the metadata, see `migrate`
+            return Err(FsError::UnsupportedFormatVersion(0));
+        }
+        let metadata_exists = metadata.is_some();
+        // for new data dirs, it's written after we check the password
+        let metadata = metadata.unwrap_or_else(|| Metadata::new(cipher, options.kdf_iterations));
         let key_provider = KeyProvider {
-            key_path: data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME),
-            salt_path: data_dir.join(SECURITY_DIR).join(KEY_SALT_FILENAME),
+            storage: storage.clone(),
             password_provider,
             cipher,
+            kdf_iterations: metadata.kdf_t_cost,
         };
         let key = ExpireValue::new(key_provider, Duration::from_secs(10 * 60));
-
-        ensure_structure_created(&data_dir.clone()).await?;
         key.get().await?; // this will check the password
+        if !metadata_exists && !read_only {
+            write_metadata(&*storage, &metadata)?;
+        }
+        let config = metadata.into();
+        let current_ino = read_inode_counter(&*storage)?;
 
         let fs = Self {
-            data_dir,
+            storage,
             write_handles: RwLock::new(HashMap::new()),
             read_handles: RwLock::new(HashMap::new()),
+            dir_handles: RwLock::new(HashMap::new()),
             current_handle: AtomicU64::new(1),
+            current_ino: AtomicU64::new(current_ino),
+            current_ino_lock: std::sync::Mutex::new(()),
             cipher,
+            config,
             opened_files_for_read: RwLock::new(HashMap::new()),
             opened_files_for_write: RwLock::new(HashMap::new()),
             serialize_inode_locks: Arc::new(ArcHashMap::default()),
@@ -604,17 +1280,39 @@ impl EncryptedFs {
             self_weak: std::sync::Mutex::new(None),
             read_write_locks: ArcHashMap::default(),
             // todo: take duration from param
-            attr_cache: ExpireValue::new(AttrCacheProvider {}, Duration::from_secs(10 * 60)),
+            attr_cache: ExpireValue::new(
+                AttrCacheProvider {
+                    size: attr_cache_size,
+                },
+                Duration::from_secs(10 * 60),
+            ),
             // todo: take duration from param
             dir_entries_name_cache: ExpireValue::new(
-                DirEntryNameCacheProvider {},
+                DirEntryNameCacheProvider {
+                    size: dir_entries_cache_size,
+                },
                 Duration::from_secs(10 * 60),
             ),
             // todo: take duration from param
             dir_entries_meta_cache: ExpireValue::new(
-                DirEntryMetaCacheProvider {},
+                DirEntryMetaCacheProvider {
+                    size: dir_entries_cache_size,
+                },
                 Duration::from_secs(10 * 60),
             ),
+            idle_readers: std::sync::Mutex::new(LruCache::new(idle_readers_cache_size)),
+            enforce_permissions: std::sync::RwLock::new(None),
+            atime_policy: std::sync::RwLock::new(AtimePolicy::default()),
+            change_log: std::sync::Mutex::new(false),
+            batch: std::sync::Mutex::new(BatchState::default()),
+            quota: std::sync::Mutex::new(QuotaState::default()),
+            inode_count: std::sync::Mutex::new(None),
+            #[cfg(test)]
+            inode_writes: std::sync::Mutex::new(HashMap::new()),
+            #[cfg(test)]
+            fail_point: std::sync::Mutex::new(None),
+            read_only,
+            _lock: lock,
         };
 
         let arc = Arc::new(fs);
@@ -623,42 +1321,401 @@ impl EncryptedFs {
             .expect("cannot obtain lock")
             .replace(Arc::downgrade(&arc));
 
-        arc.ensure_root_exists().await?;
+        if read_only {
+            if arc
+                .storage
+                .read_dir(&Path::new(SECURITY_DIR).join(JOURNAL_DIR))
+                .is_ok_and(|names| !names.is_empty())
+            {
+                warn!("interrupted operations are completed on the next read-write open");
+            }
+        } else {
+            arc.ensure_root_exists().await?;
+            arc.replay_journal().await?;
+        }
 
         Ok(arc)
     }
 
+    /// If it was opened with [`EncryptedFs::new_read_only`].
+    pub const fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    const fn check_writable(&self) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        Ok(())
+    }
+
+    /// Parameters the data dir was created with.
+    pub const fn config(&self) -> &FsConfig {
+        &self.config
+    }
+
     pub fn exists(&self, ino: u64) -> bool {
-        self.ino_file(ino).is_file()
+        self.storage.is_file(&Self::ino_file(ino))
     }
 
-    pub fn is_dir(&self, ino: u64) -> bool {
-        self.contents_path(ino).is_dir()
+    /// Check permissions as the user `uid` and group `gid` on [`EncryptedFs::open`], [`EncryptedFs::open_with`],
+    /// [`EncryptedFs::read_dir`] and [`EncryptedFs::read_dir_plus`], `None` disables the checks.
+    ///
+    /// It's disabled by default. Reads and writes are checked when the handle is opened.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn set_enforce_permissions(&self, identity: Option<(u32, u32)>) {
+        *self
+            .enforce_permissions
+            .write()
+            .expect("cannot obtain lock") = identity;
     }
 
-    pub fn is_file(&self, ino: u64) -> bool {
-        self.contents_path(ino).is_file()
+    /// Set when reads update the access time of files and directories, see [`AtimePolicy`].
+    ///
+    /// It's [`AtimePolicy::Relatime`] by default.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn set_atime_policy(&self, policy: AtimePolicy) {
+        *self.atime_policy.write().expect("cannot obtain lock") = policy;
     }
 
-    /// Create a new node in the filesystem
+    /// It's always [`AtimePolicy::Noatime`] when read-only.
     #[allow(clippy::missing_panics_doc)]
-    #[allow(clippy::missing_errors_doc)]
-    #[allow(clippy::too_many_lines)]
-    pub async fn create(
-        &self,
-        parent: u64,
-        name: &SecretString,
-        create_attr: CreateFileAttr,
+    pub fn atime_policy(&self) -> AtimePolicy {
+        if self.read_only {
+            return AtimePolicy::Noatime;
+        }
+        *self.atime_policy.read().expect("cannot obtain lock")
+    }
+
+    /// Limit the total size of the files to `bytes`, `None` removes the limit.
+    ///
+    /// Writes, [`EncryptedFs::set_len`] and [`EncryptedFs::allocate`] fail with [`FsError::QuotaExceeded`]
+    /// if they would grow the files over it. The size is the logical one, the sum of the file sizes,
+    /// not what the encrypted files take on disk. It's computed once, then kept up to date as files change.
+    #[allow(clippy::missing_panics_doc)]
+    pub async fn set_quota(&self, bytes: Option<u64>) -> FsResult<()> {
+        if bytes.is_some() {
+            self.files_size().await?;
+        }
+        self.quota.lock().expect("cannot obtain lock").limit = bytes;
+        Ok(())
+    }
+
+    #[allow(clippy::missing_panics_doc)]
+    pub fn quota(&self) -> Option<u64> {
+        self.quota.lock().expect("cannot obtain lock").limit
+    }
+
+    /// Total size of the files counted for the quota, `None` if a quota was never set
+    /// and [`EncryptedFs::statfs`] was never called.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn quota_used(&self) -> Option<u64> {
+        self.quota.lock().expect("cannot obtain lock").used
+    }
+
+    /// Total size of the files, computed the first time it's needed, then kept up to date
+    /// with [`EncryptedFs::grow_quota_used`] and [`EncryptedFs::shrink_quota_used`].
+    async fn files_size(&self) -> FsResult<u64> {
+        let used = self.quota.lock().expect("cannot obtain lock").used;
+        if let Some(used) = used {
+            return Ok(used);
+        }
+        let used = self.total_files_size().await?;
+        let mut quota = self.quota.lock().expect("cannot obtain lock");
+        // it could have been computed meanwhile, that one is more recent
+        Ok(*quota.used.get_or_insert(used))
+    }
+
+    async fn total_files_size(&self) -> FsResult<u64> {
+        let mut size = 0;
+        for name in self.storage.read_dir(Path::new(INODES_DIR))? {
+            let Ok(ino) = name.to_string_lossy().parse::<u64>() else {
+                continue;
+            };
+            let attr = self.get_attr(ino).await?;
+            if attr.kind == FileType::RegularFile {
+                size += attr.size;
+            }
+        }
+        Ok(size)
+    }
+
+    /// Count `len` more bytes for the quota, fails if it would exceed it.
+    fn grow_quota_used(&self, len: u64) -> FsResult<()> {
+        let mut quota = self.quota.lock().expect("cannot obtain lock");
+        if let Some(used) = quota.used {
+            let used = used.saturating_add(len);
+            if quota.limit.is_some_and(|limit| used > limit) {
+                return Err(FsError::QuotaExceeded);
+            }
+            quota.used = Some(used);
+        }
+        drop(quota);
+        Ok(())
+    }
+
+    fn shrink_quota_used(&self, len: u64) {
+        let mut quota = self.quota.lock().expect("cannot obtain lock");
+        quota.used = quota.used.map(|used| used.saturating_sub(len));
+    }
+
+    /// Number of inodes, computed the first time it's needed, then kept up to date
+    /// with [`EncryptedFs::change_inode_count`].
+    fn inode_count(&self) -> FsResult<u64> {
+        let mut count = self.inode_count.lock().expect("cannot obtain lock");
+        if let Some(count) = *count {
+            return Ok(count);
+        }
+        let computed = self
+            .storage
+            .read_dir(Path::new(INODES_DIR))?
+            .iter()
+            .filter(|name| name.to_string_lossy().parse::<u64>().is_ok())
+            .count() as u64;
+        Ok(*count.get_or_insert(computed))
+    }
+
+    fn change_inode_count(&self, delta: i64) {
+        let mut count = self.inode_count.lock().expect("cannot obtain lock");
+        *count = count.map(|count| count.saturating_add_signed(delta));
+    }
+
+    /// Record in the data dir when inodes are created, modified or deleted, so backup tools
+    /// can copy only the files that changed, see [`EncryptedFs::changes_since`].
+    ///
+    /// It's disabled by default, changes made while disabled are not recorded.
+    /// The log only grows, use [`EncryptedFs::compact_changes`] to shrink it.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn set_change_log(&self, enabled: bool) {
+        *self.change_log.lock().expect("cannot obtain lock") = enabled;
+    }
+
+    /// Changes recorded at or after `since`, in the order they happened.
+    #[allow(clippy::missing_panics_doc)]
+    pub async fn changes_since(&self, since: SystemTime) -> FsResult<ChangeIterator> {
+        let key = self.key.get().await?;
+        let records = {
+            let _guard = self.change_log.lock().expect("cannot obtain lock");
+            self.read_changes(&key)?
+        };
+        Ok(ChangeIterator(
+            records
+                .into_iter()
+                .filter(|record| record.time >= since)
+                .collect(),
+        ))
+    }
+
+    /// Keep only the last change of each inode in the change log.
+    ///
+    /// [`EncryptedFs::changes_since`] returns the same inodes as before, but not all their changes.
+    #[allow(clippy::missing_panics_doc)]
+    pub async fn compact_changes(&self) -> FsResult<()> {
+        self.check_writable()?;
+        let key = self.key.get().await?;
+        let _guard = self.change_log.lock().expect("cannot obtain lock");
+        let records = self.read_changes(&key)?;
+        let mut last = HashMap::new();
+        for (i, record) in records.iter().enumerate() {
+            last.insert(record.ino, i);
+        }
+        let mut file = self.storage.open_atomic(&Self::changes_path())?;
+        for (i, record) in records.iter().enumerate() {
+            if last.get(&record.ino) == Some(&i) {
+                self.write_change(&mut file, record, &key)?;
+            }
+        }
+        file.commit()?;
+        Ok(())
+    }
+
+    fn changes_path() -> PathBuf {
+        Path::new(SECURITY_DIR).join(CHANGES_FILENAME)
+    }
+
+    /// Append the change to the log, if enabled.
+    async fn record_change(&self, ino: u64, kind: ChangeKind) -> FsResult<()> {
+        if !*self.change_log.lock().expect("cannot obtain lock") {
+            return Ok(());
+        }
+        let key = self.key.get().await?;
+        let record = ChangeRecord {
+            ino,
+            kind,
+            time: SystemTime::now(),
+        };
+        let enabled = self.change_log.lock().expect("cannot obtain lock");
+        if *enabled {
+            let mut file = self.storage.append(&Self::changes_path())?;
+            self.write_change(&mut file, &record, &key)?;
+            file.sync_data()?;
+        }
+        drop(enabled);
+        Ok(())
+    }
+
+    /// Each change is encrypted separately on its own line, so we can append to the log.
+    fn write_change(
+        &self,
+        w: &mut impl Write,
+        record: &ChangeRecord,
+        key: &SecretVec<u8>,
+    ) -> FsResult<()> {
+        let mut buf = vec![];
+        crypto::serialize_encrypt_into(&mut buf, record, self.cipher, key)?;
+        // write the whole line at once, so a crash doesn't leave a partial line in the middle
+        w.write_all(format!("{}\n", crypto::BASE64.encode(buf)).as_bytes())?;
+        Ok(())
+    }
+
+    fn read_changes(&self, key: &SecretVec<u8>) -> FsResult<Vec<ChangeRecord>> {
+        let data = match self.storage.read(&Self::changes_path()) {
+            Ok(data) => String::from_utf8(data)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err.into()),
+        };
+        let mut records = vec![];
+        // the part after the last newline is an append interrupted by a crash, skip it
+        let mut lines = data.split('\n').collect::<Vec<_>>();
+        lines.pop();
+        for line in lines {
+            let data = crypto::BASE64.decode(line).map_err(crypto::Error::from)?;
+            records.push(bincode::deserialize_from(crypto::create_read(
+                io::Cursor::new(data),
+                self.cipher,
+                key,
+            ))?);
+        }
+        Ok(records)
+    }
+
+    /// Update the access time of `ino` after it was read, if [`AtimePolicy`] says so.
+    async fn touch_atime(&self, ino: u64) -> FsResult<()> {
+        let now = SystemTime::now();
+        let update = match self.atime_policy() {
+            AtimePolicy::Always => true,
+            AtimePolicy::Noatime => false,
+            policy @ AtimePolicy::Relatime => {
+                let attr = self.get_attr(ino).await?;
+                policy.should_update(attr.atime, attr.mtime, attr.ctime, now)
+            }
+        };
+        if update {
+            self.set_attr(ino, SetFileAttr::default().with_atime(now))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Check if the user `uid` and group `gid` have access to `ino` for `mask`, a combination of
+    /// `libc::R_OK`, `libc::W_OK` and `libc::X_OK`.
+    ///
+    /// Returns [`FsError::PermissionDenied`] if they don't.
+    pub async fn check_access(&self, ino: u64, uid: u32, gid: u32, mask: i32) -> FsResult<()> {
+        let attr = self.get_attr(ino).await?;
+        if check_access(attr.uid, attr.gid, attr.perm, uid, gid, mask) {
+            Ok(())
+        } else {
+            Err(FsError::PermissionDenied)
+        }
+    }
+
+    /// Answer an `access(2)` call, without opening the file.
+    ///
+    /// Like [`EncryptedFs::check_access`], but `mask` is validated first, it can only be `libc::F_OK`,
+    /// which only checks `ino` exists, or a combination of `libc::R_OK`, `libc::W_OK` and `libc::X_OK`.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn access(&self, ino: u64, uid: u32, gid: u32, mask: i32) -> FsResult<()> {
+        if mask & !(libc::R_OK | libc::W_OK | libc::X_OK) != 0 {
+            return Err(FsError::InvalidInput("invalid access mask"));
+        }
+        self.check_access(ino, uid, gid, mask).await
+    }
+
+    async fn enforce_access(&self, ino: u64, mask: i32) -> FsResult<()> {
+        let identity = *self.enforce_permissions.read().expect("cannot obtain lock");
+        match identity {
+            Some((uid, gid)) => self.check_access(ino, uid, gid, mask).await,
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_dir(&self, ino: u64) -> bool {
+        self.storage.is_dir(&Self::contents_path(ino))
+    }
+
+    pub fn is_file(&self, ino: u64) -> bool {
+        self.storage.is_file(&Self::contents_path(ino))
+    }
+
+    /// Create a new node in the filesystem without opening it.
+    ///
+    /// Same as [`EncryptedFs::create`] with `read` and `write` false, use [`EncryptedFs::open`] later if needed.
+    pub async fn create_node(
+        &self,
+        parent: u64,
+        name: &SecretString,
+        create_attr: CreateFileAttr,
+    ) -> FsResult<FileAttr> {
+        Ok(self
+            .create(parent, name, create_attr, false, false)
+            .await?
+            .1)
+    }
+
+    /// Create a new node, or open the existing one if not [`CreateFlags::exclusive`].
+    ///
+    /// Returns the handle, 0 if the file was not opened, and the attributes.
+    /// Opening an existing directory fails with [`FsError::InvalidInodeType`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn create_with(
+        &self,
+        parent: u64,
+        name: &SecretString,
+        create_attr: CreateFileAttr,
+        flags: CreateFlags,
+    ) -> FsResult<(u64, FileAttr)> {
+        let attr = match self.create_node(parent, name, create_attr).await {
+            Ok(attr) => attr,
+            Err(FsError::AlreadyExists) if !flags.exclusive => {
+                let attr = self
+                    .find_by_name(parent, name)
+                    .await?
+                    .ok_or(FsError::NotFound("name not found"))?;
+                if attr.kind == FileType::Directory {
+                    return Err(FsError::InvalidInodeType);
+                }
+                attr
+            }
+            Err(err) => return Err(err),
+        };
+        if !flags.open.read && !flags.open.write {
+            return Ok((0, attr));
+        }
+        let fh = self.open_with(attr.ino, flags.open).await?;
+        Ok((fh, self.get_attr(attr.ino).await?))
+    }
+
+    /// Create a new node in the filesystem
+    #[allow(clippy::missing_panics_doc)]
+    #[allow(clippy::missing_errors_doc)]
+    #[allow(clippy::too_many_lines)]
+    pub async fn create(
+        &self,
+        parent: u64,
+        name: &SecretString,
+        create_attr: CreateFileAttr,
         read: bool,
         write: bool,
     ) -> FsResult<(u64, FileAttr)> {
-        if name.expose_secret() == "." || name.expose_secret() == ".." {
-            return Err(FsError::InvalidInput("name cannot be '.' or '..'"));
-        }
+        self.check_writable()?;
+        check_not_dot_name(name)?;
+        self.check_name_len(name)?;
         if !self.exists(parent) {
-            return Err(FsError::InodeNotFound);
+            return Err(FsError::InodeNotFound(parent));
         }
-        if self.exists_by_name(parent, name)? {
+        if self.exists_by_name(parent, name).await? {
             return Err(FsError::AlreadyExists);
         }
 
@@ -675,32 +1732,45 @@ impl EncryptedFs {
         NOD_RT
             .spawn(async move {
                 let mut attr: FileAttr = create_attr.into();
-                attr.ino = self_clone.generate_next_inode();
+                attr.ino = self_clone.generate_next_inode()?;
 
                 let fs = self_clone;
                 let mut join_set = JoinSet::new();
 
+                fs.write_journal(&JournalEntry::Create {
+                    parent,
+                    ino: attr.ino,
+                    name: name_clone.expose_secret().clone(),
+                })
+                .await?;
+
                 // write inode
                 let self_clone = fs.clone();
                 self_clone.write_inode_to_storage(&attr).await?;
+                self_clone.change_inode_count(1);
+                #[cfg(test)]
+                fs.check_fail_point("create:after_inode")?;
 
                 match attr.kind {
-                    FileType::RegularFile => {
+                    FileType::RegularFile
+                    | FileType::Symlink
+                    | FileType::NamedPipe
+                    | FileType::CharDevice
+                    | FileType::BlockDevice => {
                         let self_clone = fs.clone();
                         join_set.spawn(async move {
-                            // create in contents directory
-                            let file = File::create(self_clone.contents_path(attr.ino))?;
+                            // create in contents directory, for pipes and devices it's just a placeholder
+                            // as we only keep the metadata
+                            let file = self_clone.storage.create(&Self::contents_path(attr.ino))?;
                             // sync_all file and parent
                             // these operations are a bit slow, but are needed to make sure the file is correctly created
                             // i.e. creating 100 files takes 0.965 sec with sync_all and 0.130 sec without
                             file.sync_all()?;
-                            File::open(
-                                self_clone
-                                    .contents_path(attr.ino)
+                            self_clone.storage.sync_dir(
+                                Self::contents_path(attr.ino)
                                     .parent()
                                     .expect("oops, we don't have a parent"),
-                            )?
-                            .sync_all()?;
+                            )?;
                             Ok::<(), FsError>(())
                         });
                     }
@@ -709,13 +1779,15 @@ impl EncryptedFs {
                         let attr_clone = attr;
                         join_set.spawn(async move {
                             // create in contents directory
-                            let contents_dir = self_clone.contents_path(attr.ino);
-                            fs::create_dir(contents_dir.clone())?;
+                            let contents_dir = Self::contents_path(attr.ino);
+                            self_clone.storage.create_dir(&contents_dir)?;
                             // used to keep encrypted file names used by [`read_dir`] and [`read_dir_plus`]
-                            fs::create_dir(contents_dir.join(LS_DIR))?;
+                            self_clone.storage.create_dir(&contents_dir.join(LS_DIR))?;
                             // used to keep hashes of encrypted file names used by [`exists_by_name`] and [`find_by_name`]
                             // this optimizes the search process as we don't need to decrypt all file names and search
-                            fs::create_dir(contents_dir.join(HASH_DIR))?;
+                            self_clone
+                                .storage
+                                .create_dir(&contents_dir.join(HASH_DIR))?;
 
                             // add "." and ".." entries
                             self_clone
@@ -772,6 +1844,9 @@ impl EncryptedFs {
                                 .with_atime(now),
                         )
                         .await?;
+                    if attr_clone.kind == FileType::Directory {
+                        self_clone.change_dir_nlink(parent, 1).await?;
+                    }
                     Ok::<(), FsError>(())
                 });
 
@@ -779,6 +1854,7 @@ impl EncryptedFs {
                 while let Some(res) = join_set.join_next().await {
                     res??;
                 }
+                fs.remove_journal(attr.ino)?;
 
                 let self_clone = fs.clone();
                 let handle = if attr.kind == FileType::RegularFile {
@@ -798,6 +1874,47 @@ impl EncryptedFs {
             .await?
     }
 
+    /// Create a symbolic link pointing to `target`.
+    /// The target is kept encrypted in the contents file. Relative and absolute targets are stored as they are,
+    /// resolving them is up to the caller.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn create_symlink(
+        &self,
+        parent: u64,
+        name: &SecretString,
+        target: &SecretString,
+        mut create_attr: CreateFileAttr,
+    ) -> FsResult<FileAttr> {
+        create_attr.kind = FileType::Symlink;
+        let (_, attr) = self.create(parent, name, create_attr, false, false).await?;
+        atomic_serialize_encrypt_into(
+            &*self.storage,
+            &Self::contents_path(attr.ino),
+            target.expose_secret(),
+            self.cipher,
+            &*self.key.get().await?,
+        )?;
+        // like on other filesystems, the size of a symlink is the length of the target
+        let set_attr = SetFileAttr::default().with_size(target.expose_secret().len() as u64);
+        self.set_attr2(attr.ino, set_attr, true).await?;
+        self.get_attr(attr.ino).await
+    }
+
+    /// Read the target of a symbolic link.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn read_link(&self, ino: u64) -> FsResult<SecretString> {
+        let attr = self.get_inode_from_cache_or_storage(ino).await?;
+        if !matches!(attr.kind, FileType::Symlink) {
+            return Err(FsError::InvalidInodeType);
+        }
+        let target: String = bincode::deserialize_from(crypto::create_read(
+            self.storage.open(&Self::contents_path(ino))?,
+            self.cipher,
+            &*self.key.get().await?,
+        ))?;
+        Ok(SecretString::new(target))
+    }
+
     #[allow(clippy::missing_panics_doc)]
     #[allow(clippy::missing_errors_doc)]
     pub async fn find_by_name(
@@ -806,14 +1923,596 @@ impl EncryptedFs {
         name: &SecretString,
     ) -> FsResult<Option<FileAttr>> {
         if !self.exists(parent) {
-            return Err(FsError::InodeNotFound);
+            return Err(FsError::InodeNotFound(parent));
         }
         if !self.is_dir(parent) {
             return Err(FsError::InvalidInodeType);
         }
-        let hash = crypto::hash_file_name(name);
-        let hash_path = self.contents_path(parent).join(HASH_DIR).join(hash);
-        if !hash_path.is_file() {
+        let ino = if is_root_dot_dot(parent, name) {
+            Some(ROOT_INODE)
+        } else {
+            self.find_ino_by_name(parent, name).await?
+        };
+        let Some(ino) = ino else {
+            return Ok(None);
+        };
+        self.get_inode_from_cache_or_storage(ino).await.map(Some)
+    }
+
+    /// Resolve a path like `/a/b/c.txt` starting from [`ROOT_INODE`].
+    ///
+    /// Empty and `.` components are ignored and `..` goes to the parent, which for root is itself.
+    /// Returns [`FsError::NotFound`] if any component doesn't exist and [`FsError::InvalidInodeType`]
+    /// if a component other than the last one is not a directory.
+    pub async fn lookup_path(&self, path: &str) -> FsResult<FileAttr> {
+        let mut attr = self.get_attr(ROOT_INODE).await?;
+        for component in path.split('/') {
+            match component {
+                "" | "." => {}
+                ".." if attr.ino == ROOT_INODE => {}
+                _ => {
+                    if attr.kind != FileType::Directory {
+                        return Err(FsError::InvalidInodeType);
+                    }
+                    attr = self
+                        .find_by_name(attr.ino, &SecretString::new(component.to_string()))
+                        .await?
+                        .ok_or(FsError::NotFound("path component not found"))?;
+                }
+            }
+        }
+        Ok(attr)
+    }
+
+    /// Create all missing directories in `path`, like `mkdir -p`. Existing directories are left as they are.
+    ///
+    /// Returns the attributes of the last directory.
+    /// If a component exists but is not a directory it will return [`FsError::InvalidInodeType`].
+    pub async fn create_dir_all_path(
+        &self,
+        path: &str,
+        create_attr: CreateFileAttr,
+    ) -> FsResult<FileAttr> {
+        if create_attr.kind != FileType::Directory {
+            return Err(FsError::InvalidInodeType);
+        }
+        let mut attr = self.get_attr(ROOT_INODE).await?;
+        for component in path.split('/') {
+            match component {
+                "" | "." => {}
+                ".." if attr.ino == ROOT_INODE => {}
+                _ => {
+                    if attr.kind != FileType::Directory {
+                        return Err(FsError::InvalidInodeType);
+                    }
+                    let name = SecretString::new(component.to_string());
+                    attr = match self.find_by_name(attr.ino, &name).await? {
+                        Some(attr) => attr,
+                        None => {
+                            self.create(attr.ino, &name, create_attr.clone(), false, false)
+                                .await?
+                                .1
+                        }
+                    };
+                }
+            }
+        }
+        if attr.kind != FileType::Directory {
+            return Err(FsError::InvalidInodeType);
+        }
+        Ok(attr)
+    }
+
+    /// Create a file at `path`, creating the missing parent directories first.
+    ///
+    /// Parent directories are created with the same owner and permissions as the file,
+    /// plus execute permission where read is allowed, so they can be traversed.
+    pub async fn create_file_path(
+        &self,
+        path: &str,
+        create_attr: CreateFileAttr,
+        read: bool,
+        write: bool,
+    ) -> FsResult<(u64, FileAttr)> {
+        let (parent_path, name) = path
+            .trim_end_matches('/')
+            .rsplit_once('/')
+            .unwrap_or(("", path));
+        if name.is_empty() {
+            return Err(FsError::InvalidInput("path doesn't contain a file name"));
+        }
+        let dir_attr = CreateFileAttr {
+            kind: FileType::Directory,
+            perm: create_attr.perm | ((create_attr.perm & 0o444) >> 2),
+            ..create_attr.clone()
+        };
+        let parent = self.create_dir_all_path(parent_path, dir_attr).await?;
+        self.create(
+            parent.ino,
+            &SecretString::new(name.to_string()),
+            create_attr,
+            read,
+            write,
+        )
+        .await
+    }
+
+    /// Read the whole content of the file at `path`.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn read_file(&self, path: &str) -> FsResult<Vec<u8>> {
+        let attr = self.lookup_path(path).await?;
+        if attr.kind != FileType::RegularFile {
+            return Err(FsError::InvalidInodeType);
+        }
+        let fh = self.open(attr.ino, true, false).await?;
+        let res = async {
+            let mut data = vec![];
+            let mut buf = vec![0; stream_util::BUF_SIZE];
+            loop {
+                let len = self.read(attr.ino, data.len() as u64, &mut buf, fh).await?;
+                if len == 0 {
+                    break;
+                }
+                data.extend_from_slice(&buf[..len]);
+            }
+            Ok::<_, FsError>(data)
+        }
+        .await;
+        // release also on error, but report the first one
+        let release = self.release(fh).await;
+        let data = res?;
+        release?;
+        Ok(data)
+    }
+
+    /// Write `data` to the file at `path`, replacing its content.
+    ///
+    /// The file is created if it doesn't exist, with the owner of the parent directory and `0o644`
+    /// permissions. The parent directory needs to exist.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn write_file(&self, path: &str, data: &[u8]) -> FsResult<()> {
+        let path = path.trim_end_matches('/');
+        let (parent_path, name) = path.rsplit_once('/').unwrap_or(("", path));
+        if name.is_empty() {
+            return Err(FsError::InvalidInput("path doesn't contain a file name"));
+        }
+        let parent = self.lookup_path(parent_path).await?;
+        if parent.kind != FileType::Directory {
+            return Err(FsError::InvalidInodeType);
+        }
+        let name = SecretString::new(name.to_string());
+        let (fh, ino) = match self.find_by_name(parent.ino, &name).await? {
+            Some(attr) if attr.kind != FileType::RegularFile => {
+                return Err(FsError::InvalidInodeType);
+            }
+            Some(attr) => {
+                let flags = OpenFlags::default().with_write(true).with_truncate(true);
+                (self.open_with(attr.ino, flags).await?, attr.ino)
+            }
+            None => {
+                let create_attr = CreateFileAttr {
+                    kind: FileType::RegularFile,
+                    perm: 0o644,
+                    uid: parent.uid,
+                    gid: parent.gid,
+                    rdev: 0,
+                    flags: 0,
+                };
+                let (fh, attr) = self
+                    .create(parent.ino, &name, create_attr, false, true)
+                    .await?;
+                (fh, attr.ino)
+            }
+        };
+        let res = self.write_at(ino, 0, data, fh).await;
+        let release = self.release(fh).await;
+        if res? != data.len() {
+            return Err(FsError::Other("Failed to write all bytes"));
+        }
+        release
+    }
+
+    /// Check the integrity of the data dir and report any inconsistencies found. It doesn't change anything.
+    ///
+    /// It checks that every inode has contents, every directory entry points to an existing inode,
+    /// directories have `.` and `..` and `nlink` matches the number of entries pointing to a file.
+    pub async fn verify(&self) -> FsResult<Vec<Inconsistency>> {
+        let mut res = vec![];
+
+        let mut inodes = BTreeMap::new();
+        for name in self.storage.read_dir(Path::new(INODES_DIR))? {
+            let Ok(ino) = name.to_string_lossy().parse::<u64>() else {
+                continue;
+            };
+            match self.get_inode_from_storage(ino).await {
+                Ok(attr) => {
+                    inodes.insert(ino, attr);
+                }
+                Err(_) => res.push(Inconsistency::CorruptedInode(ino)),
+            }
+        }
+
+        let mut entries: HashMap<u64, u32> = HashMap::new();
+        for attr in inodes.values() {
+            let contents_path = Self::contents_path(attr.ino);
+            if attr.kind != FileType::Directory {
+                if !self.storage.is_file(&contents_path) {
+                    res.push(Inconsistency::MissingContents(attr.ino));
+                }
+                continue;
+            }
+            let ls_dir = contents_path.join(LS_DIR);
+            if !self.storage.is_dir(&ls_dir) {
+                res.push(Inconsistency::MissingContents(attr.ino));
+                continue;
+            }
+            // we don't use `read_dir` as it would update the access time
+            let (mut has_dot, mut has_dot_dot) = (false, false);
+            for entry in read_ls_dir(&*self.storage, &ls_dir)? {
+                let Ok(entry) = self.create_directory_entry(entry).await else {
+                    res.push(Inconsistency::CorruptedEntry { parent: attr.ino });
+                    continue;
+                };
+                match entry.name.expose_secret().as_str() {
+                    "." => has_dot = true,
+                    ".." => has_dot_dot = true,
+                    _ => {
+                        if inodes.contains_key(&entry.ino) {
+                            *entries.entry(entry.ino).or_default() += 1;
+                        } else {
+                            res.push(Inconsistency::DanglingEntry {
+                                parent: attr.ino,
+                                ino: entry.ino,
+                            });
+                        }
+                    }
+                }
+            }
+            if !has_dot {
+                res.push(Inconsistency::MissingDotEntry {
+                    ino: attr.ino,
+                    name: ".",
+                });
+            }
+            if !has_dot_dot && attr.ino != ROOT_INODE {
+                res.push(Inconsistency::MissingDotEntry {
+                    ino: attr.ino,
+                    name: "..",
+                });
+            }
+        }
+
+        for attr in inodes.values().filter(|attr| attr.ino != ROOT_INODE) {
+            let count = entries.get(&attr.ino).copied().unwrap_or(0);
+            if count == 0 {
+                res.push(Inconsistency::OrphanInode(attr.ino));
+            } else if attr.kind != FileType::Directory && count != attr.nlink {
+                res.push(Inconsistency::NlinkMismatch {
+                    ino: attr.ino,
+                    nlink: attr.nlink,
+                    entries: count,
+                });
+            }
+        }
+
+        Ok(res)
+    }
+
+    /// Fix the inconsistencies reported by [`EncryptedFs::verify`] that can be fixed without losing data.
+    ///
+    /// Orphan inodes are linked in [`LOST_AND_FOUND_DIR`] under root, named after the inode, and missing
+    /// `.` and `..` entries are recreated. It's safe to run it multiple times.
+    ///
+    /// Returns the inconsistencies that were fixed.
+    #[allow(clippy::missing_panics_doc)]
+    pub async fn repair(&self) -> FsResult<Vec<Inconsistency>> {
+        self.check_writable()?;
+        let mut res = vec![];
+        let inconsistencies = self.verify().await?;
+
+        for inconsistency in &inconsistencies {
+            if let Inconsistency::OrphanInode(ino) = *inconsistency {
+                let lost_and_found = self.get_or_create_lost_and_found().await?;
+                let attr = self.get_inode_from_storage(ino).await?;
+                self.insert_directory_entry(
+                    lost_and_found,
+                    &DirectoryEntry {
+                        ino,
+                        name: SecretString::new(ino.to_string()),
+                        kind: attr.kind,
+                    },
+                )
+                .await?;
+                if attr.kind == FileType::Directory {
+                    self.insert_directory_entry(
+                        ino,
+                        &DirectoryEntry {
+                            ino: lost_and_found,
+                            name: SecretString::from_str("$..").expect("cannot parse"),
+                            kind: FileType::Directory,
+                        },
+                    )
+                    .await?;
+                }
+                res.push(inconsistency.clone());
+            }
+        }
+
+        for inconsistency in &inconsistencies {
+            if let Inconsistency::MissingDotEntry { ino, name } = *inconsistency {
+                let (parent, name) = if name == "." {
+                    (ino, "$.")
+                } else {
+                    if inconsistencies.contains(&Inconsistency::OrphanInode(ino)) {
+                        // already linked to lost+found
+                        continue;
+                    }
+                    let Some(parent) = self.find_parent(ino).await? else {
+                        continue;
+                    };
+                    (parent, "$..")
+                };
+                self.insert_directory_entry(
+                    ino,
+                    &DirectoryEntry {
+                        ino: parent,
+                        name: SecretString::from_str(name).expect("cannot parse"),
+                        kind: FileType::Directory,
+                    },
+                )
+                .await?;
+                res.push(inconsistency.clone());
+            }
+        }
+
+        Ok(res)
+    }
+
+    async fn get_or_create_lost_and_found(&self) -> FsResult<u64> {
+        let name = SecretString::from_str(LOST_AND_FOUND_DIR).expect("cannot parse");
+        if let Some(attr) = self.find_by_name(ROOT_INODE, &name).await? {
+            if attr.kind != FileType::Directory {
+                return Err(FsError::InvalidInodeType);
+            }
+            return Ok(attr.ino);
+        }
+        let root = self.get_attr(ROOT_INODE).await?;
+        let (_, attr) = self
+            .create(
+                ROOT_INODE,
+                &name,
+                CreateFileAttr {
+                    kind: FileType::Directory,
+                    perm: 0o700,
+                    uid: root.uid,
+                    gid: root.gid,
+                    rdev: 0,
+                    flags: 0,
+                },
+                false,
+                false,
+            )
+            .await?;
+        Ok(attr.ino)
+    }
+
+    /// Find the directory that has an entry pointing to `ino`, by looking in all directories.
+    async fn find_parent(&self, ino: u64) -> FsResult<Option<u64>> {
+        for name in self.storage.read_dir(Path::new(CONTENTS_DIR))? {
+            let Ok(dir_ino) = name.to_string_lossy().parse::<u64>() else {
+                continue;
+            };
+            let ls_dir = Self::contents_path(dir_ino).join(LS_DIR);
+            if dir_ino == ino || !self.storage.is_dir(&ls_dir) {
+                continue;
+            }
+            for entry in read_ls_dir(&*self.storage, &ls_dir)? {
+                let Ok(entry) = self.create_directory_entry(entry).await else {
+                    continue;
+                };
+                let name = entry.name.expose_secret();
+                if entry.ino == ino && name != "." && name != ".." {
+                    return Ok(Some(dir_ino));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Write the whole tree as a tar archive, with the decrypted content.
+    ///
+    /// It keeps the directory structure, symlinks, hard links, permissions, owner and modification time.
+    /// Returns the `writer` after the archive is finished.
+    pub async fn export_tar<W: Write + Send>(&self, writer: W) -> FsResult<W> {
+        let mut builder = tar::Builder::new(writer);
+        let mut exported: HashMap<u64, PathBuf> = HashMap::new();
+        for entry in self.walk(ROOT_INODE).await? {
+            let (path, attr) = entry?;
+            let mut header = tar::Header::new_gnu();
+            header.set_mode(u32::from(attr.perm));
+            header.set_uid(u64::from(attr.uid));
+            header.set_gid(u64::from(attr.gid));
+            header.set_mtime(
+                attr.mtime
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs()),
+            );
+            if let Some(target) = exported.get(&attr.ino) {
+                // hard link to a file we already exported
+                header.set_entry_type(tar::EntryType::Link);
+                header.set_size(0);
+                builder.append_link(&mut header, &path, target)?;
+                continue;
+            }
+            match attr.kind {
+                FileType::Directory => {
+                    header.set_entry_type(tar::EntryType::Directory);
+                    header.set_size(0);
+                    builder.append_data(&mut header, &path, io::empty())?;
+                }
+                FileType::RegularFile => {
+                    header.set_entry_type(tar::EntryType::Regular);
+                    header.set_size(attr.size);
+                    let reader = self.open_reader(attr.ino).await?;
+                    builder.append_data(&mut header, &path, reader)?;
+                }
+                FileType::Symlink => {
+                    header.set_entry_type(tar::EntryType::Symlink);
+                    header.set_size(0);
+                    let target = self.read_link(attr.ino).await?;
+                    builder.append_link(&mut header, &path, target.expose_secret())?;
+                }
+                FileType::NamedPipe | FileType::CharDevice | FileType::BlockDevice => {
+                    header.set_entry_type(match attr.kind {
+                        FileType::NamedPipe => tar::EntryType::Fifo,
+                        FileType::CharDevice => tar::EntryType::Char,
+                        _ => tar::EntryType::Block,
+                    });
+                    header.set_size(0);
+                    header.set_device_major(rdev_major(attr.rdev))?;
+                    header.set_device_minor(rdev_minor(attr.rdev))?;
+                    builder.append_data(&mut header, &path, io::empty())?;
+                }
+            }
+            if attr.kind != FileType::Directory && attr.nlink > 1 {
+                exported.insert(attr.ino, path);
+            }
+        }
+        Ok(builder.into_inner()?)
+    }
+
+    /// Import a tar archive created by [`EncryptedFs::export_tar`] into an empty filesystem.
+    ///
+    /// Paths are relative to root and parent directories need to be before their content in the archive.
+    /// Fails with [`FsError::NotEmpty`] if root already has entries, we don't merge into existing content.
+    // tar entries are not `Send`, but we don't need to spawn this
+    #[allow(clippy::future_not_send)]
+    pub async fn import_tar<R: Read>(&self, reader: R) -> FsResult<()> {
+        if !self.is_empty_dir(ROOT_INODE)? {
+            return Err(FsError::NotEmpty(ROOT_INODE));
+        }
+        // adding entries changes the parents every time, write them only once at the end
+        let batch = self.begin_batch();
+        let res = self.import_tar_entries(reader).await;
+        let commit = batch.commit().await;
+        res?;
+        commit
+    }
+
+    #[allow(clippy::future_not_send, clippy::too_many_lines)]
+    async fn import_tar_entries<R: Read>(&self, reader: R) -> FsResult<()> {
+        let mut archive = tar::Archive::new(reader);
+        let mut times = vec![];
+        let mut buf = Zeroizing::new(vec![0; stream_util::BUF_SIZE]);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.to_string_lossy().to_string();
+            let path = path.trim_end_matches('/');
+            let (parent_path, name) = path.rsplit_once('/').unwrap_or(("", path));
+            let parent = self.lookup_path(parent_path).await?;
+            let name = SecretString::new(name.to_string());
+            let header = entry.header();
+            let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(header.mtime()?);
+            let create_attr = |kind| -> FsResult<CreateFileAttr> {
+                Ok(CreateFileAttr {
+                    kind,
+                    perm: u16::try_from(header.mode()? & 0o7777)
+                        .map_err(|_| FsError::InvalidInput("invalid mode"))?,
+                    uid: u32::try_from(header.uid()?)
+                        .map_err(|_| FsError::InvalidInput("invalid uid"))?,
+                    gid: u32::try_from(header.gid()?)
+                        .map_err(|_| FsError::InvalidInput("invalid gid"))?,
+                    rdev: 0,
+                    flags: 0,
+                })
+            };
+            let attr = match header.entry_type() {
+                tar::EntryType::Directory => {
+                    let create_attr = create_attr(FileType::Directory)?;
+                    self.create(parent.ino, &name, create_attr, false, false)
+                        .await?
+                        .1
+                }
+                tar::EntryType::Regular | tar::EntryType::Continuous => {
+                    let create_attr = create_attr(FileType::RegularFile)?;
+                    let (fh, attr) = self
+                        .create(parent.ino, &name, create_attr, false, true)
+                        .await?;
+                    let mut offset = 0;
+                    loop {
+                        let len = entry.read(&mut buf)?;
+                        if len == 0 {
+                            break;
+                        }
+                        if self.write_at(attr.ino, offset, &buf[..len], fh).await? != len {
+                            return Err(FsError::Other("Failed to write all bytes"));
+                        }
+                        offset += len as u64;
+                    }
+                    self.release(fh).await?;
+                    attr
+                }
+                tar::EntryType::Symlink => {
+                    let target = entry
+                        .link_name()?
+                        .ok_or(FsError::InvalidInput("symlink without target"))?
+                        .to_string_lossy()
+                        .to_string();
+                    let create_attr = create_attr(FileType::Symlink)?;
+                    self.create_symlink(parent.ino, &name, &SecretString::new(target), create_attr)
+                        .await?
+                }
+                tar::EntryType::Fifo | tar::EntryType::Char | tar::EntryType::Block => {
+                    let kind = match header.entry_type() {
+                        tar::EntryType::Fifo => FileType::NamedPipe,
+                        tar::EntryType::Char => FileType::CharDevice,
+                        _ => FileType::BlockDevice,
+                    };
+                    let rdev = make_rdev(
+                        header.device_major()?.unwrap_or(0),
+                        header.device_minor()?.unwrap_or(0),
+                    );
+                    let create_attr = CreateFileAttr {
+                        rdev,
+                        ..create_attr(kind)?
+                    };
+                    self.create(parent.ino, &name, create_attr, false, false)
+                        .await?
+                        .1
+                }
+                tar::EntryType::Link => {
+                    let target = entry
+                        .link_name()?
+                        .ok_or(FsError::InvalidInput("hard link without target"))?
+                        .to_string_lossy()
+                        .to_string();
+                    let target = self.lookup_path(&target).await?;
+                    self.link(target.ino, parent.ino, &name).await?;
+                    continue;
+                }
+                entry_type => {
+                    warn!(?entry_type, "skipping unsupported tar entry");
+                    continue;
+                }
+            };
+            times.push((attr.ino, mtime));
+        }
+        // set times at the end, adding entries changes the time of the parent
+        for (ino, mtime) in times {
+            self.update_attr(
+                ino,
+                SetFileAttr::default().with_atime(mtime).with_mtime(mtime),
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Like [`EncryptedFs::find_by_name`] but returns only the inode, without reading the attributes.
+    async fn find_ino_by_name(&self, parent: u64, name: &SecretString) -> FsResult<Option<u64>> {
+        let hash = crypto::hash_file_name(name, &*self.key.get().await?);
+        let hash_path = Self::contents_path(parent).join(HASH_DIR).join(hash);
+        if !self.storage.is_file(&hash_path) {
             return Ok(None);
         }
         let lock = self
@@ -823,40 +2522,62 @@ impl EncryptedFs {
             });
         let guard = lock.read().await;
         let (ino, _, _): (u64, FileType, String) = bincode::deserialize_from(crypto::create_read(
-            File::open(hash_path)?,
+            self.storage.open(&hash_path)?,
             self.cipher,
             &*self.key.get().await?,
-        ))?;
+        ))
+        .map_err(|err| {
+            error!(err = %err, "deserializing directory entry");
+            FsError::InvalidDirectoryEntry
+        })?;
         drop(guard);
-        self.get_inode_from_cache_or_storage(ino).await.map(Some)
+        Ok(Some(ino))
     }
 
     /// Count children of a directory. This **EXCLUDES** "." and "..".
     #[allow(clippy::missing_errors_doc)]
     pub fn len(&self, ino: u64) -> FsResult<usize> {
+        let mut count = 0;
+        for entry in self.ls_dir_entries(ino)? {
+            if !is_synthetic_entry(entry.file_name().unwrap_or_default()) {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Whether the directory has no children besides "." and "..".
+    #[allow(clippy::missing_errors_doc)]
+    pub fn is_empty_dir(&self, ino: u64) -> FsResult<bool> {
+        for entry in self.ls_dir_entries(ino)? {
+            if !is_synthetic_entry(entry.file_name().unwrap_or_default()) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn ls_dir_entries(&self, ino: u64) -> FsResult<impl Iterator<Item = PathBuf>> {
         if !self.is_dir(ino) {
             return Err(FsError::InvalidInodeType);
         }
-        let mut count = fs::read_dir(self.contents_path(ino).join(LS_DIR))?.count();
-        if ino == ROOT_INODE {
-            // we don't count "."
-            count -= 1;
-        } else {
-            // we don't count "." and ".."
-            count -= 2;
-        }
-        Ok(count)
+        Ok(read_ls_dir(
+            &*self.storage,
+            &Self::contents_path(ino).join(LS_DIR),
+        )?)
     }
 
     /// Delete a directory
     #[allow(clippy::missing_panics_doc)]
     #[allow(clippy::missing_errors_doc)]
     pub async fn remove_dir(&self, parent: u64, name: &SecretString) -> FsResult<()> {
+        self.check_writable()?;
+        check_not_dot_name(name)?;
         if !self.is_dir(parent) {
             return Err(FsError::InvalidInodeType);
         }
 
-        if !self.exists_by_name(parent, name)? {
+        if !self.exists_by_name(parent, name).await? {
             return Err(FsError::NotFound("name not found"));
         }
 
@@ -867,9 +2588,8 @@ impl EncryptedFs {
         if !matches!(attr.kind, FileType::Directory) {
             return Err(FsError::InvalidInodeType);
         }
-        // check if it's empty
-        if self.len(attr.ino)? > 0 {
-            return Err(FsError::NotEmpty);
+        if !self.is_empty_dir(attr.ino)? {
+            return Err(FsError::NotEmpty(attr.ino));
         }
         let self_clone = self
             .self_weak
@@ -888,15 +2608,22 @@ impl EncryptedFs {
                         .serialize_inode_locks
                         .get_or_insert_with(attr.ino, || RwLock::new(false));
                     let _guard = lock.write();
-                    fs::remove_file(self_clone.ino_file(attr.ino))?;
+                    self_clone.storage.remove_file(&Self::ino_file(attr.ino))?;
+                    self_clone.batch.lock().unwrap().pending.remove(&attr.ino);
                 }
+                self_clone.change_inode_count(-1);
 
                 // remove contents directory
-                fs::remove_dir_all(self_clone.contents_path(attr.ino))?;
+                self_clone
+                    .storage
+                    .remove_dir_all(&Self::contents_path(attr.ino))?;
                 // remove from parent directory
                 self_clone
                     .remove_directory_entry(parent, &name_clone)
                     .await?;
+                self_clone
+                    .record_change(attr.ino, ChangeKind::Deleted)
+                    .await?;
                 // remove from cache
                 self_clone
                     .attr_cache
@@ -904,7 +2631,7 @@ impl EncryptedFs {
                     .await?
                     .write()
                     .await
-                    .demote(&attr.ino);
+                    .pop(&attr.ino);
 
                 let now = SystemTime::now();
                 self_clone
@@ -916,20 +2643,77 @@ impl EncryptedFs {
                             .with_atime(now),
                     )
                     .await?;
+                self_clone.change_dir_nlink(parent, -1).await?;
 
                 Ok(())
             })
             .await?
     }
 
+    /// Delete a directory with everything under it, like `rm -r`.
+    ///
+    /// The tree is removed depth-first, files with [`EncryptedFs::remove_file`] and directories
+    /// with [`EncryptedFs::remove_dir`] once they are empty, so hard linked files are kept if they
+    /// still have other links.\
+    /// On error it stops and returns [`FsError::RemoveFailed`] with the inode it couldn't remove,
+    /// what was removed until then stays removed.
+    #[allow(clippy::missing_panics_doc)]
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn remove_dir_all(&self, parent: u64, name: &SecretString) -> FsResult<()> {
+        check_not_dot_name(name)?;
+        let attr = self
+            .find_by_name(parent, name)
+            .await?
+            .ok_or(FsError::NotFound("name not found"))?;
+        if attr.kind != FileType::Directory {
+            return Err(FsError::InvalidInodeType);
+        }
+        // (parent, name, ino) of the directories we are emptying
+        let mut stack = vec![(parent, name.clone(), attr.ino)];
+        while let Some((_, _, dir)) = stack.last() {
+            let dir = *dir;
+            let wrap = |ino, err| FsError::RemoveFailed {
+                ino,
+                source: Box::new(err),
+            };
+            let mut subdir = None;
+            for entry in self.read_dir(dir).await.map_err(|err| wrap(dir, err))? {
+                let entry = entry.map_err(|err| wrap(dir, err))?;
+                let entry_name = entry.name.expose_secret();
+                if entry_name == "." || entry_name == ".." {
+                    continue;
+                }
+                if entry.kind == FileType::Directory {
+                    subdir = Some((dir, entry.name, entry.ino));
+                    break;
+                }
+                self.remove_file(dir, &entry.name)
+                    .await
+                    .map_err(|err| wrap(entry.ino, err))?;
+            }
+            if let Some(subdir) = subdir {
+                // empty it first and come back to this one after
+                stack.push(subdir);
+                continue;
+            }
+            let (parent, name, ino) = stack.pop().unwrap();
+            self.remove_dir(parent, &name)
+                .await
+                .map_err(|err| wrap(ino, err))?;
+        }
+        Ok(())
+    }
+
     /// Delete a file
     #[allow(clippy::missing_panics_doc)]
     #[allow(clippy::missing_errors_doc)]
     pub async fn remove_file(&self, parent: u64, name: &SecretString) -> FsResult<()> {
+        self.check_writable()?;
+        check_not_dot_name(name)?;
         if !self.is_dir(parent) {
             return Err(FsError::InvalidInodeType);
         }
-        if !self.exists_by_name(parent, name)? {
+        if !self.exists_by_name(parent, name).await? {
             return Err(FsError::NotFound("name not found"));
         }
 
@@ -937,9 +2721,30 @@ impl EncryptedFs {
             .find_by_name(parent, name)
             .await?
             .ok_or(FsError::NotFound("name not found"))?;
-        if !matches!(attr.kind, FileType::RegularFile) {
+        if matches!(attr.kind, FileType::Directory) {
             return Err(FsError::InvalidInodeType);
         }
+        if attr.nlink > 1 {
+            // there are other links to this inode, keep the data and just remove this name
+            self.remove_directory_entry(parent, name).await?;
+            let now = SystemTime::now();
+            self.set_attr(
+                attr.ino,
+                SetFileAttr::default()
+                    .with_nlink(attr.nlink - 1)
+                    .with_ctime(now),
+            )
+            .await?;
+            self.set_attr(
+                parent,
+                SetFileAttr::default()
+                    .with_mtime(now)
+                    .with_ctime(now)
+                    .with_atime(now),
+            )
+            .await?;
+            return Ok(());
+        }
         let self_clone = self
             .self_weak
             .lock()
@@ -951,21 +2756,41 @@ impl EncryptedFs {
         let name_clone = name.clone();
         NOD_RT
             .spawn(async move {
+                self_clone
+                    .write_journal(&JournalEntry::RemoveFile {
+                        parent,
+                        ino: attr.ino,
+                        name: name_clone.expose_secret().clone(),
+                    })
+                    .await?;
                 // remove inode file
                 {
                     let lock = self_clone
                         .serialize_inode_locks
                         .get_or_insert_with(attr.ino, || RwLock::new(false));
                     let _guard = lock.write();
-                    fs::remove_file(self_clone.ino_file(attr.ino))?;
+                    self_clone.storage.remove_file(&Self::ino_file(attr.ino))?;
                 }
+                self_clone.change_inode_count(-1);
+                #[cfg(test)]
+                self_clone.check_fail_point("remove_file:after_inode")?;
 
                 // remove from contents directory
-                fs::remove_file(self_clone.contents_path(attr.ino))?;
+                self_clone
+                    .storage
+                    .remove_file(&Self::contents_path(attr.ino))?;
+                self_clone.idle_readers.lock().unwrap().pop(&attr.ino);
                 // remove from parent directory
                 self_clone
                     .remove_directory_entry(parent, &name_clone)
                     .await?;
+                self_clone.remove_journal(attr.ino)?;
+                if attr.kind == FileType::RegularFile {
+                    self_clone.shrink_quota_used(attr.size);
+                }
+                self_clone
+                    .record_change(attr.ino, ChangeKind::Deleted)
+                    .await?;
                 // remove from cache
                 self_clone
                     .attr_cache
@@ -973,7 +2798,7 @@ impl EncryptedFs {
                     .await?
                     .write()
                     .await
-                    .demote(&attr.ino);
+                    .pop(&attr.ino);
 
                 let now = SystemTime::now();
                 self_clone
@@ -986,23 +2811,85 @@ impl EncryptedFs {
                     )
                     .await?;
 
-                Ok(())
-            })
-            .await?
+                Ok(())
+            })
+            .await?
+    }
+
+    /// Create a hard link `new_name` in `new_parent` to the existing inode `ino`.
+    /// Hard links to directories are not allowed.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn link(
+        &self,
+        ino: u64,
+        new_parent: u64,
+        new_name: &SecretString,
+    ) -> FsResult<FileAttr> {
+        self.check_writable()?;
+        check_not_dot_name(new_name)?;
+        self.check_name_len(new_name)?;
+        if !self.exists(ino) {
+            return Err(FsError::InodeNotFound(ino));
+        }
+        if !self.exists(new_parent) {
+            return Err(FsError::InodeNotFound(new_parent));
+        }
+        if !self.is_dir(new_parent) {
+            return Err(FsError::InvalidInodeType);
+        }
+        let attr = self.get_attr(ino).await?;
+        if matches!(attr.kind, FileType::Directory) {
+            return Err(FsError::InvalidInodeType);
+        }
+        if self.exists_by_name(new_parent, new_name).await? {
+            return Err(FsError::AlreadyExists);
+        }
+
+        self.insert_directory_entry(
+            new_parent,
+            &DirectoryEntry {
+                ino,
+                name: new_name.clone(),
+                kind: attr.kind,
+            },
+        )
+        .await?;
+
+        let now = SystemTime::now();
+        self.set_attr(
+            ino,
+            SetFileAttr::default()
+                .with_nlink(attr.nlink + 1)
+                .with_ctime(now),
+        )
+        .await?;
+        self.set_attr(
+            new_parent,
+            SetFileAttr::default()
+                .with_mtime(now)
+                .with_ctime(now)
+                .with_atime(now),
+        )
+        .await?;
+
+        self.get_attr(ino).await
     }
 
     #[allow(clippy::missing_panics_doc)]
     #[allow(clippy::missing_errors_doc)]
-    pub fn exists_by_name(&self, parent: u64, name: &SecretString) -> FsResult<bool> {
+    pub async fn exists_by_name(&self, parent: u64, name: &SecretString) -> FsResult<bool> {
         if !self.exists(parent) {
-            return Err(FsError::InodeNotFound);
+            return Err(FsError::InodeNotFound(parent));
         }
         if !self.is_dir(parent) {
             return Err(FsError::InvalidInodeType);
         }
-        let hash = crypto::hash_file_name(name);
-        let hash_path = self.contents_path(parent).join(HASH_DIR).join(hash);
-        Ok(hash_path.is_file())
+        if is_root_dot_dot(parent, name) {
+            return Ok(true);
+        }
+        let hash = crypto::hash_file_name(name, &*self.key.get().await?);
+        let hash_path = Self::contents_path(parent).join(HASH_DIR).join(hash);
+        Ok(self.storage.is_file(&hash_path))
     }
 
     #[allow(clippy::missing_errors_doc)]
@@ -1010,37 +2897,211 @@ impl EncryptedFs {
         if !self.is_dir(ino) {
             return Err(FsError::InvalidInodeType);
         }
-        let ls_dir = self.contents_path(ino).join(LS_DIR);
-        if !ls_dir.is_dir() {
+        self.enforce_access(ino, libc::R_OK).await?;
+        let ls_dir = Self::contents_path(ino).join(LS_DIR);
+        if !self.storage.is_dir(&ls_dir) {
             return Err(FsError::InvalidInodeType);
         }
 
-        let iter = fs::read_dir(ls_dir)?;
-        let set_attr = SetFileAttr::default().with_atime(SystemTime::now());
-        self.set_attr(ino, set_attr).await?;
+        let iter = read_ls_dir(&*self.storage, &ls_dir)?;
+        self.touch_atime(ino).await?;
         Ok(self.create_directory_entry_iterator(iter).await)
     }
 
+    /// Like [`EncryptedFs::read_dir`] but yields only entries of type `kind`.
+    ///
+    /// Errors are still returned, so the caller can handle them.
+    pub async fn read_dir_filter(
+        &self,
+        ino: u64,
+        kind: FileType,
+    ) -> FsResult<DirectoryEntryIterator> {
+        let mut iter = self.read_dir(ino).await?;
+        iter.0
+            .retain(|entry| entry.as_ref().map_or(true, |entry| entry.kind == kind));
+        Ok(iter)
+    }
+
+    /// Like [`EncryptedFs::read_dir`] but entries are sorted by the decrypted name.
+    ///
+    /// Errors are returned after all the entries.
+    pub async fn read_dir_sorted(&self, ino: u64) -> FsResult<DirectoryEntryIterator> {
+        let (mut entries, errors): (Vec<_>, Vec<_>) =
+            self.read_dir(ino).await?.partition(Result::is_ok);
+        entries.sort_by(|a, b| match (a, b) {
+            (Ok(a), Ok(b)) => a.name.expose_secret().cmp(b.name.expose_secret()),
+            _ => std::cmp::Ordering::Equal,
+        });
+        Ok(DirectoryEntryIterator(
+            entries.into_iter().chain(errors).collect(),
+        ))
+    }
+
     /// Like [`EncryptedFs::read_dir`] but with [`FileAttr`] so we don't need to query again for those.
     pub async fn read_dir_plus(&self, ino: u64) -> FsResult<DirectoryEntryPlusIterator> {
         if !self.is_dir(ino) {
             return Err(FsError::InvalidInodeType);
         }
-        let ls_dir = self.contents_path(ino).join(LS_DIR);
-        if !ls_dir.is_dir() {
+        self.enforce_access(ino, libc::R_OK).await?;
+        let ls_dir = Self::contents_path(ino).join(LS_DIR);
+        if !self.storage.is_dir(&ls_dir) {
             return Err(FsError::InvalidInodeType);
         }
 
-        let iter = fs::read_dir(ls_dir)?;
-        let set_attr = SetFileAttr::default().with_atime(SystemTime::now());
-        self.set_attr(ino, set_attr).await?;
+        let iter = read_ls_dir(&*self.storage, &ls_dir)?;
+        self.touch_atime(ino).await?;
         Ok(self.create_directory_entry_plus_iterator(iter).await)
     }
 
-    async fn create_directory_entry_plus(
+    /// Open a directory for listing with [`EncryptedFs::readdir`], returns the handle.
+    ///
+    /// The entries are read now, later changes to the directory are not seen by the handle,
+    /// so listing it in more calls doesn't skip or repeat entries.\
+    /// Call [`EncryptedFs::releasedir`] when done.
+    pub async fn opendir(&self, ino: u64) -> FsResult<u64> {
+        let entries = self.read_dir(ino).await?.collect::<FsResult<Vec<_>>>()?;
+        let handle = self.next_handle()?;
+        self.dir_handles.write().await.insert(handle, entries);
+        Ok(handle)
+    }
+
+    /// Entries of a directory opened with [`EncryptedFs::opendir`], starting from `offset`.
+    ///
+    /// The offset is the index in the entries, including `.` and `..`, so it's stable between calls.
+    pub async fn readdir(&self, handle: u64, offset: u64) -> FsResult<DirectoryEntryIterator> {
+        let guard = self.dir_handles.read().await;
+        let entries = guard
+            .get(&handle)
+            .ok_or(FsError::InvalidFileHandle)?
+            .iter()
+            .skip(usize::try_from(offset).unwrap_or(usize::MAX))
+            .cloned()
+            .map(Ok)
+            .collect();
+        Ok(DirectoryEntryIterator(entries))
+    }
+
+    /// Close a handle from [`EncryptedFs::opendir`].
+    pub async fn releasedir(&self, handle: u64) -> FsResult<()> {
+        self.dir_handles
+            .write()
+            .await
+            .remove(&handle)
+            .map(|_| ())
+            .ok_or(FsError::InvalidFileHandle)
+    }
+
+    /// Entries of `parent` with names matching `pattern`, a glob where `*` matches any sequence of
+    /// characters and `?` exactly one. `.` and `..` are not included.
+    ///
+    /// Names are encrypted, so each one needs to be decrypted to be matched.
+    pub async fn find_matching(
+        &self,
+        parent: u64,
+        pattern: &str,
+    ) -> FsResult<DirectoryEntryIterator> {
+        // a plain prefix doesn't need the glob
+        let prefix = pattern
+            .strip_suffix('*')
+            .filter(|prefix| !prefix.contains(['*', '?']));
+        let entries = self
+            .read_dir(parent)
+            .await?
+            .filter(|entry| {
+                entry.as_ref().map_or(true, |entry| {
+                    let name = entry.name.expose_secret();
+                    name != "."
+                        && name != ".."
+                        && prefix.map_or_else(
+                            || glob_match(pattern, name),
+                            |prefix| name.starts_with(prefix),
+                        )
+                })
+            })
+            .collect();
+        Ok(DirectoryEntryIterator(entries))
+    }
+
+    /// Total logical size of `ino` and everything under it, like `du --apparent-size`.
+    ///
+    /// Hard linked files are counted only once.
+    pub async fn disk_usage(&self, ino: u64) -> FsResult<u64> {
+        let attr = self.get_attr(ino).await?;
+        if attr.kind != FileType::Directory {
+            return Ok(attr.size);
+        }
+        let mut seen = HashSet::new();
+        let mut size = 0;
+        for entry in self.walk(ino).await? {
+            let (_, attr) = entry?;
+            if attr.kind != FileType::Directory && seen.insert(attr.ino) {
+                size += attr.size;
+            }
+        }
+        Ok(size)
+    }
+
+    /// Depth-first traversal of the whole tree under `ino`.
+    ///
+    /// Yields the path relative to `ino` and the attributes for every node, `.` and `..` are skipped.
+    /// Each directory is visited only once, even if it's reachable from more places.
+    pub async fn walk(&self, ino: u64) -> FsResult<WalkIterator> {
+        if !self.is_dir(ino) {
+            return Err(FsError::InvalidInodeType);
+        }
+        let mut res = VecDeque::new();
+        let mut visited = HashSet::new();
+        visited.insert(ino);
+        let mut stack = vec![];
+        self.push_walk_entries(ino, &PathBuf::new(), &mut stack, &mut res)
+            .await;
+        while let Some((path, entry)) = stack.pop() {
+            let is_dir = entry.kind == FileType::Directory;
+            if is_dir && !visited.insert(entry.ino) {
+                warn!(ino = entry.ino, "directory already visited, skipping");
+                continue;
+            }
+            res.push_back(Ok((path.clone(), entry.attr)));
+            if is_dir {
+                self.push_walk_entries(entry.ino, &path, &mut stack, &mut res)
+                    .await;
+            }
+        }
+        Ok(WalkIterator(res))
+    }
+
+    /// Push the children of `ino` on the `stack`, in reverse order so they are popped in listing order.
+    async fn push_walk_entries(
         &self,
-        entry: io::Result<DirEntry>,
-    ) -> FsResult<DirectoryEntryPlus> {
+        ino: u64,
+        path: &Path,
+        stack: &mut Vec<(PathBuf, DirectoryEntryPlus)>,
+        res: &mut VecDeque<FsResult<(PathBuf, FileAttr)>>,
+    ) {
+        let iter = match self.read_dir_plus(ino).await {
+            Ok(iter) => iter,
+            Err(err) => {
+                res.push_back(Err(err));
+                return;
+            }
+        };
+        let mut entries = vec![];
+        for entry in iter {
+            match entry {
+                Ok(entry) => {
+                    let name = entry.name.expose_secret();
+                    if name == "." || name == ".." {
+                        continue;
+                    }
+                    entries.push((path.join(name), entry));
+                }
+                Err(err) => res.push_back(Err(err)),
+            }
+        }
+        stack.extend(entries.into_iter().rev());
+    }
+
+    async fn create_directory_entry_plus(&self, entry: PathBuf) -> FsResult<DirectoryEntryPlus> {
         let entry = self.create_directory_entry(entry).await?;
         let lock = self.serialize_inode_locks.clone();
         let lock_ino = lock.get_or_insert_with(entry.ino, || RwLock::new(false));
@@ -1056,7 +3117,7 @@ impl EncryptedFs {
 
     async fn create_directory_entry_plus_iterator(
         &self,
-        read_dir: ReadDir,
+        read_dir: impl Iterator<Item = PathBuf>,
     ) -> DirectoryEntryPlusIterator {
         #[allow(clippy::cast_possible_truncation)]
         let futures: Vec<_> = read_dir
@@ -1083,19 +3144,12 @@ impl EncryptedFs {
         DirectoryEntryPlusIterator(res)
     }
 
-    async fn create_directory_entry(
-        &self,
-        entry: io::Result<DirEntry>,
-    ) -> FsResult<DirectoryEntry> {
-        if entry.is_err() {
-            return Err(entry.err().unwrap().into());
-        }
-        if let Err(e) = entry {
-            error!(err = %e, "reading directory entry");
-            return Err(e.into());
-        }
-        let entry = entry.unwrap();
-        let name = entry.file_name().to_string_lossy().to_string();
+    async fn create_directory_entry(&self, entry: PathBuf) -> FsResult<DirectoryEntry> {
+        let name = entry
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
         let name = {
             if name == "$." {
                 SecretString::from_str(".").unwrap()
@@ -1119,12 +3173,12 @@ impl EncryptedFs {
                         lock.lock().await.put(name.clone(), decrypted_name.clone());
                         decrypted_name
                     } else {
-                        return Err(FsError::InvalidInput("invalid file name"));
+                        return Err(FsError::InvalidDirectoryEntry);
                     }
                 }
             }
         };
-        let file_path = entry.path().to_str().unwrap().to_string();
+        let file_path = entry.to_str().unwrap().to_string();
         // try from cache
         let lock = self.dir_entries_meta_cache.get().await?;
         let mut cache = lock.lock().await;
@@ -1140,7 +3194,7 @@ impl EncryptedFs {
             .serialize_dir_entries_ls_locks
             .get_or_insert_with(file_path.clone(), || RwLock::new(false));
         let guard = lock.read().await;
-        let file = File::open(entry.path())?;
+        let file = self.storage.open(&entry)?;
         let res: bincode::Result<(u64, FileType)> = bincode::deserialize_from(crypto::create_read(
             file,
             self.cipher,
@@ -1149,7 +3203,7 @@ impl EncryptedFs {
         drop(guard);
         if let Err(e) = res {
             error!(err = %e, "deserializing directory entry");
-            return Err(e.into());
+            return Err(FsError::InvalidDirectoryEntry);
         }
         let (ino, kind): (u64, FileType) = res.unwrap();
         // add to cache
@@ -1168,7 +3222,10 @@ impl EncryptedFs {
         self.dir_entries_name_cache.get().await
     }
 
-    async fn create_directory_entry_iterator(&self, read_dir: ReadDir) -> DirectoryEntryIterator {
+    async fn create_directory_entry_iterator(
+        &self,
+        read_dir: impl Iterator<Item = PathBuf>,
+    ) -> DirectoryEntryIterator {
         #[allow(clippy::cast_possible_truncation)]
         let futures: Vec<_> = read_dir
             .into_iter()
@@ -1196,24 +3253,36 @@ impl EncryptedFs {
 
     #[allow(clippy::missing_errors_doc)]
     async fn get_inode_from_storage(&self, ino: u64) -> FsResult<FileAttr> {
+        if let Some(attr) = self.batch.lock().unwrap().pending.get(&ino) {
+            return Ok(*attr);
+        }
         let lock = self
             .serialize_inode_locks
             .get_or_insert_with(ino, || RwLock::new(false));
         let _guard = lock.read();
 
-        let path = self.ino_file(ino);
-        if !path.is_file() {
-            return Err(FsError::InodeNotFound);
+        let path = Self::ino_file(ino);
+        if !self.storage.is_file(&path) {
+            return Err(FsError::InodeNotFound(ino));
         }
-        let file = OpenOptions::new().read(true).open(path).map_err(|err| {
+        let file = self.storage.open(&path).map_err(|err| {
             error!(err = %err, "opening file");
-            FsError::InodeNotFound
+            FsError::InodeNotFound(ino)
         })?;
-        Ok(bincode::deserialize_from(crypto::create_read(
+        if file.size()? == 0 {
+            // we always write the inode when creating it, so an empty file means it was lost
+            error!(ino, "inode file is empty");
+            return Err(FsError::CorruptedInode(ino));
+        }
+        bincode::deserialize_from(crypto::create_read(
             file,
             self.cipher,
             &*self.key.get().await?,
-        ))?)
+        ))
+        .map_err(|err| {
+            error!(err = %err, ino, "deserializing inode");
+            FsError::CorruptedInode(ino)
+        })
     }
 
     async fn get_inode_from_cache_or_storage(&self, ino: u64) -> FsResult<FileAttr> {
@@ -1263,6 +3332,7 @@ impl EncryptedFs {
                 }
             }
         }
+        update_blocks(&mut attr);
 
         Ok(attr)
     }
@@ -1272,12 +3342,66 @@ impl EncryptedFs {
         self.set_attr2(ino, set_attr, false).await
     }
 
+    /// Change only the provided attributes, like `chmod`, `chown`, `utimes` and `truncate` do.
+    ///
+    /// Unlike [`EncryptedFs::set_attr`], timestamps are set to the exact values, even if older than the current ones.
+    /// `ctime` is set to now, if not provided. If `size` is provided it will call [`EncryptedFs::set_len`].
+    pub async fn update_attr(&self, ino: u64, set_attr: SetFileAttr) -> FsResult<()> {
+        self.check_writable()?;
+        if let Some(size) = set_attr.size {
+            self.set_len(ino, size).await?;
+        }
+
+        let serialize_update_lock = self
+            .serialize_update_inode_locks
+            .get_or_insert_with(ino, || Mutex::new(false));
+        let _serialize_update_guard = serialize_update_lock.lock().await;
+
+        let mut attr = self.get_attr(ino).await?;
+        let set_attr_no_times = SetFileAttr {
+            size: None,
+            atime: SetTime::Omit,
+            mtime: SetTime::Omit,
+            ctime: None,
+            crtime: None,
+            ..set_attr
+        };
+        merge_attr(&mut attr, &set_attr_no_times, false);
+        let now = SystemTime::now();
+        if let Some(atime) = set_attr.atime.resolve(now) {
+            attr.atime = atime;
+        }
+        if let Some(mtime) = set_attr.mtime.resolve(now) {
+            attr.mtime = mtime;
+        }
+        if let Some(crtime) = set_attr.crtime {
+            attr.crtime = crtime;
+        }
+        attr.ctime = set_attr.ctime.unwrap_or(now);
+
+        self.write_inode_to_storage(&attr).await
+    }
+
+    /// Change `nlink` of the directory `ino` by `delta`, as subdirectories are added or removed,
+    /// each one links to it with `..`. It doesn't go below 2, for `.` and the entry in its parent.
+    async fn change_dir_nlink(&self, ino: u64, delta: i32) -> FsResult<()> {
+        let serialize_update_lock = self
+            .serialize_update_inode_locks
+            .get_or_insert_with(ino, || Mutex::new(false));
+        let _serialize_update_guard = serialize_update_lock.lock().await;
+
+        let mut attr = self.get_attr(ino).await?;
+        attr.nlink = attr.nlink.saturating_add_signed(delta).max(2);
+        self.write_inode_to_storage(&attr).await
+    }
+
     async fn set_attr2(
         &self,
         ino: u64,
         set_attr: SetFileAttr,
         overwrite_size: bool,
     ) -> FsResult<()> {
+        self.check_writable()?;
         let serialize_update_lock = self
             .serialize_update_inode_locks
             .get_or_insert_with(ino, || Mutex::new(false));
@@ -1295,22 +3419,117 @@ impl EncryptedFs {
     }
 
     async fn write_inode_to_storage(&self, attr: &FileAttr) -> Result<(), FsError> {
+        let mut attr = *attr;
+        update_blocks(&mut attr);
+        let attr = &attr;
+        let batched = {
+            let mut batch = self.batch.lock().unwrap();
+            // only existing directories, so new inodes and file sizes are always persisted
+            if batch.depth > 0 && attr.kind == FileType::Directory && self.exists(attr.ino) {
+                batch.pending.insert(attr.ino, *attr);
+                true
+            } else {
+                false
+            }
+        };
+        if !batched {
+            self.write_inode_file(attr).await?;
+            // this is newer than what a previous batch didn't write yet
+            self.batch.lock().unwrap().pending.remove(&attr.ino);
+        }
+        // update cache also
+        {
+            let lock = self.attr_cache.get().await?;
+            let mut guard = lock.write().await;
+            guard.put(attr.ino, *attr);
+        }
+        Ok(())
+    }
+
+    async fn write_inode_file(&self, attr: &FileAttr) -> FsResult<()> {
+        self.check_writable()?;
+        #[cfg(test)]
+        {
+            *self
+                .inode_writes
+                .lock()
+                .unwrap()
+                .entry(attr.ino)
+                .or_default() += 1;
+        }
         let lock = self
             .serialize_inode_locks
             .get_or_insert_with(attr.ino, || RwLock::new(false));
         let guard = lock.write().await;
-        crypto::atomic_serialize_encrypt_into(
-            &self.ino_file(attr.ino),
+        let kind = if self.exists(attr.ino) {
+            ChangeKind::Modified
+        } else {
+            ChangeKind::Created
+        };
+        atomic_serialize_encrypt_into(
+            &*self.storage,
+            &Self::ino_file(attr.ino),
             attr,
             self.cipher,
             &*self.key.get().await?,
         )?;
         drop(guard);
-        // update cache also
-        {
-            let lock = self.attr_cache.get().await?;
-            let mut guard = lock.write().await;
-            guard.put(attr.ino, *attr);
+        self.record_change(attr.ino, kind).await?;
+        Ok(())
+    }
+
+    /// Start a batch, where changes to the attributes of directories, like the timestamps updated
+    /// when adding entries, are kept in memory and written once when it's committed.
+    ///
+    /// Useful for bulk operations that change the same directories many times. It applies to all
+    /// operations done meanwhile, not only the ones of the caller. Batches can be nested, the changes
+    /// are written when the outermost ends. Until then a crash loses them, but not the entries.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn begin_batch(&self) -> Batch {
+        self.batch.lock().unwrap().depth += 1;
+        let fs = self
+            .self_weak
+            .lock()
+            .unwrap()
+            .as_ref()
+            .unwrap()
+            .upgrade()
+            .unwrap();
+        Batch {
+            fs,
+            committed: false,
+        }
+    }
+
+    async fn end_batch(&self) -> FsResult<()> {
+        let inodes: Vec<u64> = {
+            let mut batch = self.batch.lock().unwrap();
+            batch.depth -= 1;
+            if batch.depth > 0 {
+                return Ok(());
+            }
+            batch.pending.keys().copied().collect()
+        };
+        for ino in inodes {
+            // so no one else updates it meanwhile
+            let lock = self
+                .serialize_update_inode_locks
+                .get_or_insert_with(ino, || Mutex::new(false));
+            let _guard = lock.lock().await;
+            // keep it pending until written, so reads don't see the old value meanwhile
+            let attr = { self.batch.lock().unwrap().pending.get(&ino).copied() };
+            let Some(attr) = attr else {
+                // already written
+                continue;
+            };
+            if self.exists(ino) {
+                self.write_inode_file(&attr).await?;
+            }
+            let mut batch = self.batch.lock().unwrap();
+            // a new batch could have changed it again
+            if batch.pending.get(&ino) == Some(&attr) {
+                batch.pending.remove(&ino);
+            }
         }
         Ok(())
     }
@@ -1328,7 +3547,7 @@ impl EncryptedFs {
         handle: u64,
     ) -> FsResult<usize> {
         if !self.exists(ino) {
-            return Err(FsError::InodeNotFound);
+            return Err(FsError::InodeNotFound(ino));
         }
         if !self.is_file(ino) {
             return Err(FsError::InvalidInodeType);
@@ -1340,10 +3559,21 @@ impl EncryptedFs {
         let lock = self
             .read_write_locks
             .get_or_insert_with(ino, || RwLock::new(false));
+        // if the handle is also opened for write, flush pending data so we can read it back
+        let has_writer = { self.write_handles.read().await.contains_key(&handle) };
+        if has_writer {
+            let write_guard = lock.write().await;
+            self.flush_pending_writes(ino, handle).await?;
+            drop(write_guard);
+        }
         let _read_guard = lock.read().await;
 
         let guard = self.read_handles.read().await;
-        let mut ctx = guard.get(&handle).unwrap().lock().await;
+        let mut ctx = guard
+            .get(&handle)
+            .ok_or(FsError::InvalidFileHandle)?
+            .lock()
+            .await;
 
         if ctx.ino != ino {
             return Err(FsError::InvalidFileHandle);
@@ -1360,10 +3590,21 @@ impl EncryptedFs {
         let len = {
             let reader = ctx.reader.as_mut().unwrap();
 
-            reader.seek(SeekFrom::Start(offset)).map_err(|err| {
-                error!(err = %err, "seeking");
-                err
-            })?;
+            // authentication of a block failing means the content was changed
+            let map_err = |err: io::Error| {
+                if err.kind() == io::ErrorKind::InvalidData {
+                    FsError::IntegrityError { ino, offset }
+                } else {
+                    err.into()
+                }
+            };
+            reader
+                .seek(SeekFrom::Start(offset))
+                .map_err(|err| {
+                    error!(err = %err, "seeking");
+                    err
+                })
+                .map_err(map_err)?;
             let pos = reader.stream_position().map_err(|err| {
                 error!(err = %err, "getting position");
                 err
@@ -1372,13 +3613,22 @@ impl EncryptedFs {
                 // we would need to seek after filesize
                 return Ok(0);
             }
-            stream_util::read(reader, buf).map_err(|err| {
-                error!(err = %err, "reading");
-                err
-            })?
+            stream_util::read(reader, buf)
+                .map_err(|err| {
+                    error!(err = %err, "reading");
+                    err
+                })
+                .map_err(map_err)?
         };
 
-        ctx.attr.atime = SystemTime::now();
+        let now = SystemTime::now();
+        if self
+            .atime_policy()
+            .should_update(ctx.attr.atime, ctx.attr.mtime, ctx.attr.ctime, now)
+        {
+            ctx.attr.atime = now;
+            ctx.atime_updated = true;
+        }
         drop(ctx);
 
         Ok(len)
@@ -1395,8 +3645,19 @@ impl EncryptedFs {
         // read
         let ctx = { self.read_handles.write().await.remove(&handle) };
         if let Some(ctx) = ctx {
-            let ctx = ctx.lock().await;
+            let mut ctx = ctx.lock().await;
 
+            // keep the reader for the next open, unless a writer could change the content meanwhile
+            if let Some(reader) = ctx.reader.take() {
+                if !self
+                    .opened_files_for_write
+                    .read()
+                    .await
+                    .contains_key(&ctx.ino)
+                {
+                    self.idle_readers.lock().unwrap().put(ctx.ino, reader);
+                }
+            }
             {
                 let mut opened_files_for_read = self.opened_files_for_read.write().await;
                 opened_files_for_read
@@ -1414,10 +3675,12 @@ impl EncryptedFs {
 
             // write attr only here to avoid serializing it multiple times while reading
             // it will merge time fields with existing data because it might got change while we kept the handle
-            let set_attr: SetFileAttr = ctx.attr.clone().into();
+            let set_attr: Option<SetFileAttr> = ctx.atime_updated.then(|| ctx.attr.clone().into());
             let ino = ctx.ino;
             drop(ctx);
-            self.set_attr(ino, set_attr).await?;
+            if let Some(set_attr) = set_attr {
+                self.set_attr(ino, set_attr).await?;
+            }
 
             valid_fh = true;
         }
@@ -1434,7 +3697,8 @@ impl EncryptedFs {
             let write_guard = lock.write().await;
             let file = writer.finish()?;
             file.sync_all()?;
-            File::open(self.contents_path(ctx.ino).parent().unwrap())?.sync_all()?;
+            self.storage
+                .sync_dir(Self::contents_path(ctx.ino).parent().unwrap())?;
             // write attr only here to avoid serializing it multiple times while writing
             // it will merge time fields with existing data because it might got change while we kept the handle
             let ino = ctx.ino;
@@ -1469,8 +3733,9 @@ impl EncryptedFs {
     /// If the file is not opened for writing, it will return an error of type ['FsError::InvalidFileHandle'].
     #[instrument(skip(self, buf))]
     pub async fn write(&self, ino: u64, offset: u64, buf: &[u8], handle: u64) -> FsResult<usize> {
+        self.check_writable()?;
         if !self.exists(ino) {
-            return Err(FsError::InodeNotFound);
+            return Err(FsError::InodeNotFound(ino));
         }
         if !self.is_file(ino) {
             return Err(FsError::InvalidInodeType);
@@ -1482,7 +3747,11 @@ impl EncryptedFs {
         }
         {
             let guard = self.write_handles.read().await;
-            let ctx = guard.get(&handle).unwrap().lock().await;
+            let ctx = guard
+                .get(&handle)
+                .ok_or(FsError::InvalidFileHandle)?
+                .lock()
+                .await;
             if ctx.ino != ino {
                 return Err(FsError::InvalidFileHandle);
             }
@@ -1491,6 +3760,7 @@ impl EncryptedFs {
             // no-op
             return Ok(0);
         }
+        self.idle_readers.lock().unwrap().pop(&ino);
 
         let lock = self
             .read_write_locks
@@ -1498,7 +3768,12 @@ impl EncryptedFs {
         let write_guard = lock.write().await;
 
         let guard = self.write_handles.read().await;
-        let mut ctx = guard.get(&handle).unwrap().lock().await;
+        let mut ctx = guard
+            .get(&handle)
+            .ok_or(FsError::InvalidFileHandle)?
+            .lock()
+            .await;
+        let offset = if ctx.append { ctx.attr.size } else { offset };
 
         // write new data
         let (pos, len) = {
@@ -1507,6 +3782,7 @@ impl EncryptedFs {
                     self.cipher.max_plaintext_len(),
                 ));
             }
+            let size = ctx.attr.size;
             let writer = ctx.writer.as_mut().unwrap();
             let pos = writer.seek(SeekFrom::Start(offset)).map_err(|err| {
                 error!(err = %err, "seeking");
@@ -1523,11 +3799,17 @@ impl EncryptedFs {
             } else {
                 buf
             };
+            // count the new size for the quota before writing, give back what was not written
+            let end = (offset + buf.len() as u64).max(size);
+            self.grow_quota_used(end - size)?;
             let len = writer.write(buf).map_err(|err| {
                 error!(err = %err, "writing");
+                self.shrink_quota_used(end - size);
                 err
             })?;
-            (writer.stream_position()?, len)
+            let pos = writer.stream_position()?;
+            self.shrink_quota_used(end - pos.max(size));
+            (pos, len)
         };
 
         if pos > ctx.attr.size {
@@ -1539,7 +3821,11 @@ impl EncryptedFs {
         ctx.attr.mtime = now;
         ctx.attr.ctime = now;
         ctx.attr.atime = now;
+        ctx.written = true;
         drop(ctx);
+        // `reset_handles` locks the handles again, keeping them locked could deadlock
+        // with a concurrent release waiting to remove a handle
+        drop(guard);
 
         drop(write_guard);
         self.reset_handles(ino, Some(handle), true).await?;
@@ -1554,22 +3840,33 @@ impl EncryptedFs {
             // in the case of directory or if the file was crated without being opened we don't use a handle
             return Ok(());
         }
-        let lock = self.read_handles.read().await;
-        let mut valid_fh = lock.get(&handle).is_some();
-        let lock = self.write_handles.read().await;
-        if let Some(ctx) = lock.get(&handle) {
-            let mut ctx = ctx.lock().await;
+        let mut valid_fh = self.read_handles.read().await.contains_key(&handle);
+        // don't keep the handles locked, `reset_handles` locks them again
+        let ino = match self.write_handles.read().await.get(&handle) {
+            Some(ctx) => Some(ctx.lock().await.ino),
+            None => None,
+        };
+        if let Some(ino) = ino {
             let lock = self
                 .read_write_locks
-                .get_or_insert_with(ctx.ino, || RwLock::new(false));
+                .get_or_insert_with(ino, || RwLock::new(false));
             let write_guard = lock.write().await;
-            ctx.writer.as_mut().expect("writer is missing").flush()?;
-            File::open(self.contents_path(ctx.ino))?.sync_all()?;
-            File::open(self.contents_path(ctx.ino).parent().unwrap())?.sync_all()?;
+            // the writer is recreated, keep the offset of the handle
+            let pos = self.write_handle_position(handle).await?;
+            // this finishes the writer and persists size and timestamps,
+            // so they are not lost if we crash before release
+            self.reset_handles(ino, None, true).await?;
+            let guard = self.write_handles.read().await;
+            if let Some(ctx) = guard.get(&handle) {
+                ctx.lock()
+                    .await
+                    .writer
+                    .as_mut()
+                    .unwrap()
+                    .seek(SeekFrom::Start(pos))?;
+            }
+            drop(guard);
             drop(write_guard);
-            let ino = ctx.ino;
-            drop(ctx);
-            self.reset_handles(ino, Some(handle), true).await?;
             valid_fh = true;
         }
 
@@ -1580,7 +3877,77 @@ impl EncryptedFs {
         Ok(())
     }
 
+    /// Current offset of the handle, where the last read or write ended.
+    ///
+    /// For handles opened for both read and write it's the offset of the reader.
+    #[allow(clippy::missing_panics_doc)]
+    pub async fn stream_position(&self, handle: u64) -> FsResult<u64> {
+        let read_handles = self.read_handles.read().await;
+        if let Some(ctx) = read_handles.get(&handle) {
+            let mut ctx = ctx.lock().await;
+            return Ok(ctx.reader.as_mut().unwrap().stream_position()?);
+        }
+        drop(read_handles);
+        self.write_handle_position(handle).await
+    }
+
+    /// Offset of the writer of `handle`.
+    async fn write_handle_position(&self, handle: u64) -> FsResult<u64> {
+        let write_handles = self.write_handles.read().await;
+        let mut ctx = write_handles
+            .get(&handle)
+            .ok_or(FsError::InvalidFileHandle)?
+            .lock()
+            .await;
+        Ok(ctx.writer.as_mut().unwrap().stream_position()?)
+    }
+
+    /// Make sure what was written is persisted, like `fsync(2)`, or `fdatasync(2)` if `datasync` is true.
+    ///
+    /// Pending writes from the handle are encrypted and written, like on [`EncryptedFs::flush`],
+    /// then the contents file is synced. Without `datasync` also the inode file with the attributes
+    /// and their directories are synced.
+    #[allow(clippy::missing_panics_doc)]
+    pub async fn fsync(&self, handle: u64, datasync: bool) -> FsResult<()> {
+        self.flush(handle).await?;
+        if handle == 0 {
+            return Ok(());
+        }
+        let ino = {
+            let read_handles = self.read_handles.read().await;
+            let write_handles = self.write_handles.read().await;
+            if let Some(ctx) = read_handles.get(&handle) {
+                ctx.lock().await.ino
+            } else if let Some(ctx) = write_handles.get(&handle) {
+                ctx.lock().await.ino
+            } else {
+                return Err(FsError::InvalidFileHandle);
+            }
+        };
+        let lock = self
+            .read_write_locks
+            .get_or_insert_with(ino, || RwLock::new(false));
+        let _guard = lock.read().await;
+        let contents_path = Self::contents_path(ino);
+        let file = self.storage.open(&contents_path)?;
+        if datasync {
+            file.sync_data()?;
+        } else {
+            file.sync_all()?;
+            self.storage.sync_dir(contents_path.parent().unwrap())?;
+            let ino_file = Self::ino_file(ino);
+            self.storage.open(&ino_file)?.sync_all()?;
+            self.storage.sync_dir(ino_file.parent().unwrap())?;
+        }
+        Ok(())
+    }
+
     /// Helpful when we want to copy just some portions of the file.
+    ///
+    /// Data is copied in chunks. If the ranges overlap in the same file we copy backward when needed,
+    /// so we don't overwrite data before we copied it.
+    ///
+    /// Returns the number of bytes copied, which could be less than `size`.
     pub async fn copy_file_range(
         &self,
         src_ino: u64,
@@ -1591,44 +3958,167 @@ impl EncryptedFs {
         src_fh: u64,
         dest_fh: u64,
     ) -> FsResult<usize> {
+        // check them here too, with an empty range we don't get to read or write
+        for ino in [src_ino, dest_ino] {
+            if !self.exists(ino) {
+                return Err(FsError::InodeNotFound(ino));
+            }
+        }
         if self.is_dir(src_ino) || self.is_dir(dest_ino) {
             return Err(FsError::InvalidInodeType);
         }
 
-        let mut buf = vec![0; size];
-        let len = self.read(src_ino, src_offset, &mut buf, src_fh).await?;
-        if len == 0 {
-            return Ok(0);
+        let mut buf = Zeroizing::new(vec![0; size.min(stream_util::BUF_SIZE)]);
+        if src_ino == dest_ino && dest_offset > src_offset {
+            // copy backward, starting with the last chunk
+            let src_size = self.get_attr(src_ino).await?.size;
+            #[allow(clippy::cast_possible_truncation)]
+            let size = size.min(src_size.saturating_sub(src_offset) as usize);
+            let mut end = size;
+            while end > 0 {
+                let len = buf.len().min(end);
+                let start = end - len;
+                let read = self
+                    .read_at(src_ino, src_offset + start as u64, &mut buf[..len], src_fh)
+                    .await?;
+                if read != len {
+                    return Err(FsError::Other("Failed to read the range to copy"));
+                }
+                let written = self
+                    .write_at(dest_ino, dest_offset + start as u64, &buf[..len], dest_fh)
+                    .await?;
+                if written != len {
+                    return Err(FsError::Other("Failed to copy all read bytes"));
+                }
+                end = start;
+            }
+            return Ok(size);
         }
+
         let mut copied = 0;
         while copied < size {
+            let len = buf.len().min(size - copied);
+            let read = self
+                .read(src_ino, src_offset + copied as u64, &mut buf[..len], src_fh)
+                .await?;
+            if read == 0 {
+                break;
+            }
+            let written = self
+                .write_at(dest_ino, dest_offset + copied as u64, &buf[..read], dest_fh)
+                .await?;
+            copied += written;
+            if written < read {
+                // short write, report what we managed to copy
+                warn!(copied, "Failed to copy all read bytes");
+                break;
+            }
+        }
+        Ok(copied)
+    }
+
+    /// Like [`EncryptedFs::read`] but reads until `buf` is full or we reach the end of the file.
+    ///
+    /// Returns the number of bytes read, `0` if `offset` is at or after the end of the file.
+    pub async fn read_at(
+        &self,
+        ino: u64,
+        offset: u64,
+        buf: &mut [u8],
+        handle: u64,
+    ) -> FsResult<usize> {
+        let mut pos = 0;
+        while pos < buf.len() {
             let len = self
-                .write(dest_ino, dest_offset, &buf[copied..len], dest_fh)
+                .read(ino, offset + pos as u64, &mut buf[pos..], handle)
                 .await?;
-            if len == 0 && copied < size {
-                error!(len, "Failed to copy all read bytes");
-                return Err(FsError::Other("Failed to copy all read bytes"));
+            if len == 0 {
+                break;
             }
-            copied += len;
+            pos += len;
         }
-        Ok(len)
+        Ok(pos)
+    }
+
+    /// Like [`EncryptedFs::read_at`] but fails if we reach the end of the file before `buf` is full.
+    pub async fn read_exact_at(
+        &self,
+        ino: u64,
+        offset: u64,
+        buf: &mut [u8],
+        handle: u64,
+    ) -> FsResult<()> {
+        let len = self.read_at(ino, offset, buf, handle).await?;
+        if len < buf.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "failed to fill whole buffer",
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Write until all `buf` is written or the writer doesn't accept more.
+    async fn write_at(&self, ino: u64, offset: u64, buf: &[u8], handle: u64) -> FsResult<usize> {
+        let mut pos = 0;
+        while pos < buf.len() {
+            let len = self
+                .write(ino, offset + pos as u64, &buf[pos..], handle)
+                .await?;
+            if len == 0 {
+                break;
+            }
+            pos += len;
+        }
+        Ok(pos)
     }
 
     /// Open a file. We can open multiple times for read but only one to write at a time.
-    #[allow(clippy::missing_panics_doc)]
     pub async fn open(&self, ino: u64, read: bool, write: bool) -> FsResult<u64> {
+        self.open_with(ino, OpenFlags::default().with_read(read).with_write(write))
+            .await
+    }
+
+    /// Like [`EncryptedFs::open`] but with [`OpenFlags`], which can also append or truncate.
+    ///
+    /// With `truncate` the file is truncated before the handle is returned.
+    #[allow(clippy::missing_panics_doc)]
+    pub async fn open_with(&self, ino: u64, flags: OpenFlags) -> FsResult<u64> {
+        let OpenFlags {
+            read,
+            write,
+            append,
+            truncate,
+        } = flags;
         if !read && !write {
             return Err(FsError::InvalidInput(
                 "read and write cannot be false at the same time",
             ));
         }
+        if (append || truncate) && !write {
+            return Err(FsError::InvalidInput(
+                "append and truncate need the file to be opened for write",
+            ));
+        }
+        if write {
+            self.check_writable()?;
+        }
         if self.is_dir(ino) {
             return Err(FsError::InvalidInodeType);
         }
+        let mut mask = 0;
+        if read {
+            mask |= libc::R_OK;
+        }
+        if write {
+            mask |= libc::W_OK;
+        }
+        self.enforce_access(ino, mask).await?;
 
         let mut handle: Option<u64> = None;
         if read {
-            handle = Some(self.next_handle());
+            handle = Some(self.next_handle()?);
             self.do_with_read_handle(
                 *handle.as_ref().unwrap(),
                 ReadHandleContextOperation::Create { ino },
@@ -1636,18 +4126,30 @@ impl EncryptedFs {
             .await?;
         }
         if write {
-            if self.opened_files_for_write.read().await.contains_key(&ino) {
-                return Err(FsError::AlreadyOpenForWrite);
-            }
             if handle.is_none() {
-                handle = Some(self.next_handle());
+                handle = Some(self.next_handle()?);
             }
-            let res = self
-                .do_with_write_handle(
+            // check and truncate under the write lock, so concurrent opens for write can't both
+            // pass the check
+            let lock = self
+                .read_write_locks
+                .get_or_insert_with(ino, || RwLock::new(false));
+            let write_guard = lock.write().await;
+            let res = async {
+                if self.opened_files_for_write.read().await.contains_key(&ino) {
+                    return Err(FsError::AlreadyOpenForWrite);
+                }
+                if truncate {
+                    self.set_len_locked(ino, 0).await?;
+                }
+                self.do_with_write_handle(
                     *handle.as_ref().expect("handle is missing"),
-                    WriteHandleContextOperation::Create { ino },
+                    WriteHandleContextOperation::Create { ino, append },
                 )
-                .await;
+                .await
+            }
+            .await;
+            drop(write_guard);
             if res.is_err() && read {
                 // on error remove the read handle if it was added above
                 // remove the read handle if it was added above
@@ -1661,10 +4163,143 @@ impl EncryptedFs {
         Ok(handle.unwrap())
     }
 
+    /// Like [`EncryptedFs::open_with`] but returns an [`EncryptedFile`] that releases the handle
+    /// when dropped.
+    #[allow(clippy::missing_panics_doc)]
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn open_file(&self, ino: u64, flags: OpenFlags) -> FsResult<EncryptedFile> {
+        let handle = self.open_with(ino, flags).await?;
+        let fs = self
+            .self_weak
+            .lock()
+            .unwrap()
+            .as_ref()
+            .unwrap()
+            .upgrade()
+            .unwrap();
+        Ok(EncryptedFile {
+            fs,
+            ino,
+            handle,
+            pos: 0,
+            released: false,
+        })
+    }
+
+    /// Get filesystem statistics.
+    ///
+    /// Used space is the total size of the files, like for [`EncryptedFs::set_quota`], free space and
+    /// free inodes are the ones of the storage, see [`Storage::space`].\
+    /// The size of the files and the number of inodes are computed on the first call, then kept up to
+    /// date as files change, so the next calls don't go through all the files.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn statfs(&self) -> FsResult<FsStat> {
+        let files = self.inode_count()?;
+        let used = self.files_size().await?;
+        let bsize = u64::from(BLKSIZE);
+        let space = self.storage.space()?;
+
+        Ok(FsStat {
+            blocks: used.div_ceil(bsize) + space.free / bsize,
+            bfree: space.free / bsize,
+            bavail: space.avail / bsize,
+            files: files + space.files_free,
+            ffree: space.files_free,
+            bsize: BLKSIZE,
+            namelen: space.name_max,
+            frsize: BLKSIZE,
+        })
+    }
+
+    /// Open a reader with seek over the content of a file.
+    ///
+    /// Unlike [`EncryptedFs::read`] this doesn't need a handle, it can be used with normal stream I/O
+    /// and allows random access. Any pending writes are flushed first so the reader sees the latest content.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn open_reader(
+        &self,
+        ino: u64,
+    ) -> FsResult<impl CryptoReadSeek<Box<dyn StorageFile>>> {
+        let file = self.open_contents_for_read(ino).await?;
+        self.create_read_seek(ino, file).await
+    }
+
+    /// Like [`EncryptedFs::open_reader`] but a background thread decrypts up to `readahead` blocks
+    /// ahead of the current position.
+    ///
+    /// Good for large sequential reads, like streaming media. Seeking discards the blocks decrypted ahead.
+    pub async fn open_reader_buffered(
+        &self,
+        ino: u64,
+        readahead: usize,
+    ) -> FsResult<impl CryptoReadSeek<Box<dyn StorageFile>>> {
+        let file = self.open_contents_for_read(ino).await?;
+        // not using `create_read_seek`, the reader it returns borrows `self`
+        // and we need to move it to the read ahead thread
+        let reader =
+            crypto::create_read_seek_for_file(file, self.cipher, &*self.key.get().await?, ino);
+        Ok(ReadAheadCryptoRead::new(reader, readahead))
+    }
+
+    /// SHA-256 of the decrypted content of a file.
+    ///
+    /// The content is streamed through the hash, not loaded in memory. It uses its own reader,
+    /// like [`EncryptedFs::open_reader`], so the opened handles are not affected.
+    pub async fn content_hash(&self, ino: u64) -> FsResult<[u8; 32]> {
+        let mut reader = self.open_reader(ino).await?;
+        let mut context = digest::Context::new(&digest::SHA256);
+        let mut buf = Zeroizing::new(vec![0; stream_util::BUF_SIZE]);
+        loop {
+            let len = reader.read(&mut buf)?;
+            if len == 0 {
+                break;
+            }
+            context.update(&buf[..len]);
+        }
+        let mut hash = [0; 32];
+        hash.copy_from_slice(context.finish().as_ref());
+        Ok(hash)
+    }
+
+    /// Size of the encrypted content of `ino` on disk, pending writes are flushed first.
+    ///
+    /// It's larger than the size of the file by the overhead of the encryption, see
+    /// [`Cipher::ciphertext_len`].
+    pub async fn ciphertext_size(&self, ino: u64) -> FsResult<u64> {
+        Ok(self.open_contents_for_read(ino).await?.size()?)
+    }
+
+    /// Open the contents file of a regular file, flushing any pending writes first.
+    async fn open_contents_for_read(&self, ino: u64) -> FsResult<Box<dyn StorageFile>> {
+        let attr = self.get_attr(ino).await?;
+        if !matches!(attr.kind, FileType::RegularFile) {
+            return Err(FsError::InvalidInodeType);
+        }
+        {
+            let lock = self
+                .read_write_locks
+                .get_or_insert_with(ino, || RwLock::new(false));
+            let _write_guard = lock.write().await;
+            self.flush_and_reset_writers(ino).await?;
+        }
+        Ok(self.storage.open(&Self::contents_path(ino))?)
+    }
+
     /// Truncates or extends the underlying file, updating the size of this file to become size.
     #[allow(clippy::missing_panics_doc)]
-    #[allow(clippy::too_many_lines)]
     pub async fn set_len(&self, ino: u64, size: u64) -> FsResult<()> {
+        self.check_writable()?;
+        let lock = self
+            .read_write_locks
+            .get_or_insert_with(ino, || RwLock::new(false));
+        let _write_guard = lock.write().await;
+        self.set_len_locked(ino, size).await
+    }
+
+    /// Like [`EncryptedFs::set_len`].
+    /// > ⚠️ **Warning**
+    /// > Need to be called in a context with write lock on `self.read_write_locks.get(ino)`.
+    async fn set_len_locked(&self, ino: u64, size: u64) -> FsResult<()> {
         let attr = self.get_attr(ino).await?;
         if matches!(attr.kind, FileType::Directory) {
             return Err(FsError::InvalidInodeType);
@@ -1675,48 +4310,69 @@ impl EncryptedFs {
             return Ok(());
         }
 
-        let lock = self
-            .read_write_locks
-            .get_or_insert_with(ino, || RwLock::new(false));
-        let _write_guard = lock.write().await;
-
         // flush writers
         self.flush_and_reset_writers(ino).await?;
 
-        let file_path = self.contents_path(ino);
+        if size > attr.size {
+            self.grow_quota_used(size - attr.size)?;
+        }
+        if let Err(err) = self.resize_contents(ino, attr.size, size).await {
+            // give back what we counted above, the size didn't change
+            self.shrink_quota_used(size.saturating_sub(attr.size));
+            return Err(err);
+        }
+
+        // reset handles because the file has changed
+        self.reset_handles(ino, None, false).await?;
+
+        if size < attr.size {
+            self.shrink_quota_used(attr.size - size);
+        }
+
+        Ok(())
+    }
+
+    /// Rewrites the contents of `ino` from `old_size` to `size` and saves the new size.
+    async fn resize_contents(&self, ino: u64, old_size: u64, size: u64) -> FsResult<()> {
+        let file_path = Self::contents_path(ino);
         if size == 0 {
             debug!("truncate to zero");
             // truncate to zero
-            let file = File::create(&file_path)?;
+            let file = self.storage.create(&file_path)?;
             file.set_len(0)?;
             file.sync_all()?;
         } else {
             debug!("truncate size to {}", size.to_formatted_string(&Locale::en));
 
-            let mut file = fs_util::open_atomic_write(&file_path)?;
+            let mut file = self.storage.open_atomic(&file_path)?;
             {
                 // have a new scope, so we drop the reader before moving new content files
-                let mut reader = self.create_read(File::open(file_path.as_path())?).await?;
+                let mut reader = self
+                    .create_read(ino, self.storage.open(&file_path)?)
+                    .await?;
 
-                let mut writer = self.create_write(file).await?;
+                let mut writer = self.create_write_seek(ino, file).await?;
 
-                let len = if size > attr.size {
+                let len = if size > old_size {
                     // increase size, copy existing data until existing size
-                    attr.size
+                    old_size
                 } else {
                     // decrease size, copy existing data until new size
                     size
                 };
                 stream_util::copy_exact(&mut reader, &mut writer, len)?;
-                if size > attr.size {
-                    // increase size, seek to new size will write zeros
-                    stream_util::fill_zeros(&mut writer, size - attr.size)?;
+                if size > old_size {
+                    // increase size, seek to new size will write zeros,
+                    // whole blocks are left as holes
+                    writer.seek(SeekFrom::Start(size))?;
                 }
                 file = writer.finish()?;
             }
+            #[cfg(test)]
+            self.check_fail_point("set_len:before_commit")?;
             file.commit()?;
         }
-        File::open(file_path.parent().unwrap())?.sync_all()?;
+        self.storage.sync_dir(file_path.parent().unwrap())?;
 
         let now = SystemTime::now();
         let set_attr = SetFileAttr::default()
@@ -1724,22 +4380,38 @@ impl EncryptedFs {
             .with_mtime(now)
             .with_ctime(now)
             .with_atime(now);
-        self.set_attr2(ino, set_attr, true).await?;
-
-        let attr = self.get_inode_from_storage(ino).await?;
-        println!("attr 1: {:?}", attr.size);
-        let attr = self.get_attr(ino).await?;
-        println!("attr 1: {:?}", attr.size);
-
-        // reset handles because the file has changed
-        self.reset_handles(attr.ino, None, false).await?;
+        self.set_attr2(ino, set_attr, true).await
+    }
 
-        let attr = self.get_inode_from_storage(ino).await?;
-        println!("attr 2: {:?}", attr.size);
+    /// Makes sure the file has space for `len` bytes at `offset`, like `fallocate`.
+    ///
+    /// Files are sparse, growing one doesn't write ciphertext for the new range, it's written
+    /// together with the data. For the same reason nothing is reserved ahead with
+    /// [`AllocateMode::KeepSize`], in that case we only validate the range.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn allocate(
+        &self,
+        ino: u64,
+        offset: u64,
+        len: u64,
+        mode: AllocateMode,
+    ) -> FsResult<()> {
+        if len == 0 {
+            return Err(FsError::InvalidInput("length must be greater than 0"));
+        }
         let attr = self.get_attr(ino).await?;
-        println!("attr 2: {:?}", attr.size);
-
-        Ok(())
+        if attr.kind != FileType::RegularFile {
+            return Err(FsError::InvalidInodeType);
+        }
+        let max_len = self.cipher.max_plaintext_len();
+        let end = offset
+            .checked_add(len)
+            .filter(|end| *end <= max_len as u64)
+            .ok_or(FsError::MaxFilesizeExceeded(max_len))?;
+        if mode == AllocateMode::KeepSize || end <= attr.size {
+            return Ok(());
+        }
+        self.set_len(ino, end).await
     }
 
     /// This will write any dirty data to the file from all writers and reset them.
@@ -1760,7 +4432,8 @@ impl EncryptedFs {
                 let mut writer = ctx.writer.take().unwrap();
                 let file = writer.finish()?;
                 file.sync_all()?;
-                File::open(self.contents_path(ctx.ino).parent().unwrap())?.sync_all()?;
+                self.storage
+                    .sync_dir(Self::contents_path(ctx.ino).parent().unwrap())?;
                 let handle = *handle;
                 let set_attr: SetFileAttr = ctx.attr.clone().into();
                 drop(ctx);
@@ -1771,12 +4444,7 @@ impl EncryptedFs {
                 let write_handles_guard = self.write_handles.write().await;
                 let mut ctx = write_handles_guard.get(&handle).unwrap().lock().await;
                 let writer = self
-                    .create_write_seek(
-                        OpenOptions::new()
-                            .read(true)
-                            .write(true)
-                            .open(self.contents_path(ino))?,
-                    )
+                    .create_write_seek(ino, self.storage.open_rw(&Self::contents_path(ino))?)
                     .await?;
                 ctx.writer = Some(Box::new(writer));
                 let attr = self.get_inode_from_storage(ino).await?;
@@ -1786,62 +4454,150 @@ impl EncryptedFs {
         Ok(())
     }
 
-    #[allow(clippy::missing_panics_doc)]
-    pub async fn rename(
+    /// Checks if [`EncryptedFs::rename`] would succeed, without changing anything.
+    ///
+    /// Returns the same error `rename` would fail with on its preconditions.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn can_rename(
+        &self,
+        parent: u64,
+        name: &SecretString,
+        new_parent: u64,
+        new_name: &SecretString,
+    ) -> FsResult<()> {
+        self.can_rename_with(parent, name, new_parent, new_name, RenameFlags::default())
+            .await
+    }
+
+    /// Like [`EncryptedFs::can_rename`] but for [`EncryptedFs::rename_with`].
+    pub async fn can_rename_with(
         &self,
         parent: u64,
         name: &SecretString,
         new_parent: u64,
         new_name: &SecretString,
+        flags: RenameFlags,
     ) -> FsResult<()> {
+        check_not_dot_name(name)?;
+        check_not_dot_name(new_name)?;
         if !self.exists(parent) {
-            return Err(FsError::InodeNotFound);
+            return Err(FsError::InodeNotFound(parent));
         }
         if !self.is_dir(parent) {
             return Err(FsError::InvalidInodeType);
         }
         if !self.exists(new_parent) {
-            return Err(FsError::InodeNotFound);
+            return Err(FsError::InodeNotFound(new_parent));
         }
         if !self.is_dir(new_parent) {
             return Err(FsError::InvalidInodeType);
         }
-        if !self.exists_by_name(parent, name)? {
-            return Err(FsError::NotFound("name not found"));
+        let attr = self
+            .find_by_name(parent, name)
+            .await?
+            .ok_or(FsError::NotFound("name not found"))?;
+        self.check_name_len(new_name)?;
+
+        if flags.no_replace && self.exists_by_name(new_parent, new_name).await? {
+            return Err(FsError::AlreadyExists);
         }
 
         if parent == new_parent && name.expose_secret() == new_name.expose_secret() {
-            // no-op
+            // renaming to itself is a no-op
             return Ok(());
         }
 
-        // Only overwrite an existing directory if it's empty
+        if attr.kind == FileType::Directory && parent != new_parent {
+            self.check_not_inside(attr.ino, new_parent).await?;
+        }
+
+        // Only overwrite an existing node of the same kind, and a directory only if it's empty
         if let Ok(Some(new_attr)) = self.find_by_name(new_parent, new_name).await {
-            if new_attr.kind == FileType::Directory && self.len(new_attr.ino)? > 0 {
-                return Err(FsError::NotEmpty);
+            if attr.ino != new_attr.ino {
+                match (attr.kind, new_attr.kind) {
+                    (FileType::Directory, FileType::Directory) => {
+                        if !self.is_empty_dir(new_attr.ino)? {
+                            return Err(FsError::NotEmpty(new_attr.ino));
+                        }
+                    }
+                    (FileType::Directory, _) => return Err(FsError::NotADirectory),
+                    (_, FileType::Directory) => return Err(FsError::IsADirectory),
+                    _ => {}
+                }
             }
         }
 
+        Ok(())
+    }
+
+    /// Rename, replacing the destination if it exists and it's a file or an empty directory,
+    /// of the same kind as the source.
+    ///
+    /// Same as [`EncryptedFs::rename_with`] with the default flags.
+    pub async fn rename(
+        &self,
+        parent: u64,
+        name: &SecretString,
+        new_parent: u64,
+        new_name: &SecretString,
+    ) -> FsResult<()> {
+        self.rename_with(parent, name, new_parent, new_name, RenameFlags::default())
+            .await
+    }
+
+    /// Rename with the options from `flags`.
+    #[allow(clippy::missing_panics_doc)]
+    pub async fn rename_with(
+        &self,
+        parent: u64,
+        name: &SecretString,
+        new_parent: u64,
+        new_name: &SecretString,
+        flags: RenameFlags,
+    ) -> FsResult<()> {
+        self.check_writable()?;
+        self.can_rename_with(parent, name, new_parent, new_name, flags)
+            .await?;
+
+        if parent == new_parent && name.expose_secret() == new_name.expose_secret() {
+            // no-op
+            return Ok(());
+        }
+
         let attr = self
             .find_by_name(parent, name)
             .await?
             .ok_or(FsError::NotFound("name not found"))?;
+        let replaced = self.find_by_name(new_parent, new_name).await?;
+        if replaced
+            .as_ref()
+            .is_some_and(|new_attr| new_attr.ino == attr.ino)
+        {
+            // both names are links to the same inode, nothing to do
+            return Ok(());
+        }
+        // add to new parent contents before removing the old entry,
+        // so the node is reachable at any time
+        let entry = DirectoryEntry {
+            ino: attr.ino,
+            name: new_name.clone(),
+            kind: attr.kind,
+        };
+        if replaced.is_some() {
+            // the destination points to one of the inodes at any time,
+            // its data is dropped only after it points to the source
+            self.replace_directory_entry(new_parent, &entry).await?;
+            #[cfg(test)]
+            self.check_fail_point("rename:after_replace")?;
+        } else {
+            self.insert_directory_entry(new_parent, &entry).await?;
+        }
         // remove from parent contents
         self.remove_directory_entry(parent, name).await?;
-        // remove from new_parent contents, if exists
-        if self.exists_by_name(new_parent, new_name)? {
-            self.remove_directory_entry(new_parent, new_name).await?;
+        if let Some(new_attr) = replaced {
+            self.drop_replaced_inode(new_parent, new_name, new_attr)
+                .await?;
         }
-        // add to new parent contents
-        self.insert_directory_entry(
-            new_parent,
-            &DirectoryEntry {
-                ino: attr.ino,
-                name: new_name.clone(),
-                kind: attr.kind,
-            },
-        )
-        .await?;
 
         if attr.kind == FileType::Directory {
             // add the parent link to the new directory
@@ -1854,6 +4610,10 @@ impl EncryptedFs {
                 },
             )
             .await?;
+            if parent != new_parent {
+                self.change_dir_nlink(parent, -1).await?;
+                self.change_dir_nlink(new_parent, 1).await?;
+            }
         }
 
         let now = SystemTime::now();
@@ -1875,86 +4635,292 @@ impl EncryptedFs {
         Ok(())
     }
 
-    /// Create a crypto writer using internal encryption info.
+    /// Fails if `dir` is the directory `ino` or somewhere inside it, we can't move `ino` there.
+    ///
+    /// We go up from `dir` on the `..` links until the root.
+    async fn check_not_inside(&self, ino: u64, dir: u64) -> FsResult<()> {
+        let dot_dot = SecretString::from_str("..").expect("cannot parse");
+        let mut visited = HashSet::new();
+        let mut dir = dir;
+        while dir != ROOT_INODE {
+            if dir == ino {
+                return Err(FsError::InvalidInput(
+                    "cannot move a directory inside itself",
+                ));
+            }
+            if !visited.insert(dir) {
+                // a cycle, the tree is already broken
+                return Err(FsError::InvalidDirectoryEntry);
+            }
+            dir = self
+                .find_by_name(dir, &dot_dot)
+                .await?
+                .ok_or(FsError::NotFound("parent not found"))?
+                .ino;
+        }
+        Ok(())
+    }
+
+    /// Swap two existing entries, like `RENAME_EXCHANGE`.
+    ///
+    /// After this `name` in `parent` points to what `new_name` in `new_parent` pointed to and
+    /// the other way around. Both entries must exist, if any of them is a directory moved to
+    /// another parent its `..` link is updated. A directory can't be moved inside itself.
+    #[allow(clippy::missing_panics_doc)]
+    pub async fn exchange(
+        &self,
+        parent: u64,
+        name: &SecretString,
+        new_parent: u64,
+        new_name: &SecretString,
+    ) -> FsResult<()> {
+        self.check_writable()?;
+        check_not_dot_name(name)?;
+        check_not_dot_name(new_name)?;
+        for p in [parent, new_parent] {
+            if !self.exists(p) {
+                return Err(FsError::InodeNotFound(p));
+            }
+            if !self.is_dir(p) {
+                return Err(FsError::InvalidInodeType);
+            }
+        }
+        let attr = self
+            .find_by_name(parent, name)
+            .await?
+            .ok_or(FsError::NotFound("name not found"))?;
+        let new_attr = self
+            .find_by_name(new_parent, new_name)
+            .await?
+            .ok_or(FsError::NotFound("name not found"))?;
+        if attr.ino == new_attr.ino {
+            // same inode, swapping changes nothing
+            return Ok(());
+        }
+        if parent != new_parent {
+            for (ino, kind, dir_parent) in [
+                (attr.ino, attr.kind, new_parent),
+                (new_attr.ino, new_attr.kind, parent),
+            ] {
+                if kind == FileType::Directory {
+                    self.check_not_inside(ino, dir_parent).await?;
+                }
+            }
+        }
+
+        // each name is removed right before it's added back pointing to the other inode
+        self.remove_directory_entry(new_parent, new_name).await?;
+        self.insert_directory_entry(
+            new_parent,
+            &DirectoryEntry {
+                ino: attr.ino,
+                name: new_name.clone(),
+                kind: attr.kind,
+            },
+        )
+        .await?;
+        self.remove_directory_entry(parent, name).await?;
+        self.insert_directory_entry(
+            parent,
+            &DirectoryEntry {
+                ino: new_attr.ino,
+                name: name.clone(),
+                kind: new_attr.kind,
+            },
+        )
+        .await?;
+
+        if parent != new_parent {
+            for (ino, kind, dir_parent) in [
+                (attr.ino, attr.kind, new_parent),
+                (new_attr.ino, new_attr.kind, parent),
+            ] {
+                if kind == FileType::Directory {
+                    self.insert_directory_entry(
+                        ino,
+                        &DirectoryEntry {
+                            ino: dir_parent,
+                            name: SecretString::from_str("$..").expect("cannot parse"),
+                            kind: FileType::Directory,
+                        },
+                    )
+                    .await?;
+                }
+            }
+            // a directory moved to the other parent takes its `..` link with it
+            let moved = |kind| i32::from(kind == FileType::Directory);
+            let delta = moved(attr.kind) - moved(new_attr.kind);
+            if delta != 0 {
+                self.change_dir_nlink(parent, -delta).await?;
+                self.change_dir_nlink(new_parent, delta).await?;
+            }
+        }
+
+        let now = SystemTime::now();
+        for p in [parent, new_parent] {
+            let set_attr = SetFileAttr::default()
+                .with_mtime(now)
+                .with_ctime(now)
+                .with_atime(now);
+            self.set_attr(p, set_attr).await?;
+        }
+        for ino in [attr.ino, new_attr.ino] {
+            let set_attr = SetFileAttr::default().with_ctime(now).with_atime(now);
+            self.set_attr(ino, set_attr).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Create a crypto writer for the content of `ino` using internal encryption info.
     pub async fn create_write<W: Write + Seek + Send + Sync>(
         &self,
+        ino: u64,
         file: W,
     ) -> FsResult<impl CryptoWrite<W>> {
-        Ok(crypto::create_write(
+        Ok(crypto::create_write_for_file(
             file,
             self.cipher,
             &*self.key.get().await?,
+            ino,
         ))
     }
 
-    /// Create a crypto writer with seek using internal encryption info.
+    /// Create a crypto writer with seek for the content of `ino` using internal encryption info.
     pub async fn create_write_seek<W: Write + Seek + Read + Send + Sync>(
         &self,
+        ino: u64,
         file: W,
     ) -> FsResult<impl CryptoWriteSeek<W>> {
-        Ok(crypto::create_write_seek(
+        Ok(crypto::create_write_seek_for_file(
             file,
             self.cipher,
             &*self.key.get().await?,
+            ino,
         ))
     }
 
-    /// Create a crypto reader using internal encryption info.
+    /// Create a crypto reader for the content of `ino` using internal encryption info.
     pub async fn create_read<R: Read + Send + Sync>(
         &self,
+        ino: u64,
         reader: R,
     ) -> FsResult<impl CryptoRead<R>> {
-        Ok(crypto::create_read(
+        Ok(crypto::create_read_for_file(
             reader,
             self.cipher,
             &*self.key.get().await?,
+            ino,
         ))
     }
 
-    /// Create a crypto reader with seek using internal encryption info.
+    /// Create a crypto reader with seek for the content of `ino` using internal encryption info.
     pub async fn create_read_seek<R: Read + Seek + Send + Sync>(
         &self,
+        ino: u64,
         reader: R,
     ) -> FsResult<impl CryptoReadSeek<R>> {
-        Ok(crypto::create_read_seek(
+        Ok(crypto::create_read_seek_for_file(
             reader,
             self.cipher,
             &*self.key.get().await?,
+            ino,
         ))
     }
 
     /// Change the password of the filesystem used to access the encryption key.
+    #[allow(clippy::unused_async)] // kept async, it's part of the public API
     pub async fn passwd(
         data_dir: &Path,
         old_password: SecretString,
         new_password: SecretString,
         cipher: Cipher,
     ) -> FsResult<()> {
-        check_structure(data_dir, false).await?;
+        let storage = &FsStorage::new(data_dir.to_path_buf());
+        check_structure(storage, false)?;
+        let kdf_iterations = check_metadata(storage, cipher)?
+            .map_or(crypto::KDF_ITERATIONS, |metadata| metadata.kdf_t_cost);
         // decrypt key
-        let salt: Vec<u8> = bincode::deserialize_from(File::open(
-            data_dir.join(SECURITY_DIR).join(KEY_SALT_FILENAME),
-        )?)?;
-        let initial_key = crypto::derive_key(&old_password, cipher, &salt)?;
-        let enc_file = data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME);
-        let reader = crypto::create_read(File::open(enc_file)?, cipher, &initial_key);
+        let salt: Vec<u8> = bincode::deserialize_from(
+            storage.open(&Path::new(SECURITY_DIR).join(KEY_SALT_FILENAME))?,
+        )?;
+        let initial_key =
+            crypto::derive_key_with_iterations(&old_password, cipher, &salt, kdf_iterations)?;
+        let enc_file = Path::new(SECURITY_DIR).join(KEY_ENC_FILENAME);
+        let reader = crypto::create_read(storage.open(&enc_file)?, cipher, &initial_key);
         let key: Vec<u8> =
             bincode::deserialize_from(reader).map_err(|_| FsError::InvalidPassword)?;
         let key = SecretVec::new(key);
         // encrypt it with a new key derived from new password
-        let new_key = crypto::derive_key(&new_password, cipher, &salt)?;
-        crypto::atomic_serialize_encrypt_into(
-            &data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME),
-            &key.expose_secret(),
-            cipher,
-            &new_key,
-        )?;
+        let new_key =
+            crypto::derive_key_with_iterations(&new_password, cipher, &salt, kdf_iterations)?;
+        atomic_serialize_encrypt_into(storage, &enc_file, &key.expose_secret(), cipher, &new_key)?;
         Ok(())
     }
 
-    fn next_handle(&self) -> u64 {
+    /// Migrate a data dir created before the format version was kept, version `0`,
+    /// to [`FORMAT_VERSION`]. It needs the password and cipher it was created with.
+    ///
+    /// The key, the inodes, the contents and the directory entries are encrypted again in the
+    /// current format and the metadata is written last. Each file is replaced atomically
+    /// and the ones already migrated are skipped, so an interrupted migration can be run again.
+    /// Data dirs already in the current format are left as they are.
+    #[allow(clippy::unused_async)] // kept async, it's part of the public API
+    pub async fn migrate(data_dir: &Path, password: SecretString, cipher: Cipher) -> FsResult<()> {
+        let storage = &FsStorage::new(data_dir.to_path_buf());
+        check_structure(storage, false)?;
+        let _lock = lock_data_dir(storage, false)?;
+        if check_metadata(storage, cipher)?.is_some() || !has_inodes(storage)? {
+            return Ok(());
+        }
+        let salt: Vec<u8> = bincode::deserialize_from(
+            storage.open(&Path::new(SECURITY_DIR).join(KEY_SALT_FILENAME))?,
+        )?;
+        // there was only the default number of iterations
+        let derived_key = crypto::derive_key(&password, cipher, &salt)?;
+        let key: Vec<u8> = migrate_value(
+            storage,
+            &Path::new(SECURITY_DIR).join(KEY_ENC_FILENAME),
+            cipher,
+            &derived_key,
+        )
+        .map_err(|_| FsError::InvalidPassword)?;
+        let key = SecretVec::new(key);
+
+        for name in storage.read_dir(Path::new(INODES_DIR))? {
+            // skip temp files of an interrupted migration
+            let Ok(ino) = name.to_string_lossy().parse::<u64>() else {
+                continue;
+            };
+            let attr: FileAttr = migrate_value(
+                storage,
+                &Path::new(INODES_DIR).join(ino.to_string()),
+                cipher,
+                &key,
+            )?;
+            let contents_path = Self::contents_path(ino);
+            match attr.kind {
+                FileType::Directory => migrate_dir_entries(storage, &contents_path, cipher, &key)?,
+                FileType::RegularFile => {
+                    migrate_contents(storage, &contents_path, ino, cipher, &key)?;
+                }
+                // there were only files and directories
+                _ => {}
+            }
+        }
+
+        write_metadata(storage, &Metadata::new(cipher, crypto::KDF_ITERATIONS))
+    }
+
+    /// Allocate a new file handle. Handle `0` is reserved, so we fail instead of wrapping around.
+    fn next_handle(&self) -> FsResult<u64> {
         self.current_handle
-            .fetch_add(1, std::sync::atomic::Ordering::SeqCst)
+            .fetch_update(
+                std::sync::atomic::Ordering::SeqCst,
+                std::sync::atomic::Ordering::SeqCst,
+                |fh| fh.checked_add(1),
+            )
+            .map_err(|_| FsError::Other("no more file handles available"))
     }
 
     /// Reset all handles for a file.
@@ -1971,81 +4937,134 @@ impl EncryptedFs {
         skip_write_fh: Option<u64>,
         save_attr: bool,
     ) -> FsResult<()> {
-        let path = self.contents_path(ino);
+        let path = Self::contents_path(ino);
+        self.idle_readers.lock().unwrap().pop(&ino);
+
+        // we don't keep the handles locked while saving the attributes, `get_attr` locks them too,
+        // and waiting for them while someone else waits to change them would deadlock
 
         // read
-        let lock = self.opened_files_for_read.read().await;
-        if let Some(set) = lock.get(&ino) {
-            for handle in set
-                .iter()
-                .filter(|h| skip_write_fh.map_or(true, |fh| **h != fh))
-            {
+        let handles = self.opened_files_for_read.read().await.get(&ino).cloned();
+        for handle in handles
+            .iter()
+            .flatten()
+            .filter(|h| skip_write_fh.map_or(true, |fh| **h != fh))
+        {
+            let set_attr: Option<SetFileAttr> = {
                 let guard = self.read_handles.read().await;
-                let ctx = guard.get(handle).unwrap().lock().await;
-                let set_attr: SetFileAttr = ctx.attr.clone().into();
-                drop(ctx);
+                let Some(ctx) = guard.get(handle) else {
+                    // released meanwhile
+                    continue;
+                };
+                let ctx = ctx.lock().await;
+                ctx.atime_updated.then(|| ctx.attr.clone().into())
+            };
+            if let Some(set_attr) = set_attr {
                 self.set_attr(ino, set_attr).await?;
-                let attr = self.get_inode_from_storage(ino).await?;
-                let mut ctx = guard.get(handle).unwrap().lock().await;
-                let reader = self.create_read_seek(File::open(&path)?).await?;
+            }
+            let attr = self.get_inode_from_storage(ino).await?;
+            let reader = self
+                .create_read_seek(ino, self.storage.open(&path)?)
+                .await?;
+            let guard = self.read_handles.read().await;
+            if let Some(ctx) = guard.get(handle) {
+                let mut ctx = ctx.lock().await;
                 ctx.reader = Some(Box::new(reader));
                 ctx.attr = attr.into();
+                ctx.atime_updated = false;
             }
         }
 
         // write
-        let lock = self.opened_files_for_write.read().await;
-        if let Some(fh) = lock.get(&ino) {
-            if let Some(handle) = skip_write_fh {
-                if *fh == handle {
-                    return Ok(());
-                }
+        let fh = self.opened_files_for_write.read().await.get(&ino).copied();
+        if let Some(fh) = fh {
+            if skip_write_fh == Some(fh) {
+                return Ok(());
             }
-            let lock = self.write_handles.read().await;
-            if let Some(lock) = lock.get(fh) {
-                let mut ctx = lock.lock().await;
+            let set_attr: Option<SetFileAttr> = {
+                let guard = self.write_handles.read().await;
+                let Some(ctx) = guard.get(&fh) else {
+                    return Ok(());
+                };
+                let mut ctx = ctx.lock().await;
                 let writer = ctx.writer.as_mut().unwrap();
                 let file = writer.finish()?;
                 file.sync_all()?;
-                File::open(self.contents_path(ctx.ino).parent().unwrap())?.sync_all()?;
-                let set_attr: Option<SetFileAttr> = if save_attr {
-                    Some(ctx.attr.clone().into())
-                } else {
-                    None
-                };
-                drop(ctx);
-                if let Some(set_attr) = set_attr {
-                    self.set_attr(ino, set_attr).await?;
-                }
-                let writer = self
-                    .create_write_seek(OpenOptions::new().read(true).write(true).open(&path)?)
-                    .await?;
-                let mut ctx = lock.lock().await;
+                self.storage
+                    .sync_dir(Self::contents_path(ctx.ino).parent().unwrap())?;
+                save_attr.then(|| ctx.attr.clone().into())
+            };
+            if let Some(set_attr) = set_attr {
+                self.set_attr(ino, set_attr).await?;
+            }
+            let writer = self
+                .create_write_seek(ino, self.storage.open_rw(&path)?)
+                .await?;
+            let attr = self.get_inode_from_storage(ino).await?;
+            let guard = self.write_handles.read().await;
+            if let Some(ctx) = guard.get(&fh) {
+                let mut ctx = ctx.lock().await;
                 ctx.writer = Some(Box::new(writer));
-                let attr = self.get_inode_from_storage(ino).await?;
                 ctx.attr = attr.into();
+                // the reader of this handle was opened again above
+                ctx.written = false;
             }
         }
 
         Ok(())
     }
 
+    /// Writes the data the writer of `handle` keeps in its buffer and opens again the reader of
+    /// the same handle, so it sees what was written with it.
+    ///
+    /// Nothing is synced, `fsync` and `release` do that.
+    async fn flush_pending_writes(&self, ino: u64, handle: u64) -> FsResult<()> {
+        {
+            let guard = self.write_handles.read().await;
+            let Some(ctx) = guard.get(&handle) else {
+                return Ok(());
+            };
+            let mut ctx = ctx.lock().await;
+            if !ctx.written {
+                return Ok(());
+            }
+            ctx.writer.as_mut().unwrap().flush_pending()?;
+            ctx.written = false;
+        }
+        let reader = self
+            .create_read_seek(ino, self.storage.open(&Self::contents_path(ino))?)
+            .await?;
+        let guard = self.read_handles.read().await;
+        if let Some(ctx) = guard.get(&handle) {
+            ctx.lock().await.reader = Some(Box::new(reader));
+        }
+        Ok(())
+    }
+
     async fn do_with_read_handle(
         &self,
         handle: u64,
         op: ReadHandleContextOperation,
     ) -> FsResult<()> {
         let ino = op.get_ino();
-        let path = self.contents_path(ino);
+        let path = Self::contents_path(ino);
         let attr = self.get_inode_from_storage(ino).await?;
         match op {
             ReadHandleContextOperation::Create { ino } => {
                 let attr: TimesFileAttr = attr.into();
-                let reader = self.create_read_seek(File::open(&path)?).await?;
+                let cached = { self.idle_readers.lock().unwrap().pop(&ino) };
+                let reader: Box<dyn CryptoReadSeek<Box<dyn StorageFile>>> = match cached {
+                    Some(reader) => reader,
+                    None => Box::new(
+                        self.create_read_seek(ino, self.storage.open(&path)?)
+                            .await?,
+                    ),
+                };
                 let ctx = ReadHandleContext {
                     ino,
                     attr,
-                    reader: Some(Box::new(reader)),
+                    atime_updated: false,
+                    reader: Some(reader),
                 };
                 self.read_handles
                     .write()
@@ -2068,17 +5087,19 @@ impl EncryptedFs {
         op: WriteHandleContextOperation,
     ) -> FsResult<()> {
         let ino = op.get_ino();
-        let path = self.contents_path(ino);
+        let path = Self::contents_path(ino);
         match op {
-            WriteHandleContextOperation::Create { ino } => {
+            WriteHandleContextOperation::Create { ino, append } => {
                 let attr = self.get_attr(ino).await?.into();
                 let writer = self
-                    .create_write_seek(OpenOptions::new().read(true).write(true).open(&path)?)
+                    .create_write_seek(ino, self.storage.open_rw(&path)?)
                     .await?;
                 let ctx = WriteHandleContext {
                     ino,
                     attr,
                     writer: Some(Box::new(writer)),
+                    append,
+                    written: false,
                 };
                 self.write_handles
                     .write()
@@ -2115,9 +5136,10 @@ impl EncryptedFs {
             self.write_inode_to_storage(&attr).await?;
 
             // create in contents directory
-            fs::create_dir(self.contents_path(attr.ino))?;
-            fs::create_dir(self.contents_path(attr.ino).join(LS_DIR))?;
-            fs::create_dir(self.contents_path(attr.ino).join(HASH_DIR))?;
+            let contents_dir = Self::contents_path(attr.ino);
+            self.storage.create_dir(&contents_dir)?;
+            self.storage.create_dir(&contents_dir.join(LS_DIR))?;
+            self.storage.create_dir(&contents_dir.join(HASH_DIR))?;
 
             // add "." entry
             self.insert_directory_entry(
@@ -2139,7 +5161,24 @@ impl EncryptedFs {
         ino_contents_dir: u64,
         entry: &DirectoryEntry,
     ) -> FsResult<()> {
-        let parent_path = self.contents_path(ino_contents_dir);
+        let parent_path = Self::contents_path(ino_contents_dir);
+        let name = entry.name.expose_secret();
+        if name == "$." || name == "$.." {
+            // these are overwritten in place, make sure we don't keep the old link in cache
+            let file_path = parent_path.join(LS_DIR).join(name);
+            self.dir_entries_meta_cache
+                .get()
+                .await?
+                .lock()
+                .await
+                .pop(file_path.to_str().unwrap());
+        } else if let Some(ino) = self.find_ino_by_name(ino_contents_dir, &entry.name).await? {
+            // names are normalized, so different names could map to the same entry
+            if ino != entry.ino {
+                return Err(FsError::AlreadyExists);
+            }
+            return Ok(());
+        }
         let encrypted_name =
             crypto::encrypt_file_name(&entry.name, self.cipher, &*self.key.get().await?)?;
         // add to LS directory
@@ -2167,7 +5206,8 @@ impl EncryptedFs {
             let _guard = lock.write().await;
             // write inode and file type
             let entry = (entry_clone.ino, entry_clone.kind);
-            crypto::atomic_serialize_encrypt_into(
+            atomic_serialize_encrypt_into(
+                &*self_clone.storage,
                 &file_path,
                 &entry,
                 self_clone.cipher,
@@ -2186,7 +5226,7 @@ impl EncryptedFs {
             .unwrap();
         let entry_hash = entry.clone();
         tokio::spawn(async move {
-            let name = crypto::hash_file_name(&entry_hash.name);
+            let name = crypto::hash_file_name(&entry_hash.name, &*self_clone.key.get().await?);
             let file_path = parent_path.join(HASH_DIR).join(name);
             let lock = self_clone
                 .serialize_dir_entries_hash_locks
@@ -2197,7 +5237,8 @@ impl EncryptedFs {
             // write inode and file type
             // we save the encrypted name also because we need it to remove the entry on [`remove_directory_entry`]
             let entry = (entry_hash.ino, entry_hash.kind, encrypted_name);
-            crypto::atomic_serialize_encrypt_into(
+            atomic_serialize_encrypt_into(
+                &*self_clone.storage,
                 &file_path,
                 &entry,
                 self_clone.cipher,
@@ -2210,18 +5251,210 @@ impl EncryptedFs {
         Ok(())
     }
 
-    fn ino_file(&self, ino: u64) -> PathBuf {
-        self.data_dir.join(INODES_DIR).join(ino.to_string())
+    /// Point the existing entry `entry.name` in `ino_contents_dir` to `entry.ino`.
+    ///
+    /// The entry files are overwritten in place, each one atomically, so the name never goes missing.
+    async fn replace_directory_entry(
+        &self,
+        ino_contents_dir: u64,
+        entry: &DirectoryEntry,
+    ) -> FsResult<()> {
+        let parent_path = Self::contents_path(ino_contents_dir);
+        let key = self.key.get().await?;
+        let hash_path = parent_path
+            .join(HASH_DIR)
+            .join(crypto::hash_file_name(&entry.name, &key));
+        let lock = self
+            .serialize_dir_entries_hash_locks
+            .get_or_insert_with(hash_path.to_str().unwrap().to_string(), || {
+                RwLock::new(false)
+            });
+        let _hash_guard = lock.write().await;
+        let (_, _, encrypted_name): (u64, FileType, String) = bincode::deserialize_from(
+            crypto::create_read(self.storage.open(&hash_path)?, self.cipher, &key),
+        )?;
+        // lookups go through HASH, so they see the new inode first
+        atomic_serialize_encrypt_into(
+            &*self.storage,
+            &hash_path,
+            &(entry.ino, entry.kind, &encrypted_name),
+            self.cipher,
+            &key,
+        )?;
+        let ls_path = parent_path.join(LS_DIR).join(encrypted_name);
+        let lock = self
+            .serialize_dir_entries_ls_locks
+            .get_or_insert_with(ls_path.to_str().unwrap().to_string(), || RwLock::new(false));
+        let _ls_guard = lock.write().await;
+        atomic_serialize_encrypt_into(
+            &*self.storage,
+            &ls_path,
+            &(entry.ino, entry.kind),
+            self.cipher,
+            &key,
+        )?;
+        self.dir_entries_meta_cache
+            .get()
+            .await?
+            .lock()
+            .await
+            .pop(ls_path.to_str().unwrap());
+        Ok(())
+    }
+
+    /// Drop the link `name` in `parent` had to `attr`, after it was pointed to another inode by
+    /// [`EncryptedFs::rename_with`]. The inode and its contents are removed if it was the last link.
+    async fn drop_replaced_inode(
+        &self,
+        parent: u64,
+        name: &SecretString,
+        attr: FileAttr,
+    ) -> FsResult<()> {
+        if attr.kind != FileType::Directory && attr.nlink > 1 {
+            // there are other links to this inode, keep the data
+            self.set_attr(
+                attr.ino,
+                SetFileAttr::default()
+                    .with_nlink(attr.nlink - 1)
+                    .with_ctime(SystemTime::now()),
+            )
+            .await?;
+            return Ok(());
+        }
+        // the name doesn't point to the inode anymore, so the replay only removes the files
+        self.write_journal(&JournalEntry::RemoveFile {
+            parent,
+            ino: attr.ino,
+            name: name.expose_secret().clone(),
+        })
+        .await?;
+        {
+            let lock = self
+                .serialize_inode_locks
+                .get_or_insert_with(attr.ino, || RwLock::new(false));
+            let _guard = lock.write().await;
+            self.remove_inode_files(attr.ino)?;
+        }
+        self.remove_journal(attr.ino)?;
+        self.idle_readers.lock().unwrap().pop(&attr.ino);
+        match attr.kind {
+            FileType::RegularFile => self.shrink_quota_used(attr.size),
+            // its `..` link to parent is gone
+            FileType::Directory => self.change_dir_nlink(parent, -1).await?,
+            _ => {}
+        }
+        self.record_change(attr.ino, ChangeKind::Deleted).await?;
+        self.attr_cache.get().await?.write().await.pop(&attr.ino);
+        Ok(())
+    }
+
+    fn journal_path(ino: u64) -> PathBuf {
+        Path::new(SECURITY_DIR)
+            .join(JOURNAL_DIR)
+            .join(ino.to_string())
+    }
+
+    async fn write_journal(&self, entry: &JournalEntry) -> FsResult<()> {
+        atomic_serialize_encrypt_into(
+            &*self.storage,
+            &Self::journal_path(entry.ino()),
+            entry,
+            self.cipher,
+            &*self.key.get().await?,
+        )?;
+        Ok(())
+    }
+
+    fn remove_journal(&self, ino: u64) -> FsResult<()> {
+        self.storage.remove_file(&Self::journal_path(ino))?;
+        Ok(())
+    }
+
+    /// Completes or rolls back the operations interrupted by a crash, see [`JournalEntry`].
+    async fn replay_journal(&self) -> FsResult<()> {
+        let journal_dir = Path::new(SECURITY_DIR).join(JOURNAL_DIR);
+        for name in self.storage.read_dir(&journal_dir)? {
+            if name
+                .to_str()
+                .and_then(|name| name.parse::<u64>().ok())
+                .is_none()
+            {
+                // temp file of an interrupted journal write, the operation didn't start
+                continue;
+            }
+            let path = journal_dir.join(name);
+            let entry: JournalEntry = bincode::deserialize_from(crypto::create_read(
+                self.storage.open(&path)?,
+                self.cipher,
+                &*self.key.get().await?,
+            ))?;
+            warn!(?path, "replaying interrupted operation");
+            match &entry {
+                JournalEntry::Create { parent, ino, name } => {
+                    let name = SecretString::new(name.clone());
+                    if self.find_ino_by_name(*parent, &name).await? != Some(*ino) {
+                        // not added to parent, roll back
+                        self.remove_inode_files(*ino)?;
+                    }
+                }
+                JournalEntry::RemoveFile { parent, ino, name } => {
+                    let name = SecretString::new(name.clone());
+                    self.remove_inode_files(*ino)?;
+                    if self.find_ino_by_name(*parent, &name).await? == Some(*ino) {
+                        self.remove_directory_entry(*parent, &name).await?;
+                    }
+                    self.record_change(*ino, ChangeKind::Deleted).await?;
+                }
+            }
+            self.remove_journal(entry.ino())?;
+        }
+        Ok(())
+    }
+
+    /// Removes the inode and contents of `ino`, the ones that exist.
+    fn remove_inode_files(&self, ino: u64) -> FsResult<()> {
+        if self.storage.exists(&Self::ino_file(ino)) {
+            self.storage.remove_file(&Self::ino_file(ino))?;
+            self.change_inode_count(-1);
+        }
+        self.batch.lock().unwrap().pending.remove(&ino);
+        let contents = Self::contents_path(ino);
+        if self.storage.is_dir(&contents) {
+            self.storage.remove_dir_all(&contents)?;
+        } else if self.storage.exists(&contents) {
+            self.storage.remove_file(&contents)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    fn check_fail_point(&self, name: &'static str) -> FsResult<()> {
+        if *self.fail_point.lock().unwrap() == Some(name) {
+            return Err(FsError::Other("fail point"));
+        }
+        Ok(())
+    }
+
+    fn ino_file(ino: u64) -> PathBuf {
+        Path::new(INODES_DIR).join(ino.to_string())
+    }
+
+    /// Check the encrypted name fits in [`NAME_MAX`] on the host filesystem.
+    fn check_name_len(&self, name: &SecretString) -> FsResult<()> {
+        if crypto::encrypted_file_name_len(name, self.cipher) > NAME_MAX {
+            return Err(FsError::NameTooLong);
+        }
+        Ok(())
     }
 
-    fn contents_path(&self, ino: u64) -> PathBuf {
-        self.data_dir.join(CONTENTS_DIR).join(ino.to_string())
+    fn contents_path(ino: u64) -> PathBuf {
+        Path::new(CONTENTS_DIR).join(ino.to_string())
     }
 
     async fn remove_directory_entry(&self, parent: u64, name: &SecretString) -> FsResult<()> {
-        let parent_path = self.contents_path(parent);
+        let parent_path = Self::contents_path(parent);
         // remove from HASH
-        let name = crypto::hash_file_name(name);
+        let name = crypto::hash_file_name(name, &*self.key.get().await?);
         let path = parent_path.join(HASH_DIR).join(name);
         let lock = self
             .serialize_dir_entries_hash_locks
@@ -2229,11 +5462,11 @@ impl EncryptedFs {
         let guard = lock.write().await;
         let (_, _, name): (u64, FileType, String) =
             bincode::deserialize_from(crypto::create_read(
-                File::open(path.clone())?,
+                self.storage.open(&path)?,
                 self.cipher,
                 &*self.key.get().await?,
             ))?;
-        fs::remove_file(path)?;
+        self.storage.remove_file(&path)?;
         drop(guard);
         // remove from LS
         let path = parent_path.join(LS_DIR).join(name);
@@ -2241,109 +5474,374 @@ impl EncryptedFs {
             .serialize_dir_entries_ls_locks
             .get_or_insert_with(path.to_str().unwrap().to_string(), || RwLock::new(false));
         let _guard = lock.write().await;
-        fs::remove_file(path)?;
+        self.storage.remove_file(&path)?;
         Ok(())
     }
 
-    fn generate_next_inode(&self) -> u64 {
-        loop {
-            let ino = crypto::create_rng().next_u64();
+    /// Allocate the next inode. Inodes are allocated sequentially, starting after [`ROOT_INODE`].
+    /// The counter is persisted before the inode is handed out, so it's never reused after remount.
+    fn generate_next_inode(&self) -> FsResult<u64> {
+        let _guard = self.current_ino_lock.lock().expect("cannot obtain lock");
+        let ino = self
+            .current_ino
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        let path = Path::new(SECURITY_DIR).join(INODE_COUNTER_FILENAME);
+        let mut file = self.storage.open_atomic(&path)?;
+        bincode::serialize_into(&mut file, &(ino + 1))?;
+        file.commit()?;
+        Ok(ino)
+    }
+}
 
-            if ino <= ROOT_INODE {
-                continue;
-            }
-            if self.exists(ino) {
-                continue;
+/// Read the next inode to allocate.
+/// For data dirs created before the counter was persisted we continue after the biggest existing inode.
+fn read_inode_counter(storage: &dyn Storage) -> FsResult<u64> {
+    let path = Path::new(SECURITY_DIR).join(INODE_COUNTER_FILENAME);
+    if storage.exists(&path) {
+        return Ok(bincode::deserialize_from(storage.open(&path)?)?);
+    }
+    let mut max = ROOT_INODE;
+    if storage.is_dir(Path::new(INODES_DIR)) {
+        for name in storage.read_dir(Path::new(INODES_DIR))? {
+            if let Ok(ino) = name.to_string_lossy().parse::<u64>() {
+                max = max.max(ino);
             }
+        }
+    }
+    Ok(max + 1)
+}
+
+/// A multistep operation, recorded before it starts and removed after it finishes.
+///
+/// If we crash in the middle the entry is still there on the next [`EncryptedFs::new`],
+/// where the operation is completed or rolled back.
+#[derive(Debug, Serialize, Deserialize)]
+enum JournalEntry {
+    /// `ino` is created and added to `parent` as `name`, rolled back if the entry was not added.
+    Create { parent: u64, ino: u64, name: String },
+    /// `ino` is deleted and `name` is removed from `parent` if it still points to it, always completed.
+    RemoveFile { parent: u64, ino: u64, name: String },
+}
+
+impl JournalEntry {
+    const fn ino(&self) -> u64 {
+        match self {
+            Self::Create { ino, .. } | Self::RemoveFile { ino, .. } => *ino,
+        }
+    }
+}
+
+impl Drop for JournalEntry {
+    fn drop(&mut self) {
+        match self {
+            Self::Create { name, .. } | Self::RemoveFile { name, .. } => name.zeroize(),
+        }
+    }
+}
+
+/// Describes how the data dir was created, so we can detect if it's opened with different settings.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Metadata {
+    // keep it first, it's read before the rest, which can change between versions
+    version: u32,
+    cipher: Cipher,
+    kdf_m_cost: u32,
+    kdf_t_cost: u32,
+    kdf_p_cost: u32,
+}
+
+impl Metadata {
+    const fn new(cipher: Cipher, kdf_iterations: u32) -> Self {
+        Self {
+            version: FORMAT_VERSION,
+            cipher,
+            kdf_m_cost: argon2::Params::DEFAULT_M_COST,
+            kdf_t_cost: kdf_iterations,
+            kdf_p_cost: argon2::Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+impl From<Metadata> for FsConfig {
+    fn from(value: Metadata) -> Self {
+        Self {
+            format_version: value.version,
+            cipher: value.cipher,
+            kdf_m_cost: value.kdf_m_cost,
+            kdf_t_cost: value.kdf_t_cost,
+            kdf_p_cost: value.kdf_p_cost,
+        }
+    }
+}
+
+fn read_metadata(storage: &dyn Storage) -> FsResult<Metadata> {
+    let path = Path::new(SECURITY_DIR).join(METADATA_FILENAME);
+    let mut file = storage.open(&path)?;
+    let version: u32 = bincode::deserialize_from(&mut file)?;
+    if version != FORMAT_VERSION {
+        return Err(FsError::UnsupportedFormatVersion(version));
+    }
+    file.seek(SeekFrom::Start(0))?;
+    Ok(bincode::deserialize_from(file)?)
+}
+
+/// Validate the metadata of the data dir against the settings we're opening it with.
+/// Returns `None` if there is no metadata yet.
+///
+/// The key derivation iterations are taken from the metadata, they are chosen when it's created.
+fn check_metadata(storage: &dyn Storage, cipher: Cipher) -> FsResult<Option<Metadata>> {
+    let path = Path::new(SECURITY_DIR).join(METADATA_FILENAME);
+    if !storage.is_file(&path) {
+        return Ok(None);
+    }
+    let metadata = read_metadata(storage)?;
+    let expected = Metadata::new(cipher, metadata.kdf_t_cost);
+    if metadata.cipher != expected.cipher {
+        return Err(FsError::Other(
+            "cipher doesn't match the one the data directory was created with",
+        ));
+    }
+    if metadata != expected {
+        return Err(FsError::Other(
+            "key derivation parameters don't match the ones the data directory was created with",
+        ));
+    }
+    Ok(Some(metadata))
+}
+
+/// If any inode was written in the data dir, data dirs without metadata and inodes are new.
+fn has_inodes(storage: &dyn Storage) -> FsResult<bool> {
+    Ok(!storage.read_dir(Path::new(INODES_DIR))?.is_empty())
+}
+
+fn write_metadata(storage: &dyn Storage, metadata: &Metadata) -> FsResult<()> {
+    let path = Path::new(SECURITY_DIR).join(METADATA_FILENAME);
+    let mut file = storage.open_atomic(&path)?;
+    bincode::serialize_into(&mut file, metadata)?;
+    file.commit()?;
+    storage.sync_dir(Path::new(SECURITY_DIR))?;
+    Ok(())
+}
+
+/// Read a value serialized in the format before the version was kept and write it in the current one,
+/// see [`EncryptedFs::migrate`]. If it's already in the current format it's only read.
+fn migrate_value<T>(
+    storage: &dyn Storage,
+    path: &Path,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+) -> FsResult<T>
+where
+    T: Serialize + for<'de> Deserialize<'de>,
+{
+    if let Ok(value) =
+        bincode::deserialize_from(crypto::create_read(storage.open(path)?, cipher, key))
+    {
+        return Ok(value);
+    }
+    let value =
+        bincode::deserialize_from(crypto::create_read_legacy(storage.open(path)?, cipher, key))?;
+    atomic_serialize_encrypt_into(storage, path, &value, cipher, key)?;
+    Ok(value)
+}
+
+/// Encrypt the content of file `ino` again in the current format, see [`EncryptedFs::migrate`].
+fn migrate_contents(
+    storage: &dyn Storage,
+    path: &Path,
+    ino: u64,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+) -> FsResult<()> {
+    if !storage.is_file(path) || storage.file_size(path)? == 0 {
+        return Ok(());
+    }
+    let mut reader = crypto::create_read_for_file(storage.open(path)?, cipher, key, ino);
+    if reader.read(&mut [0; 1]).is_ok() {
+        // the first block is in the current format, so it was already migrated
+        return Ok(());
+    }
+    let mut reader = crypto::create_read_legacy(storage.open(path)?, cipher, key);
+    let mut writer = crypto::create_write_for_file(storage.open_atomic(path)?, cipher, key, ino);
+    io::copy(&mut reader, &mut writer)?;
+    writer.finish()?.commit()?;
+    storage.sync_dir(path.parent().unwrap())?;
+    Ok(())
+}
 
-            return ino;
+/// Encrypt the entries of a directory again in the current format and rebuild its `hash` dir
+/// with [`crypto::hash_file_name`], see [`EncryptedFs::migrate`].
+fn migrate_dir_entries(
+    storage: &dyn Storage,
+    path: &Path,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+) -> FsResult<()> {
+    let ls_dir = path.join(LS_DIR);
+    let hash_dir = path.join(HASH_DIR);
+    for ls_path in read_ls_dir(storage, &ls_dir)? {
+        let file_name = ls_path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
+        if is_synthetic_entry(OsStr::new(&file_name)) {
+            let (ino, kind): (u64, FileType) = migrate_value(storage, &ls_path, cipher, key)?;
+            atomic_serialize_encrypt_into(
+                storage,
+                &hash_dir.join(&file_name),
+                &(ino, kind, file_name),
+                cipher,
+                key,
+            )?;
+            continue;
+        }
+        if crypto::decrypt_file_name(&file_name, cipher, key).is_ok() {
+            continue;
+        }
+        let name = crypto::decrypt_file_name_legacy(&file_name, cipher, key)?;
+        let (ino, kind): (u64, FileType) = bincode::deserialize_from(crypto::create_read_legacy(
+            storage.open(&ls_path)?,
+            cipher,
+            key,
+        ))?;
+        // the hash entry is written first, if we were interrupted after it we keep the name from it,
+        // so the entry is not added twice
+        let hash_path = hash_dir.join(crypto::hash_file_name(&name, key));
+        let existing: Option<(u64, FileType, String)> =
+            storage.open(&hash_path).ok().and_then(|file| {
+                bincode::deserialize_from(crypto::create_read(file, cipher, key)).ok()
+            });
+        let encrypted_name = if let Some((_, _, encrypted_name)) = existing {
+            encrypted_name
+        } else {
+            let encrypted_name = crypto::encrypt_file_name(&name, cipher, key)?;
+            atomic_serialize_encrypt_into(
+                storage,
+                &hash_path,
+                &(ino, kind, encrypted_name.clone()),
+                cipher,
+                key,
+            )?;
+            encrypted_name
+        };
+        atomic_serialize_encrypt_into(
+            storage,
+            &ls_dir.join(encrypted_name),
+            &(ino, kind),
+            cipher,
+            key,
+        )?;
+        storage.remove_file(&ls_path)?;
+    }
+    // what's left in the old format are the hashes of the old names
+    for name in storage.read_dir(&hash_dir)? {
+        let hash_path = hash_dir.join(name);
+        let entry: bincode::Result<(u64, FileType, String)> =
+            bincode::deserialize_from(crypto::create_read(storage.open(&hash_path)?, cipher, key));
+        if entry.is_err() {
+            storage.remove_file(&hash_path)?;
         }
     }
+    storage.sync_dir(&ls_dir)?;
+    storage.sync_dir(&hash_dir)?;
+    Ok(())
 }
 
 fn read_or_create_key(
-    key_path: &PathBuf,
-    salt_path: &PathBuf,
+    storage: &dyn Storage,
     password: &SecretString,
     cipher: Cipher,
+    kdf_iterations: u32,
 ) -> FsResult<SecretVec<u8>> {
-    let salt = if salt_path.exists() {
-        bincode::deserialize_from(File::open(salt_path)?).map_err(|_| FsError::InvalidPassword)?
+    let key_path = Path::new(SECURITY_DIR).join(KEY_ENC_FILENAME);
+    let salt_path = Path::new(SECURITY_DIR).join(KEY_SALT_FILENAME);
+    let salt = if storage.exists(&salt_path) {
+        bincode::deserialize_from(storage.open(&salt_path)?)
+            .map_err(|_| FsError::InvalidPassword)?
     } else {
         let mut salt = vec![0; 16];
         crypto::create_rng().fill_bytes(&mut salt);
-        let mut file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(salt_path)?;
+        let mut file = storage.create(&salt_path)?;
         bincode::serialize_into(&mut file, &salt)?;
         file.flush()?;
         file.sync_all()?;
-        File::open(salt_path.parent().expect("oops, we don't have a parent"))?.sync_all()?;
+        storage.sync_dir(Path::new(SECURITY_DIR))?;
         salt
     };
     // derive key from password
-    let derived_key = crypto::derive_key(password, cipher, &salt)?;
-    if key_path.exists() {
+    let derived_key = crypto::derive_key_with_iterations(password, cipher, &salt, kdf_iterations)?;
+    if storage.exists(&key_path) {
+        if storage.file_size(&key_path)? == 0 {
+            // don't report this as a wrong password
+            error!("key file is empty");
+            return Err(FsError::InvalidDataDirStructure);
+        }
         // read key
-        let reader = crypto::create_read(File::open(key_path)?, cipher, &derived_key);
+        let reader = crypto::create_read(storage.open(&key_path)?, cipher, &derived_key);
         let key: Vec<u8> =
             bincode::deserialize_from(reader).map_err(|_| FsError::InvalidPassword)?;
-        Ok(SecretVec::new(key))
+        let key = SecretVec::new(key);
+        // ring would panic when using a key of the wrong length
+        if key.expose_secret().len() != cipher.key_len() {
+            error!("key length doesn't match the cipher");
+            return Err(FsError::InvalidInput("key length doesn't match the cipher"));
+        }
+        Ok(key)
     } else {
         // first time, create a random key and encrypt it with the derived key from password
         let mut key: Vec<u8> = vec![];
         let key_len = cipher.key_len();
         key.resize(key_len, 0);
         crypto::create_rng().fill_bytes(&mut key);
-        let mut writer = crypto::create_write(
-            OpenOptions::new()
-                .read(true)
-                .write(true)
-                .create(true)
-                .truncate(true)
-                .open(key_path)?,
-            cipher,
-            &derived_key,
-        );
+        let mut writer = crypto::create_write(storage.create(&key_path)?, cipher, &derived_key);
         bincode::serialize_into(&mut writer, &key)?;
         let file = writer.finish()?;
         file.sync_all()?;
-        File::open(key_path.parent().unwrap())?.sync_all()?;
+        storage.sync_dir(Path::new(SECURITY_DIR))?;
         Ok(SecretVec::new(key))
     }
 }
 
-async fn ensure_structure_created(data_dir: &PathBuf) -> FsResult<()> {
-    if data_dir.exists() {
-        check_structure(data_dir, true).await?;
+/// Lock the data dir, shared if `read_only`, so it's not changed by others while we use it.
+fn lock_data_dir(storage: &dyn Storage, read_only: bool) -> FsResult<Box<dyn Send + Sync>> {
+    let path = Path::new(SECURITY_DIR).join(LOCK_FILENAME);
+    match storage.try_lock(&path, read_only) {
+        Ok(lock) => Ok(lock),
+        Err(err) if err.kind() == io::ErrorKind::WouldBlock => Err(FsError::DataDirInUse),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn ensure_structure_created(storage: &dyn Storage) -> FsResult<()> {
+    let root = Path::new("");
+    if storage.exists(root) {
+        check_structure(storage, true)?;
     } else {
-        fs::create_dir_all(data_dir)?;
+        storage.create_dir_all(root)?;
     }
 
     // create directories
     let dirs = vec![INODES_DIR, CONTENTS_DIR, SECURITY_DIR];
     for dir in dirs {
-        let path = data_dir.join(dir);
-        if !path.exists() {
-            fs::create_dir_all(path)?;
+        let path = Path::new(dir);
+        if !storage.exists(path) {
+            storage.create_dir_all(path)?;
         }
     }
 
     Ok(())
 }
 
-async fn check_structure(data_dir: &Path, ignore_empty: bool) -> FsResult<()> {
-    if !data_dir.exists() || !data_dir.is_dir() {
+fn check_structure(storage: &dyn Storage, ignore_empty: bool) -> FsResult<()> {
+    let root = Path::new("");
+    if !storage.exists(root) || !storage.is_dir(root) {
         return Err(FsError::InvalidDataDirStructure);
     }
-    let mut vec = ReadDirStream::new(tokio::fs::read_dir(data_dir).await?)
-        .try_collect::<Vec<_>>()
-        .await?
+    let mut vec = storage
+        .read_dir(root)?
         .iter()
-        .map(|dir| dir.file_name().to_string_lossy().to_string())
+        .map(|dir| dir.to_string_lossy().to_string())
         .collect::<Vec<String>>();
     if vec.is_empty() && ignore_empty {
         return Ok(());
@@ -2356,11 +5854,9 @@ async fn check_structure(data_dir: &Path, ignore_empty: bool) -> FsResult<()> {
     let mut vec2 = vec![INODES_DIR, CONTENTS_DIR, SECURITY_DIR];
     vec2.sort_unstable();
     if vec != vec2
-        || !data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME).is_file()
-        || !data_dir
-            .join(SECURITY_DIR)
-            .join(KEY_SALT_FILENAME)
-            .is_file()
+        || vec2.iter().any(|dir| !storage.is_dir(Path::new(dir)))
+        || !storage.is_file(&Path::new(SECURITY_DIR).join(KEY_ENC_FILENAME))
+        || !storage.is_file(&Path::new(SECURITY_DIR).join(KEY_SALT_FILENAME))
     {
         return Err(FsError::InvalidDataDirStructure);
     }
@@ -2368,6 +5864,147 @@ async fn check_structure(data_dir: &Path, ignore_empty: bool) -> FsResult<()> {
     Ok(())
 }
 
+/// Standard owner, group and other `rwx` evaluation of `mask` for the user `uid` and group `gid`.
+///
+/// Root can read and write anything, but can execute only if one of the `x` bits is set.
+pub(crate) fn check_access(
+    #[allow(clippy::similar_names)] file_uid: u32,
+    #[allow(clippy::similar_names)] file_gid: u32,
+    file_mode: u16,
+    uid: u32,
+    gid: u32,
+    mut access_mask: i32,
+) -> bool {
+    // F_OK tests for existence of file
+    if access_mask == libc::F_OK {
+        return true;
+    }
+    let file_mode = i32::from(file_mode);
+
+    // root is allowed to read & write anything
+    if uid == 0 {
+        // root only allowed to exec if one of the X bits is set
+        access_mask &= libc::X_OK;
+        access_mask -= access_mask & (file_mode >> 6);
+        access_mask -= access_mask & (file_mode >> 3);
+        access_mask -= access_mask & file_mode;
+        return access_mask == 0;
+    }
+
+    if uid == file_uid {
+        access_mask -= access_mask & (file_mode >> 6);
+    } else if gid == file_gid {
+        access_mask -= access_mask & (file_mode >> 3);
+    } else {
+        access_mask -= access_mask & file_mode;
+    }
+
+    access_mask == 0
+}
+
+/// Match `name` against a glob where `*` matches any sequence of characters and `?` exactly one.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // position of the last `*` and where in name we started matching after it
+    let mut backtrack = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                // let the last `*` take one more character
+                Some((star, start)) => {
+                    backtrack = Some((star, start + 1));
+                    p = star + 1;
+                    n = start + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// The "." and ".." entries, they are stored unencrypted as `$.` and `$..` in the `ls` dir.
+fn is_synthetic_entry(file_name: &OsStr) -> bool {
+    file_name == "$." || file_name == "$.."
+}
+
+/// Entries of an `ls` dir, without the temp files of the entries being written.
+///
+/// Entries are written with [`atomic_serialize_encrypt_into`], which on [`FsStorage`] creates a temp
+/// file starting with a dot next to the entry, encrypted names never start with one.\
+/// Returns the paths of the entries.
+fn read_ls_dir(storage: &dyn Storage, ls_dir: &Path) -> io::Result<impl Iterator<Item = PathBuf>> {
+    let ls_dir = ls_dir.to_path_buf();
+    Ok(storage
+        .read_dir(&ls_dir)?
+        .into_iter()
+        .filter(|name| !name.as_encoded_bytes().starts_with(b"."))
+        .map(move |name| ls_dir.join(name)))
+}
+
+/// Like [`crypto::atomic_serialize_encrypt_into`], in `storage`.
+fn atomic_serialize_encrypt_into<T>(
+    storage: &dyn Storage,
+    path: &Path,
+    value: &T,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+) -> FsResult<()>
+where
+    T: Serialize + ?Sized,
+{
+    let mut file = storage.open_atomic(path)?;
+    crypto::serialize_encrypt_into(&mut file, value, cipher, key)?;
+    file.commit()?;
+    storage.sync_dir(path.parent().expect("oops, we don't have a parent"))?;
+    Ok(())
+}
+
+/// Root doesn't have a `..` entry, its parent is itself.
+///
+/// Only lookups resolve it, the operations changing entries reject it with [`check_not_dot_name`].
+fn is_root_dot_dot(parent: u64, name: &SecretString) -> bool {
+    parent == ROOT_INODE && matches!(name.expose_secret().as_str(), ".." | "$..")
+}
+
+/// `.` and `..`, also as they are stored, can't be created, removed or renamed.
+fn check_not_dot_name(name: &SecretString) -> FsResult<()> {
+    if matches!(name.expose_secret().as_str(), "." | ".." | "$." | "$..") {
+        return Err(FsError::InvalidInput("name cannot be '.' or '..'"));
+    }
+    Ok(())
+}
+
+/// Set `blocks` from size, in units of 512 bytes like `stat` reports, and `blksize` to [`BLKSIZE`].
+const fn update_blocks(attr: &mut FileAttr) {
+    attr.blocks = attr.size.div_ceil(512);
+    attr.blksize = BLKSIZE;
+}
+
+// `rdev` uses the Linux encoding, 12 bits for major and 20 bits for minor
+
+const fn rdev_major(rdev: u32) -> u32 {
+    (rdev >> 8) & 0xfff
+}
+
+const fn rdev_minor(rdev: u32) -> u32 {
+    (rdev & 0xff) | ((rdev >> 12) & 0xf_ff00)
+}
+
+const fn make_rdev(major: u32, minor: u32) -> u32 {
+    ((major & 0xfff) << 8) | (minor & 0xff) | ((minor & 0xf_ff00) << 12)
+}
+
 fn merge_attr(attr: &mut FileAttr, set_attr: &SetFileAttr, overwrite_size: bool) {
     if let Some(size) = set_attr.size {
         if overwrite_size {
@@ -2376,10 +6013,11 @@ fn merge_attr(attr: &mut FileAttr, set_attr: &SetFileAttr, overwrite_size: bool)
             attr.size = attr.size.max(size);
         }
     }
-    if let Some(atime) = set_attr.atime {
+    let now = SystemTime::now();
+    if let Some(atime) = set_attr.atime.resolve(now) {
         attr.atime = attr.atime.max(atime);
     }
-    if let Some(mtime) = set_attr.mtime {
+    if let Some(mtime) = set_attr.mtime.resolve(now) {
         attr.mtime = attr.mtime.max(mtime);
     }
     if let Some(ctime) = set_attr.ctime {
@@ -2391,12 +6029,18 @@ fn merge_attr(attr: &mut FileAttr, set_attr: &SetFileAttr, overwrite_size: bool)
     if let Some(perm) = set_attr.perm {
         attr.perm = perm;
     }
+    if let Some(nlink) = set_attr.nlink {
+        attr.nlink = nlink;
+    }
     if let Some(uid) = set_attr.uid {
         attr.uid = uid;
     }
     if let Some(gid) = set_attr.gid {
         attr.gid = gid;
     }
+    if let Some(rdev) = set_attr.rdev {
+        attr.rdev = rdev;
+    }
     if let Some(flags) = set_attr.flags {
         attr.flags = flags;
     }
@@ -2408,7 +6052,7 @@ pub async fn write_all_string_to_fs(
     offset: u64,
     s: &str,
     fh: u64,
-) -> FsResult<()> {
+) -> FsResult<usize> {
     write_all_bytes_to_fs(fs, ino, offset, s.as_bytes(), fh).await
 }
 
@@ -2419,10 +6063,10 @@ pub async fn write_all_bytes_to_fs(
     offset: u64,
     buf: &[u8],
     fh: u64,
-) -> FsResult<()> {
+) -> FsResult<usize> {
     let mut pos = 0_usize;
     loop {
-        let len = fs.write(ino, offset, &buf[pos..], fh).await?;
+        let len = fs.write(ino, offset + pos as u64, &buf[pos..], fh).await?;
         pos += len;
         if pos == buf.len() {
             break;
@@ -2431,5 +6075,5 @@ pub async fn write_all_bytes_to_fs(
         }
     }
     fs.flush(fh).await?;
-    Ok(())
+    Ok(pos)
 }
@@ -62,6 +62,7 @@ fn bench_exists_by_name(b: &mut Bencher) {
                             ))
                             .unwrap(),
                         )
+                        .await
                         .unwrap();
                 });
             });
@@ -171,3 +172,40 @@ fn bench_read_dir_plus(b: &mut Bencher) {
         });
     });
 }
+
+#[bench]
+fn bench_open_read_release(b: &mut Bencher) {
+    test_common::bench("bench_open_read_release", 1, async {
+        let fs = get_fs().await;
+
+        let test_file = SecretString::from_str("test-file").unwrap();
+        let (fh, attr) = fs
+            .create(
+                ROOT_INODE,
+                &test_file,
+                create_attr(FileType::RegularFile),
+                false,
+                true,
+            )
+            .await
+            .unwrap();
+        fs.write(attr.ino, 0, b"small file content", fh)
+            .await
+            .unwrap();
+        fs.release(fh).await.unwrap();
+
+        // small reads of the same file, opening it every time
+        let mut buf = vec![0; 18];
+        b.iter(|| {
+            black_box({
+                async_util::call_async(async {
+                    for _ in 0..10_000 {
+                        let fh = fs.open(attr.ino, true, false).await.unwrap();
+                        black_box(fs.read(attr.ino, 0, &mut buf, fh).await.unwrap());
+                        fs.release(fh).await.unwrap();
+                    }
+                });
+            });
+        });
+    });
+}
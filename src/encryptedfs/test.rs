@@ -1,21 +1,44 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::string::ToString;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
+use ring::aead::{CHACHA20_POLY1305, NONCE_LEN};
 use secrecy::{ExposeSecret, SecretString};
+use strum::IntoEnumIterator;
 use tracing_test::traced_test;
 
+use crate::crypto::write::{CryptoWrite, BLOCK_SIZE, HEADER_LEN};
+use crate::crypto::Cipher;
+use crate::encryptedfs::atomic_serialize_encrypt_into;
 use crate::encryptedfs::write_all_bytes_to_fs;
+use crate::encryptedfs::write_all_string_to_fs;
+use crate::encryptedfs::BLKSIZE;
+use crate::encryptedfs::FORMAT_VERSION;
 use crate::encryptedfs::HASH_DIR;
 use crate::encryptedfs::INODES_DIR;
+use crate::encryptedfs::INODE_COUNTER_FILENAME;
+use crate::encryptedfs::JOURNAL_DIR;
 use crate::encryptedfs::KEY_ENC_FILENAME;
 use crate::encryptedfs::KEY_SALT_FILENAME;
+use crate::encryptedfs::LOST_AND_FOUND_DIR;
+use crate::encryptedfs::LS_DIR;
+use crate::encryptedfs::METADATA_FILENAME;
 use crate::encryptedfs::SECURITY_DIR;
 use crate::encryptedfs::{
-    DirectoryEntry, DirectoryEntryPlus, FileType, FsError, FsResult, CONTENTS_DIR, ROOT_INODE,
+    AllocateMode, AtimePolicy, ChangeKind, ChangeRecord, CreateFileAttr, CreateFlags,
+    DirectoryEntry, DirectoryEntryPlus, EncryptedFile, EncryptedFs, FileType, FsError, FsOptions,
+    FsResult, Inconsistency, OpenFlags, PasswordProvider, RenameFlags, SetFileAttr, SetTime,
+    WalkIterator, CONTENTS_DIR, ROOT_INODE,
 };
-use crate::test_common::run_test;
+use crate::storage::{MemoryStorage, Storage};
 use crate::test_common::TestSetup;
-use crate::test_common::{create_attr, get_fs};
+use crate::test_common::{create_attr, get_fs, TESTS_DATA_DIR};
+use crate::test_common::{run_test, run_test_with_storages};
 use crate::{crypto, test_common};
 
 static ROOT_INODE_STR: &str = "1";
@@ -24,7 +47,7 @@ static ROOT_INODE_STR: &str = "1";
 #[traced_test]
 #[allow(clippy::too_many_lines)]
 async fn test_write() {
-    run_test(TestSetup { key: "test_write" }, async {
+    run_test_with_storages(TestSetup { key: "test_write" }, || async {
         let fs = get_fs().await;
 
         let test_file = SecretString::from_str("test-file").unwrap();
@@ -141,7 +164,7 @@ async fn test_write() {
         ));
         assert!(matches!(
             fs.write(0, 0, &buf, fh).await,
-            Err(FsError::InodeNotFound)
+            Err(FsError::InodeNotFound(_))
         ));
         let test_dir = SecretString::from_str("test-dir").unwrap();
         let (fh, dir_attr) = fs
@@ -167,7 +190,7 @@ async fn test_write() {
 #[allow(clippy::too_many_lines)]
 // #[ignore]
 async fn test_read() {
-    run_test(TestSetup { key: "test_read" }, async {
+    run_test_with_storages(TestSetup { key: "test_read" }, || async {
         let fs = get_fs().await;
 
         let test_test_file = SecretString::from_str("test-file").unwrap();
@@ -316,7 +339,7 @@ async fn test_read() {
         ));
         assert!(matches!(
             fs.read(0, 0, &mut buf, fh).await,
-            Err(FsError::InodeNotFound)
+            Err(FsError::InodeNotFound(_))
         ));
         let test_dir = SecretString::from_str("test-dir").unwrap();
         let (fh, dir_attr) = fs
@@ -342,11 +365,11 @@ async fn test_read() {
 #[allow(clippy::too_many_lines)]
 // #[ignore]
 async fn test_set_len() {
-    run_test(
+    run_test_with_storages(
         TestSetup {
             key: "test_set_len",
         },
-        async {
+        || async {
             let fs = get_fs().await;
 
             let test_file = SecretString::from_str("test-file").unwrap();
@@ -416,11 +439,11 @@ async fn test_set_len() {
 #[traced_test]
 #[allow(clippy::too_many_lines)]
 async fn test_copy_file_range() {
-    run_test(
+    run_test_with_storages(
         TestSetup {
             key: "test_copy_file_range",
         },
-        async {
+        || async {
             let fs = get_fs().await;
 
             let test_file_1 = SecretString::from_str("test-file-1").unwrap();
@@ -491,7 +514,7 @@ async fn test_copy_file_range() {
             // invalid inodes
             assert!(matches!(
                 fs.copy_file_range(0, 0, 0, 0, 0, fh, fh_2).await,
-                Err(FsError::InodeNotFound)
+                Err(FsError::InodeNotFound(_))
             ));
         },
     )
@@ -502,11 +525,11 @@ async fn test_copy_file_range() {
 #[traced_test]
 #[allow(clippy::too_many_lines)]
 async fn test_read_dir() {
-    run_test(
+    run_test_with_storages(
         TestSetup {
             key: "test_read_dir",
         },
-        async {
+        || async {
             let fs = get_fs().await;
 
             // file and directory in root
@@ -680,11 +703,11 @@ async fn test_read_dir() {
 #[traced_test]
 #[allow(clippy::too_many_lines)]
 async fn test_read_dir_plus() {
-    run_test(
+    run_test_with_storages(
         TestSetup {
             key: "test_read_dir_plus",
         },
-        async {
+        || async {
             let fs = get_fs().await;
 
             // file and directory in root
@@ -883,11 +906,11 @@ async fn test_read_dir_plus() {
 #[traced_test]
 #[allow(clippy::too_many_lines)]
 async fn test_find_by_name() {
-    run_test(
+    run_test_with_storages(
         TestSetup {
             key: "test_find_by_name",
         },
-        async {
+        || async {
             let fs = get_fs().await;
 
             let test_file = SecretString::from_str("test-file").unwrap();
@@ -921,11 +944,11 @@ async fn test_find_by_name() {
 #[traced_test]
 #[allow(clippy::too_many_lines)]
 async fn test_exists_by_name() {
-    run_test(
+    run_test_with_storages(
         TestSetup {
             key: "test_exists_by_name",
         },
-        async {
+        || async {
             let fs = get_fs().await;
 
             let test_file = SecretString::from_str("test-file").unwrap();
@@ -940,9 +963,10 @@ async fn test_exists_by_name() {
                 .await
                 .unwrap();
 
-            assert!(fs.exists_by_name(ROOT_INODE, &test_file).unwrap());
+            assert!(fs.exists_by_name(ROOT_INODE, &test_file).await.unwrap());
             assert!(
                 !(fs.exists_by_name(ROOT_INODE, &SecretString::from_str("42").unwrap())
+                    .await
                     .unwrap())
             );
         },
@@ -950,15 +974,91 @@ async fn test_exists_by_name() {
     .await;
 }
 
+#[tokio::test]
+#[traced_test]
+async fn test_exists_by_name_keyed_hash() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_exists_by_name_keyed_hash",
+        },
+        || async {
+            let fs = get_fs().await;
+            let key = fs.key.get().await.unwrap();
+            let hash_path = |name: &SecretString| {
+                EncryptedFs::contents_path(ROOT_INODE)
+                    .join(HASH_DIR)
+                    .join(crypto::hash_file_name(name, &key))
+            };
+
+            let file = SecretString::from_str("file").unwrap();
+            let (_, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            assert!(fs.exists_by_name(ROOT_INODE, &file).await.unwrap());
+            assert!(fs.storage.is_file(&hash_path(&file)));
+            assert!(matches!(
+                fs.create(
+                    ROOT_INODE,
+                    &file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await,
+                Err(FsError::AlreadyExists)
+            ));
+
+            let link = SecretString::from_str("link").unwrap();
+            fs.link(attr.ino, ROOT_INODE, &link).await.unwrap();
+            assert!(fs.exists_by_name(ROOT_INODE, &link).await.unwrap());
+            assert!(fs.storage.is_file(&hash_path(&link)));
+            assert!(matches!(
+                fs.link(attr.ino, ROOT_INODE, &file).await,
+                Err(FsError::AlreadyExists)
+            ));
+
+            let renamed = SecretString::from_str("renamed").unwrap();
+            fs.rename(ROOT_INODE, &file, ROOT_INODE, &renamed)
+                .await
+                .unwrap();
+            assert!(!fs.exists_by_name(ROOT_INODE, &file).await.unwrap());
+            assert!(fs.exists_by_name(ROOT_INODE, &renamed).await.unwrap());
+            assert!(!fs.storage.exists(&hash_path(&file)));
+            assert!(fs.storage.is_file(&hash_path(&renamed)));
+            assert!(matches!(
+                fs.rename(ROOT_INODE, &file, ROOT_INODE, &link).await,
+                Err(FsError::NotFound(_))
+            ));
+
+            fs.remove_file(ROOT_INODE, &link).await.unwrap();
+            assert!(!fs.exists_by_name(ROOT_INODE, &link).await.unwrap());
+            assert!(!fs.storage.exists(&hash_path(&link)));
+            assert!(matches!(
+                fs.remove_file(ROOT_INODE, &link).await,
+                Err(FsError::NotFound(_))
+            ));
+            assert!(fs.exists_by_name(ROOT_INODE, &renamed).await.unwrap());
+        },
+    )
+    .await;
+}
+
 #[tokio::test]
 #[traced_test]
 #[allow(clippy::too_many_lines)]
 async fn test_remove_dir() {
-    run_test(
+    run_test_with_storages(
         TestSetup {
             key: "test_remove_dir",
         },
-        async {
+        || async {
             let fs = get_fs().await;
 
             let test_dir = SecretString::from_str("test-dir").unwrap();
@@ -973,9 +1073,9 @@ async fn test_remove_dir() {
                 .await
                 .unwrap();
 
-            assert!(fs.exists_by_name(ROOT_INODE, &test_dir).unwrap());
+            assert!(fs.exists_by_name(ROOT_INODE, &test_dir).await.unwrap());
             fs.remove_dir(ROOT_INODE, &test_dir).await.unwrap();
-            assert!(!fs.exists_by_name(ROOT_INODE, &test_dir).unwrap());
+            assert!(!fs.exists_by_name(ROOT_INODE, &test_dir).await.unwrap());
             assert_eq!(None, fs.find_by_name(ROOT_INODE, &test_dir).await.unwrap());
             assert_eq!(
                 0,
@@ -996,11 +1096,11 @@ async fn test_remove_dir() {
 #[traced_test]
 #[allow(clippy::too_many_lines)]
 async fn test_remove_file() {
-    run_test(
+    run_test_with_storages(
         TestSetup {
             key: "test_remove_file",
         },
-        async {
+        || async {
             let fs = get_fs().await;
 
             let test_file = SecretString::from_str("test-file").unwrap();
@@ -1015,9 +1115,9 @@ async fn test_remove_file() {
                 .await
                 .unwrap();
 
-            assert!(fs.exists_by_name(ROOT_INODE, &test_file).unwrap());
+            assert!(fs.exists_by_name(ROOT_INODE, &test_file).await.unwrap());
             fs.remove_file(ROOT_INODE, &test_file).await.unwrap();
-            assert!(!fs.exists_by_name(ROOT_INODE, &test_file).unwrap());
+            assert!(!fs.exists_by_name(ROOT_INODE, &test_file).await.unwrap());
             assert_eq!(None, fs.find_by_name(ROOT_INODE, &test_file).await.unwrap());
             assert_eq!(
                 0,
@@ -1038,11 +1138,11 @@ async fn test_remove_file() {
 #[traced_test]
 #[allow(clippy::too_many_lines)]
 async fn test_find_by_name_exists_by_name100files() {
-    run_test(
+    run_test_with_storages(
         TestSetup {
             key: "test_find_by_name_exists_by_name_many_files",
         },
-        async {
+        || async {
             let fs = get_fs().await;
 
             for i in 0..100 {
@@ -1060,7 +1160,7 @@ async fn test_find_by_name_exists_by_name100files() {
             }
 
             let test_file = SecretString::from_str("test-file-42").unwrap();
-            assert!(fs.exists_by_name(ROOT_INODE, &test_file).unwrap());
+            assert!(fs.exists_by_name(ROOT_INODE, &test_file).await.unwrap());
             assert!(fs
                 .find_by_name(ROOT_INODE, &test_file)
                 .await
@@ -1075,28 +1175,28 @@ async fn test_find_by_name_exists_by_name100files() {
 #[traced_test]
 #[allow(clippy::too_many_lines)]
 async fn test_create_structure_and_root() {
-    run_test(TestSetup { key: "test_sample" }, async {
+    run_test_with_storages(TestSetup { key: "test_sample" }, || async {
         let fs = get_fs().await;
 
         assert!(fs.exists(ROOT_INODE));
         assert!(fs.is_dir(ROOT_INODE));
 
-        assert!(fs.data_dir.join(INODES_DIR).is_dir());
-        assert!(fs.data_dir.join(CONTENTS_DIR).is_dir());
-        assert!(fs.data_dir.join(SECURITY_DIR).is_dir());
+        assert!(fs.storage.is_dir(&Path::new(INODES_DIR)));
+        assert!(fs.storage.is_dir(&Path::new(CONTENTS_DIR)));
+        assert!(fs.storage.is_dir(&Path::new(SECURITY_DIR)));
         assert!(fs
-            .data_dir
-            .join(SECURITY_DIR)
-            .join(KEY_ENC_FILENAME)
-            .is_file());
+            .storage
+            .is_file(&Path::new(SECURITY_DIR).join(KEY_ENC_FILENAME)));
         assert!(fs
-            .data_dir
-            .join(SECURITY_DIR)
-            .join(KEY_SALT_FILENAME)
-            .is_file());
+            .storage
+            .is_file(&Path::new(SECURITY_DIR).join(KEY_SALT_FILENAME)));
 
-        assert!(fs.data_dir.join(INODES_DIR).join(ROOT_INODE_STR).is_file());
-        assert!(fs.data_dir.join(CONTENTS_DIR).join(ROOT_INODE_STR).is_dir());
+        assert!(fs
+            .storage
+            .is_file(&Path::new(INODES_DIR).join(ROOT_INODE_STR)));
+        assert!(fs
+            .storage
+            .is_dir(&Path::new(CONTENTS_DIR).join(ROOT_INODE_STR)));
     })
     .await;
 }
@@ -1105,7 +1205,7 @@ async fn test_create_structure_and_root() {
 #[traced_test]
 #[allow(clippy::too_many_lines)]
 async fn test_create() {
-    run_test(TestSetup { key: "test_create" }, async {
+    run_test_with_storages(TestSetup { key: "test_create" }, || async {
         let fs = get_fs().await;
 
         // file in root
@@ -1123,22 +1223,20 @@ async fn test_create() {
         assert_ne!(fh, 0);
         assert_ne!(attr.ino, 0);
         assert!(fs
-            .data_dir
-            .join(INODES_DIR)
-            .join(attr.ino.to_string())
-            .is_file());
-        assert!(fs
-            .data_dir
-            .join(CONTENTS_DIR)
-            .join(attr.ino.to_string())
-            .is_file());
+            .storage
+            .is_file(&Path::new(INODES_DIR).join(attr.ino.to_string())));
         assert!(fs
-            .data_dir
-            .join(CONTENTS_DIR)
-            .join(ROOT_INODE_STR)
-            .join(HASH_DIR)
-            .join(crypto::hash_file_name(&test_file))
-            .is_file());
+            .storage
+            .is_file(&Path::new(CONTENTS_DIR).join(attr.ino.to_string())));
+        assert!(fs.storage.is_file(
+            &Path::new(CONTENTS_DIR)
+                .join(ROOT_INODE_STR)
+                .join(HASH_DIR)
+                .join(crypto::hash_file_name(
+                    &test_file,
+                    &*fs.key.get().await.unwrap()
+                ))
+        ));
         assert!(fs.exists(attr.ino));
         assert_eq!(attr, fs.get_attr(attr.ino).await.unwrap());
         let mut entries: Vec<DirectoryEntryPlus> = fs
@@ -1149,7 +1247,7 @@ async fn test_create() {
             .collect();
         entries.sort_by(|a, b| a.name.expose_secret().cmp(b.name.expose_secret()));
         assert_eq!(attr, entries[1].attr);
-        assert!(fs.exists_by_name(ROOT_INODE, &test_file).unwrap());
+        assert!(fs.exists_by_name(ROOT_INODE, &test_file).await.unwrap());
         assert_eq!(
             attr,
             fs.find_by_name(ROOT_INODE, &test_file)
@@ -1172,22 +1270,20 @@ async fn test_create() {
             .unwrap();
         assert_ne!(attr.ino, 0);
         assert!(fs
-            .data_dir
-            .join(INODES_DIR)
-            .join(attr.ino.to_string())
-            .is_file());
+            .storage
+            .is_file(&Path::new(INODES_DIR).join(attr.ino.to_string())));
         assert!(fs
-            .data_dir
-            .join(CONTENTS_DIR)
-            .join(attr.ino.to_string())
-            .is_dir());
-        assert!(fs
-            .data_dir
-            .join(CONTENTS_DIR)
-            .join(ROOT_INODE_STR)
-            .join(HASH_DIR)
-            .join(crypto::hash_file_name(&test_dir))
-            .is_file());
+            .storage
+            .is_dir(&Path::new(CONTENTS_DIR).join(attr.ino.to_string())));
+        assert!(fs.storage.is_file(
+            &Path::new(CONTENTS_DIR)
+                .join(ROOT_INODE_STR)
+                .join(HASH_DIR)
+                .join(crypto::hash_file_name(
+                    &test_dir,
+                    &*fs.key.get().await.unwrap()
+                ))
+        ));
         assert!(fs.exists(attr.ino));
         assert_eq!(attr, fs.get_attr(attr.ino).await.unwrap());
         assert!(fs.is_dir(attr.ino));
@@ -1200,7 +1296,7 @@ async fn test_create() {
         entries.sort_by(|a, b| a.name.expose_secret().cmp(b.name.expose_secret()));
         assert_eq!(ROOT_INODE, entries[0].attr.ino);
         assert_eq!(attr, entries[1].attr);
-        assert!(fs.exists_by_name(ROOT_INODE, &test_dir).unwrap());
+        assert!(fs.exists_by_name(ROOT_INODE, &test_dir).await.unwrap());
         assert_eq!(
             attr,
             fs.find_by_name(ROOT_INODE, &test_dir)
@@ -1223,22 +1319,20 @@ async fn test_create() {
             .await
             .unwrap();
         assert!(fs
-            .data_dir
-            .join(INODES_DIR)
-            .join(attr.ino.to_string())
-            .is_file());
-        assert!(fs
-            .data_dir
-            .join(CONTENTS_DIR)
-            .join(attr.ino.to_string())
-            .is_dir());
+            .storage
+            .is_file(&Path::new(INODES_DIR).join(attr.ino.to_string())));
         assert!(fs
-            .data_dir
-            .join(CONTENTS_DIR)
-            .join(parent.to_string())
-            .join(HASH_DIR)
-            .join(crypto::hash_file_name(&test_dir_2))
-            .is_file());
+            .storage
+            .is_dir(&Path::new(CONTENTS_DIR).join(attr.ino.to_string())));
+        assert!(fs.storage.is_file(
+            &Path::new(CONTENTS_DIR)
+                .join(parent.to_string())
+                .join(HASH_DIR)
+                .join(crypto::hash_file_name(
+                    &test_dir_2,
+                    &*fs.key.get().await.unwrap()
+                ))
+        ));
         assert!(fs.exists(attr.ino));
         assert_eq!(attr, fs.get_attr(attr.ino).await.unwrap());
         assert!(fs.is_dir(attr.ino));
@@ -1251,7 +1345,7 @@ async fn test_create() {
         entries.sort_by(|a, b| a.name.expose_secret().cmp(b.name.expose_secret()));
         assert_eq!(attr, entries[2].attr);
         assert_eq!(parent, entries[0].attr.ino);
-        assert!(fs.exists_by_name(parent, &test_dir_2).unwrap());
+        assert!(fs.exists_by_name(parent, &test_dir_2).await.unwrap());
         assert_eq!(
             attr,
             fs.find_by_name(parent, &test_dir_2).await.unwrap().unwrap()
@@ -1290,7 +1384,7 @@ async fn test_create() {
 #[traced_test]
 #[allow(clippy::too_many_lines)]
 async fn test_rename() {
-    run_test(TestSetup { key: "test_rename" }, async {
+    run_test_with_storages(TestSetup { key: "test_rename" }, || async {
         let fs = get_fs().await;
 
         // new file in same directory
@@ -1310,8 +1404,8 @@ async fn test_rename() {
         fs.rename(ROOT_INODE, &file_1, new_parent, &file_1_new)
             .await
             .unwrap();
-        assert!(!fs.exists_by_name(ROOT_INODE, &file_1).unwrap());
-        assert!(fs.exists_by_name(new_parent, &file_1_new).unwrap());
+        assert!(!fs.exists_by_name(ROOT_INODE, &file_1).await.unwrap());
+        assert!(fs.exists_by_name(new_parent, &file_1_new).await.unwrap());
         let new_attr = fs
             .find_by_name(new_parent, &file_1_new)
             .await
@@ -1357,8 +1451,8 @@ async fn test_rename() {
         fs.rename(ROOT_INODE, &dir_1, new_parent, &dir_1_new)
             .await
             .unwrap();
-        assert!(!fs.exists_by_name(ROOT_INODE, &dir_1).unwrap());
-        assert!(fs.exists_by_name(new_parent, &dir_1_new).unwrap());
+        assert!(!fs.exists_by_name(ROOT_INODE, &dir_1).await.unwrap());
+        assert!(fs.exists_by_name(new_parent, &dir_1_new).await.unwrap());
         let new_attr = fs
             .find_by_name(new_parent, &dir_1_new)
             .await
@@ -1447,8 +1541,8 @@ async fn test_rename() {
         fs.rename(ROOT_INODE, &file_1, new_parent, &file_2)
             .await
             .unwrap();
-        assert!(!fs.exists_by_name(ROOT_INODE, &file_1).unwrap());
-        assert!(fs.exists_by_name(new_parent, &file_2).unwrap());
+        assert!(!fs.exists_by_name(ROOT_INODE, &file_1).await.unwrap());
+        assert!(fs.exists_by_name(new_parent, &file_2).await.unwrap());
         let new_attr = fs.find_by_name(new_parent, &file_2).await.unwrap().unwrap();
         assert!(fs.is_file(new_attr.ino));
         assert_eq!(new_attr.ino, attr.ino);
@@ -1501,8 +1595,8 @@ async fn test_rename() {
         fs.rename(ROOT_INODE, &dir_1, new_parent, &dir_2)
             .await
             .unwrap();
-        assert!(!fs.exists_by_name(ROOT_INODE, &dir_1).unwrap());
-        assert!(fs.exists_by_name(new_parent, &dir_2).unwrap());
+        assert!(!fs.exists_by_name(ROOT_INODE, &dir_1).await.unwrap());
+        assert!(fs.exists_by_name(new_parent, &dir_2).await.unwrap());
         let new_attr = fs.find_by_name(new_parent, &dir_2).await.unwrap().unwrap();
         assert!(fs.is_dir(new_attr.ino));
         assert_eq!(new_attr.ino, attr.ino);
@@ -1597,8 +1691,8 @@ async fn test_rename() {
         fs.rename(ROOT_INODE, &file_1, new_parent, &file_2)
             .await
             .unwrap();
-        assert!(!fs.exists_by_name(ROOT_INODE, &file_1).unwrap());
-        assert!(fs.exists_by_name(new_parent, &file_2).unwrap());
+        assert!(!fs.exists_by_name(ROOT_INODE, &file_1).await.unwrap());
+        assert!(fs.exists_by_name(new_parent, &file_2).await.unwrap());
         let new_attr = fs.find_by_name(new_parent, &file_2).await.unwrap().unwrap();
         assert!(fs.is_file(new_attr.ino));
         assert_eq!(new_attr.ino, attr.ino);
@@ -1649,8 +1743,8 @@ async fn test_rename() {
         fs.rename(ROOT_INODE, &dir_1, new_parent, &dir_2)
             .await
             .unwrap();
-        assert!(!fs.exists_by_name(ROOT_INODE, &dir_1).unwrap());
-        assert!(fs.exists_by_name(new_parent, &dir_2).unwrap());
+        assert!(!fs.exists_by_name(ROOT_INODE, &dir_1).await.unwrap());
+        assert!(fs.exists_by_name(new_parent, &dir_2).await.unwrap());
         let new_attr = fs.find_by_name(new_parent, &dir_2).await.unwrap().unwrap();
         assert!(fs.is_dir(new_attr.ino));
         assert_eq!(new_attr.ino, attr.ino);
@@ -1733,8 +1827,8 @@ async fn test_rename() {
         fs.rename(ROOT_INODE, &file_1, new_parent, &file_1)
             .await
             .unwrap();
-        assert!(!fs.exists_by_name(ROOT_INODE, &file_1).unwrap());
-        assert!(fs.exists_by_name(new_parent, &file_1).unwrap());
+        assert!(!fs.exists_by_name(ROOT_INODE, &file_1).await.unwrap());
+        assert!(fs.exists_by_name(new_parent, &file_1).await.unwrap());
         let new_attr = fs.find_by_name(new_parent, &file_1).await.unwrap().unwrap();
         assert!(fs.is_file(new_attr.ino));
         assert_eq!(new_attr.ino, attr.ino);
@@ -1785,8 +1879,8 @@ async fn test_rename() {
         fs.rename(ROOT_INODE, &dir_1, new_parent, &dir_1)
             .await
             .unwrap();
-        assert!(!fs.exists_by_name(ROOT_INODE, &dir_1).unwrap());
-        assert!(fs.exists_by_name(new_parent, &dir_1).unwrap());
+        assert!(!fs.exists_by_name(ROOT_INODE, &dir_1).await.unwrap());
+        assert!(fs.exists_by_name(new_parent, &dir_1).await.unwrap());
         let new_attr = fs.find_by_name(new_parent, &dir_1).await.unwrap().unwrap();
         assert!(fs.is_dir(new_attr.ino));
         assert_eq!(new_attr.ino, attr.ino);
@@ -1844,7 +1938,7 @@ async fn test_rename() {
             1
         );
 
-        // overwriting directory with file
+        // overwriting directory with file is not allowed, the directory has to be removed first
         let new_parent = ROOT_INODE;
         let (_, attr) = fs
             .create(
@@ -1856,7 +1950,7 @@ async fn test_rename() {
             )
             .await
             .unwrap();
-        let (_, _attr_2) = fs
+        let (_, attr_2) = fs
             .create(
                 new_parent,
                 &dir_1,
@@ -1866,11 +1960,24 @@ async fn test_rename() {
             )
             .await
             .unwrap();
+        assert!(matches!(
+            fs.rename(ROOT_INODE, &file_1, new_parent, &dir_1).await,
+            Err(FsError::IsADirectory)
+        ));
+        assert_eq!(
+            attr_2.ino,
+            fs.find_by_name(new_parent, &dir_1)
+                .await
+                .unwrap()
+                .unwrap()
+                .ino
+        );
+        fs.remove_dir(new_parent, &dir_1).await.unwrap();
         fs.rename(ROOT_INODE, &file_1, new_parent, &dir_1)
             .await
             .unwrap();
-        assert!(!fs.exists_by_name(ROOT_INODE, &file_1).unwrap());
-        assert!(fs.exists_by_name(new_parent, &dir_1).unwrap());
+        assert!(!fs.exists_by_name(ROOT_INODE, &file_1).await.unwrap());
+        assert!(fs.exists_by_name(new_parent, &dir_1).await.unwrap());
         let new_attr = fs.find_by_name(new_parent, &dir_1).await.unwrap().unwrap();
         assert!(fs.is_file(new_attr.ino));
         assert_eq!(new_attr.ino, attr.ino);
@@ -1922,8 +2029,8 @@ async fn test_rename() {
         fs.rename(ROOT_INODE, &dir_3, new_parent, &file_1)
             .await
             .unwrap();
-        assert!(!fs.exists_by_name(ROOT_INODE, &dir_3).unwrap());
-        assert!(fs.exists_by_name(new_parent, &file_1).unwrap());
+        assert!(!fs.exists_by_name(ROOT_INODE, &dir_3).await.unwrap());
+        assert!(fs.exists_by_name(new_parent, &file_1).await.unwrap());
         let new_attr = fs.find_by_name(new_parent, &file_1).await.unwrap().unwrap();
         assert!(fs.is_dir(new_attr.ino));
         assert_eq!(new_attr.ino, attr.ino);
@@ -1997,10 +2104,10 @@ async fn test_rename() {
         let name_2 = dir_new_parent;
         assert!(matches!(
             fs.rename(ROOT_INODE, &dir_3, new_parent, &name_2).await,
-            Err(FsError::NotEmpty)
+            Err(FsError::NotEmpty(_))
         ));
-        assert!(fs.exists_by_name(ROOT_INODE, &dir_3).unwrap());
-        assert!(fs.exists_by_name(new_parent, &name_2).unwrap());
+        assert!(fs.exists_by_name(ROOT_INODE, &dir_3).await.unwrap());
+        assert!(fs.exists_by_name(new_parent, &name_2).await.unwrap());
         let attr_3 = fs.find_by_name(ROOT_INODE, &dir_3).await.unwrap().unwrap();
         assert!(fs.is_dir(attr_3.ino));
         let attr_2 = fs.find_by_name(new_parent, &name_2).await.unwrap().unwrap();
@@ -2080,7 +2187,7 @@ async fn test_rename() {
         fs.rename(ROOT_INODE, &file_3, new_parent, &file_3)
             .await
             .unwrap();
-        assert!(fs.exists_by_name(new_parent, &file_3).unwrap());
+        assert!(fs.exists_by_name(new_parent, &file_3).await.unwrap());
         let new_attr = fs.find_by_name(new_parent, &file_3).await.unwrap().unwrap();
         assert!(fs.is_file(new_attr.ino));
         assert_eq!(new_attr.ino, attr.ino);
@@ -2112,7 +2219,7 @@ async fn test_rename() {
         fs.rename(ROOT_INODE, &dir_5, new_parent, &dir_5)
             .await
             .unwrap();
-        assert!(fs.exists_by_name(new_parent, &dir_5).unwrap());
+        assert!(fs.exists_by_name(new_parent, &dir_5).await.unwrap());
         let new_attr = fs.find_by_name(new_parent, &dir_5).await.unwrap().unwrap();
         assert!(fs.is_dir(new_attr.ino));
         assert_eq!(new_attr.ino, attr.ino);
@@ -2164,7 +2271,7 @@ async fn test_rename() {
         let invalid = SecretString::from_str("invalid").unwrap();
         assert!(matches!(
             fs.rename(0, &invalid, 0, &invalid).await,
-            Err(FsError::InodeNotFound)
+            Err(FsError::InodeNotFound(_))
         ));
         let existing_file = SecretString::from_str("existing-file").unwrap();
         let (_, attr_file) = fs
@@ -2187,7 +2294,7 @@ async fn test_rename() {
         ));
         assert!(matches!(
             fs.rename(ROOT_INODE, &existing_file, 0, &invalid).await,
-            Err(FsError::InodeNotFound)
+            Err(FsError::InodeNotFound(_))
         ));
         assert!(matches!(
             fs.rename(ROOT_INODE, &existing_file, attr_file.ino, &invalid)
@@ -2201,7 +2308,7 @@ async fn test_rename() {
 #[tokio::test]
 #[traced_test]
 async fn test_open() {
-    run_test(TestSetup { key: "test_open" }, async {
+    run_test_with_storages(TestSetup { key: "test_open" }, || async {
         let fs = get_fs().await;
 
         let test_file = SecretString::from_str("test-file").unwrap();
@@ -2232,12 +2339,6651 @@ async fn test_open() {
     .await;
 }
 
+#[tokio::test]
+#[traced_test]
+async fn test_open_with() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_open_with",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_string_to_fs(&fs, attr.ino, 0, "test-", fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+
+            for flags in [
+                OpenFlags::default(),
+                OpenFlags::default().with_read(true).with_append(true),
+                OpenFlags::default().with_read(true).with_truncate(true),
+            ] {
+                assert!(matches!(
+                    fs.open_with(attr.ino, flags).await,
+                    Err(FsError::InvalidInput(_))
+                ));
+            }
+
+            // append, the offset is ignored
+            let fh = fs
+                .open_with(
+                    attr.ino,
+                    OpenFlags::default().with_write(true).with_append(true),
+                )
+                .await
+                .unwrap();
+            write_all_string_to_fs(&fs, attr.ino, 0, "42", fh)
+                .await
+                .unwrap();
+            write_all_string_to_fs(&fs, attr.ino, 1, "!", fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            assert_eq!("test-42!", test_common::read_to_string(attr.ino, &fs).await);
+
+            // truncate
+            let fh = fs
+                .open_with(
+                    attr.ino,
+                    OpenFlags::default()
+                        .with_read(true)
+                        .with_write(true)
+                        .with_truncate(true),
+                )
+                .await
+                .unwrap();
+            assert_eq!(0, fs.get_attr(attr.ino).await.unwrap().size);
+            write_all_string_to_fs(&fs, attr.ino, 0, "new", fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            assert_eq!("new", test_common::read_to_string(attr.ino, &fs).await);
+
+            // only one of concurrent truncating opens succeeds
+            let flags = OpenFlags::default().with_write(true).with_truncate(true);
+            let (res1, res2) =
+                tokio::join!(fs.open_with(attr.ino, flags), fs.open_with(attr.ino, flags));
+            let fh = match (res1, res2) {
+                (Ok(fh), Err(FsError::AlreadyOpenForWrite))
+                | (Err(FsError::AlreadyOpenForWrite), Ok(fh)) => fh,
+                res => panic!("{res:?}"),
+            };
+            write_all_string_to_fs(&fs, attr.ino, 0, "kept", fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            // a failed truncating open leaves the content as it is
+            assert!(matches!(
+                fs.open_with(attr.ino, flags).await,
+                Err(FsError::AlreadyOpenForWrite)
+            ));
+            fs.release(fh).await.unwrap();
+            assert_eq!("kept", test_common::read_to_string(attr.ino, &fs).await);
+        },
+    )
+    .await;
+}
+
 // #[tokio::test]
 // #[traced_test]
 #[allow(clippy::too_many_lines)]
 async fn _test_sample() {
-    run_test(TestSetup { key: "test_sample" }, async {
+    run_test_with_storages(TestSetup { key: "test_sample" }, || async {
         let _ = get_fs().await;
     })
     .await;
 }
+
+struct TestPasswordProvider(&'static str);
+impl PasswordProvider for TestPasswordProvider {
+    fn get_password(&self) -> Option<SecretString> {
+        Some(SecretString::from_str(self.0).unwrap())
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_different_keys_cannot_read_each_other() {
+    let data_dir_1 = TESTS_DATA_DIR.join("test_different_keys_1");
+    let data_dir_2 = TESTS_DATA_DIR.join("test_different_keys_2");
+    let _ = fs::remove_dir_all(&data_dir_1);
+    let _ = fs::remove_dir_all(&data_dir_2);
+
+    let fs_1 = EncryptedFs::new(
+        data_dir_1.clone(),
+        Box::new(TestPasswordProvider("password-1")),
+        Cipher::ChaCha20Poly1305,
+    )
+    .await
+    .unwrap();
+    let fs_2 = EncryptedFs::new(
+        data_dir_2.clone(),
+        Box::new(TestPasswordProvider("password-2")),
+        Cipher::ChaCha20Poly1305,
+    )
+    .await
+    .unwrap();
+
+    let test_file = SecretString::from_str("test-file").unwrap();
+    let (_, attr) = fs_1
+        .create(
+            ROOT_INODE,
+            &test_file,
+            create_attr(FileType::RegularFile),
+            false,
+            false,
+        )
+        .await
+        .unwrap();
+
+    // move the inode from the first store into the second one, it should not decrypt
+    fs::copy(
+        data_dir_1.join(INODES_DIR).join(attr.ino.to_string()),
+        data_dir_2.join(INODES_DIR).join(attr.ino.to_string()),
+    )
+    .unwrap();
+    assert!(fs_2.get_attr(attr.ino).await.is_err());
+    assert!(fs_1.get_attr(attr.ino).await.is_ok());
+    drop(fs_1);
+    drop(fs_2);
+
+    // opening with the key of the other store should fail
+    assert!(matches!(
+        EncryptedFs::new(
+            data_dir_1.clone(),
+            Box::new(TestPasswordProvider("password-2")),
+            Cipher::ChaCha20Poly1305,
+        )
+        .await,
+        Err(FsError::InvalidPassword)
+    ));
+
+    fs::remove_dir_all(data_dir_1).unwrap();
+    fs::remove_dir_all(data_dir_2).unwrap();
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_key_derived_from_password_and_stored_salt() {
+    let data_dir = TESTS_DATA_DIR.join("test_key_derived_from_password_and_stored_salt");
+    let _ = fs::remove_dir_all(&data_dir);
+    let salt_path = data_dir.join(SECURITY_DIR).join(KEY_SALT_FILENAME);
+
+    let fs = EncryptedFs::new(
+        data_dir.clone(),
+        Box::new(TestPasswordProvider("password")),
+        Cipher::ChaCha20Poly1305,
+    )
+    .await
+    .unwrap();
+    let test_file = SecretString::from_str("test-file").unwrap();
+    let (fh, attr) = fs
+        .create(
+            ROOT_INODE,
+            &test_file,
+            create_attr(FileType::RegularFile),
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+    write_all_bytes_to_fs(&fs, attr.ino, 0, b"test-42", fh)
+        .await
+        .unwrap();
+    fs.flush(fh).await.unwrap();
+    fs.release(fh).await.unwrap();
+    drop(fs);
+    let salt = fs::read(&salt_path).unwrap();
+
+    // wrong password
+    assert!(matches!(
+        EncryptedFs::new(
+            data_dir.clone(),
+            Box::new(TestPasswordProvider("wrong-password")),
+            Cipher::ChaCha20Poly1305,
+        )
+        .await,
+        Err(FsError::InvalidPassword)
+    ));
+
+    // salt is reused and the key is derived again on reopen
+    let fs = EncryptedFs::new(
+        data_dir.clone(),
+        Box::new(TestPasswordProvider("password")),
+        Cipher::ChaCha20Poly1305,
+    )
+    .await
+    .unwrap();
+    assert_eq!(salt, fs::read(&salt_path).unwrap());
+    assert_eq!("test-42", test_common::read_to_string(attr.ino, &fs).await);
+    drop(fs);
+
+    fs::remove_dir_all(data_dir).unwrap();
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_key_len_validated() {
+    let data_dir = TESTS_DATA_DIR.join("test_key_len_validated");
+    let _ = fs::remove_dir_all(&data_dir);
+    let cipher = Cipher::Aes256Gcm;
+    assert_eq!(32, cipher.key_len());
+
+    // the generated key has the right length
+    let fs = EncryptedFs::new(
+        data_dir.clone(),
+        Box::new(TestPasswordProvider("password")),
+        cipher,
+    )
+    .await
+    .unwrap();
+    drop(fs);
+
+    // replace it with a too short one, encrypted with the same password
+    let salt: Vec<u8> = bincode::deserialize_from(
+        fs::File::open(data_dir.join(SECURITY_DIR).join(KEY_SALT_FILENAME)).unwrap(),
+    )
+    .unwrap();
+    let derived_key =
+        crypto::derive_key(&SecretString::from_str("password").unwrap(), cipher, &salt).unwrap();
+    let mut writer = crypto::create_write(
+        fs::File::create(data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME)).unwrap(),
+        cipher,
+        &derived_key,
+    );
+    bincode::serialize_into(&mut writer, &vec![0_u8; 16]).unwrap();
+    writer.finish().unwrap();
+
+    assert!(matches!(
+        EncryptedFs::new(
+            data_dir.clone(),
+            Box::new(TestPasswordProvider("password")),
+            cipher,
+        )
+        .await,
+        Err(FsError::InvalidInput(_))
+    ));
+
+    fs::remove_dir_all(data_dir).unwrap();
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_data_dir_not_a_dir() {
+    let data_dir = TESTS_DATA_DIR.join("test_data_dir_not_a_dir");
+    let _ = fs::remove_dir_all(&data_dir);
+    let _ = fs::remove_file(&data_dir);
+    fs::create_dir_all(TESTS_DATA_DIR.as_path()).unwrap();
+
+    let new_fs = || {
+        EncryptedFs::new(
+            data_dir.clone(),
+            Box::new(TestPasswordProvider("password")),
+            Cipher::ChaCha20Poly1305,
+        )
+    };
+
+    // data dir is a file
+    fs::write(&data_dir, b"not a dir").unwrap();
+    assert!(matches!(
+        new_fs().await,
+        Err(FsError::InvalidDataDirStructure)
+    ));
+    assert_eq!(b"not a dir", fs::read(&data_dir).unwrap().as_slice());
+    fs::remove_file(&data_dir).unwrap();
+
+    // one of its directories is a file
+    drop(new_fs().await.unwrap());
+    // keep it outside, so the data dir has the same entries
+    let backup = TESTS_DATA_DIR.join("test_data_dir_not_a_dir_backup");
+    let _ = fs::remove_dir_all(&backup);
+    for dir in [INODES_DIR, CONTENTS_DIR] {
+        let path = data_dir.join(dir);
+        fs::rename(&path, &backup).unwrap();
+        fs::write(&path, b"not a dir").unwrap();
+        assert!(matches!(
+            new_fs().await,
+            Err(FsError::InvalidDataDirStructure)
+        ));
+        fs::remove_file(&path).unwrap();
+        fs::rename(&backup, &path).unwrap();
+    }
+    drop(new_fs().await.unwrap());
+
+    fs::remove_dir_all(data_dir).unwrap();
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_data_dir_lock() {
+    let data_dir = TESTS_DATA_DIR.join("test_data_dir_lock");
+    let _ = fs::remove_dir_all(&data_dir);
+    let open = |read_only: bool| {
+        let data_dir = data_dir.clone();
+        async move {
+            let password_provider = Box::new(TestPasswordProvider("password"));
+            if read_only {
+                EncryptedFs::new_read_only(data_dir, password_provider, Cipher::ChaCha20Poly1305)
+                    .await
+            } else {
+                EncryptedFs::new(data_dir, password_provider, Cipher::ChaCha20Poly1305).await
+            }
+        }
+    };
+
+    // read-only needs an existing data dir
+    assert!(matches!(
+        open(true).await,
+        Err(FsError::InvalidDataDirStructure)
+    ));
+
+    let fs = open(false).await.unwrap();
+    assert!(!fs.is_read_only());
+    assert!(matches!(open(false).await, Err(FsError::DataDirInUse)));
+    assert!(matches!(open(true).await, Err(FsError::DataDirInUse)));
+    let file = SecretString::from_str("file").unwrap();
+    let (fh, attr) = fs
+        .create(
+            ROOT_INODE,
+            &file,
+            create_attr(FileType::RegularFile),
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+    write_all_bytes_to_fs(&fs, attr.ino, 0, b"test-42", fh)
+        .await
+        .unwrap();
+    fs.release(fh).await.unwrap();
+    drop(fs);
+
+    // many readers, but no writer
+    let fs = open(true).await.unwrap();
+    let fs2 = open(true).await.unwrap();
+    assert!(fs.is_read_only());
+    assert!(matches!(open(false).await, Err(FsError::DataDirInUse)));
+    assert_eq!("test-42", test_common::read_to_string(attr.ino, &fs).await);
+    assert_eq!(AtimePolicy::Noatime, fs.atime_policy());
+    assert_eq!(
+        fs.read_dir(ROOT_INODE)
+            .await
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().name.expose_secret() == file.expose_secret())
+            .count(),
+        1
+    );
+    assert!(matches!(
+        fs.create(
+            ROOT_INODE,
+            &SecretString::from_str("file-2").unwrap(),
+            create_attr(FileType::RegularFile),
+            false,
+            false,
+        )
+        .await,
+        Err(FsError::ReadOnly)
+    ));
+    assert!(matches!(
+        fs.open(attr.ino, false, true).await,
+        Err(FsError::ReadOnly)
+    ));
+    assert!(matches!(
+        fs.set_attr(attr.ino, SetFileAttr::default().with_perm(0o600))
+            .await,
+        Err(FsError::ReadOnly)
+    ));
+    assert!(matches!(
+        fs.remove_file(ROOT_INODE, &file).await,
+        Err(FsError::ReadOnly)
+    ));
+    assert!(fs2.exists_by_name(ROOT_INODE, &file).await.unwrap());
+    drop(fs);
+    drop(fs2);
+
+    drop(open(false).await.unwrap());
+
+    fs::remove_dir_all(data_dir).unwrap();
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_memory_storage_reopen() {
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+    let open = |read_only: bool| {
+        EncryptedFs::new_with_storage(
+            storage.clone(),
+            Box::new(TestPasswordProvider("password")),
+            Cipher::ChaCha20Poly1305,
+            FsOptions::default().with_read_only(read_only),
+        )
+    };
+
+    let fs = open(false).await.unwrap();
+    assert!(matches!(open(false).await, Err(FsError::DataDirInUse)));
+    assert!(matches!(open(true).await, Err(FsError::DataDirInUse)));
+    let file = SecretString::from_str("file").unwrap();
+    let (fh, attr) = fs
+        .create(
+            ROOT_INODE,
+            &file,
+            create_attr(FileType::RegularFile),
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+    write_all_bytes_to_fs(&fs, attr.ino, 0, b"test-42", fh)
+        .await
+        .unwrap();
+    fs.release(fh).await.unwrap();
+    drop(fs);
+
+    // the files are kept in the storage, not in the instance
+    let fs = open(true).await.unwrap();
+    assert!(fs.exists_by_name(ROOT_INODE, &file).await.unwrap());
+    assert_eq!("test-42", test_common::read_to_string(attr.ino, &fs).await);
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_passwd() {
+    let data_dir = TESTS_DATA_DIR.join("test_passwd");
+    let _ = fs::remove_dir_all(&data_dir);
+
+    let fs = EncryptedFs::new(
+        data_dir.clone(),
+        Box::new(TestPasswordProvider("old-password")),
+        Cipher::ChaCha20Poly1305,
+    )
+    .await
+    .unwrap();
+    let test_file = SecretString::from_str("test-file").unwrap();
+    let (fh, attr) = fs
+        .create(
+            ROOT_INODE,
+            &test_file,
+            create_attr(FileType::RegularFile),
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+    write_all_bytes_to_fs(&fs, attr.ino, 0, b"test-42", fh)
+        .await
+        .unwrap();
+    fs.flush(fh).await.unwrap();
+    fs.release(fh).await.unwrap();
+    drop(fs);
+
+    // wrong old password
+    assert!(matches!(
+        EncryptedFs::passwd(
+            &data_dir,
+            SecretString::from_str("wrong-password").unwrap(),
+            SecretString::from_str("new-password").unwrap(),
+            Cipher::ChaCha20Poly1305,
+        )
+        .await,
+        Err(FsError::InvalidPassword)
+    ));
+
+    EncryptedFs::passwd(
+        &data_dir,
+        SecretString::from_str("old-password").unwrap(),
+        SecretString::from_str("new-password").unwrap(),
+        Cipher::ChaCha20Poly1305,
+    )
+    .await
+    .unwrap();
+
+    // old password is not valid anymore
+    assert!(matches!(
+        EncryptedFs::new(
+            data_dir.clone(),
+            Box::new(TestPasswordProvider("old-password")),
+            Cipher::ChaCha20Poly1305,
+        )
+        .await,
+        Err(FsError::InvalidPassword)
+    ));
+
+    // content is still readable with the new password
+    let fs = EncryptedFs::new(
+        data_dir.clone(),
+        Box::new(TestPasswordProvider("new-password")),
+        Cipher::ChaCha20Poly1305,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        attr.ino,
+        fs.find_by_name(ROOT_INODE, &test_file)
+            .await
+            .unwrap()
+            .unwrap()
+            .ino
+    );
+    assert_eq!("test-42", test_common::read_to_string(attr.ino, &fs).await);
+    drop(fs);
+
+    fs::remove_dir_all(data_dir).unwrap();
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_sequential_inodes() {
+    let data_dir = TESTS_DATA_DIR.join("test_sequential_inodes");
+    let _ = fs::remove_dir_all(&data_dir);
+
+    let fs = EncryptedFs::new(
+        data_dir.clone(),
+        Box::new(TestPasswordProvider("password")),
+        Cipher::ChaCha20Poly1305,
+    )
+    .await
+    .unwrap();
+    for i in 0..3 {
+        let (_, attr) = fs
+            .create(
+                ROOT_INODE,
+                &SecretString::new(format!("test-file-{i}")),
+                create_attr(FileType::RegularFile),
+                false,
+                false,
+            )
+            .await
+            .unwrap();
+        assert_eq!(ROOT_INODE + 1 + i, attr.ino);
+    }
+    drop(fs);
+
+    // counter survives remount
+    let fs = EncryptedFs::new(
+        data_dir.clone(),
+        Box::new(TestPasswordProvider("password")),
+        Cipher::ChaCha20Poly1305,
+    )
+    .await
+    .unwrap();
+    let (_, attr) = fs
+        .create(
+            ROOT_INODE,
+            &SecretString::from_str("test-dir").unwrap(),
+            create_attr(FileType::Directory),
+            false,
+            false,
+        )
+        .await
+        .unwrap();
+    assert_eq!(ROOT_INODE + 4, attr.ino);
+    drop(fs);
+
+    // without a persisted counter we continue after the existing inodes
+    fs::remove_file(data_dir.join(SECURITY_DIR).join(INODE_COUNTER_FILENAME)).unwrap();
+    let fs = EncryptedFs::new(
+        data_dir.clone(),
+        Box::new(TestPasswordProvider("password")),
+        Cipher::ChaCha20Poly1305,
+    )
+    .await
+    .unwrap();
+    let (_, attr) = fs
+        .create(
+            ROOT_INODE,
+            &SecretString::from_str("test-file").unwrap(),
+            create_attr(FileType::RegularFile),
+            false,
+            false,
+        )
+        .await
+        .unwrap();
+    assert_eq!(ROOT_INODE + 5, attr.ino);
+    drop(fs);
+
+    fs::remove_dir_all(data_dir).unwrap();
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_set_len_non_zero() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_set_len_non_zero",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            // shrink
+            let data: Vec<u8> = b"0123456789"
+                .iter()
+                .copied()
+                .cycle()
+                .take(10 * 1024)
+                .collect();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file-shrink").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, &data, fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            fs.set_len(attr.ino, 3 * 1024).await.unwrap();
+            assert_eq!(3 * 1024, fs.get_attr(attr.ino).await.unwrap().size);
+            let mut buf = vec![0; 10 * 1024];
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            let mut read = 0;
+            loop {
+                let len = fs
+                    .read(attr.ino, read as u64, &mut buf[read..], fh)
+                    .await
+                    .unwrap();
+                if len == 0 {
+                    break;
+                }
+                read += len;
+            }
+            fs.release(fh).await.unwrap();
+            assert_eq!(3 * 1024, read);
+            assert_eq!(&data[..3 * 1024], &buf[..read]);
+
+            // grow
+            let data: Vec<u8> = b"0123456789"
+                .iter()
+                .copied()
+                .cycle()
+                .take(2 * 1024)
+                .collect();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file-grow").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, &data, fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            fs.set_len(attr.ino, 5 * 1024).await.unwrap();
+            assert_eq!(5 * 1024, fs.get_attr(attr.ino).await.unwrap().size);
+            let mut buf = vec![1; 5 * 1024];
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            test_common::read_exact(&fs, attr.ino, 0, &mut buf, fh).await;
+            fs.release(fh).await.unwrap();
+            assert_eq!(&data[..], &buf[..2 * 1024]);
+            assert!(buf[2 * 1024..].iter().all(|b| *b == 0));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_symlink() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_symlink",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            // absolute target
+            let link_abs = SecretString::from_str("link-abs").unwrap();
+            let target_abs = SecretString::from_str("/tmp/some/target").unwrap();
+            let attr = fs
+                .create_symlink(
+                    ROOT_INODE,
+                    &link_abs,
+                    &target_abs,
+                    create_attr(FileType::Symlink),
+                )
+                .await
+                .unwrap();
+            assert_eq!(FileType::Symlink, attr.kind);
+            assert_eq!(target_abs.expose_secret().len() as u64, attr.size);
+            assert_eq!(
+                target_abs.expose_secret(),
+                fs.read_link(attr.ino).await.unwrap().expose_secret()
+            );
+            assert_eq!(FileType::Symlink, fs.get_attr(attr.ino).await.unwrap().kind);
+            assert_eq!(
+                attr.ino,
+                fs.find_by_name(ROOT_INODE, &link_abs)
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino
+            );
+
+            // relative target
+            let link_rel = SecretString::from_str("link-rel").unwrap();
+            let target_rel = SecretString::from_str("../some/target").unwrap();
+            let attr = fs
+                .create_symlink(
+                    ROOT_INODE,
+                    &link_rel,
+                    &target_rel,
+                    create_attr(FileType::RegularFile),
+                )
+                .await
+                .unwrap();
+            assert_eq!(FileType::Symlink, attr.kind);
+            assert_eq!(
+                target_rel.expose_secret(),
+                fs.read_link(attr.ino).await.unwrap().expose_secret()
+            );
+            let entries: Vec<DirectoryEntry> = fs
+                .read_dir(ROOT_INODE)
+                .await
+                .unwrap()
+                .map(Result::unwrap)
+                .collect();
+            assert!(entries
+                .iter()
+                .any(|e| e.ino == attr.ino && e.kind == FileType::Symlink));
+
+            // not a symlink
+            assert!(matches!(
+                fs.read_link(ROOT_INODE).await,
+                Err(FsError::InvalidInodeType)
+            ));
+
+            // remove
+            fs.remove_file(ROOT_INODE, &link_rel).await.unwrap();
+            assert!(!fs.exists(attr.ino));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_link() {
+    run_test_with_storages(TestSetup { key: "test_link" }, || async {
+        let fs = get_fs().await;
+
+        let file_1 = SecretString::from_str("file-1").unwrap();
+        let (fh, attr) = fs
+            .create(
+                ROOT_INODE,
+                &file_1,
+                create_attr(FileType::RegularFile),
+                false,
+                true,
+            )
+            .await
+            .unwrap();
+        write_all_bytes_to_fs(&fs, attr.ino, 0, b"test-42", fh)
+            .await
+            .unwrap();
+        fs.flush(fh).await.unwrap();
+        fs.release(fh).await.unwrap();
+        assert_eq!(1, attr.nlink);
+
+        // link in another directory
+        let (_, dir_attr) = fs
+            .create(
+                ROOT_INODE,
+                &SecretString::from_str("dir").unwrap(),
+                create_attr(FileType::Directory),
+                false,
+                false,
+            )
+            .await
+            .unwrap();
+        let file_2 = SecretString::from_str("file-2").unwrap();
+        let link_attr = fs.link(attr.ino, dir_attr.ino, &file_2).await.unwrap();
+        assert_eq!(attr.ino, link_attr.ino);
+        assert_eq!(2, link_attr.nlink);
+        assert_eq!(
+            attr.ino,
+            fs.find_by_name(dir_attr.ino, &file_2)
+                .await
+                .unwrap()
+                .unwrap()
+                .ino
+        );
+
+        // changes through one name are visible through the other
+        let ino_2 = fs
+            .find_by_name(dir_attr.ino, &file_2)
+            .await
+            .unwrap()
+            .unwrap()
+            .ino;
+        let fh = fs.open(ino_2, false, true).await.unwrap();
+        write_all_bytes_to_fs(&fs, ino_2, 5, b"37", fh)
+            .await
+            .unwrap();
+        fs.flush(fh).await.unwrap();
+        fs.release(fh).await.unwrap();
+        let ino_1 = fs
+            .find_by_name(ROOT_INODE, &file_1)
+            .await
+            .unwrap()
+            .unwrap()
+            .ino;
+        assert_eq!("test-37", test_common::read_to_string(ino_1, &fs).await);
+
+        // removing one name keeps the data
+        fs.remove_file(ROOT_INODE, &file_1).await.unwrap();
+        assert!(!fs.exists_by_name(ROOT_INODE, &file_1).await.unwrap());
+        assert!(fs.exists(attr.ino));
+        assert_eq!(1, fs.get_attr(attr.ino).await.unwrap().nlink);
+        assert_eq!("test-37", test_common::read_to_string(attr.ino, &fs).await);
+
+        // removing the last name removes the data
+        fs.remove_file(dir_attr.ino, &file_2).await.unwrap();
+        assert!(!fs.exists(attr.ino));
+
+        // existing name
+        assert!(matches!(
+            fs.link(
+                dir_attr.ino,
+                ROOT_INODE,
+                &SecretString::from_str("dir").unwrap()
+            )
+            .await,
+            Err(FsError::InvalidInodeType)
+        ));
+    })
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_tampered_directory_entry() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_tampered_directory_entry",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            fs.create(
+                ROOT_INODE,
+                &test_file,
+                create_attr(FileType::RegularFile),
+                false,
+                false,
+            )
+            .await
+            .unwrap();
+            let root_path = EncryptedFs::contents_path(ROOT_INODE);
+
+            // flip a byte in the hash entry
+            let hash_path = root_path.join(HASH_DIR).join(crypto::hash_file_name(
+                &test_file,
+                &*fs.key.get().await.unwrap(),
+            ));
+            let mut data = fs.storage.read(&hash_path).unwrap();
+            let len = data.len();
+            data[len - 1] ^= 0xff;
+            fs.storage.write(&hash_path, &data).unwrap();
+            assert!(matches!(
+                fs.find_by_name(ROOT_INODE, &test_file).await,
+                Err(FsError::InvalidDirectoryEntry)
+            ));
+
+            // flip a byte in the listing entry
+            let ls_path = fs
+                .storage
+                .read_dir(&root_path.join(LS_DIR))
+                .unwrap()
+                .into_iter()
+                .map(|name| root_path.join(LS_DIR).join(name))
+                .find(|path| !path.file_name().unwrap().to_str().unwrap().starts_with('$'))
+                .unwrap();
+            let mut data = fs.storage.read(&ls_path).unwrap();
+            let len = data.len();
+            data[len - 1] ^= 0xff;
+            fs.storage.write(&ls_path, &data).unwrap();
+            assert!(fs
+                .read_dir(ROOT_INODE)
+                .await
+                .unwrap()
+                .any(|entry| matches!(entry, Err(FsError::InvalidDirectoryEntry))));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_distinct_nonces_for_identical_content() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_distinct_nonces_for_identical_content",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            let mut inodes = vec![];
+            for name in ["test-file-1", "test-file-2"] {
+                let (fh, attr) = fs
+                    .create(
+                        ROOT_INODE,
+                        &SecretString::from_str(name).unwrap(),
+                        create_attr(FileType::RegularFile),
+                        false,
+                        true,
+                    )
+                    .await
+                    .unwrap();
+                write_all_bytes_to_fs(&fs, attr.ino, 0, b"same plaintext", fh)
+                    .await
+                    .unwrap();
+                fs.flush(fh).await.unwrap();
+                fs.release(fh).await.unwrap();
+                inodes.push(attr.ino);
+            }
+
+            let content_1 = fs
+                .storage
+                .read(&EncryptedFs::contents_path(inodes[0]))
+                .unwrap();
+            let content_2 = fs
+                .storage
+                .read(&EncryptedFs::contents_path(inodes[1]))
+                .unwrap();
+            assert_eq!(content_1.len(), content_2.len());
+            // each block starts with its own random nonce
+            assert_ne!(content_1[..NONCE_LEN], content_2[..NONCE_LEN]);
+            assert_ne!(content_1, content_2);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_open_reader() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_open_reader",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let data: Vec<u8> = b"0123456789abcdefghijklmnopqrstuvwxyz"
+                .iter()
+                .copied()
+                .cycle()
+                .take(1042)
+                .collect();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, &data, fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+
+            let mut reader = fs.open_reader(attr.ino).await.unwrap();
+            let mut all = vec![];
+            reader.read_to_end(&mut all).unwrap();
+            assert_eq!(data, all);
+
+            // random access, forward and backward
+            for (offset, len) in [(500, 42), (3, 10), (999, 43), (0, 1042), (250, 300), (1, 1)] {
+                let mut buf = vec![0; len];
+                reader.seek(SeekFrom::Start(offset as u64)).unwrap();
+                reader.read_exact(&mut buf).unwrap();
+                assert_eq!(&data[offset..offset + len], &buf[..]);
+            }
+            let pos = reader.seek(SeekFrom::End(-2)).unwrap();
+            assert_eq!(1040, pos);
+            let mut buf = vec![];
+            reader.read_to_end(&mut buf).unwrap();
+            assert_eq!(&data[1040..], &buf[..]);
+
+            // directories don't have content
+            assert!(matches!(
+                fs.open_reader(ROOT_INODE).await,
+                Err(FsError::InvalidInodeType)
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_open_reader_buffered() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_open_reader_buffered",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let data: Vec<u8> = b"0123456789abcdefghijklmnopqrstuvwxyz"
+                .iter()
+                .copied()
+                .cycle()
+                .take(BLOCK_SIZE * 20 + 42)
+                .collect();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, &data, fh)
+                .await
+                .unwrap();
+            // not flushed, open should see the pending writes
+
+            let mut reader = fs.open_reader_buffered(attr.ino, 4).await.unwrap();
+            let mut all = vec![];
+            reader.read_to_end(&mut all).unwrap();
+            assert_eq!(data, all);
+
+            let mut buf = vec![0; 42];
+            reader.seek(SeekFrom::Start(500)).unwrap();
+            reader.read_exact(&mut buf).unwrap();
+            assert_eq!(&data[500..542], &buf[..]);
+            fs.release(fh).await.unwrap();
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_write_in_the_middle_changes_one_block() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_write_in_the_middle_changes_one_block",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let data = vec![42_u8; 10 * BLOCK_SIZE];
+            write_all_bytes_to_fs(&fs, attr.ino, 0, &data, fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            let path = EncryptedFs::contents_path(attr.ino);
+            let before = fs.storage.read(&path).unwrap();
+
+            // change one byte in the middle of the 6th block
+            let fh = fs.open(attr.ino, false, true).await.unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 5 * BLOCK_SIZE as u64 + 50, b"7", fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            let after = fs.storage.read(&path).unwrap();
+
+            assert_eq!(before.len(), after.len());
+            let ciphertext_block_size = NONCE_LEN + BLOCK_SIZE + CHACHA20_POLY1305.tag_len();
+            let changed: Vec<usize> = before[HEADER_LEN..]
+                .chunks(ciphertext_block_size)
+                .zip(after[HEADER_LEN..].chunks(ciphertext_block_size))
+                .enumerate()
+                .filter(|(_, (a, b))| a != b)
+                .map(|(i, _)| i)
+                .collect();
+            assert_eq!(vec![5], changed);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_all_ciphers() {
+    for cipher in Cipher::iter() {
+        let data_dir = TESTS_DATA_DIR.join(format!("test_all_ciphers_{cipher}"));
+        let _ = fs::remove_dir_all(&data_dir);
+
+        let fs = EncryptedFs::new(
+            data_dir.clone(),
+            Box::new(TestPasswordProvider("password")),
+            cipher,
+        )
+        .await
+        .unwrap();
+        let test_file = SecretString::from_str("test-file").unwrap();
+        let (fh, attr) = fs
+            .create(
+                ROOT_INODE,
+                &test_file,
+                create_attr(FileType::RegularFile),
+                false,
+                true,
+            )
+            .await
+            .unwrap();
+        write_all_bytes_to_fs(&fs, attr.ino, 0, b"test-42", fh)
+            .await
+            .unwrap();
+        fs.flush(fh).await.unwrap();
+        fs.release(fh).await.unwrap();
+        assert_eq!("test-42", test_common::read_to_string(attr.ino, &fs).await);
+        drop(fs);
+
+        // reopen
+        let fs = EncryptedFs::new(
+            data_dir.clone(),
+            Box::new(TestPasswordProvider("password")),
+            cipher,
+        )
+        .await
+        .unwrap();
+        assert!(fs.exists_by_name(ROOT_INODE, &test_file).await.unwrap());
+        assert_eq!("test-42", test_common::read_to_string(attr.ino, &fs).await);
+        drop(fs);
+
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_cipher_mismatch() {
+    let data_dir = TESTS_DATA_DIR.join("test_cipher_mismatch");
+    let _ = fs::remove_dir_all(&data_dir);
+    let metadata_path = data_dir.join(SECURITY_DIR).join(METADATA_FILENAME);
+
+    let fs = EncryptedFs::new(
+        data_dir.clone(),
+        Box::new(TestPasswordProvider("password")),
+        Cipher::ChaCha20Poly1305,
+    )
+    .await
+    .unwrap();
+    drop(fs);
+    assert!(metadata_path.is_file());
+
+    assert!(matches!(
+        EncryptedFs::new(
+            data_dir.clone(),
+            Box::new(TestPasswordProvider("password")),
+            Cipher::Aes256Gcm,
+        )
+        .await,
+        Err(FsError::Other(_))
+    ));
+    assert!(matches!(
+        EncryptedFs::passwd(
+            &data_dir,
+            SecretString::from_str("password").unwrap(),
+            SecretString::from_str("new-password").unwrap(),
+            Cipher::Aes256Gcm,
+        )
+        .await,
+        Err(FsError::Other(_))
+    ));
+
+    fs::remove_dir_all(data_dir).unwrap();
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_open_without_metadata() {
+    let data_dir = TESTS_DATA_DIR.join("test_open_without_metadata");
+    let _ = fs::remove_dir_all(&data_dir);
+    let metadata_path = data_dir.join(SECURITY_DIR).join(METADATA_FILENAME);
+
+    let fs = EncryptedFs::new(
+        data_dir.clone(),
+        Box::new(TestPasswordProvider("password")),
+        Cipher::ChaCha20Poly1305,
+    )
+    .await
+    .unwrap();
+    drop(fs);
+
+    // data dirs with inodes but without metadata were created with an older format
+    fs::remove_file(&metadata_path).unwrap();
+    assert!(matches!(
+        EncryptedFs::new(
+            data_dir.clone(),
+            Box::new(TestPasswordProvider("password")),
+            Cipher::ChaCha20Poly1305,
+        )
+        .await,
+        Err(FsError::UnsupportedFormatVersion(0))
+    ));
+    assert!(matches!(
+        EncryptedFs::new_read_only(
+            data_dir.clone(),
+            Box::new(TestPasswordProvider("password")),
+            Cipher::ChaCha20Poly1305,
+        )
+        .await,
+        Err(FsError::UnsupportedFormatVersion(0))
+    ));
+    assert!(!metadata_path.exists());
+
+    // without inodes there is nothing written yet, it gets the metadata
+    for dir in [INODES_DIR, CONTENTS_DIR] {
+        fs::remove_dir_all(data_dir.join(dir)).unwrap();
+        fs::create_dir(data_dir.join(dir)).unwrap();
+    }
+    let fs = EncryptedFs::new(
+        data_dir.clone(),
+        Box::new(TestPasswordProvider("password")),
+        Cipher::ChaCha20Poly1305,
+    )
+    .await
+    .unwrap();
+    assert_eq!(FORMAT_VERSION, fs.config().format_version);
+    drop(fs);
+    assert!(metadata_path.is_file());
+
+    fs::remove_dir_all(data_dir).unwrap();
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_unsupported_format_version() {
+    let data_dir = TESTS_DATA_DIR.join("test_unsupported_format_version");
+    let _ = fs::remove_dir_all(&data_dir);
+    let metadata_path = data_dir.join(SECURITY_DIR).join(METADATA_FILENAME);
+
+    let fs = EncryptedFs::new(
+        data_dir.clone(),
+        Box::new(TestPasswordProvider("password")),
+        Cipher::ChaCha20Poly1305,
+    )
+    .await
+    .unwrap();
+    drop(fs);
+
+    // older and newer versions, the rest of the metadata can be different in those
+    for version in [FORMAT_VERSION - 1, FORMAT_VERSION + 1] {
+        let mut metadata = bincode::serialize(&version).unwrap();
+        metadata.extend_from_slice(b"other fields");
+        fs::write(&metadata_path, metadata).unwrap();
+        assert!(matches!(
+            EncryptedFs::new(
+                data_dir.clone(),
+                Box::new(TestPasswordProvider("password")),
+                Cipher::ChaCha20Poly1305,
+            )
+            .await,
+            Err(FsError::UnsupportedFormatVersion(v)) if v == version
+        ));
+        assert!(matches!(
+            EncryptedFs::new_read_only(
+                data_dir.clone(),
+                Box::new(TestPasswordProvider("password")),
+                Cipher::ChaCha20Poly1305,
+            )
+            .await,
+            Err(FsError::UnsupportedFormatVersion(v)) if v == version
+        ));
+    }
+
+    fs::remove_dir_all(data_dir).unwrap();
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_migrate() {
+    let data_dir = TESTS_DATA_DIR.join("test_migrate");
+    let _ = fs::remove_dir_all(&data_dir);
+    // created with the code before the format version was kept, with the test block size:
+    // `hello.txt`, `big.bin` with 250 bytes, `empty` and `dir` with `nested.txt` and `with/slash`
+    let archive = &include_bytes!("test/baseline_data_dir.tar")[..];
+    tar::Archive::new(archive).unpack(&data_dir).unwrap();
+    let password = || SecretString::from_str("password").unwrap();
+
+    assert!(matches!(
+        EncryptedFs::new(
+            data_dir.clone(),
+            Box::new(TestPasswordProvider("password")),
+            Cipher::ChaCha20Poly1305,
+        )
+        .await,
+        Err(FsError::UnsupportedFormatVersion(0))
+    ));
+    assert!(matches!(
+        EncryptedFs::migrate(
+            &data_dir,
+            SecretString::from_str("wrong").unwrap(),
+            Cipher::ChaCha20Poly1305
+        )
+        .await,
+        Err(FsError::InvalidPassword)
+    ));
+    EncryptedFs::migrate(&data_dir, password(), Cipher::ChaCha20Poly1305)
+        .await
+        .unwrap();
+
+    // interrupted before the metadata, with some files not migrated yet
+    fs::remove_file(data_dir.join(SECURITY_DIR).join(METADATA_FILENAME)).unwrap();
+    for mut entry in tar::Archive::new(archive)
+        .entries()
+        .unwrap()
+        .map(Result::unwrap)
+    {
+        if entry.path().unwrap().starts_with(format!("./{INODES_DIR}")) {
+            entry.unpack_in(&data_dir).unwrap();
+        }
+    }
+    EncryptedFs::migrate(&data_dir, password(), Cipher::ChaCha20Poly1305)
+        .await
+        .unwrap();
+    // nothing left to do
+    EncryptedFs::migrate(&data_dir, password(), Cipher::ChaCha20Poly1305)
+        .await
+        .unwrap();
+
+    let fs = EncryptedFs::new(
+        data_dir.clone(),
+        Box::new(TestPasswordProvider("password")),
+        Cipher::ChaCha20Poly1305,
+    )
+    .await
+    .unwrap();
+    assert_eq!(FORMAT_VERSION, fs.config().format_version);
+    assert_eq!(Vec::<Inconsistency>::new(), fs.verify().await.unwrap());
+    let mut names: Vec<_> = fs
+        .read_dir(ROOT_INODE)
+        .await
+        .unwrap()
+        .map(|entry| entry.unwrap().name.expose_secret().clone())
+        .collect();
+    names.sort();
+    assert_eq!(vec![".", "big.bin", "dir", "empty", "hello.txt"], names);
+
+    let find = |parent, name| {
+        let fs = fs.clone();
+        async move {
+            fs.find_by_name(parent, &SecretString::from_str(name).unwrap())
+                .await
+                .unwrap()
+                .unwrap()
+        }
+    };
+    let attr = find(ROOT_INODE, "hello.txt").await;
+    assert_eq!("hello", test_common::read_to_string(attr.ino, &fs).await);
+    let attr = find(ROOT_INODE, "big.bin").await;
+    assert_eq!(250, attr.size);
+    let fh = fs.open(attr.ino, true, false).await.unwrap();
+    let mut buf = [0; 250];
+    test_common::read_exact(&fs, attr.ino, 0, &mut buf, fh).await;
+    fs.release(fh).await.unwrap();
+    #[allow(clippy::cast_possible_truncation)]
+    let expected: Vec<u8> = (0..250).map(|i| i as u8).collect();
+    assert_eq!(expected, buf);
+    let attr = find(ROOT_INODE, "empty").await;
+    assert_eq!("", test_common::read_to_string(attr.ino, &fs).await);
+    let dir = find(ROOT_INODE, "dir").await;
+    let attr = find(dir.ino, "nested.txt").await;
+    assert_eq!("nested", test_common::read_to_string(attr.ino, &fs).await);
+    // the slash was replaced when it was created
+    let attr = find(dir.ino, "with/slash").await;
+    assert_eq!("slash", test_common::read_to_string(attr.ino, &fs).await);
+
+    // and we can keep using it
+    let (fh, attr) = fs
+        .create(
+            dir.ino,
+            &SecretString::from_str("new").unwrap(),
+            create_attr(FileType::RegularFile),
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+    write_all_string_to_fs(&fs, attr.ino, 0, "new", fh)
+        .await
+        .unwrap();
+    fs.release(fh).await.unwrap();
+    assert_eq!("new", test_common::read_to_string(attr.ino, &fs).await);
+    drop(fs);
+
+    fs::remove_dir_all(data_dir).unwrap();
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_truncated_inode_file() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_truncated_inode_file",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            let (_, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            fs.storage
+                .open_rw(&EncryptedFs::ino_file(attr.ino))
+                .unwrap()
+                .set_len(5)
+                .unwrap();
+            assert!(matches!(
+                fs.get_inode_from_storage(attr.ino).await,
+                Err(FsError::CorruptedInode(ino)) if ino == attr.ino
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_empty_inode_file() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_empty_inode_file",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            let (_, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            fs.storage
+                .write(&EncryptedFs::ino_file(attr.ino), b"")
+                .unwrap();
+            assert!(matches!(
+                fs.get_inode_from_storage(attr.ino).await,
+                Err(FsError::CorruptedInode(ino)) if ino == attr.ino
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_statfs() {
+    run_test_with_storages(TestSetup { key: "test_statfs" }, || async {
+        let fs = get_fs().await;
+
+        let stat = fs.statfs().await.unwrap();
+        assert_eq!(BLKSIZE, stat.bsize);
+        assert!(stat.blocks >= stat.bfree);
+        assert!(stat.files >= stat.ffree);
+        let used_files = stat.files - stat.ffree;
+        assert_eq!(1, used_files);
+
+        let (fh, attr) = fs
+            .create(
+                ROOT_INODE,
+                &SecretString::from_str("test-file").unwrap(),
+                create_attr(FileType::RegularFile),
+                false,
+                true,
+            )
+            .await
+            .unwrap();
+        write_all_bytes_to_fs(&fs, attr.ino, 0, &[42; 10 * 1024], fh)
+            .await
+            .unwrap();
+        fs.flush(fh).await.unwrap();
+        fs.release(fh).await.unwrap();
+        fs.create(
+            ROOT_INODE,
+            &SecretString::from_str("test-dir").unwrap(),
+            create_attr(FileType::Directory),
+            false,
+            false,
+        )
+        .await
+        .unwrap();
+
+        let stat = fs.statfs().await.unwrap();
+        assert_eq!(3, stat.files - stat.ffree);
+        assert!(stat.blocks - stat.bfree >= (10 * 1024) / u64::from(BLKSIZE));
+        let used_blocks = stat.blocks - stat.bfree;
+
+        // kept up to date after the first call
+        fs.remove_file(ROOT_INODE, &SecretString::from_str("test-file").unwrap())
+            .await
+            .unwrap();
+        fs.remove_dir(ROOT_INODE, &SecretString::from_str("test-dir").unwrap())
+            .await
+            .unwrap();
+        let stat = fs.statfs().await.unwrap();
+        assert_eq!(1, stat.files - stat.ffree);
+        assert!(stat.blocks - stat.bfree < used_blocks);
+    })
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_rename_replaces_destination_in_place() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_rename_replaces_destination_in_place",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            let file_1 = SecretString::from_str("file-1").unwrap();
+            let file_2 = SecretString::from_str("file-2").unwrap();
+            let mut attrs = vec![];
+            for (name, data) in [(&file_1, "file-1"), (&file_2, "file-2")] {
+                let (fh, attr) = fs
+                    .create(
+                        ROOT_INODE,
+                        name,
+                        create_attr(FileType::RegularFile),
+                        false,
+                        true,
+                    )
+                    .await
+                    .unwrap();
+                write_all_string_to_fs(&fs, attr.ino, 0, data, fh)
+                    .await
+                    .unwrap();
+                fs.release(fh).await.unwrap();
+                attrs.push(attr);
+            }
+
+            // fail after the destination points to the source, the old data is still there
+            fs.fail_point
+                .lock()
+                .unwrap()
+                .replace("rename:after_replace");
+            assert!(fs
+                .rename(ROOT_INODE, &file_1, ROOT_INODE, &file_2)
+                .await
+                .is_err());
+            fs.fail_point.lock().unwrap().take();
+            let attr = fs.find_by_name(ROOT_INODE, &file_2).await.unwrap().unwrap();
+            assert_eq!(attrs[0].ino, attr.ino);
+            assert_eq!("file-1", test_common::read_to_string(attr.ino, &fs).await);
+            assert_eq!(
+                1,
+                fs.read_dir(ROOT_INODE)
+                    .await
+                    .unwrap()
+                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
+                        == file_2.expose_secret())
+                    .count()
+            );
+            assert!(fs.exists(attrs[1].ino));
+            assert_eq!(
+                "file-2",
+                test_common::read_to_string(attrs[1].ino, &fs).await
+            );
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_rename_over_existing_file_and_move_dir() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_rename_over_existing_file_and_move_dir",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            // rename over existing file
+            let file_1 = SecretString::from_str("file-1").unwrap();
+            let file_2 = SecretString::from_str("file-2").unwrap();
+            let (fh, attr_1) = fs
+                .create(
+                    ROOT_INODE,
+                    &file_1,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr_1.ino, 0, b"file-1", fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            let (fh, attr_2) = fs
+                .create(
+                    ROOT_INODE,
+                    &file_2,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr_2.ino, 0, b"file-2", fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            let root_before = fs.get_inode_from_storage(ROOT_INODE).await.unwrap();
+
+            fs.rename(ROOT_INODE, &file_1, ROOT_INODE, &file_2)
+                .await
+                .unwrap();
+            assert!(!fs.exists_by_name(ROOT_INODE, &file_1).await.unwrap());
+            let attr = fs.find_by_name(ROOT_INODE, &file_2).await.unwrap().unwrap();
+            assert_eq!(attr_1.ino, attr.ino);
+            assert_eq!("file-1", test_common::read_to_string(attr.ino, &fs).await);
+            assert_eq!(
+                1,
+                fs.read_dir(ROOT_INODE)
+                    .await
+                    .unwrap()
+                    .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
+                        == file_2.expose_secret())
+                    .count()
+            );
+            // times are persisted
+            let root_after = fs.get_inode_from_storage(ROOT_INODE).await.unwrap();
+            assert!(root_after.mtime >= root_before.mtime);
+            assert!(root_after.ctime >= root_before.ctime);
+            let stored = fs.get_inode_from_storage(attr_1.ino).await.unwrap();
+            assert!(stored.ctime >= attr_1.ctime);
+
+            // move directory to another directory
+            let dir_a = SecretString::from_str("dir-a").unwrap();
+            let dir_b = SecretString::from_str("dir-b").unwrap();
+            let (_, attr_a) = fs
+                .create(
+                    ROOT_INODE,
+                    &dir_a,
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let (_, attr_b) = fs
+                .create(
+                    ROOT_INODE,
+                    &dir_b,
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let child = SecretString::from_str("child").unwrap();
+            let (_, attr_child) = fs
+                .create(
+                    attr_a.ino,
+                    &child,
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            fs.rename(attr_a.ino, &child, attr_b.ino, &child)
+                .await
+                .unwrap();
+            assert!(!fs.exists_by_name(attr_a.ino, &child).await.unwrap());
+            assert_eq!(0, fs.len(attr_a.ino).unwrap());
+            assert_eq!(1, fs.len(attr_b.ino).unwrap());
+            assert_eq!(
+                attr_b.ino,
+                fs.find_by_name(attr_child.ino, &SecretString::from_str("..").unwrap())
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino
+            );
+            let parent_entries: Vec<DirectoryEntry> = fs
+                .read_dir(attr_child.ino)
+                .await
+                .unwrap()
+                .map(Result::unwrap)
+                .filter(|entry| entry.name.expose_secret() == "..")
+                .collect();
+            assert_eq!(1, parent_entries.len());
+            assert_eq!(attr_b.ino, parent_entries[0].ino);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_overwrite_start_keeps_size() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_overwrite_start_keeps_size",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    true,
+                    true,
+                )
+                .await
+                .unwrap();
+            let data: Vec<u8> = b"0123456789"
+                .iter()
+                .copied()
+                .cycle()
+                .take(10 * 1024)
+                .collect();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, &data, fh)
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, &[b'a'; 100], fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+
+            assert_eq!(10 * 1024, fs.get_attr(attr.ino).await.unwrap().size);
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            let mut buf = vec![0; 10 * 1024];
+            test_common::read_exact(&fs, attr.ino, 0, &mut buf, fh).await;
+            fs.release(fh).await.unwrap();
+            assert_eq!(&[b'a'; 100], &buf[..100]);
+            assert_eq!(&data[100..], &buf[100..]);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_flush_persists_attr() {
+    let data_dir = TESTS_DATA_DIR.join("test_flush_persists_attr");
+    let _ = fs::remove_dir_all(&data_dir);
+
+    let fs = EncryptedFs::new(
+        data_dir.clone(),
+        Box::new(TestPasswordProvider("password")),
+        Cipher::ChaCha20Poly1305,
+    )
+    .await
+    .unwrap();
+    let (fh, attr) = fs
+        .create(
+            ROOT_INODE,
+            &SecretString::from_str("test-file").unwrap(),
+            create_attr(FileType::RegularFile),
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+    let data: Vec<u8> = b"0123456789"
+        .iter()
+        .copied()
+        .cycle()
+        .take(BLOCK_SIZE * 2 + 42)
+        .collect();
+    write_all_bytes_to_fs(&fs, attr.ino, 0, &data, fh)
+        .await
+        .unwrap();
+    fs.flush(fh).await.unwrap();
+    // the handle is still usable after flush
+    fs.write(attr.ino, data.len() as u64, b"!", fh)
+        .await
+        .unwrap();
+    fs.flush(fh).await.unwrap();
+    // simulate a crash, we never release the handle
+    drop(fs);
+
+    let fs = EncryptedFs::new(
+        data_dir.clone(),
+        Box::new(TestPasswordProvider("password")),
+        Cipher::ChaCha20Poly1305,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        data.len() as u64 + 1,
+        fs.get_attr(attr.ino).await.unwrap().size
+    );
+    let content = test_common::read_to_string(attr.ino, &fs).await;
+    assert_eq!(format!("{}!", String::from_utf8(data).unwrap()), content);
+    drop(fs);
+
+    fs::remove_dir_all(data_dir).unwrap();
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_read_after_write_same_handle() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_read_after_write_same_handle",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    true,
+                    true,
+                )
+                .await
+                .unwrap();
+            let len = fs.write(attr.ino, 0, b"test-42", fh).await.unwrap();
+            assert_eq!(7, len);
+            let mut buf = [0; 7];
+            test_common::read_exact(&fs, attr.ino, 0, &mut buf, fh).await;
+            assert_eq!(b"test-42", &buf);
+
+            // overwrite and read again on the same handle
+            fs.write(attr.ino, 5, b"37", fh).await.unwrap();
+            let mut buf = [0; 7];
+            test_common::read_exact(&fs, attr.ino, 0, &mut buf, fh).await;
+            assert_eq!(b"test-37", &buf);
+            fs.release(fh).await.unwrap();
+
+            // reopen with both read and write
+            let fh = fs.open(attr.ino, true, true).await.unwrap();
+            fs.write(attr.ino, 7, b"-append", fh).await.unwrap();
+            let mut buf = [0; 14];
+            test_common::read_exact(&fs, attr.ino, 0, &mut buf, fh).await;
+            assert_eq!(b"test-37-append", &buf);
+            fs.release(fh).await.unwrap();
+            assert_eq!(14, fs.get_attr(attr.ino).await.unwrap().size);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_read_same_handle_does_not_save_inode() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_read_same_handle_does_not_save_inode",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    true,
+                    true,
+                )
+                .await
+                .unwrap();
+            let writes = || {
+                fs.inode_writes
+                    .lock()
+                    .unwrap()
+                    .get(&attr.ino)
+                    .copied()
+                    .unwrap_or(0)
+            };
+            fs.write(attr.ino, 0, b"test-42", fh).await.unwrap();
+            let before = writes();
+            // the pending data is read back, without saving the inode
+            for _ in 0..3 {
+                let mut buf = [0; 7];
+                test_common::read_exact(&fs, attr.ino, 0, &mut buf, fh).await;
+                assert_eq!(b"test-42", &buf);
+            }
+            fs.write(attr.ino, 7, b"-37", fh).await.unwrap();
+            let mut buf = [0; 10];
+            test_common::read_exact(&fs, attr.ino, 0, &mut buf, fh).await;
+            assert_eq!(b"test-42-37", &buf);
+            assert_eq!(before, writes());
+
+            fs.release(fh).await.unwrap();
+            assert_eq!(10, fs.get_attr(attr.ino).await.unwrap().size);
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            let mut buf = [0; 10];
+            test_common::read_exact(&fs, attr.ino, 0, &mut buf, fh).await;
+            assert_eq!(b"test-42-37", &buf);
+            fs.release(fh).await.unwrap();
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_write_all_returns_len() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_write_all_returns_len",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    true,
+                    true,
+                )
+                .await
+                .unwrap();
+            let data: Vec<u8> = b"0123456789"
+                .iter()
+                .copied()
+                .cycle()
+                .take(crate::stream_util::BUF_SIZE + 42)
+                .collect();
+            let len = write_all_bytes_to_fs(&fs, attr.ino, 0, &data, fh)
+                .await
+                .unwrap();
+            assert_eq!(data.len(), len);
+            assert_eq!(
+                0,
+                write_all_bytes_to_fs(&fs, attr.ino, 0, &[], fh)
+                    .await
+                    .unwrap()
+            );
+            fs.release(fh).await.unwrap();
+            assert_eq!(data.len() as u64, fs.get_attr(attr.ino).await.unwrap().size);
+
+            // copy_file_range reports what was actually copied
+            let (fh2, attr2) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file-2").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            let len = fs
+                .copy_file_range(attr.ino, 0, attr2.ino, 0, data.len() + 100, fh, fh2)
+                .await
+                .unwrap();
+            assert_eq!(data.len(), len);
+            fs.release(fh).await.unwrap();
+            fs.release(fh2).await.unwrap();
+            let fh2 = fs.open(attr2.ino, true, false).await.unwrap();
+            let mut buf = vec![0; data.len()];
+            test_common::read_exact(&fs, attr2.ino, 0, &mut buf, fh2).await;
+            fs.release(fh2).await.unwrap();
+            assert_eq!(data, buf);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_walk() {
+    run_test_with_storages(TestSetup { key: "test_walk" }, || async {
+        let fs = get_fs().await;
+
+        let (_, dir_a) = fs
+            .create(
+                ROOT_INODE,
+                &SecretString::from_str("a").unwrap(),
+                create_attr(FileType::Directory),
+                false,
+                false,
+            )
+            .await
+            .unwrap();
+        let (_, dir_b) = fs
+            .create(
+                dir_a.ino,
+                &SecretString::from_str("b").unwrap(),
+                create_attr(FileType::Directory),
+                false,
+                false,
+            )
+            .await
+            .unwrap();
+        let mut inodes = vec![
+            (PathBuf::from("a"), dir_a.ino),
+            (PathBuf::from("a/b"), dir_b.ino),
+        ];
+        for (parent, parent_path, name) in [
+            (ROOT_INODE, "", "f1"),
+            (dir_a.ino, "a", "f2"),
+            (dir_b.ino, "a/b", "f3"),
+            (dir_b.ino, "a/b", "f4"),
+        ] {
+            let (_, attr) = fs
+                .create(
+                    parent,
+                    &SecretString::from_str(name).unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            inodes.push((PathBuf::from(parent_path).join(name), attr.ino));
+        }
+        inodes.sort();
+
+        let mut walked: Vec<(PathBuf, u64)> = fs
+            .walk(ROOT_INODE)
+            .await
+            .unwrap()
+            .map(|res| {
+                let (path, attr) = res.unwrap();
+                (path, attr.ino)
+            })
+            .collect();
+        // parents are visited before their children
+        let pos = |p: &str| walked.iter().position(|(path, _)| path == Path::new(p));
+        assert!(pos("a") < pos("a/b"));
+        assert!(pos("a/b") < pos("a/b/f3"));
+        walked.sort();
+        assert_eq!(inodes, walked);
+
+        // walk a subtree
+        let walked: Vec<PathBuf> = fs
+            .walk(dir_a.ino)
+            .await
+            .unwrap()
+            .map(|res| res.unwrap().0)
+            .collect();
+        assert_eq!(4, walked.len());
+        assert!(walked.contains(&PathBuf::from("b/f4")));
+
+        assert!(matches!(
+            fs.walk(inodes.iter().find(|(p, _)| p == Path::new("f1")).unwrap().1)
+                .await,
+            Err(FsError::InvalidInodeType)
+        ));
+    })
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_read_dir_filter() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_read_dir_filter",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            for i in 0..3 {
+                fs.create(
+                    ROOT_INODE,
+                    &SecretString::new(format!("dir-{i}")),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            }
+            for i in 0..5 {
+                fs.create(
+                    ROOT_INODE,
+                    &SecretString::new(format!("file-{i}")),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            }
+
+            let files: Vec<DirectoryEntry> = fs
+                .read_dir_filter(ROOT_INODE, FileType::RegularFile)
+                .await
+                .unwrap()
+                .map(Result::unwrap)
+                .collect();
+            assert_eq!(5, files.len());
+            assert!(files.iter().all(|entry| entry.kind == FileType::RegularFile
+                && entry.name.expose_secret().starts_with("file-")));
+
+            // `.` is a directory too
+            let dirs: Vec<DirectoryEntry> = fs
+                .read_dir_filter(ROOT_INODE, FileType::Directory)
+                .await
+                .unwrap()
+                .map(Result::unwrap)
+                .collect();
+            assert!(dirs.iter().all(|entry| entry.kind == FileType::Directory));
+            assert!(dirs.iter().any(|entry| entry.name.expose_secret() == "."));
+            assert_eq!(
+                3,
+                dirs.iter()
+                    .filter(|entry| entry.name.expose_secret().starts_with("dir-"))
+                    .count()
+            );
+
+            assert_eq!(
+                0,
+                fs.read_dir_filter(ROOT_INODE, FileType::Symlink)
+                    .await
+                    .unwrap()
+                    .count()
+            );
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_read_dir_sorted() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_read_dir_sorted",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            for name in ["delta", "alpha", "charlie", "echo", "bravo"] {
+                fs.create(
+                    ROOT_INODE,
+                    &SecretString::from_str(name).unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            }
+            fs.create(
+                ROOT_INODE,
+                &SecretString::from_str("beta-dir").unwrap(),
+                create_attr(FileType::Directory),
+                false,
+                false,
+            )
+            .await
+            .unwrap();
+
+            let names: Vec<String> = fs
+                .read_dir_sorted(ROOT_INODE)
+                .await
+                .unwrap()
+                .map(|entry| entry.unwrap().name.expose_secret().to_string())
+                .collect();
+            assert_eq!(
+                vec![".", "alpha", "beta-dir", "bravo", "charlie", "delta", "echo"],
+                names
+            );
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_update_attr() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_update_attr",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, b"test-42", fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            let attr = fs.get_attr(attr.ino).await.unwrap();
+
+            // chmod
+            fs.update_attr(attr.ino, SetFileAttr::default().with_perm(0o600))
+                .await
+                .unwrap();
+            let attr_chmod = fs.get_attr(attr.ino).await.unwrap();
+            assert_eq!(0o600, attr_chmod.perm);
+            assert_eq!(attr.uid, attr_chmod.uid);
+            assert_eq!(attr.gid, attr_chmod.gid);
+            assert_eq!(attr.size, attr_chmod.size);
+            assert_eq!(attr.mtime, attr_chmod.mtime);
+            assert_eq!(attr.atime, attr_chmod.atime);
+            assert!(attr_chmod.ctime >= attr.ctime);
+
+            // chown
+            fs.update_attr(attr.ino, SetFileAttr::default().with_uid(42).with_gid(37))
+                .await
+                .unwrap();
+            let attr_chown = fs.get_attr(attr.ino).await.unwrap();
+            assert_eq!(42, attr_chown.uid);
+            assert_eq!(37, attr_chown.gid);
+            assert_eq!(0o600, attr_chown.perm);
+            assert_eq!(attr.size, attr_chown.size);
+            assert_eq!(attr.mtime, attr_chown.mtime);
+
+            // utimes, can go back in time
+            let time = SystemTime::UNIX_EPOCH + Duration::from_secs(42);
+            fs.update_attr(
+                attr.ino,
+                SetFileAttr::default().with_atime(time).with_mtime(time),
+            )
+            .await
+            .unwrap();
+            let attr_utimes = fs.get_attr(attr.ino).await.unwrap();
+            assert_eq!(time, attr_utimes.atime);
+            assert_eq!(time, attr_utimes.mtime);
+            assert_eq!(42, attr_utimes.uid);
+
+            // truncate
+            fs.update_attr(attr.ino, SetFileAttr::default().with_size(4))
+                .await
+                .unwrap();
+            assert_eq!(4, fs.get_attr(attr.ino).await.unwrap().size);
+            assert_eq!("test", test_common::read_to_string(attr.ino, &fs).await);
+
+            // flags are not mixed with rdev
+            fs.update_attr(attr.ino, SetFileAttr::default().with_flags(1))
+                .await
+                .unwrap();
+            let attr_flags = fs.get_attr(attr.ino).await.unwrap();
+            assert_eq!(1, attr_flags.flags);
+            assert_eq!(attr.rdev, attr_flags.rdev);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_read_dir_decrypts_names() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_read_dir_decrypts_names",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            let name = SecretString::from_str("hello.txt").unwrap();
+            let (_, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &name,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+
+            let entries: Vec<DirectoryEntry> = fs
+                .read_dir(ROOT_INODE)
+                .await
+                .unwrap()
+                .map(Result::unwrap)
+                .filter(|entry| entry.ino == attr.ino)
+                .collect();
+            assert_eq!(1, entries.len());
+            assert_eq!("hello.txt", entries[0].name.expose_secret());
+            let entries: Vec<DirectoryEntryPlus> = fs
+                .read_dir_plus(ROOT_INODE)
+                .await
+                .unwrap()
+                .map(Result::unwrap)
+                .filter(|entry| entry.ino == attr.ino)
+                .collect();
+            assert_eq!(1, entries.len());
+            assert_eq!("hello.txt", entries[0].name.expose_secret());
+
+            // on disk we have the encrypted name, which decrypts to the original
+            let on_disk: Vec<String> = fs
+                .storage
+                .read_dir(&EncryptedFs::contents_path(ROOT_INODE).join(LS_DIR))
+                .unwrap()
+                .into_iter()
+                .map(|name| name.to_string_lossy().to_string())
+                .filter(|name| name != "$.")
+                .collect();
+            assert_eq!(1, on_disk.len());
+            assert_ne!("hello.txt", on_disk[0]);
+            assert_eq!(
+                "hello.txt",
+                crypto::decrypt_file_name(&on_disk[0], fs.cipher, &*fs.key.get().await.unwrap())
+                    .unwrap()
+                    .expose_secret()
+            );
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_directory_entry_round_trip() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_directory_entry_round_trip",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            let name = SecretString::from_str("round-trip.txt").unwrap();
+            let (_, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &name,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let ls_dir = EncryptedFs::contents_path(ROOT_INODE).join(LS_DIR);
+            let hash_path = EncryptedFs::contents_path(ROOT_INODE)
+                .join(HASH_DIR)
+                .join(crypto::hash_file_name(&name, &*fs.key.get().await.unwrap()));
+            assert!(fs.storage.is_file(&hash_path));
+            assert_eq!(2, fs.storage.read_dir(&ls_dir).unwrap().len());
+
+            // find
+            assert!(fs.exists_by_name(ROOT_INODE, &name).await.unwrap());
+            let found = fs.find_by_name(ROOT_INODE, &name).await.unwrap().unwrap();
+            assert_eq!(attr.ino, found.ino);
+
+            // list
+            let entries: Vec<DirectoryEntry> = fs
+                .read_dir(ROOT_INODE)
+                .await
+                .unwrap()
+                .map(Result::unwrap)
+                .filter(|entry| entry.name.expose_secret() != ".")
+                .collect();
+            assert_eq!(
+                vec![DirectoryEntry {
+                    ino: attr.ino,
+                    name: name.clone(),
+                    kind: FileType::RegularFile,
+                }],
+                entries
+            );
+
+            // remove
+            fs.remove_file(ROOT_INODE, &name).await.unwrap();
+            assert!(!fs.exists_by_name(ROOT_INODE, &name).await.unwrap());
+            assert!(fs.find_by_name(ROOT_INODE, &name).await.unwrap().is_none());
+            assert!(!fs.storage.exists(&hash_path));
+            assert_eq!(1, fs.storage.read_dir(&ls_dir).unwrap().len());
+            assert_eq!(0, fs.len(ROOT_INODE).unwrap());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_normalized_name_collision() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_normalized_name_collision",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            // `a/b` is stored as `a b`
+            let (_, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("a/b").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            for name in ["a b", "a\\b"] {
+                let name = SecretString::from_str(name).unwrap();
+                assert!(matches!(
+                    fs.create(
+                        ROOT_INODE,
+                        &name,
+                        create_attr(FileType::RegularFile),
+                        false,
+                        false,
+                    )
+                    .await,
+                    Err(FsError::AlreadyExists)
+                ));
+                assert!(matches!(
+                    fs.link(attr.ino, ROOT_INODE, &name).await,
+                    Err(FsError::AlreadyExists)
+                ));
+            }
+            // the first entry was not clobbered
+            let entries: Vec<DirectoryEntry> = fs
+                .read_dir(ROOT_INODE)
+                .await
+                .unwrap()
+                .map(Result::unwrap)
+                .filter(|entry| entry.name.expose_secret() != ".")
+                .collect();
+            assert_eq!(1, entries.len());
+            assert_eq!(attr.ino, entries[0].ino);
+            assert_eq!(
+                attr.ino,
+                fs.find_by_name(ROOT_INODE, &SecretString::from_str("a b").unwrap())
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino
+            );
+        },
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+#[traced_test]
+async fn test_concurrent_writes_different_files() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_concurrent_writes_different_files",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            let mut handles = vec![];
+            for i in 0..8_u8 {
+                let fs = fs.clone();
+                handles.push(tokio::spawn(async move {
+                    let (fh, attr) = fs
+                        .create(
+                            ROOT_INODE,
+                            &SecretString::new(format!("test-file-{i}")),
+                            create_attr(FileType::RegularFile),
+                            false,
+                            true,
+                        )
+                        .await
+                        .unwrap();
+                    let data = vec![b'a' + i; BLOCK_SIZE * 3 + usize::from(i)];
+                    // write in small chunks so the writes interleave
+                    for (pos, chunk) in data.chunks(17).enumerate() {
+                        write_all_bytes_to_fs(&fs, attr.ino, (pos * 17) as u64, chunk, fh)
+                            .await
+                            .unwrap();
+                    }
+                    fs.release(fh).await.unwrap();
+                    (attr.ino, data)
+                }));
+            }
+            for h in handles {
+                let (ino, data) = h.await.unwrap();
+                assert_eq!(data.len() as u64, fs.get_attr(ino).await.unwrap().size);
+                let fh = fs.open(ino, true, false).await.unwrap();
+                let mut buf = vec![0; data.len()];
+                test_common::read_exact(&fs, ino, 0, &mut buf, fh).await;
+                fs.release(fh).await.unwrap();
+                assert_eq!(data, buf);
+            }
+            assert_eq!(8, fs.len(ROOT_INODE).unwrap());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_copy_file_range_large() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_copy_file_range_large",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            let (fh, attr_1) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file-1").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    true,
+                    true,
+                )
+                .await
+                .unwrap();
+            let data: Vec<u8> = b"0123456789"
+                .iter()
+                .copied()
+                .cycle()
+                .take(2 * 1024 * 1024)
+                .collect();
+            write_all_bytes_to_fs(&fs, attr_1.ino, 0, &data, fh)
+                .await
+                .unwrap();
+            let (fh2, attr_2) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file-2").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let len = fs
+                .copy_file_range(attr_1.ino, 0, attr_2.ino, 0, data.len(), fh, fh2)
+                .await
+                .unwrap();
+            assert_eq!(data.len(), len);
+            fs.release(fh).await.unwrap();
+            fs.release(fh2).await.unwrap();
+
+            let fh = fs.open(attr_2.ino, true, false).await.unwrap();
+            let mut buf = vec![0; data.len()];
+            test_common::read_exact(&fs, attr_2.ino, 0, &mut buf, fh).await;
+            fs.release(fh).await.unwrap();
+            assert_eq!(data, buf);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_copy_file_range_overlapping() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_copy_file_range_overlapping",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            let data: Vec<u8> = b"0123456789abcdefghij"
+                .iter()
+                .copied()
+                .cycle()
+                .take(1000)
+                .collect();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    true,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, &data, fh)
+                .await
+                .unwrap();
+
+            // shift right
+            let len = fs
+                .copy_file_range(attr.ino, 0, attr.ino, 150, 800, fh, fh)
+                .await
+                .unwrap();
+            assert_eq!(800, len);
+            let mut expected = data.clone();
+            expected.copy_within(0..800, 150);
+            let mut buf = vec![0; expected.len()];
+            test_common::read_exact(&fs, attr.ino, 0, &mut buf, fh).await;
+            assert_eq!(expected, buf);
+
+            // shift left, with separate handles
+            let fh_read = fs.open(attr.ino, true, false).await.unwrap();
+            let len = fs
+                .copy_file_range(attr.ino, 250, attr.ino, 10, 750, fh_read, fh)
+                .await
+                .unwrap();
+            assert_eq!(750, len);
+            expected.copy_within(250..1000, 10);
+            fs.release(fh_read).await.unwrap();
+            fs.release(fh).await.unwrap();
+            assert_eq!(1000, fs.get_attr(attr.ino).await.unwrap().size);
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            let mut buf = vec![0; expected.len()];
+            test_common::read_exact(&fs, attr.ino, 0, &mut buf, fh).await;
+            fs.release(fh).await.unwrap();
+            assert_eq!(expected, buf);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_read_at() {
+    run_test_with_storages(TestSetup { key: "test_read_at" }, || async {
+        let fs = get_fs().await;
+
+        let (fh, attr) = fs
+            .create(
+                ROOT_INODE,
+                &SecretString::from_str("test-file").unwrap(),
+                create_attr(FileType::RegularFile),
+                false,
+                true,
+            )
+            .await
+            .unwrap();
+        let data: Vec<u8> = b"0123456789"
+            .iter()
+            .copied()
+            .cycle()
+            .take(BLOCK_SIZE * 3 + 42)
+            .collect();
+        write_all_bytes_to_fs(&fs, attr.ino, 0, &data, fh)
+            .await
+            .unwrap();
+        fs.release(fh).await.unwrap();
+        let fh = fs.open(attr.ino, true, false).await.unwrap();
+
+        // spanning multiple blocks
+        let mut buf = vec![0; BLOCK_SIZE * 2];
+        assert_eq!(
+            buf.len(),
+            fs.read_at(attr.ino, 42, &mut buf, fh).await.unwrap()
+        );
+        assert_eq!(&data[42..42 + BLOCK_SIZE * 2], &buf);
+        fs.read_exact_at(attr.ino, 42, &mut buf, fh).await.unwrap();
+        assert_eq!(&data[42..42 + BLOCK_SIZE * 2], &buf);
+
+        // exceeding file size
+        let mut buf = vec![0; 100];
+        let offset = data.len() as u64 - 10;
+        assert_eq!(10, fs.read_at(attr.ino, offset, &mut buf, fh).await.unwrap());
+        assert_eq!(&data[data.len() - 10..], &buf[..10]);
+        assert!(matches!(
+            fs.read_exact_at(attr.ino, offset, &mut buf, fh).await,
+            Err(FsError::Io { source, .. }) if source.kind() == std::io::ErrorKind::UnexpectedEof
+        ));
+
+        // at file size
+        assert_eq!(
+            0,
+            fs.read_at(attr.ino, data.len() as u64, &mut buf, fh)
+                .await
+                .unwrap()
+        );
+        fs.read_exact_at(attr.ino, data.len() as u64, &mut [], fh)
+            .await
+            .unwrap();
+        fs.release(fh).await.unwrap();
+    })
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_read_invalid_handle_and_past_eof() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_read_invalid_handle_and_past_eof",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    true,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, b"test-42", fh)
+                .await
+                .unwrap();
+
+            // bogus handle
+            let mut buf = [0; 10];
+            assert!(matches!(
+                fs.read(attr.ino, 0, &mut buf, 42_000).await,
+                Err(FsError::InvalidFileHandle)
+            ));
+            assert!(matches!(
+                fs.write(attr.ino, 0, b"test", 42_000).await,
+                Err(FsError::InvalidFileHandle)
+            ));
+            // released handle
+            fs.release(fh).await.unwrap();
+            assert!(matches!(
+                fs.read(attr.ino, 0, &mut buf, fh).await,
+                Err(FsError::InvalidFileHandle)
+            ));
+
+            // past EOF
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            assert_eq!(0, fs.read(attr.ino, 7, &mut buf, fh).await.unwrap());
+            assert_eq!(0, fs.read(attr.ino, 42, &mut buf, fh).await.unwrap());
+            assert_eq!(0, fs.read(attr.ino, u64::MAX, &mut buf, fh).await.unwrap());
+            // still usable after that
+            assert_eq!(7, fs.read_at(attr.ino, 0, &mut buf, fh).await.unwrap());
+            assert_eq!(b"test-42", &buf[..7]);
+            fs.release(fh).await.unwrap();
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_attr_cache() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_attr_cache",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            let name = SecretString::from_str("test-file").unwrap();
+            let (_, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &name,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let attr = fs.get_attr(attr.ino).await.unwrap();
+
+            // after the first read we don't touch the file anymore
+            let ino_file = EncryptedFs::ino_file(attr.ino);
+            let content = fs.storage.read(&ino_file).unwrap();
+            fs.storage.write(&ino_file, b"corrupted").unwrap();
+            assert_eq!(attr, fs.get_attr(attr.ino).await.unwrap());
+            assert!(fs.get_inode_from_storage(attr.ino).await.is_err());
+            fs.storage.write(&ino_file, &content).unwrap();
+
+            // writes go to disk too
+            fs.set_attr(attr.ino, SetFileAttr::default().with_perm(0o600))
+                .await
+                .unwrap();
+            assert_eq!(0o600, fs.get_attr(attr.ino).await.unwrap().perm);
+            assert_eq!(
+                0o600,
+                fs.get_inode_from_storage(attr.ino).await.unwrap().perm
+            );
+
+            // removed from cache on delete
+            fs.remove_file(ROOT_INODE, &name).await.unwrap();
+            assert!(fs.get_attr(attr.ino).await.is_err());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_cache_sizes() {
+    let data_dir = TESTS_DATA_DIR.join("test_cache_sizes");
+    let _ = fs::remove_dir_all(&data_dir);
+
+    assert!(matches!(
+        EncryptedFs::new_with(
+            data_dir.clone(),
+            Box::new(TestPasswordProvider("password")),
+            Cipher::ChaCha20Poly1305,
+            FsOptions::default().with_attr_cache_size(0),
+        )
+        .await,
+        Err(FsError::InvalidInput(_))
+    ));
+
+    let fs = EncryptedFs::new_with(
+        data_dir.clone(),
+        Box::new(TestPasswordProvider("password")),
+        Cipher::ChaCha20Poly1305,
+        FsOptions::default().with_attr_cache_size(1),
+    )
+    .await
+    .unwrap();
+    let mut attrs = vec![];
+    for name in ["file-1", "file-2"] {
+        let (_, attr) = fs
+            .create(
+                ROOT_INODE,
+                &SecretString::from_str(name).unwrap(),
+                create_attr(FileType::RegularFile),
+                false,
+                false,
+            )
+            .await
+            .unwrap();
+        attrs.push(attr);
+    }
+    for attr in &attrs {
+        fs.get_attr(attr.ino).await.unwrap();
+    }
+
+    // only the last one is kept, the first one is read from disk again
+    let ino_file = data_dir.join(EncryptedFs::ino_file(attrs[0].ino));
+    fs::write(&ino_file, b"corrupted").unwrap();
+    assert!(fs.get_attr(attrs[0].ino).await.is_err());
+    let ino_file = data_dir.join(EncryptedFs::ino_file(attrs[1].ino));
+    fs::write(&ino_file, b"corrupted").unwrap();
+    assert_eq!(attrs[1].ino, fs.get_attr(attrs[1].ino).await.unwrap().ino);
+    drop(fs);
+
+    fs::remove_dir_all(data_dir).unwrap();
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_next_handle() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_next_handle",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            let handles: Vec<u64> = std::thread::scope(|s| {
+                let threads: Vec<_> = (0..8)
+                    .map(|_| {
+                        s.spawn(|| {
+                            (0..1000)
+                                .map(|_| fs.next_handle().unwrap())
+                                .collect::<Vec<_>>()
+                        })
+                    })
+                    .collect();
+                threads
+                    .into_iter()
+                    .flat_map(|t| t.join().unwrap())
+                    .collect()
+            });
+            let unique: HashSet<u64> = handles.iter().copied().collect();
+            assert_eq!(8000, handles.len());
+            assert_eq!(handles.len(), unique.len());
+            assert!(!unique.contains(&0));
+
+            // we don't wrap around
+            fs.current_handle
+                .store(u64::MAX - 1, std::sync::atomic::Ordering::SeqCst);
+            assert_eq!(u64::MAX - 1, fs.next_handle().unwrap());
+            assert!(matches!(fs.next_handle(), Err(FsError::Other(_))));
+            assert!(matches!(
+                fs.create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    true,
+                    false,
+                )
+                .await,
+                Err(FsError::Other(_))
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_lookup_path() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_lookup_path",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            let (_, dir_a) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("a").unwrap(),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let (_, dir_b) = fs
+                .create(
+                    dir_a.ino,
+                    &SecretString::from_str("b").unwrap(),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let (_, file) = fs
+                .create(
+                    dir_b.ino,
+                    &SecretString::from_str("c.txt").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+
+            // nested
+            for path in ["/a/b/c.txt", "a/b/c.txt", "//a/./b//c.txt"] {
+                assert_eq!(file.ino, fs.lookup_path(path).await.unwrap().ino);
+            }
+            assert_eq!(dir_b.ino, fs.lookup_path("/a/b/").await.unwrap().ino);
+            for path in ["/", "", ".", "/.."] {
+                assert_eq!(ROOT_INODE, fs.lookup_path(path).await.unwrap().ino);
+            }
+
+            // with ..
+            assert_eq!(dir_a.ino, fs.lookup_path("/a/b/..").await.unwrap().ino);
+            assert_eq!(
+                file.ino,
+                fs.lookup_path("/a/b/../b/c.txt").await.unwrap().ino
+            );
+
+            // missing component
+            assert!(matches!(
+                fs.lookup_path("/a/missing/c.txt").await,
+                Err(FsError::NotFound(_))
+            ));
+            assert!(matches!(
+                fs.lookup_path("/a/b/missing.txt").await,
+                Err(FsError::NotFound(_))
+            ));
+            // file in the middle
+            assert!(matches!(
+                fs.lookup_path("/a/b/c.txt/d").await,
+                Err(FsError::InvalidInodeType)
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_create_path() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_create_path",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            // deep creation
+            let attr = fs
+                .create_dir_all_path("/a/b/c/d", create_attr(FileType::Directory))
+                .await
+                .unwrap();
+            assert_eq!(FileType::Directory, attr.kind);
+            assert_eq!(attr.ino, fs.lookup_path("/a/b/c/d").await.unwrap().ino);
+            // existing is a no-op
+            let attr2 = fs
+                .create_dir_all_path("a/b/c/d/", create_attr(FileType::Directory))
+                .await
+                .unwrap();
+            assert_eq!(attr.ino, attr2.ino);
+            assert_eq!(1, fs.len(ROOT_INODE).unwrap());
+            assert_eq!(
+                1,
+                fs.len(fs.lookup_path("/a/b").await.unwrap().ino).unwrap()
+            );
+
+            let (_, file) = fs
+                .create_file_path(
+                    "/a/x/y/f.txt",
+                    CreateFileAttr {
+                        perm: 0o644,
+                        ..create_attr(FileType::RegularFile)
+                    },
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            assert_eq!(FileType::RegularFile, file.kind);
+            assert_eq!(file.ino, fs.lookup_path("/a/x/y/f.txt").await.unwrap().ino);
+            let dir = fs.lookup_path("/a/x/y").await.unwrap();
+            assert_eq!(FileType::Directory, dir.kind);
+            assert_eq!(0o755, dir.perm);
+            assert_eq!(0o644, file.perm);
+            assert!(matches!(
+                fs.create_file_path(
+                    "/a/x/y/f.txt",
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await,
+                Err(FsError::AlreadyExists)
+            ));
+
+            // file in the way
+            assert!(matches!(
+                fs.create_dir_all_path("/a/x/y/f.txt/z", create_attr(FileType::Directory))
+                    .await,
+                Err(FsError::InvalidInodeType)
+            ));
+            assert!(matches!(
+                fs.create_dir_all_path("/a/x/y/f.txt", create_attr(FileType::Directory))
+                    .await,
+                Err(FsError::InvalidInodeType)
+            ));
+            assert!(matches!(
+                fs.create_file_path(
+                    "/a/x/y/f.txt/g.txt",
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await,
+                Err(FsError::InvalidInodeType)
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_blocks() {
+    run_test_with_storages(TestSetup { key: "test_blocks" }, || async {
+        let fs = get_fs().await;
+
+        let (fh, attr) = fs
+            .create(
+                ROOT_INODE,
+                &SecretString::from_str("test-file").unwrap(),
+                create_attr(FileType::RegularFile),
+                false,
+                true,
+            )
+            .await
+            .unwrap();
+        assert_eq!(0, attr.blocks);
+        assert_eq!(BLKSIZE, attr.blksize);
+        write_all_bytes_to_fs(&fs, attr.ino, 0, &[42; 5000], fh)
+            .await
+            .unwrap();
+        // while still open
+        let attr_open = fs.get_attr(attr.ino).await.unwrap();
+        assert_eq!(5000, attr_open.size);
+        assert_eq!(10, attr_open.blocks);
+        fs.release(fh).await.unwrap();
+
+        let attr = fs.get_inode_from_storage(attr.ino).await.unwrap();
+        assert_eq!(10, attr.blocks);
+        assert_eq!(BLKSIZE, attr.blksize);
+
+        fs.set_len(attr.ino, 512).await.unwrap();
+        assert_eq!(1, fs.get_attr(attr.ino).await.unwrap().blocks);
+        assert_eq!(BLKSIZE, fs.get_attr(ROOT_INODE).await.unwrap().blksize);
+    })
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_verify() {
+    run_test_with_storages(TestSetup { key: "test_verify" }, || async {
+        let fs = get_fs().await;
+
+        let (_, dir) = fs
+            .create(
+                ROOT_INODE,
+                &SecretString::from_str("test-dir").unwrap(),
+                create_attr(FileType::Directory),
+                false,
+                false,
+            )
+            .await
+            .unwrap();
+        let mut files = vec![];
+        for i in 0..3 {
+            let (_, attr) = fs
+                .create(
+                    dir.ino,
+                    &SecretString::new(format!("test-file-{i}")),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            files.push(attr);
+        }
+        fs.link(
+            files[0].ino,
+            ROOT_INODE,
+            &SecretString::from_str("test-link").unwrap(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(Vec::<Inconsistency>::new(), fs.verify().await.unwrap());
+
+        // dangling entry
+        fs.storage
+            .remove_file(&EncryptedFs::ino_file(files[1].ino))
+            .unwrap();
+        fs.storage
+            .remove_file(&EncryptedFs::contents_path(files[1].ino))
+            .unwrap();
+        // missing contents
+        fs.storage
+            .remove_file(&EncryptedFs::contents_path(files[2].ino))
+            .unwrap();
+
+        let res = fs.verify().await.unwrap();
+        assert_eq!(2, res.len());
+        assert!(res.contains(&Inconsistency::DanglingEntry {
+            parent: dir.ino,
+            ino: files[1].ino,
+        }));
+        assert!(res.contains(&Inconsistency::MissingContents(files[2].ino)));
+        // nothing was changed
+        assert_eq!(res, fs.verify().await.unwrap());
+    })
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_repair() {
+    run_test_with_storages(TestSetup { key: "test_repair" }, || async {
+        let fs = get_fs().await;
+
+        let name = SecretString::from_str("test-file").unwrap();
+        let (fh, attr) = fs
+            .create(
+                ROOT_INODE,
+                &name,
+                create_attr(FileType::RegularFile),
+                false,
+                true,
+            )
+            .await
+            .unwrap();
+        write_all_bytes_to_fs(&fs, attr.ino, 0, b"test-42", fh)
+            .await
+            .unwrap();
+        fs.release(fh).await.unwrap();
+        let dir_name = SecretString::from_str("test-dir").unwrap();
+        let (_, dir) = fs
+            .create(
+                ROOT_INODE,
+                &dir_name,
+                create_attr(FileType::Directory),
+                false,
+                false,
+            )
+            .await
+            .unwrap();
+        let (_, sub_dir) = fs
+            .create(
+                dir.ino,
+                &SecretString::from_str("sub-dir").unwrap(),
+                create_attr(FileType::Directory),
+                false,
+                false,
+            )
+            .await
+            .unwrap();
+
+        // remove the entries but keep the inodes
+        fs.remove_directory_entry(ROOT_INODE, &name).await.unwrap();
+        fs.remove_directory_entry(ROOT_INODE, &dir_name)
+            .await
+            .unwrap();
+        // and the `..` of a directory
+        fs.storage
+            .remove_file(
+                &EncryptedFs::contents_path(sub_dir.ino)
+                    .join(LS_DIR)
+                    .join("$.."),
+            )
+            .unwrap();
+        fs.storage
+            .remove_file(
+                &EncryptedFs::contents_path(sub_dir.ino)
+                    .join(HASH_DIR)
+                    .join("$.."),
+            )
+            .unwrap();
+        let inconsistencies = fs.verify().await.unwrap();
+        assert_eq!(3, inconsistencies.len());
+
+        let repaired = fs.repair().await.unwrap();
+        assert_eq!(inconsistencies.len(), repaired.len());
+        assert_eq!(Vec::<Inconsistency>::new(), fs.verify().await.unwrap());
+        // idempotent
+        assert_eq!(Vec::<Inconsistency>::new(), fs.repair().await.unwrap());
+
+        // data is reachable
+        let found = fs
+            .lookup_path(&format!("/{LOST_AND_FOUND_DIR}/{}", attr.ino))
+            .await
+            .unwrap();
+        assert_eq!(attr.ino, found.ino);
+        assert_eq!("test-42", test_common::read_to_string(found.ino, &fs).await);
+        let found = fs
+            .lookup_path(&format!("/{LOST_AND_FOUND_DIR}/{}/sub-dir/..", dir.ino))
+            .await
+            .unwrap();
+        assert_eq!(dir.ino, found.ino);
+        let found = fs
+            .lookup_path(&format!("/{LOST_AND_FOUND_DIR}/{}/..", dir.ino))
+            .await
+            .unwrap();
+        assert_eq!(
+            fs.lookup_path(&format!("/{LOST_AND_FOUND_DIR}"))
+                .await
+                .unwrap()
+                .ino,
+            found.ino
+        );
+    })
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_tar_round_trip() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_tar_round_trip",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            let file_attr = CreateFileAttr {
+                perm: 0o640,
+                uid: 42,
+                gid: 43,
+                ..create_attr(FileType::RegularFile)
+            };
+            let dir_attr = CreateFileAttr {
+                perm: 0o750,
+                ..create_attr(FileType::Directory)
+            };
+            fs.create_dir_all_path("/dir/sub-dir", dir_attr)
+                .await
+                .unwrap();
+            let data: Vec<u8> = b"0123456789"
+                .iter()
+                .copied()
+                .cycle()
+                .take(BLOCK_SIZE * 3 + 42)
+                .collect();
+            let (fh, attr) = fs
+                .create_file_path("/dir/sub-dir/file", file_attr.clone(), false, true)
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, &data, fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            let (fh, empty) = fs
+                .create_file_path("/empty", file_attr, false, true)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            let dir = fs.lookup_path("/dir").await.unwrap();
+            fs.link(
+                attr.ino,
+                dir.ino,
+                &SecretString::from_str("hard-link").unwrap(),
+            )
+            .await
+            .unwrap();
+            fs.create_symlink(
+                ROOT_INODE,
+                &SecretString::from_str("symlink").unwrap(),
+                &SecretString::from_str("dir/sub-dir/file").unwrap(),
+                create_attr(FileType::Symlink),
+            )
+            .await
+            .unwrap();
+            fs.create(
+                dir.ino,
+                &SecretString::from_str("device").unwrap(),
+                CreateFileAttr {
+                    rdev: (8 << 8) | 1,
+                    ..create_attr(FileType::BlockDevice)
+                },
+                false,
+                false,
+            )
+            .await
+            .unwrap();
+            let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+            for ino in [attr.ino, empty.ino, dir.ino] {
+                fs.update_attr(ino, SetFileAttr::default().with_mtime(mtime))
+                    .await
+                    .unwrap();
+            }
+
+            let archive = fs.export_tar(vec![]).await.unwrap();
+
+            let data_dir = TESTS_DATA_DIR.join("test_tar_round_trip_import");
+            let _ = fs::remove_dir_all(&data_dir);
+            let fs2 = EncryptedFs::new(
+                data_dir.clone(),
+                Box::new(TestPasswordProvider("password")),
+                Cipher::ChaCha20Poly1305,
+            )
+            .await
+            .unwrap();
+            fs2.import_tar(archive.as_slice()).await.unwrap();
+
+            let summary = |walk: WalkIterator| {
+                let mut res: Vec<_> = walk
+                    .map(|entry| {
+                        let (path, attr) = entry.unwrap();
+                        (
+                            path, attr.kind, attr.perm, attr.uid, attr.gid, attr.size, attr.rdev,
+                        )
+                    })
+                    .collect();
+                res.sort_by(|a, b| a.0.cmp(&b.0));
+                res
+            };
+            assert_eq!(
+                summary(fs.walk(ROOT_INODE).await.unwrap()),
+                summary(fs2.walk(ROOT_INODE).await.unwrap())
+            );
+
+            let attr2 = fs2.lookup_path("/dir/sub-dir/file").await.unwrap();
+            assert_eq!(mtime, attr2.mtime);
+            assert_eq!(2, attr2.nlink);
+            assert_eq!(
+                attr2.ino,
+                fs2.lookup_path("/dir/hard-link").await.unwrap().ino
+            );
+            assert_eq!(mtime, fs2.lookup_path("/dir").await.unwrap().mtime);
+            assert_eq!(
+                String::from_utf8(data).unwrap(),
+                test_common::read_to_string(attr2.ino, &fs2).await
+            );
+            let symlink = fs2.lookup_path("/symlink").await.unwrap();
+            assert_eq!(
+                "dir/sub-dir/file",
+                fs2.read_link(symlink.ino).await.unwrap().expose_secret()
+            );
+
+            // we don't merge into existing content
+            assert!(matches!(
+                fs2.import_tar(archive.as_slice()).await,
+                Err(FsError::NotEmpty(ROOT_INODE))
+            ));
+            assert_eq!(
+                summary(fs.walk(ROOT_INODE).await.unwrap()),
+                summary(fs2.walk(ROOT_INODE).await.unwrap())
+            );
+
+            drop(fs2);
+            fs::remove_dir_all(&data_dir).unwrap();
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_create_special_files() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_create_special_files",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            let fifo_name = SecretString::from_str("fifo").unwrap();
+            let (fh, fifo) = fs
+                .create(
+                    ROOT_INODE,
+                    &fifo_name,
+                    create_attr(FileType::NamedPipe),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            assert_eq!(0, fh);
+            // /dev/null is major 1 minor 3
+            let rdev = (1 << 8) | 3;
+            let device_name = SecretString::from_str("null").unwrap();
+            let (_, device) = fs
+                .create(
+                    ROOT_INODE,
+                    &device_name,
+                    CreateFileAttr {
+                        rdev,
+                        ..create_attr(FileType::CharDevice)
+                    },
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+
+            let attr = fs.get_attr(fifo.ino).await.unwrap();
+            assert_eq!(FileType::NamedPipe, attr.kind);
+            assert_eq!(0, attr.rdev);
+            let attr = fs.get_inode_from_storage(device.ino).await.unwrap();
+            assert_eq!(FileType::CharDevice, attr.kind);
+            assert_eq!(rdev, attr.rdev);
+            let attr = fs
+                .find_by_name(ROOT_INODE, &device_name)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(FileType::CharDevice, attr.kind);
+            let kinds: Vec<_> = fs
+                .read_dir(ROOT_INODE)
+                .await
+                .unwrap()
+                .map(Result::unwrap)
+                .filter(|entry| entry.ino == fifo.ino || entry.ino == device.ino)
+                .map(|entry| entry.kind)
+                .collect();
+            assert_eq!(2, kinds.len());
+            assert!(kinds.contains(&FileType::NamedPipe));
+            assert!(kinds.contains(&FileType::CharDevice));
+            assert_eq!(Vec::<Inconsistency>::new(), fs.verify().await.unwrap());
+
+            fs.remove_file(ROOT_INODE, &fifo_name).await.unwrap();
+            assert!(!fs.exists(fifo.ino));
+        },
+    )
+    .await;
+}
+
+#[test]
+fn test_to_errno() {
+    for (err, errno) in [
+        (FsError::NotFound("test"), libc::ENOENT),
+        (FsError::InodeNotFound(42), libc::ENOENT),
+        (FsError::AlreadyExists, libc::EEXIST),
+        (FsError::NotEmpty(42), libc::ENOTEMPTY),
+        (FsError::NotADirectory, libc::ENOTDIR),
+        (FsError::IsADirectory, libc::EISDIR),
+        (FsError::InvalidInput("test"), libc::EINVAL),
+        (FsError::InvalidInodeType, libc::EINVAL),
+        (FsError::PermissionDenied, libc::EACCES),
+        (FsError::NameTooLong, libc::ENAMETOOLONG),
+        (FsError::QuotaExceeded, libc::EDQUOT),
+        (FsError::DataDirInUse, libc::EBUSY),
+        (FsError::ReadOnly, libc::EROFS),
+        (FsError::InvalidFileHandle, libc::EIO),
+        (FsError::AlreadyOpenForWrite, libc::EIO),
+        (FsError::Other("test"), libc::EIO),
+        (FsError::InvalidPassword, libc::EIO),
+        (FsError::CorruptedInode(42), libc::EIO),
+        (FsError::MaxFilesizeExceeded(42), libc::EIO),
+        (io::Error::other("test").into(), libc::EIO),
+    ] {
+        assert_eq!(errno, err.to_errno(), "{err:?}");
+    }
+}
+
+/// Writes some data then fails, like a crash in the middle of serializing.
+struct FailingSerialize;
+
+impl serde::Serialize for FailingSerialize {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::{Error, SerializeTuple};
+
+        let mut tuple = serializer.serialize_tuple(2)?;
+        tuple.serialize_element(&"x".repeat(BLOCK_SIZE * 3))?;
+        Err(S::Error::custom("failed in the middle"))
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_failed_inode_write_keeps_previous() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_failed_inode_write_keeps_previous",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            let (_, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let res = atomic_serialize_encrypt_into(
+                &*fs.storage,
+                &EncryptedFs::ino_file(attr.ino),
+                &FailingSerialize,
+                fs.cipher,
+                &*fs.key.get().await.unwrap(),
+            );
+            assert!(res.is_err());
+
+            let attr2 = fs.get_inode_from_storage(attr.ino).await.unwrap();
+            assert_eq!(attr.ino, attr2.ino);
+            assert_eq!(attr.kind, attr2.kind);
+            assert_eq!(attr.size, attr2.size);
+            // no temp files left behind
+            for name in fs.storage.read_dir(Path::new(INODES_DIR)).unwrap() {
+                assert!(name.to_string_lossy().parse::<u64>().is_ok(), "{name:?}");
+            }
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_read_corrupted_content() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_read_corrupted_content",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let data: Vec<u8> = b"0123456789"
+                .iter()
+                .copied()
+                .cycle()
+                .take(BLOCK_SIZE * 3)
+                .collect();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, &data, fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+
+            // flip a byte in the second block
+            let path = EncryptedFs::contents_path(attr.ino);
+            let mut contents = fs.storage.read(&path).unwrap();
+            let ciphertext_block_size = (contents.len() - HEADER_LEN) / 3;
+            contents[HEADER_LEN + ciphertext_block_size + NONCE_LEN + 5] ^= 1;
+            fs.storage.write(&path, &contents).unwrap();
+
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            // first block is still fine
+            let mut buf = vec![0; 10];
+            assert_eq!(10, fs.read(attr.ino, 0, &mut buf, fh).await.unwrap());
+            assert_eq!(&data[..10], &buf[..]);
+            let mut buf = vec![0; data.len()];
+            let offset = BLOCK_SIZE as u64 + 1;
+            assert!(matches!(
+                fs.read(attr.ino, offset, &mut buf, fh).await,
+                Err(FsError::IntegrityError { ino, offset: o }) if ino == attr.ino && o == offset
+            ));
+            fs.release(fh).await.unwrap();
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_read_zeroed_block() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_read_zeroed_block",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let data: Vec<u8> = b"0123456789"
+                .iter()
+                .copied()
+                .cycle()
+                .take(BLOCK_SIZE * 3)
+                .collect();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, &data, fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+
+            // zero the second block, it looks like a hole now
+            let path = EncryptedFs::contents_path(attr.ino);
+            let mut contents = fs.storage.read(&path).unwrap();
+            let ciphertext_block_size = (contents.len() - HEADER_LEN) / 3;
+            contents[HEADER_LEN + ciphertext_block_size..HEADER_LEN + ciphertext_block_size * 2]
+                .fill(0);
+            fs.storage.write(&path, &contents).unwrap();
+
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            let mut buf = vec![0; data.len()];
+            let offset = BLOCK_SIZE as u64 + 1;
+            assert!(matches!(
+                fs.read(attr.ino, offset, &mut buf, fh).await,
+                Err(FsError::IntegrityError { ino, offset: o }) if ino == attr.ino && o == offset
+            ));
+            fs.release(fh).await.unwrap();
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_create_node_without_handles() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_create_node_without_handles",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            let handle = fs.next_handle().unwrap();
+            for i in 0..1000 {
+                let attr = fs
+                    .create_node(
+                        ROOT_INODE,
+                        &SecretString::new(format!("test-file-{i}")),
+                        create_attr(FileType::RegularFile),
+                    )
+                    .await
+                    .unwrap();
+                assert_eq!(FileType::RegularFile, attr.kind);
+            }
+            assert!(fs.read_handles.read().await.is_empty());
+            assert!(fs.write_handles.read().await.is_empty());
+            assert_eq!(handle + 1, fs.next_handle().unwrap());
+            assert_eq!(
+                1000,
+                fs.read_dir_filter(ROOT_INODE, FileType::RegularFile)
+                    .await
+                    .unwrap()
+                    .count()
+            );
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_root_dot_dot() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_root_dot_dot",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            let dot_dot = SecretString::from_str("..").unwrap();
+            assert!(fs.exists_by_name(ROOT_INODE, &dot_dot).await.unwrap());
+            let attr = fs
+                .find_by_name(ROOT_INODE, &dot_dot)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(ROOT_INODE, attr.ino);
+            assert_eq!(FileType::Directory, attr.kind);
+
+            // other directories still resolve to their parent
+            let dir = fs
+                .create_node(
+                    ROOT_INODE,
+                    &SecretString::from_str("dir").unwrap(),
+                    create_attr(FileType::Directory),
+                )
+                .await
+                .unwrap();
+            let sub_dir = fs
+                .create_node(
+                    dir.ino,
+                    &SecretString::from_str("sub-dir").unwrap(),
+                    create_attr(FileType::Directory),
+                )
+                .await
+                .unwrap();
+            let attr = fs
+                .find_by_name(sub_dir.ino, &dot_dot)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(dir.ino, attr.ino);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_dot_names_not_changed() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_dot_names_not_changed",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            let (fh, file) = fs
+                .create_file_path(
+                    "/dir/sub-dir/file",
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            let dir = fs.lookup_path("/dir").await.unwrap();
+            let sub_dir = fs.lookup_path("/dir/sub-dir").await.unwrap();
+            let tree = || async {
+                let mut res: Vec<_> = fs
+                    .walk(ROOT_INODE)
+                    .await
+                    .unwrap()
+                    .map(|entry| {
+                        let (path, attr) = entry.unwrap();
+                        (path, attr.ino)
+                    })
+                    .collect();
+                res.sort();
+                res
+            };
+            let before = tree().await;
+
+            let x = SecretString::from_str("x").unwrap();
+            let file_name = SecretString::from_str("file").unwrap();
+            for name in [".", "..", "$.", "$.."] {
+                let name = SecretString::from_str(name).unwrap();
+                for parent in [ROOT_INODE, dir.ino, sub_dir.ino] {
+                    let check = |res: FsResult<()>| {
+                        assert!(
+                            matches!(res, Err(FsError::InvalidInput(_))),
+                            "{} in {parent}: {res:?}",
+                            name.expose_secret()
+                        );
+                    };
+                    check(fs.remove_dir(parent, &name).await);
+                    check(fs.remove_dir_all(parent, &name).await);
+                    check(fs.remove_file(parent, &name).await);
+                    check(fs.rename(parent, &name, sub_dir.ino, &x).await);
+                    check(fs.rename(sub_dir.ino, &file_name, parent, &name).await);
+                    check(
+                        fs.can_rename_with(parent, &name, sub_dir.ino, &x, RenameFlags::default())
+                            .await,
+                    );
+                    check(fs.exchange(parent, &name, sub_dir.ino, &file_name).await);
+                    check(fs.exchange(sub_dir.ino, &file_name, parent, &name).await);
+                }
+            }
+            assert_eq!(before, tree().await);
+            assert_eq!(
+                file.ino,
+                fs.lookup_path("/dir/sub-dir/file").await.unwrap().ino
+            );
+            // lookups still resolve them
+            assert_eq!(
+                ROOT_INODE,
+                fs.find_by_name(ROOT_INODE, &SecretString::from_str("..").unwrap())
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino
+            );
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_disk_usage() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_disk_usage",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            let attr = create_attr(FileType::RegularFile);
+            let files = [
+                ("/a", BLOCK_SIZE * 2 + 42),
+                ("/dir/b", 5),
+                ("/dir/sub-dir/c", BLOCK_SIZE),
+            ];
+            let mut inodes = vec![];
+            for (path, len) in files {
+                // parent directories are created too
+                let (fh, file) = fs
+                    .create_file_path(path, attr.clone(), false, true)
+                    .await
+                    .unwrap();
+                let data: Vec<u8> = b"0123456789".iter().copied().cycle().take(len).collect();
+                write_all_bytes_to_fs(&fs, file.ino, 0, &data, fh)
+                    .await
+                    .unwrap();
+                fs.release(fh).await.unwrap();
+                inodes.push(file.ino);
+            }
+            // hard link in another directory is counted once
+            let sub_dir = fs.lookup_path("/dir/sub-dir").await.unwrap();
+            fs.link(
+                inodes[0],
+                sub_dir.ino,
+                &SecretString::from_str("a-link").unwrap(),
+            )
+            .await
+            .unwrap();
+            fs.create_dir_all_path("/empty", create_attr(FileType::Directory))
+                .await
+                .unwrap();
+
+            // /a is also linked under /dir, so both have the same total
+            let total = (BLOCK_SIZE * 3 + 42 + 5) as u64;
+            assert_eq!(total, fs.disk_usage(ROOT_INODE).await.unwrap());
+            let dir = fs.lookup_path("/dir").await.unwrap();
+            assert_eq!(total, fs.disk_usage(dir.ino).await.unwrap());
+            assert_eq!(
+                (BLOCK_SIZE * 3 + 42) as u64,
+                fs.disk_usage(sub_dir.ino).await.unwrap()
+            );
+            assert_eq!(5, fs.disk_usage(inodes[1]).await.unwrap());
+            let empty = fs.lookup_path("/empty").await.unwrap();
+            assert_eq!(0, fs.disk_usage(empty.ino).await.unwrap());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_check_access() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_check_access",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            let (owner, group, other) = (1000, 1000, 2000);
+            let (_, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
+                    CreateFileAttr {
+                        perm: 0o640,
+                        uid: owner,
+                        gid: group,
+                        ..create_attr(FileType::RegularFile)
+                    },
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+
+            fs.check_access(attr.ino, owner, group, libc::R_OK | libc::W_OK)
+                .await
+                .unwrap();
+            fs.check_access(attr.ino, other, group, libc::R_OK)
+                .await
+                .unwrap();
+            assert!(matches!(
+                fs.check_access(attr.ino, other, group, libc::W_OK).await,
+                Err(FsError::PermissionDenied)
+            ));
+            assert!(matches!(
+                fs.check_access(attr.ino, other, other, libc::R_OK).await,
+                Err(FsError::PermissionDenied)
+            ));
+            // root can write anything but not execute without an x bit
+            fs.check_access(attr.ino, 0, 0, libc::W_OK).await.unwrap();
+            assert!(matches!(
+                fs.check_access(attr.ino, 0, 0, libc::X_OK).await,
+                Err(FsError::PermissionDenied)
+            ));
+
+            // not enforced by default
+            let fh = fs.open(attr.ino, false, true).await.unwrap();
+            fs.release(fh).await.unwrap();
+
+            fs.set_enforce_permissions(Some((owner, group)));
+            let fh = fs.open(attr.ino, true, true).await.unwrap();
+            fs.release(fh).await.unwrap();
+
+            fs.set_enforce_permissions(Some((other, other)));
+            assert!(matches!(
+                fs.open(attr.ino, false, true).await,
+                Err(FsError::PermissionDenied)
+            ));
+            assert!(matches!(
+                fs.open(attr.ino, true, false).await,
+                Err(FsError::PermissionDenied)
+            ));
+            // root dir is 0o755
+            assert!(fs.read_dir(ROOT_INODE).await.is_ok());
+
+            fs.set_enforce_permissions(None);
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            fs.release(fh).await.unwrap();
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_sparse_file() {
+    run_test(
+        TestSetup {
+            key: "test_sparse_file",
+        },
+        async {
+            use std::os::unix::fs::MetadataExt;
+
+            let fs = get_fs().await;
+
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let offset = 100 * 1024 * 1024;
+            assert_eq!(1, fs.write(attr.ino, offset, b"x", fh).await.unwrap());
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            assert_eq!(offset + 1, fs.get_attr(attr.ino).await.unwrap().size);
+
+            // only the last blocks are actually stored
+            let metadata = fs::metadata(
+                TESTS_DATA_DIR
+                    .join("test_sparse_file")
+                    .join(EncryptedFs::contents_path(attr.ino)),
+            )
+            .unwrap();
+            assert!(metadata.len() > offset);
+            assert!(metadata.blocks() * 512 < 1024 * 1024);
+
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            let mut buf = vec![1; 1024];
+            assert_eq!(1024, fs.read(attr.ino, 0, &mut buf, fh).await.unwrap());
+            assert!(buf.iter().all(|b| *b == 0));
+            let mut buf = vec![1; 1024];
+            assert_eq!(
+                1024,
+                fs.read(attr.ino, offset - 1023, &mut buf, fh)
+                    .await
+                    .unwrap()
+            );
+            assert!(buf[..1023].iter().all(|b| *b == 0));
+            assert_eq!(b'x', buf[1023]);
+            fs.release(fh).await.unwrap();
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_rename_over_file_removes_its_data() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_rename_over_file_removes_its_data",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            let a = SecretString::from_str("a.txt").unwrap();
+            let b = SecretString::from_str("b.txt").unwrap();
+            let mut attrs = vec![];
+            for name in [&a, &b] {
+                let (fh, attr) = fs
+                    .create(
+                        ROOT_INODE,
+                        name,
+                        create_attr(FileType::RegularFile),
+                        false,
+                        true,
+                    )
+                    .await
+                    .unwrap();
+                write_all_string_to_fs(&fs, attr.ino, 0, name.expose_secret(), fh)
+                    .await
+                    .unwrap();
+                fs.release(fh).await.unwrap();
+                attrs.push(attr);
+            }
+            let (attr_a, attr_b) = (attrs[0], attrs[1]);
+
+            fs.rename(ROOT_INODE, &a, ROOT_INODE, &b).await.unwrap();
+            assert!(!fs.exists_by_name(ROOT_INODE, &a).await.unwrap());
+            assert_eq!(
+                attr_a.ino,
+                fs.find_by_name(ROOT_INODE, &b).await.unwrap().unwrap().ino
+            );
+            assert_eq!("a.txt", test_common::read_to_string(attr_a.ino, &fs).await);
+            // b's old inode and contents are gone
+            assert!(!fs.exists(attr_b.ino));
+            assert!(!fs.storage.exists(&EncryptedFs::ino_file(attr_b.ino)));
+            assert!(!fs.storage.exists(&EncryptedFs::contents_path(attr_b.ino)));
+
+            // when the destination has other links only the name is removed
+            let c = SecretString::from_str("c.txt").unwrap();
+            let (fh, attr_c) = fs
+                .create(
+                    ROOT_INODE,
+                    &c,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            let d = SecretString::from_str("d.txt").unwrap();
+            fs.link(attr_c.ino, ROOT_INODE, &d).await.unwrap();
+            fs.rename(ROOT_INODE, &b, ROOT_INODE, &c).await.unwrap();
+            assert!(fs.storage.exists(&EncryptedFs::contents_path(attr_c.ino)));
+            let attr_d = fs.find_by_name(ROOT_INODE, &d).await.unwrap().unwrap();
+            assert_eq!(attr_c.ino, attr_d.ino);
+            assert_eq!(1, attr_d.nlink);
+
+            // renaming over another link to the same inode is a no-op
+            fs.link(attr_c.ino, ROOT_INODE, &b).await.unwrap();
+            fs.rename(ROOT_INODE, &b, ROOT_INODE, &d).await.unwrap();
+            assert!(fs.exists_by_name(ROOT_INODE, &b).await.unwrap());
+            assert!(fs.exists_by_name(ROOT_INODE, &d).await.unwrap());
+            assert!(fs.storage.exists(&EncryptedFs::contents_path(attr_c.ino)));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_create_with() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_create_with",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            let name = SecretString::from_str("test-file").unwrap();
+            let flags = CreateFlags::default()
+                .with_open(OpenFlags::default().with_read(true).with_write(true));
+            let (fh, attr) = fs
+                .create_with(
+                    ROOT_INODE,
+                    &name,
+                    create_attr(FileType::RegularFile),
+                    flags.with_exclusive(true),
+                )
+                .await
+                .unwrap();
+            write_all_string_to_fs(&fs, attr.ino, 0, "existing", fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+
+            // exclusive fails on existing
+            assert!(matches!(
+                fs.create_with(
+                    ROOT_INODE,
+                    &name,
+                    create_attr(FileType::RegularFile),
+                    flags.with_exclusive(true),
+                )
+                .await,
+                Err(FsError::AlreadyExists)
+            ));
+
+            // non exclusive opens the existing one
+            let (fh, attr2) = fs
+                .create_with(ROOT_INODE, &name, create_attr(FileType::RegularFile), flags)
+                .await
+                .unwrap();
+            assert_ne!(0, fh);
+            assert_eq!(attr.ino, attr2.ino);
+            assert_eq!(8, attr2.size);
+            let mut buf = vec![0; 8];
+            fs.read_exact_at(attr.ino, 0, &mut buf, fh).await.unwrap();
+            assert_eq!(b"existing", &buf[..]);
+            fs.release(fh).await.unwrap();
+
+            // and honors the open flags
+            let (fh, attr2) = fs
+                .create_with(
+                    ROOT_INODE,
+                    &name,
+                    create_attr(FileType::RegularFile),
+                    flags.with_open(flags.open.with_truncate(true)),
+                )
+                .await
+                .unwrap();
+            assert_eq!(attr.ino, attr2.ino);
+            assert_eq!(0, attr2.size);
+            fs.release(fh).await.unwrap();
+
+            // without read or write no handle is opened
+            let (fh, _) = fs
+                .create_with(
+                    ROOT_INODE,
+                    &SecretString::from_str("no-handle").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    CreateFlags::default(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(0, fh);
+
+            // existing directories can't be opened
+            let dir = SecretString::from_str("dir").unwrap();
+            fs.create_node(ROOT_INODE, &dir, create_attr(FileType::Directory))
+                .await
+                .unwrap();
+            assert!(matches!(
+                fs.create_with(ROOT_INODE, &dir, create_attr(FileType::RegularFile), flags)
+                    .await,
+                Err(FsError::InvalidInodeType)
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_journal_recovery() {
+    let data_dir = TESTS_DATA_DIR.join("test_journal_recovery");
+    let _ = fs::remove_dir_all(&data_dir);
+    let journal_dir = data_dir.join(SECURITY_DIR).join(JOURNAL_DIR);
+    let open = || {
+        let data_dir = data_dir.clone();
+        async move {
+            EncryptedFs::new(
+                data_dir,
+                Box::new(TestPasswordProvider("password")),
+                Cipher::ChaCha20Poly1305,
+            )
+            .await
+            .unwrap()
+        }
+    };
+
+    let fs = open().await;
+    let test_file = SecretString::from_str("test-file").unwrap();
+
+    // crash after the inode is written but before it's added to parent
+    fs.fail_point.lock().unwrap().replace("create:after_inode");
+    assert!(fs
+        .create(
+            ROOT_INODE,
+            &test_file,
+            create_attr(FileType::RegularFile),
+            false,
+            false,
+        )
+        .await
+        .is_err());
+    let journal: Vec<_> = fs::read_dir(&journal_dir).unwrap().collect();
+    assert_eq!(1, journal.len());
+    let ino: u64 = journal[0]
+        .as_ref()
+        .unwrap()
+        .file_name()
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(fs.exists(ino));
+    assert!(!fs.exists_by_name(ROOT_INODE, &test_file).await.unwrap());
+    drop(fs);
+
+    // the create is rolled back
+    let fs = open().await;
+    assert!(!fs.exists(ino));
+    assert!(!fs.storage.exists(&EncryptedFs::contents_path(ino)));
+    assert_eq!(0, fs::read_dir(&journal_dir).unwrap().count());
+    assert!(fs.verify().await.unwrap().is_empty());
+
+    let (_, attr) = fs
+        .create(
+            ROOT_INODE,
+            &test_file,
+            create_attr(FileType::RegularFile),
+            false,
+            false,
+        )
+        .await
+        .unwrap();
+    assert_eq!(0, fs::read_dir(&journal_dir).unwrap().count());
+
+    // crash after the inode is removed but before the name is removed from parent
+    fs.fail_point
+        .lock()
+        .unwrap()
+        .replace("remove_file:after_inode");
+    assert!(fs.remove_file(ROOT_INODE, &test_file).await.is_err());
+    assert!(!fs.exists(attr.ino));
+    assert!(fs.exists_by_name(ROOT_INODE, &test_file).await.unwrap());
+    drop(fs);
+
+    // the remove is completed
+    let fs = open().await;
+    assert!(!fs.exists_by_name(ROOT_INODE, &test_file).await.unwrap());
+    assert!(!fs.storage.exists(&EncryptedFs::contents_path(attr.ino)));
+    assert_eq!(0, fs::read_dir(&journal_dir).unwrap().count());
+    assert!(fs.verify().await.unwrap().is_empty());
+    drop(fs);
+
+    fs::remove_dir_all(data_dir).unwrap();
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_errors_include_inode() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_errors_include_inode",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            let name = SecretString::from_str("test").unwrap();
+            let err = fs.find_by_name(4242, &name).await.unwrap_err();
+            assert!(matches!(err, FsError::InodeNotFound(4242)));
+            assert_eq!("inode 4242 not found", err.to_string());
+
+            let err = fs.rename(ROOT_INODE, &name, 4242, &name).await.unwrap_err();
+            assert!(err.to_string().contains("4242"));
+
+            let (_, dir) = fs
+                .create(
+                    ROOT_INODE,
+                    &name,
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            fs.create_node(
+                dir.ino,
+                &SecretString::from_str("child").unwrap(),
+                create_attr(FileType::RegularFile),
+            )
+            .await
+            .unwrap();
+            let err = fs.remove_dir(ROOT_INODE, &name).await.unwrap_err();
+            assert!(matches!(err, FsError::NotEmpty(ino) if ino == dir.ino));
+            assert!(err.to_string().contains(&dir.ino.to_string()));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_can_rename() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_can_rename",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            let file = SecretString::from_str("file").unwrap();
+            let dir = SecretString::from_str("dir").unwrap();
+            let missing = SecretString::from_str("missing").unwrap();
+            let file_attr = fs
+                .create_node(ROOT_INODE, &file, create_attr(FileType::RegularFile))
+                .await
+                .unwrap();
+            let dir_attr = fs
+                .create_node(ROOT_INODE, &dir, create_attr(FileType::Directory))
+                .await
+                .unwrap();
+
+            // valid, and nothing is changed
+            fs.can_rename(ROOT_INODE, &file, dir_attr.ino, &file)
+                .await
+                .unwrap();
+            fs.can_rename(ROOT_INODE, &file, ROOT_INODE, &file)
+                .await
+                .unwrap();
+            assert!(fs.exists_by_name(ROOT_INODE, &file).await.unwrap());
+            assert!(!fs.exists_by_name(dir_attr.ino, &file).await.unwrap());
+
+            assert!(matches!(
+                fs.can_rename(4242, &file, ROOT_INODE, &missing).await,
+                Err(FsError::InodeNotFound(4242))
+            ));
+            assert!(matches!(
+                fs.can_rename(file_attr.ino, &file, ROOT_INODE, &missing)
+                    .await,
+                Err(FsError::InvalidInodeType)
+            ));
+            assert!(matches!(
+                fs.can_rename(ROOT_INODE, &file, 4242, &missing).await,
+                Err(FsError::InodeNotFound(4242))
+            ));
+            assert!(matches!(
+                fs.can_rename(ROOT_INODE, &file, file_attr.ino, &missing)
+                    .await,
+                Err(FsError::InvalidInodeType)
+            ));
+            assert!(matches!(
+                fs.can_rename(ROOT_INODE, &missing, ROOT_INODE, &file).await,
+                Err(FsError::NotFound(_))
+            ));
+
+            // over a non empty directory
+            let dir_2 = SecretString::from_str("dir-2").unwrap();
+            fs.create_node(ROOT_INODE, &dir_2, create_attr(FileType::Directory))
+                .await
+                .unwrap();
+            fs.create_node(dir_attr.ino, &file, create_attr(FileType::RegularFile))
+                .await
+                .unwrap();
+            assert!(matches!(
+                fs.can_rename(ROOT_INODE, &dir_2, ROOT_INODE, &dir).await,
+                Err(FsError::NotEmpty(ino)) if ino == dir_attr.ino
+            ));
+            assert!(matches!(
+                fs.rename(ROOT_INODE, &dir_2, ROOT_INODE, &dir).await,
+                Err(FsError::NotEmpty(ino)) if ino == dir_attr.ino
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_rename_over_other_kind() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_rename_over_other_kind",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            let file = SecretString::from_str("file").unwrap();
+            let dir = SecretString::from_str("dir").unwrap();
+            let file_attr = fs
+                .create_node(ROOT_INODE, &file, create_attr(FileType::RegularFile))
+                .await
+                .unwrap();
+            let dir_attr = fs
+                .create_node(ROOT_INODE, &dir, create_attr(FileType::Directory))
+                .await
+                .unwrap();
+
+            // directory over a file
+            assert!(matches!(
+                fs.can_rename(ROOT_INODE, &dir, ROOT_INODE, &file).await,
+                Err(FsError::NotADirectory)
+            ));
+            let err = fs
+                .rename(ROOT_INODE, &dir, ROOT_INODE, &file)
+                .await
+                .unwrap_err();
+            assert!(matches!(err, FsError::NotADirectory));
+            assert_eq!(libc::ENOTDIR, err.to_errno());
+
+            // file over an empty directory
+            assert!(matches!(
+                fs.can_rename(ROOT_INODE, &file, ROOT_INODE, &dir).await,
+                Err(FsError::IsADirectory)
+            ));
+            let err = fs
+                .rename(ROOT_INODE, &file, ROOT_INODE, &dir)
+                .await
+                .unwrap_err();
+            assert!(matches!(err, FsError::IsADirectory));
+            assert_eq!(libc::EISDIR, err.to_errno());
+
+            // both are left as they were
+            let attr = fs.find_by_name(ROOT_INODE, &file).await.unwrap().unwrap();
+            assert_eq!(file_attr.ino, attr.ino);
+            assert_eq!(FileType::RegularFile, attr.kind);
+            let attr = fs.find_by_name(ROOT_INODE, &dir).await.unwrap().unwrap();
+            assert_eq!(dir_attr.ino, attr.ino);
+            assert_eq!(FileType::Directory, attr.kind);
+            assert!(fs.exists(file_attr.ino));
+            assert!(fs.exists(dir_attr.ino));
+            assert!(fs.verify().await.unwrap().is_empty());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_rename_inside_itself() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_rename_inside_itself",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            let a = SecretString::from_str("a").unwrap();
+            let a2 = SecretString::from_str("a2").unwrap();
+            let b = SecretString::from_str("b").unwrap();
+            let c = SecretString::from_str("c").unwrap();
+            let other = SecretString::from_str("other").unwrap();
+            let a_attr = fs
+                .create_node(ROOT_INODE, &a, create_attr(FileType::Directory))
+                .await
+                .unwrap();
+            let b_attr = fs
+                .create_node(a_attr.ino, &b, create_attr(FileType::Directory))
+                .await
+                .unwrap();
+            fs.create_node(b_attr.ino, &c, create_attr(FileType::Directory))
+                .await
+                .unwrap();
+            fs.create_node(ROOT_INODE, &other, create_attr(FileType::Directory))
+                .await
+                .unwrap();
+
+            // into itself and deeper in its subtree
+            for new_parent in [a_attr.ino, b_attr.ino] {
+                let err = fs
+                    .can_rename(ROOT_INODE, &a, new_parent, &a2)
+                    .await
+                    .unwrap_err();
+                assert!(matches!(err, FsError::InvalidInput(_)));
+                assert_eq!(libc::EINVAL, err.to_errno());
+                assert!(matches!(
+                    fs.rename(ROOT_INODE, &a, new_parent, &a2).await,
+                    Err(FsError::InvalidInput(_))
+                ));
+            }
+            // swapping with a node inside it
+            assert!(matches!(
+                fs.exchange(ROOT_INODE, &a, b_attr.ino, &c).await,
+                Err(FsError::InvalidInput(_))
+            ));
+            assert!(matches!(
+                fs.exchange(b_attr.ino, &c, ROOT_INODE, &a).await,
+                Err(FsError::InvalidInput(_))
+            ));
+            assert_eq!(
+                a_attr.ino,
+                fs.find_by_name(ROOT_INODE, &a).await.unwrap().unwrap().ino
+            );
+            assert!(fs.verify().await.unwrap().is_empty());
+
+            // moving a subdirectory up or to a sibling is fine
+            fs.can_rename(a_attr.ino, &b, ROOT_INODE, &b).await.unwrap();
+            fs.rename(a_attr.ino, &b, ROOT_INODE, &other).await.unwrap();
+            fs.rename(ROOT_INODE, &a, b_attr.ino, &a2).await.unwrap();
+            assert!(fs.verify().await.unwrap().is_empty());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_allocate() {
+    run_test(
+        TestSetup {
+            key: "test_allocate",
+        },
+        async {
+            use std::os::unix::fs::MetadataExt;
+
+            let fs = get_fs().await;
+
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_string_to_fs(&fs, attr.ino, 0, "data", fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+
+            // keep size doesn't change the size
+            fs.allocate(attr.ino, 0, 1024, AllocateMode::KeepSize)
+                .await
+                .unwrap();
+            assert_eq!(4, fs.get_attr(attr.ino).await.unwrap().size);
+
+            // a range inside the file doesn't change it either
+            fs.allocate(attr.ino, 1, 2, AllocateMode::Default)
+                .await
+                .unwrap();
+            assert_eq!(4, fs.get_attr(attr.ino).await.unwrap().size);
+
+            // grow
+            let len = 10 * 1024 * 1024;
+            fs.allocate(attr.ino, 2, len, AllocateMode::Default)
+                .await
+                .unwrap();
+            let attr2 = fs.get_attr(attr.ino).await.unwrap();
+            assert_eq!(len + 2, attr2.size);
+            assert_eq!(attr2.size.div_ceil(512), attr2.blocks);
+            // without writing the zeros
+            let metadata = fs::metadata(
+                TESTS_DATA_DIR
+                    .join("test_allocate")
+                    .join(EncryptedFs::contents_path(attr.ino)),
+            )
+            .unwrap();
+            assert!(metadata.blocks() * 512 < 1024 * 1024);
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            let mut buf = vec![0; 4];
+            fs.read_exact_at(attr.ino, 0, &mut buf, fh).await.unwrap();
+            assert_eq!(b"data", &buf[..]);
+            let mut buf = vec![1; 100];
+            fs.read_exact_at(attr.ino, len - 98, &mut buf, fh)
+                .await
+                .unwrap();
+            assert!(buf.iter().all(|b| *b == 0));
+            fs.release(fh).await.unwrap();
+
+            assert!(matches!(
+                fs.allocate(attr.ino, 0, 0, AllocateMode::Default).await,
+                Err(FsError::InvalidInput(_))
+            ));
+            assert!(matches!(
+                fs.allocate(attr.ino, u64::MAX, 1, AllocateMode::Default)
+                    .await,
+                Err(FsError::MaxFilesizeExceeded(_))
+            ));
+            assert!(matches!(
+                fs.allocate(ROOT_INODE, 0, 1, AllocateMode::Default).await,
+                Err(FsError::InvalidInodeType)
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_remove_dir_all() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_remove_dir_all",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            // dir/{a, sub/{b, sub2/{c}, empty}}
+            let (_, dir) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("dir").unwrap(),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let (_, sub) = fs
+                .create(
+                    dir.ino,
+                    &SecretString::from_str("sub").unwrap(),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let (_, sub2) = fs
+                .create(
+                    sub.ino,
+                    &SecretString::from_str("sub2").unwrap(),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let (_, empty) = fs
+                .create(
+                    sub.ino,
+                    &SecretString::from_str("empty").unwrap(),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let mut inodes = vec![dir.ino, sub.ino, sub2.ino, empty.ino];
+            for (parent, name) in [(dir.ino, "a"), (sub.ino, "b"), (sub2.ino, "c")] {
+                let (fh, attr) = fs
+                    .create(
+                        parent,
+                        &SecretString::from_str(name).unwrap(),
+                        create_attr(FileType::RegularFile),
+                        false,
+                        true,
+                    )
+                    .await
+                    .unwrap();
+                write_all_string_to_fs(&fs, attr.ino, 0, name, fh)
+                    .await
+                    .unwrap();
+                fs.release(fh).await.unwrap();
+                inodes.push(attr.ino);
+            }
+            // a file also linked from outside the tree is kept
+            let (fh, linked) = fs
+                .create(
+                    sub2.ino,
+                    &SecretString::from_str("linked").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            fs.link(
+                linked.ino,
+                ROOT_INODE,
+                &SecretString::from_str("linked").unwrap(),
+            )
+            .await
+            .unwrap();
+
+            fs.remove_dir_all(ROOT_INODE, &SecretString::from_str("dir").unwrap())
+                .await
+                .unwrap();
+
+            assert!(!fs
+                .exists_by_name(ROOT_INODE, &SecretString::from_str("dir").unwrap())
+                .await
+                .unwrap());
+            for ino in inodes {
+                assert!(!fs.storage.exists(&EncryptedFs::ino_file(ino)));
+                assert!(!fs.storage.exists(&EncryptedFs::contents_path(ino)));
+                assert!(!fs.exists(ino));
+            }
+            assert!(fs.storage.exists(&EncryptedFs::ino_file(linked.ino)));
+            assert!(fs.storage.exists(&EncryptedFs::contents_path(linked.ino)));
+            assert_eq!(1, fs.get_attr(linked.ino).await.unwrap().nlink);
+
+            // not a directory
+            assert!(matches!(
+                fs.remove_dir_all(ROOT_INODE, &SecretString::from_str("linked").unwrap())
+                    .await,
+                Err(FsError::InvalidInodeType)
+            ));
+            assert!(matches!(
+                fs.remove_dir_all(ROOT_INODE, &SecretString::from_str("dir").unwrap())
+                    .await,
+                Err(FsError::NotFound(_))
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_config() {
+    let data_dir = TESTS_DATA_DIR.join("test_config");
+    let _ = fs::remove_dir_all(&data_dir);
+
+    let fs = EncryptedFs::new(
+        data_dir.clone(),
+        Box::new(TestPasswordProvider("password")),
+        Cipher::Aes256Gcm,
+    )
+    .await
+    .unwrap();
+    drop(fs);
+
+    // read back from the metadata on open
+    let fs = EncryptedFs::new(
+        data_dir.clone(),
+        Box::new(TestPasswordProvider("password")),
+        Cipher::Aes256Gcm,
+    )
+    .await
+    .unwrap();
+    let config = fs.config();
+    assert_eq!(Cipher::Aes256Gcm, config.cipher);
+    assert_eq!(FORMAT_VERSION, config.format_version);
+    let params = argon2::Params::default();
+    assert_eq!(params.m_cost(), config.kdf_m_cost);
+    assert_eq!(params.t_cost(), config.kdf_t_cost);
+    assert_eq!(params.p_cost(), config.kdf_p_cost);
+    drop(fs);
+
+    fs::remove_dir_all(data_dir).unwrap();
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_kdf_iterations() {
+    let data_dir = TESTS_DATA_DIR.join("test_kdf_iterations");
+    let _ = fs::remove_dir_all(&data_dir);
+    let cipher = Cipher::ChaCha20Poly1305;
+    let iterations = crypto::KDF_ITERATIONS + 1;
+
+    assert!(matches!(
+        EncryptedFs::new_with(
+            data_dir.clone(),
+            Box::new(TestPasswordProvider("password")),
+            cipher,
+            FsOptions::default().with_kdf_iterations(0),
+        )
+        .await,
+        Err(FsError::InvalidInput(_))
+    ));
+
+    let fs = EncryptedFs::new_with(
+        data_dir.clone(),
+        Box::new(TestPasswordProvider("password")),
+        cipher,
+        FsOptions::default().with_kdf_iterations(iterations),
+    )
+    .await
+    .unwrap();
+    assert_eq!(iterations, fs.config().kdf_t_cost);
+    drop(fs);
+
+    // the key is derived with them
+    let salt: Vec<u8> = bincode::deserialize_from(
+        fs::File::open(data_dir.join(SECURITY_DIR).join(KEY_SALT_FILENAME)).unwrap(),
+    )
+    .unwrap();
+    let password = SecretString::from_str("password").unwrap();
+    let read_key = |derived_key: secrecy::SecretVec<u8>| {
+        let reader = crypto::create_read(
+            fs::File::open(data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME)).unwrap(),
+            cipher,
+            &derived_key,
+        );
+        bincode::deserialize_from::<_, Vec<u8>>(reader)
+    };
+    read_key(crypto::derive_key_with_iterations(&password, cipher, &salt, iterations).unwrap())
+        .unwrap();
+    read_key(crypto::derive_key(&password, cipher, &salt).unwrap()).unwrap_err();
+
+    // later opens use the ones from the metadata
+    let fs = EncryptedFs::new(
+        data_dir.clone(),
+        Box::new(TestPasswordProvider("password")),
+        cipher,
+    )
+    .await
+    .unwrap();
+    assert_eq!(iterations, fs.config().kdf_t_cost);
+    drop(fs);
+
+    // and so does changing the password
+    EncryptedFs::passwd(
+        &data_dir,
+        SecretString::from_str("password").unwrap(),
+        SecretString::from_str("new-password").unwrap(),
+        cipher,
+    )
+    .await
+    .unwrap();
+    let fs = EncryptedFs::new(
+        data_dir.clone(),
+        Box::new(TestPasswordProvider("new-password")),
+        cipher,
+    )
+    .await
+    .unwrap();
+    assert_eq!(iterations, fs.config().kdf_t_cost);
+    drop(fs);
+
+    fs::remove_dir_all(data_dir).unwrap();
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_content_hash() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_content_hash",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            let content = "a".repeat(BLOCK_SIZE * 3 + 7);
+            let mut inodes = vec![];
+            for name in ["file-1", "file-2"] {
+                let (fh, attr) = fs
+                    .create(
+                        ROOT_INODE,
+                        &SecretString::from_str(name).unwrap(),
+                        create_attr(FileType::RegularFile),
+                        false,
+                        true,
+                    )
+                    .await
+                    .unwrap();
+                write_all_string_to_fs(&fs, attr.ino, 0, &content, fh)
+                    .await
+                    .unwrap();
+                fs.release(fh).await.unwrap();
+                inodes.push(attr.ino);
+            }
+            let hash = fs.content_hash(inodes[0]).await.unwrap();
+            assert_eq!(hash, fs.content_hash(inodes[1]).await.unwrap());
+            assert_eq!(
+                ring::digest::digest(&ring::digest::SHA256, content.as_bytes()).as_ref(),
+                &hash[..]
+            );
+
+            // change one byte, with the write still pending in the handle
+            let fh = fs.open(inodes[1], false, true).await.unwrap();
+            write_all_string_to_fs(&fs, inodes[1], BLOCK_SIZE as u64 * 2, "b", fh)
+                .await
+                .unwrap();
+            let hash2 = fs.content_hash(inodes[1]).await.unwrap();
+            assert_ne!(hash, hash2);
+            fs.release(fh).await.unwrap();
+            assert_eq!(hash2, fs.content_hash(inodes[1]).await.unwrap());
+
+            // directories have no content
+            assert!(matches!(
+                fs.content_hash(ROOT_INODE).await,
+                Err(FsError::InvalidInodeType)
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_fsync() {
+    run_test_with_storages(TestSetup { key: "test_fsync" }, || async {
+        let fs = get_fs().await;
+
+        let (fh, attr) = fs
+            .create(
+                ROOT_INODE,
+                &SecretString::from_str("test-file").unwrap(),
+                create_attr(FileType::RegularFile),
+                true,
+                true,
+            )
+            .await
+            .unwrap();
+        write_all_string_to_fs(&fs, attr.ino, 0, "test-42", fh)
+            .await
+            .unwrap();
+        fs.fsync(fh, true).await.unwrap();
+        fs.fsync(fh, false).await.unwrap();
+        // pending data was written
+        assert_eq!(7, fs.get_attr(attr.ino).await.unwrap().size);
+        assert_eq!("test-42", test_common::read_to_string(attr.ino, &fs).await);
+        fs.release(fh).await.unwrap();
+
+        let fh = fs.open(attr.ino, true, false).await.unwrap();
+        fs.fsync(fh, false).await.unwrap();
+        fs.release(fh).await.unwrap();
+
+        assert!(matches!(
+            fs.fsync(fh, false).await,
+            Err(FsError::InvalidFileHandle)
+        ));
+    })
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_stream_position() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_stream_position",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            assert_eq!(0, fs.stream_position(fh).await.unwrap());
+            write_all_bytes_to_fs(&fs, attr.ino, 0, &[1; 250], fh)
+                .await
+                .unwrap();
+            assert_eq!(250, fs.stream_position(fh).await.unwrap());
+            fs.release(fh).await.unwrap();
+
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            let mut buf = [0; 100];
+            fs.read_exact_at(attr.ino, 0, &mut buf, fh).await.unwrap();
+            assert_eq!(100, fs.stream_position(fh).await.unwrap());
+            fs.read_exact_at(attr.ino, 120, &mut buf, fh).await.unwrap();
+            assert_eq!(220, fs.stream_position(fh).await.unwrap());
+            fs.release(fh).await.unwrap();
+
+            assert!(matches!(
+                fs.stream_position(fh).await,
+                Err(FsError::InvalidFileHandle)
+            ));
+            assert!(matches!(
+                fs.stream_position(42).await,
+                Err(FsError::InvalidFileHandle)
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_opendir_readdir_pages() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_opendir_readdir_pages",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            // root has no ".."
+            let mut expected = HashSet::from([".".to_string()]);
+            for i in 0..25 {
+                let name = format!("file-{i}");
+                let (fh, _) = fs
+                    .create(
+                        ROOT_INODE,
+                        &SecretString::from_str(&name).unwrap(),
+                        create_attr(FileType::RegularFile),
+                        false,
+                        false,
+                    )
+                    .await
+                    .unwrap();
+                fs.release(fh).await.unwrap();
+                expected.insert(name);
+            }
+
+            let handle = fs.opendir(ROOT_INODE).await.unwrap();
+            let mut names = vec![];
+            let mut offset = 0;
+            loop {
+                let page = fs
+                    .readdir(handle, offset)
+                    .await
+                    .unwrap()
+                    .take(7)
+                    .map(|entry| entry.unwrap().name.expose_secret().to_string())
+                    .collect::<Vec<_>>();
+                if page.is_empty() {
+                    break;
+                }
+                offset += page.len() as u64;
+                names.extend(page);
+                if offset == 14 {
+                    // changes while listing are not seen by the handle
+                    fs.remove_file(ROOT_INODE, &SecretString::from_str("file-3").unwrap())
+                        .await
+                        .unwrap();
+                    let (fh, _) = fs
+                        .create(
+                            ROOT_INODE,
+                            &SecretString::from_str("new-file").unwrap(),
+                            create_attr(FileType::RegularFile),
+                            false,
+                            false,
+                        )
+                        .await
+                        .unwrap();
+                    fs.release(fh).await.unwrap();
+                }
+            }
+            // no gaps and no duplicates
+            assert_eq!(expected.len(), names.len());
+            assert_eq!(expected, names.into_iter().collect::<HashSet<_>>());
+
+            // a new handle sees the changes
+            let handle2 = fs.opendir(ROOT_INODE).await.unwrap();
+            let names = fs
+                .readdir(handle2, 0)
+                .await
+                .unwrap()
+                .map(|entry| entry.unwrap().name.expose_secret().to_string())
+                .collect::<HashSet<_>>();
+            assert!(names.contains("new-file"));
+            assert!(!names.contains("file-3"));
+            assert_eq!(0, fs.readdir(handle2, 1000).await.unwrap().count());
+
+            fs.releasedir(handle).await.unwrap();
+            fs.releasedir(handle2).await.unwrap();
+            assert!(matches!(
+                fs.readdir(handle, 0).await,
+                Err(FsError::InvalidFileHandle)
+            ));
+            assert!(matches!(
+                fs.releasedir(handle).await,
+                Err(FsError::InvalidFileHandle)
+            ));
+            assert!(matches!(
+                fs.opendir(999).await,
+                Err(FsError::InvalidInodeType)
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_rename_no_replace() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_rename_no_replace",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            let name = |s: &str| SecretString::from_str(s).unwrap();
+            let mut inodes = vec![];
+            for (s, kind) in [
+                ("file-1", FileType::RegularFile),
+                ("file-2", FileType::RegularFile),
+                ("dir", FileType::Directory),
+            ] {
+                let (fh, attr) = fs
+                    .create(ROOT_INODE, &name(s), create_attr(kind), false, false)
+                    .await
+                    .unwrap();
+                fs.release(fh).await.unwrap();
+                inodes.push(attr.ino);
+            }
+            let no_replace = RenameFlags::default().with_no_replace(true);
+
+            // existing file or directory
+            for dst in ["file-2", "dir"] {
+                assert!(matches!(
+                    fs.rename_with(
+                        ROOT_INODE,
+                        &name("file-1"),
+                        ROOT_INODE,
+                        &name(dst),
+                        no_replace
+                    )
+                    .await,
+                    Err(FsError::AlreadyExists)
+                ));
+                assert!(matches!(
+                    fs.can_rename_with(
+                        ROOT_INODE,
+                        &name("file-1"),
+                        ROOT_INODE,
+                        &name(dst),
+                        no_replace
+                    )
+                    .await,
+                    Err(FsError::AlreadyExists)
+                ));
+            }
+            // nothing changed
+            assert_eq!(
+                inodes[0],
+                fs.find_by_name(ROOT_INODE, &name("file-1"))
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino
+            );
+            assert_eq!(
+                inodes[1],
+                fs.find_by_name(ROOT_INODE, &name("file-2"))
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino
+            );
+
+            // new name works
+            fs.rename_with(
+                ROOT_INODE,
+                &name("file-1"),
+                inodes[2],
+                &name("file-1"),
+                no_replace,
+            )
+            .await
+            .unwrap();
+            assert!(fs
+                .find_by_name(ROOT_INODE, &name("file-1"))
+                .await
+                .unwrap()
+                .is_none());
+
+            // the default replaces it
+            fs.rename(inodes[2], &name("file-1"), ROOT_INODE, &name("file-2"))
+                .await
+                .unwrap();
+            assert_eq!(
+                inodes[0],
+                fs.find_by_name(ROOT_INODE, &name("file-2"))
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino
+            );
+            assert!(!fs.exists(inodes[1]));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_exchange() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_exchange",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            let name = |s: &str| SecretString::from_str(s).unwrap();
+            let ino_of = |parent, s: &'static str| {
+                let fs = fs.clone();
+                async move {
+                    fs.find_by_name(parent, &name(s))
+                        .await
+                        .unwrap()
+                        .unwrap()
+                        .ino
+                }
+            };
+            let mut inodes = vec![];
+            for (s, kind) in [
+                ("file-1", FileType::RegularFile),
+                ("file-2", FileType::RegularFile),
+                ("dir", FileType::Directory),
+            ] {
+                let (fh, attr) = fs
+                    .create(ROOT_INODE, &name(s), create_attr(kind), false, false)
+                    .await
+                    .unwrap();
+                fs.release(fh).await.unwrap();
+                inodes.push(attr.ino);
+            }
+            let (fh, sub) = fs
+                .create(
+                    inodes[2],
+                    &name("sub"),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+
+            // two files in the same directory
+            fs.exchange(ROOT_INODE, &name("file-1"), ROOT_INODE, &name("file-2"))
+                .await
+                .unwrap();
+            assert_eq!(inodes[1], ino_of(ROOT_INODE, "file-1").await);
+            assert_eq!(inodes[0], ino_of(ROOT_INODE, "file-2").await);
+            assert_eq!(3, fs.len(ROOT_INODE).unwrap());
+
+            // a file with a directory in another parent, the parent link follows the directory
+            fs.exchange(ROOT_INODE, &name("file-1"), inodes[2], &name("sub"))
+                .await
+                .unwrap();
+            assert_eq!(sub.ino, ino_of(ROOT_INODE, "file-1").await);
+            assert_eq!(inodes[1], ino_of(inodes[2], "sub").await);
+            assert_eq!(ROOT_INODE, ino_of(sub.ino, "..").await);
+            assert_eq!(
+                FileType::Directory,
+                fs.get_attr(sub.ino).await.unwrap().kind
+            );
+
+            // both must exist
+            assert!(matches!(
+                fs.exchange(ROOT_INODE, &name("file-2"), ROOT_INODE, &name("missing"))
+                    .await,
+                Err(FsError::NotFound(_))
+            ));
+            assert!(matches!(
+                fs.exchange(ROOT_INODE, &name("missing"), ROOT_INODE, &name("file-2"))
+                    .await,
+                Err(FsError::NotFound(_))
+            ));
+            assert_eq!(inodes[0], ino_of(ROOT_INODE, "file-2").await);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_idle_readers_reused() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_idle_readers_reused",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            let name = SecretString::from_str("file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &name,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let data = "hello world, ".repeat(20);
+            write_all_bytes_to_fs(&fs, attr.ino, 0, data.as_bytes(), fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            let is_idle = |fs: &EncryptedFs| fs.idle_readers.lock().unwrap().contains(&attr.ino);
+
+            assert_eq!(data, test_common::read_to_string(attr.ino, &fs).await);
+            assert!(is_idle(&fs));
+            // the cached reader is at the end, reusing it gives the same data
+            for _ in 0..3 {
+                assert_eq!(data, test_common::read_to_string(attr.ino, &fs).await);
+            }
+            // also when reading from the middle
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            assert!(!is_idle(&fs));
+            let mut buf = vec![0; 5];
+            test_common::read_exact(&fs, attr.ino, 6, &mut buf, fh).await;
+            assert_eq!(b"world", &buf[..]);
+            fs.release(fh).await.unwrap();
+            assert!(is_idle(&fs));
+
+            // changing the content drops it
+            let fh = fs.open(attr.ino, false, true).await.unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, b"HELLO", fh)
+                .await
+                .unwrap();
+            assert!(!is_idle(&fs));
+            fs.release(fh).await.unwrap();
+            let data = format!("HELLO{}", &data[5..]);
+            assert_eq!(data, test_common::read_to_string(attr.ino, &fs).await);
+
+            fs.set_len(attr.ino, 5).await.unwrap();
+            assert!(!is_idle(&fs));
+            assert_eq!("HELLO", test_common::read_to_string(attr.ino, &fs).await);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_set_len_with_open_writer() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_set_len_with_open_writer",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            let name = SecretString::from_str("file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &name,
+                    create_attr(FileType::RegularFile),
+                    true,
+                    true,
+                )
+                .await
+                .unwrap();
+            let data: Vec<u8> = (0..8 * 1024)
+                .map(|i| u8::try_from(i % 251).unwrap())
+                .collect();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, &data, fh)
+                .await
+                .unwrap();
+
+            // the pending data of the handle is flushed before and the writer follows the new size
+            fs.set_len(attr.ino, 4 * 1024).await.unwrap();
+            assert_eq!(4 * 1024, fs.get_attr(attr.ino).await.unwrap().size);
+            let mut buf = vec![0; 8 * 1024];
+            let mut read = 0;
+            loop {
+                let len = fs
+                    .read(attr.ino, read as u64, &mut buf[read..], fh)
+                    .await
+                    .unwrap();
+                if len == 0 {
+                    break;
+                }
+                read += len;
+            }
+            assert_eq!(4 * 1024, read);
+            assert_eq!(&data[..4 * 1024], &buf[..read]);
+
+            // the same handle keeps writing after the truncation
+            write_all_bytes_to_fs(&fs, attr.ino, 4 * 1024, b"end", fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            assert_eq!(4 * 1024 + 3, fs.get_attr(attr.ino).await.unwrap().size);
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            let mut buf = vec![0; 4 * 1024 + 3];
+            test_common::read_exact(&fs, attr.ino, 0, &mut buf, fh).await;
+            fs.release(fh).await.unwrap();
+            assert_eq!(&data[..4 * 1024], &buf[..4 * 1024]);
+            assert_eq!(b"end", &buf[4 * 1024..]);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_read_read_only_contents() {
+    run_test(
+        TestSetup {
+            key: "test_read_read_only_contents",
+        },
+        async {
+            use std::os::unix::fs::PermissionsExt;
+
+            let fs = get_fs().await;
+
+            let mut inodes = vec![];
+            for (name, data) in [("empty", ""), ("file", "read only content")] {
+                let (fh, attr) = fs
+                    .create(
+                        ROOT_INODE,
+                        &SecretString::from_str(name).unwrap(),
+                        create_attr(FileType::RegularFile),
+                        false,
+                        true,
+                    )
+                    .await
+                    .unwrap();
+                write_all_bytes_to_fs(&fs, attr.ino, 0, data.as_bytes(), fh)
+                    .await
+                    .unwrap();
+                fs.release(fh).await.unwrap();
+                inodes.push((attr.ino, data));
+            }
+
+            for (ino, data) in inodes {
+                let path = TESTS_DATA_DIR
+                    .join("test_read_read_only_contents")
+                    .join(EncryptedFs::contents_path(ino));
+                fs::set_permissions(&path, fs::Permissions::from_mode(0o444)).unwrap();
+                let before = fs::read(&path).unwrap();
+
+                // readers only open the contents for read and never write a header
+                assert_eq!(data, test_common::read_to_string(ino, &fs).await);
+                assert_eq!(before, fs::read(&path).unwrap());
+                assert_eq!(
+                    0o444,
+                    fs::metadata(&path).unwrap().permissions().mode() & 0o777
+                );
+            }
+        },
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+#[traced_test]
+async fn test_concurrent_insert_and_list_entries() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_concurrent_insert_and_list_entries",
+        },
+        || async {
+            use std::sync::atomic::{AtomicBool, Ordering};
+            use std::sync::Arc;
+
+            let fs = get_fs().await;
+
+            let mut dirs = vec![];
+            for name in ["dir", "a", "b"] {
+                let (_, attr) = fs
+                    .create(
+                        ROOT_INODE,
+                        &SecretString::from_str(name).unwrap(),
+                        create_attr(FileType::Directory),
+                        false,
+                        false,
+                    )
+                    .await
+                    .unwrap();
+                dirs.push(attr.ino);
+            }
+            let (dir, a, b) = (dirs[0], dirs[1], dirs[2]);
+            let (_, sub) = fs
+                .create(
+                    a,
+                    &SecretString::from_str("sub").unwrap(),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let done = Arc::new(AtomicBool::new(false));
+
+            // entries are only added to `dir` while the ".." entry of `sub` is rewritten in place
+            let mut writers = vec![];
+            for i in 0..4 {
+                let fs = fs.clone();
+                writers.push(tokio::spawn(async move {
+                    for j in 0..25 {
+                        let (fh, _) = fs
+                            .create(
+                                dir,
+                                &SecretString::new(format!("file-{i}-{j}")),
+                                create_attr(FileType::RegularFile),
+                                false,
+                                false,
+                            )
+                            .await
+                            .unwrap();
+                        fs.release(fh).await.unwrap();
+                    }
+                }));
+            }
+            {
+                let fs = fs.clone();
+                writers.push(tokio::spawn(async move {
+                    let name = SecretString::from_str("sub").unwrap();
+                    for i in 0..50 {
+                        let (from, to) = if i % 2 == 0 { (a, b) } else { (b, a) };
+                        fs.rename(from, &name, to, &name).await.unwrap();
+                    }
+                }));
+            }
+            let mut readers = vec![];
+            for ino in [dir, dir, sub.ino] {
+                let fs = fs.clone();
+                let done = done.clone();
+                readers.push(tokio::spawn(async move {
+                    let mut lists = 0;
+                    while !done.load(Ordering::SeqCst) || lists == 0 {
+                        for entry in fs.read_dir(ino).await.unwrap() {
+                            // a torn entry would fail to deserialize
+                            entry.unwrap();
+                        }
+                        lists += 1;
+                    }
+                }));
+            }
+
+            for h in writers {
+                h.await.unwrap();
+            }
+            done.store(true, Ordering::SeqCst);
+            for h in readers {
+                h.await.unwrap();
+            }
+            assert_eq!(100, fs.len(dir).unwrap());
+            assert_eq!(
+                a,
+                fs.find_by_name(sub.ino, &SecretString::from_str("..").unwrap())
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino
+            );
+        },
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_encrypted_file() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_encrypted_file",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+
+            let handle = {
+                let mut file: EncryptedFile = fs
+                    .open_file(
+                        attr.ino,
+                        OpenFlags::default().with_read(true).with_write(true),
+                    )
+                    .await
+                    .unwrap();
+                assert_eq!(attr.ino, file.ino());
+                write!(file, "hello {}", "world").unwrap();
+                file.write_all(b"!").unwrap();
+                file.flush().unwrap();
+
+                assert_eq!(0, file.seek(SeekFrom::Start(0)).unwrap());
+                let mut buf = String::new();
+                file.read_to_string(&mut buf).unwrap();
+                assert_eq!("hello world!", buf);
+                assert_eq!(12, file.stream_position().unwrap());
+
+                assert_eq!(6, file.seek(SeekFrom::End(-6)).unwrap());
+                let mut buf = [0; 5];
+                file.read_exact(&mut buf).unwrap();
+                assert_eq!(b"world", &buf);
+                assert_eq!(
+                    io::ErrorKind::InvalidInput,
+                    file.seek(SeekFrom::Current(-12)).unwrap_err().kind()
+                );
+                // a failed seek keeps the position
+                assert_eq!(11, file.stream_position().unwrap());
+
+                file.seek(SeekFrom::Start(0)).unwrap();
+                let mut copy = vec![];
+                io::copy(&mut file, &mut copy).unwrap();
+                assert_eq!(b"hello world!".to_vec(), copy);
+
+                assert!(fs.is_read_handle(file.handle()).await);
+                assert!(fs.is_write_handle(file.handle()).await);
+                file.handle()
+            };
+            // released on scope exit
+            assert!(!fs.is_read_handle(handle).await);
+            assert!(!fs.is_write_handle(handle).await);
+            assert_eq!(12, fs.get_attr(attr.ino).await.unwrap().size);
+
+            // or explicitly
+            let file = fs
+                .open_file(attr.ino, OpenFlags::default().with_read(true))
+                .await
+                .unwrap();
+            let handle = file.handle();
+            file.close().await.unwrap();
+            assert!(!fs.is_read_handle(handle).await);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_read_write_file() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_read_write_file",
+        },
+        || async {
+            use rand::RngCore;
+
+            let fs = get_fs().await;
+
+            let mut blob = vec![0; BLOCK_SIZE * 5 + 7];
+            rand::thread_rng().fill_bytes(&mut blob);
+
+            // created
+            fs.write_file("/blob", &blob).await.unwrap();
+            assert_eq!(blob, fs.read_file("/blob").await.unwrap());
+            let attr = fs.lookup_path("blob").await.unwrap();
+            assert_eq!(blob.len() as u64, attr.size);
+            assert_eq!(0o644, attr.perm);
+
+            // replaced and truncated
+            fs.write_file("/blob", &blob[..10]).await.unwrap();
+            assert_eq!(&blob[..10], &fs.read_file("blob").await.unwrap()[..]);
+            assert_eq!(attr.ino, fs.lookup_path("/blob").await.unwrap().ino);
+            fs.write_file("/blob", &[]).await.unwrap();
+            assert!(fs.read_file("/blob").await.unwrap().is_empty());
+
+            // in a directory
+            fs.create_dir_all_path("/a/b", create_attr(FileType::Directory))
+                .await
+                .unwrap();
+            fs.write_file("/a/b/blob", &blob).await.unwrap();
+            assert_eq!(blob, fs.read_file("/a/./b/../b/blob").await.unwrap());
+
+            assert!(matches!(
+                fs.read_file("/missing").await,
+                Err(FsError::NotFound(_))
+            ));
+            assert!(matches!(
+                fs.read_file("/a").await,
+                Err(FsError::InvalidInodeType)
+            ));
+            assert!(matches!(
+                fs.write_file("/missing/blob", &blob).await,
+                Err(FsError::NotFound(_))
+            ));
+            assert!(matches!(
+                fs.write_file("/a/b", &blob).await,
+                Err(FsError::InvalidInodeType)
+            ));
+            assert!(matches!(
+                fs.write_file("/blob/file", &blob).await,
+                Err(FsError::InvalidInodeType)
+            ));
+        },
+    )
+    .await;
+}
+
+#[test]
+fn test_glob_match() {
+    use crate::encryptedfs::glob_match;
+
+    for (pattern, name) in [
+        ("*", ""),
+        ("*", "a.txt"),
+        ("*.txt", "a.txt"),
+        ("*.txt", ".txt"),
+        ("a*b*c", "aXbYbZc"),
+        ("?", "ä"),
+        ("file-?.rs", "file-1.rs"),
+        ("**a", "ba"),
+        ("exact", "exact"),
+    ] {
+        assert!(glob_match(pattern, name), "{pattern} {name}");
+    }
+    for (pattern, name) in [
+        ("*.txt", "a.txt.bak"),
+        ("?", ""),
+        ("?", "ab"),
+        ("a*b*c", "aXbYc-"),
+        ("exact", "exac"),
+        ("", "a"),
+    ] {
+        assert!(!glob_match(pattern, name), "{pattern} {name}");
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_find_matching() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_find_matching",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            for (name, kind) in [
+                ("a.txt", FileType::RegularFile),
+                ("b.txt", FileType::RegularFile),
+                ("ab.txt", FileType::RegularFile),
+                ("notes.md", FileType::RegularFile),
+                ("docs.txt", FileType::Directory),
+            ] {
+                fs.create(
+                    ROOT_INODE,
+                    &SecretString::from_str(name).unwrap(),
+                    create_attr(kind),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            }
+            let find = |pattern: &'static str| {
+                let fs = fs.clone();
+                async move {
+                    let mut names: Vec<String> = fs
+                        .find_matching(ROOT_INODE, pattern)
+                        .await
+                        .unwrap()
+                        .map(|entry| entry.unwrap().name.expose_secret().clone())
+                        .collect();
+                    names.sort();
+                    names
+                }
+            };
+
+            assert_eq!(
+                vec!["a.txt", "ab.txt", "b.txt", "docs.txt"],
+                find("*.txt").await
+            );
+            assert_eq!(vec!["a.txt", "b.txt"], find("?.txt").await);
+            // prefix
+            assert_eq!(vec!["a.txt", "ab.txt"], find("a*").await);
+            assert_eq!(vec!["notes.md"], find("notes.md").await);
+            // `.` and `..` are not listed
+            assert_eq!(5, find("*").await.len());
+            assert!(find("*.rs").await.is_empty());
+            assert!(fs
+                .find_matching(ROOT_INODE, "?")
+                .await
+                .unwrap()
+                .next()
+                .is_none());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_atime_policy() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_atime_policy",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            assert_eq!(AtimePolicy::Relatime, fs.atime_policy());
+            let (fh, file) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, file.ino, 0, b"content", fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            let (_, dir) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("dir").unwrap(),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+
+            let modified = SystemTime::now() - Duration::from_secs(60 * 60);
+            let after = modified + Duration::from_secs(60);
+            let before = modified - Duration::from_secs(60);
+            for (policy, atime, updated) in [
+                (AtimePolicy::Noatime, before, false),
+                (AtimePolicy::Noatime, after, false),
+                (AtimePolicy::Relatime, after, false),
+                (AtimePolicy::Relatime, before, true),
+                (AtimePolicy::Always, after, true),
+            ] {
+                fs.set_atime_policy(policy);
+                for ino in [file.ino, dir.ino] {
+                    fs.update_attr(
+                        ino,
+                        SetFileAttr::default()
+                            .with_atime(atime)
+                            .with_mtime(modified)
+                            .with_ctime(modified),
+                    )
+                    .await
+                    .unwrap();
+                }
+
+                test_common::read_to_string(file.ino, &fs).await;
+                fs.read_dir(dir.ino).await.unwrap().for_each(drop);
+
+                for ino in [file.ino, dir.ino] {
+                    let attr = fs.get_attr(ino).await.unwrap();
+                    assert_eq!(updated, attr.atime != atime, "{policy:?} {ino}");
+                    if !updated {
+                        // nothing was written
+                        assert_eq!(modified, attr.ctime, "{policy:?} {ino}");
+                    }
+                }
+            }
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_batch() {
+    run_test_with_storages(TestSetup { key: "test_batch" }, || async {
+        let fs = get_fs().await;
+
+        let writes = |ino| {
+            fs.inode_writes
+                .lock()
+                .unwrap()
+                .get(&ino)
+                .copied()
+                .unwrap_or(0)
+        };
+
+        // import
+        let mut builder = tar::Builder::new(vec![]);
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_mode(0o755);
+        header.set_mtime(1_700_000_000);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_size(0);
+        builder
+            .append_data(&mut header, "dir/", io::empty())
+            .unwrap();
+        for i in 0..1000 {
+            let data = format!("content {i}");
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(tar::EntryType::Regular);
+            header.set_mode(0o644);
+            header.set_mtime(1_700_000_000);
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_size(data.len() as u64);
+            builder
+                .append_data(&mut header, format!("dir/file-{i}"), data.as_bytes())
+                .unwrap();
+        }
+        let archive = builder.into_inner().unwrap();
+        let root_writes = writes(ROOT_INODE);
+        fs.import_tar(archive.as_slice()).await.unwrap();
+
+        let dir = fs.lookup_path("dir").await.unwrap();
+        assert!(writes(dir.ino) < 10, "{}", writes(dir.ino));
+        assert!(writes(ROOT_INODE) - root_writes < 10);
+        assert_eq!(1000, fs.len(dir.ino).unwrap());
+        assert_eq!(
+            "content 999",
+            String::from_utf8(fs.read_file("dir/file-999").await.unwrap()).unwrap()
+        );
+        // what was written is the latest
+        assert!(fs.batch.lock().unwrap().pending.is_empty());
+        assert_eq!(dir, fs.get_inode_from_storage(dir.ino).await.unwrap());
+        assert_eq!(
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            dir.mtime
+        );
+
+        // explicit, nested
+        let batch = fs.begin_batch();
+        let inner = fs.begin_batch();
+        let dir_writes = writes(dir.ino);
+        for i in 0..10 {
+            fs.create(
+                dir.ino,
+                &SecretString::new(format!("new-{i}")),
+                create_attr(FileType::RegularFile),
+                false,
+                false,
+            )
+            .await
+            .unwrap();
+        }
+        inner.commit().await.unwrap();
+        // changes are seen before they are written
+        assert_eq!(dir_writes, writes(dir.ino));
+        let attr = fs.get_attr(dir.ino).await.unwrap();
+        assert!(attr.mtime > dir.mtime);
+        assert_eq!(attr, fs.get_inode_from_storage(dir.ino).await.unwrap());
+        batch.commit().await.unwrap();
+        assert_eq!(dir_writes + 1, writes(dir.ino));
+        assert!(fs.batch.lock().unwrap().pending.is_empty());
+        let attr = fs.get_attr(dir.ino).await.unwrap();
+        assert_eq!(attr, fs.get_inode_from_storage(dir.ino).await.unwrap());
+    })
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_dir_nlink() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_dir_nlink",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            let name = |s: &str| SecretString::from_str(s).unwrap();
+            let nlink = |ino| {
+                let fs = fs.clone();
+                async move { fs.get_attr(ino).await.unwrap().nlink }
+            };
+            let root_nlink = nlink(ROOT_INODE).await;
+            let (_, dir) = fs
+                .create(
+                    ROOT_INODE,
+                    &name("dir"),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            assert_eq!(2, dir.nlink);
+            assert_eq!(root_nlink + 1, nlink(ROOT_INODE).await);
+
+            for (s, kind) in [
+                ("sub-1", FileType::Directory),
+                ("sub-2", FileType::Directory),
+                ("file", FileType::RegularFile),
+            ] {
+                fs.create(dir.ino, &name(s), create_attr(kind), false, false)
+                    .await
+                    .unwrap();
+            }
+            // files don't count
+            assert_eq!(4, nlink(dir.ino).await);
+
+            fs.remove_dir(dir.ino, &name("sub-1")).await.unwrap();
+            assert_eq!(3, nlink(dir.ino).await);
+
+            // moving it to another parent moves the link
+            fs.rename(dir.ino, &name("sub-2"), ROOT_INODE, &name("sub-2"))
+                .await
+                .unwrap();
+            assert_eq!(2, nlink(dir.ino).await);
+            assert_eq!(root_nlink + 2, nlink(ROOT_INODE).await);
+            // renaming in the same parent doesn't change it
+            fs.rename(ROOT_INODE, &name("sub-2"), ROOT_INODE, &name("sub-3"))
+                .await
+                .unwrap();
+            assert_eq!(root_nlink + 2, nlink(ROOT_INODE).await);
+
+            // exchanging a directory with a file
+            fs.exchange(ROOT_INODE, &name("sub-3"), dir.ino, &name("file"))
+                .await
+                .unwrap();
+            assert_eq!(3, nlink(dir.ino).await);
+            assert_eq!(root_nlink + 1, nlink(ROOT_INODE).await);
+
+            fs.remove_dir_all(ROOT_INODE, &name("dir")).await.unwrap();
+            assert_eq!(root_nlink, nlink(ROOT_INODE).await);
+        },
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_api_is_send() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_api_is_send",
+        },
+        || async {
+            fn assert_send<T: Send>(_: &T) {}
+
+            let fs = get_fs().await;
+
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    true,
+                    true,
+                )
+                .await
+                .unwrap();
+            let mut buf = [0; 4];
+            // the futures can be spawned on the runtime instead of blocking a thread
+            assert_send(&fs.write(attr.ino, 0, b"", fh));
+            assert_send(&fs.read(attr.ino, 0, &mut buf, fh));
+            assert_send(&fs.get_attr(attr.ino));
+            assert_send(&fs.read_dir(ROOT_INODE));
+            assert_send(&fs.open(attr.ino, true, false));
+            assert_send(&fs.release(fh));
+
+            let data: Vec<u8> = (0..BLOCK_SIZE * 3 + 5)
+                .map(|i| u8::try_from(i % 256).unwrap())
+                .collect();
+            let written = {
+                let fs = fs.clone();
+                let data = data.clone();
+                tokio::spawn(async move {
+                    write_all_bytes_to_fs(&fs, attr.ino, 0, &data, fh)
+                        .await
+                        .unwrap();
+                    fs.release(fh).await.unwrap();
+                    fs.get_attr(attr.ino).await.unwrap().size
+                })
+                .await
+                .unwrap()
+            };
+            assert_eq!(data.len() as u64, written);
+
+            let read = {
+                let fs = fs.clone();
+                let len = data.len();
+                tokio::spawn(async move {
+                    let fh = fs.open(attr.ino, true, false).await.unwrap();
+                    let mut buf = vec![0; len];
+                    fs.read_exact_at(attr.ino, 0, &mut buf, fh).await.unwrap();
+                    fs.release(fh).await.unwrap();
+                    buf
+                })
+                .await
+                .unwrap()
+            };
+            assert_eq!(data, read);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_ciphertext_size() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_ciphertext_size",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            for (i, len) in [0, 1, BLOCK_SIZE, BLOCK_SIZE + 1, BLOCK_SIZE * 3 + 42]
+                .into_iter()
+                .enumerate()
+            {
+                let (fh, attr) = fs
+                    .create(
+                        ROOT_INODE,
+                        &SecretString::new(format!("file-{i}")),
+                        create_attr(FileType::RegularFile),
+                        false,
+                        true,
+                    )
+                    .await
+                    .unwrap();
+                write_all_bytes_to_fs(&fs, attr.ino, 0, &vec![42; len], fh)
+                    .await
+                    .unwrap();
+
+                // pending writes are included
+                let size = fs.ciphertext_size(attr.ino).await.unwrap();
+                fs.release(fh).await.unwrap();
+                assert_eq!(
+                    fs.storage
+                        .file_size(&EncryptedFs::contents_path(attr.ino))
+                        .unwrap(),
+                    size,
+                    "{len}"
+                );
+                assert_eq!(fs.cipher.ciphertext_len(len as u64), size, "{len}");
+                assert_eq!(len as u64, fs.get_attr(attr.ino).await.unwrap().size);
+            }
+
+            let (_, dir) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("dir").unwrap(),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            assert!(matches!(
+                fs.ciphertext_size(dir.ino).await,
+                Err(FsError::InvalidInodeType)
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_name_too_long() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_name_too_long",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            let long_name = SecretString::new("a".repeat(200));
+            assert!(matches!(
+                fs.create(
+                    ROOT_INODE,
+                    &long_name,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false
+                )
+                .await,
+                Err(FsError::NameTooLong)
+            ));
+            assert!(!fs.exists_by_name(ROOT_INODE, &long_name).await.unwrap());
+
+            let name = SecretString::from_str("file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &name,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            assert!(matches!(
+                fs.rename(ROOT_INODE, &name, ROOT_INODE, &long_name).await,
+                Err(FsError::NameTooLong)
+            ));
+            assert!(matches!(
+                fs.link(attr.ino, ROOT_INODE, &long_name).await,
+                Err(FsError::NameTooLong)
+            ));
+            assert!(fs.exists_by_name(ROOT_INODE, &name).await.unwrap());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_is_empty_dir() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_is_empty_dir",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            // root has only "."
+            assert!(fs.is_empty_dir(ROOT_INODE).unwrap());
+
+            let (_, dir) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("dir").unwrap(),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            assert!(!fs.is_empty_dir(ROOT_INODE).unwrap());
+            assert!(fs.is_empty_dir(dir.ino).unwrap());
+            assert_eq!(0, fs.len(dir.ino).unwrap());
+
+            let file = SecretString::from_str("file").unwrap();
+            let (fh, file_attr) = fs
+                .create(
+                    dir.ino,
+                    &file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            assert!(!fs.is_empty_dir(dir.ino).unwrap());
+            assert_eq!(1, fs.len(dir.ino).unwrap());
+            assert!(matches!(
+                fs.remove_dir(ROOT_INODE, &SecretString::from_str("dir").unwrap())
+                    .await,
+                Err(FsError::NotEmpty(ino)) if ino == dir.ino
+            ));
+            assert!(matches!(
+                fs.is_empty_dir(file_attr.ino),
+                Err(FsError::InvalidInodeType)
+            ));
+
+            // a missing synthetic entry doesn't make the dir look empty, or the count underflow
+            fs.storage
+                .remove_file(&EncryptedFs::contents_path(dir.ino).join(LS_DIR).join("$.."))
+                .unwrap();
+            assert!(!fs.is_empty_dir(dir.ino).unwrap());
+            assert_eq!(1, fs.len(dir.ino).unwrap());
+            fs.remove_file(dir.ino, &file).await.unwrap();
+            assert!(fs.is_empty_dir(dir.ino).unwrap());
+            assert_eq!(0, fs.len(dir.ino).unwrap());
+            fs.remove_dir(ROOT_INODE, &SecretString::from_str("dir").unwrap())
+                .await
+                .unwrap();
+            assert!(fs.is_empty_dir(ROOT_INODE).unwrap());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_changes_since() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_changes_since",
+        },
+        || async {
+            let fs = get_fs().await;
+            fs.set_change_log(true);
+
+            let create = |name: &'static str, kind| {
+                let fs = fs.clone();
+                async move {
+                    let (_, attr) = fs
+                        .create(
+                            ROOT_INODE,
+                            &SecretString::from_str(name).unwrap(),
+                            create_attr(kind),
+                            false,
+                            false,
+                        )
+                        .await
+                        .unwrap();
+                    attr.ino
+                }
+            };
+            let inos = |changes: &[ChangeRecord]| {
+                changes
+                    .iter()
+                    .map(|change| change.ino)
+                    .collect::<HashSet<_>>()
+            };
+            let changed_inos = |since| {
+                let fs = fs.clone();
+                async move { inos(&fs.changes_since(since).await.unwrap().collect::<Vec<_>>()) }
+            };
+
+            let a = create("a", FileType::RegularFile).await;
+            let c = create("c", FileType::RegularFile).await;
+            let untouched = create("untouched", FileType::Directory).await;
+            assert!(changed_inos(SystemTime::UNIX_EPOCH)
+                .await
+                .contains(&untouched));
+
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            let since = SystemTime::now();
+
+            let fh = fs.open(a, false, true).await.unwrap();
+            write_all_bytes_to_fs(&fs, a, 0, b"content", fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            let b = create("b", FileType::RegularFile).await;
+            fs.remove_file(ROOT_INODE, &SecretString::from_str("c").unwrap())
+                .await
+                .unwrap();
+
+            let changes: Vec<_> = fs.changes_since(since).await.unwrap().collect();
+            assert_eq!(HashSet::from([a, b, c, ROOT_INODE]), inos(&changes));
+            assert!(changes.iter().all(|change| change.time >= since));
+            assert!(changes
+                .iter()
+                .any(|change| change.ino == b && change.kind == ChangeKind::Created));
+            assert!(changes
+                .iter()
+                .all(|change| change.ino != a || change.kind == ChangeKind::Modified));
+            assert_eq!(
+                ChangeKind::Deleted,
+                changes.iter().rfind(|change| change.ino == c).unwrap().kind
+            );
+
+            // compacting keeps one change per inode
+            let all = fs
+                .changes_since(SystemTime::UNIX_EPOCH)
+                .await
+                .unwrap()
+                .count();
+            fs.compact_changes().await.unwrap();
+            let compacted: Vec<_> = fs
+                .changes_since(SystemTime::UNIX_EPOCH)
+                .await
+                .unwrap()
+                .collect();
+            assert!(compacted.len() < all);
+            assert_eq!(compacted.len(), inos(&compacted).len());
+            assert_eq!(
+                HashSet::from([a, b, c, ROOT_INODE]),
+                changed_inos(since).await
+            );
+
+            // not recorded when disabled
+            fs.set_change_log(false);
+            let d = create("d", FileType::RegularFile).await;
+            assert!(!changed_inos(since).await.contains(&d));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_quota() {
+    run_test_with_storages(TestSetup { key: "test_quota" }, || async {
+        let fs = get_fs().await;
+
+        let create = |name: &'static str| {
+            let fs = fs.clone();
+            async move {
+                fs.create(
+                    ROOT_INODE,
+                    &SecretString::from_str(name).unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap()
+            }
+        };
+
+        let (fh_a, a) = create("a").await;
+        write_all_bytes_to_fs(&fs, a.ino, 0, &[1; 30], fh_a)
+            .await
+            .unwrap();
+        assert_eq!(None, fs.quota_used());
+        // existing files are counted when the quota is set
+        fs.set_quota(Some(100)).await.unwrap();
+        assert_eq!(Some(100), fs.quota());
+        assert_eq!(Some(30), fs.quota_used());
+
+        // fits
+        write_all_bytes_to_fs(&fs, a.ino, 30, &[1; 30], fh_a)
+            .await
+            .unwrap();
+        assert_eq!(Some(60), fs.quota_used());
+        // overwriting doesn't grow the file
+        write_all_bytes_to_fs(&fs, a.ino, 0, &[2; 60], fh_a)
+            .await
+            .unwrap();
+        assert_eq!(Some(60), fs.quota_used());
+
+        // exceeds
+        assert!(matches!(
+            fs.write(a.ino, 60, &[1; 50], fh_a).await,
+            Err(FsError::QuotaExceeded)
+        ));
+        assert!(matches!(
+            fs.set_len(a.ino, 101).await,
+            Err(FsError::QuotaExceeded)
+        ));
+        assert!(matches!(
+            fs.allocate(a.ino, 0, 101, AllocateMode::Default).await,
+            Err(FsError::QuotaExceeded)
+        ));
+        assert_eq!(60, fs.get_attr(a.ino).await.unwrap().size);
+        assert_eq!(Some(60), fs.quota_used());
+        fs.release(fh_a).await.unwrap();
+
+        let (fh_b, b) = create("b").await;
+        assert!(matches!(
+            fs.write(b.ino, 0, &[1; 50], fh_b).await,
+            Err(FsError::QuotaExceeded)
+        ));
+        // deleting frees the quota
+        fs.remove_file(ROOT_INODE, &SecretString::from_str("a").unwrap())
+            .await
+            .unwrap();
+        assert_eq!(Some(0), fs.quota_used());
+        write_all_bytes_to_fs(&fs, b.ino, 0, &[1; 50], fh_b)
+            .await
+            .unwrap();
+        assert_eq!(Some(50), fs.quota_used());
+        // shrinking too
+        fs.set_len(b.ino, 10).await.unwrap();
+        assert_eq!(Some(10), fs.quota_used());
+        // a failed truncate gives the space back
+        fs.fail_point
+            .lock()
+            .unwrap()
+            .replace("set_len:before_commit");
+        assert!(fs.set_len(b.ino, 90).await.is_err());
+        fs.fail_point.lock().unwrap().take();
+        assert_eq!(10, fs.get_attr(b.ino).await.unwrap().size);
+        assert_eq!(Some(10), fs.quota_used());
+
+        // no limit, but still counted
+        fs.set_quota(None).await.unwrap();
+        write_all_bytes_to_fs(&fs, b.ino, 0, &[1; 200], fh_b)
+            .await
+            .unwrap();
+        assert_eq!(Some(200), fs.quota_used());
+        fs.release(fh_b).await.unwrap();
+    })
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_access() {
+    run_test_with_storages(TestSetup { key: "test_access" }, || async {
+        let fs = get_fs().await;
+
+        let (owner, group, other) = (1000, 1000, 2000);
+        let (_, attr) = fs
+            .create(
+                ROOT_INODE,
+                &SecretString::from_str("test-file").unwrap(),
+                CreateFileAttr {
+                    perm: 0o751,
+                    uid: owner,
+                    gid: group,
+                    ..create_attr(FileType::RegularFile)
+                },
+                false,
+                false,
+            )
+            .await
+            .unwrap();
+
+        for (uid, gid, mask, allowed) in [
+            (owner, group, libc::R_OK, true),
+            (owner, group, libc::W_OK, true),
+            (owner, group, libc::X_OK, true),
+            (other, group, libc::R_OK, true),
+            (other, group, libc::W_OK, false),
+            (other, group, libc::X_OK, true),
+            (other, other, libc::R_OK, false),
+            (other, other, libc::W_OK, false),
+            (other, other, libc::X_OK, true),
+            (other, group, libc::R_OK | libc::X_OK, true),
+            (other, group, libc::R_OK | libc::W_OK, false),
+            (other, other, libc::F_OK, true),
+        ] {
+            let res = fs.access(attr.ino, uid, gid, mask).await;
+            if allowed {
+                assert!(res.is_ok(), "{uid} {gid} {mask}");
+            } else {
+                assert!(
+                    matches!(res, Err(FsError::PermissionDenied)),
+                    "{uid} {gid} {mask}"
+                );
+            }
+        }
+
+        assert!(matches!(
+            fs.access(attr.ino, owner, group, 0o100).await,
+            Err(FsError::InvalidInput(_))
+        ));
+        assert!(matches!(
+            fs.access(attr.ino + 1, owner, group, libc::F_OK).await,
+            Err(FsError::InodeNotFound(_))
+        ));
+    })
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_update_attr_set_time() {
+    run_test_with_storages(
+        TestSetup {
+            key: "test_update_attr_set_time",
+        },
+        || async {
+            let fs = get_fs().await;
+
+            let (_, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+
+            // explicit set
+            let time = SystemTime::UNIX_EPOCH + Duration::from_secs(42);
+            fs.update_attr(
+                attr.ino,
+                SetFileAttr::default()
+                    .with_atime(SetTime::Set(time))
+                    .with_mtime(time),
+            )
+            .await
+            .unwrap();
+            let attr = fs.get_attr(attr.ino).await.unwrap();
+            assert_eq!(time, attr.atime);
+            assert_eq!(time, attr.mtime);
+
+            // now, like `touch -a`, mtime is omitted
+            let before = SystemTime::now();
+            fs.update_attr(
+                attr.ino,
+                SetFileAttr::default()
+                    .with_atime(SetTime::Now)
+                    .with_mtime(SetTime::Omit),
+            )
+            .await
+            .unwrap();
+            let attr = fs.get_attr(attr.ino).await.unwrap();
+            assert!(attr.atime >= before);
+            assert_eq!(time, attr.mtime);
+
+            // omitted by default
+            fs.update_attr(attr.ino, SetFileAttr::default().with_mtime(SetTime::Now))
+                .await
+                .unwrap();
+            let attr_touch_m = fs.get_attr(attr.ino).await.unwrap();
+            assert_eq!(attr.atime, attr_touch_m.atime);
+            assert!(attr_touch_m.mtime >= before);
+
+            assert_eq!(None, SetTime::Omit.resolve(before));
+            assert_eq!(Some(before), SetTime::Now.resolve(before));
+            assert_eq!(Some(time), SetTime::Set(time).resolve(before));
+        },
+    )
+    .await;
+}
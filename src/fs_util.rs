@@ -36,9 +36,46 @@ pub async fn rename_dir_content(src: &Path, dst: &Path) -> io::Result<()> {
     Ok(())
 }
 
+/// Open a file for atomic write.
+///
+/// The temporary file is created in the same directory as `file`, so the final rename is never
+/// across filesystems, no matter where the system temp directory is.\
+/// It's opened for read too, the writers read back the blocks they already wrote.
 pub fn open_atomic_write(file: &Path) -> io::Result<AtomicWriteFile> {
     let mut opt = AtomicWriteFile::options();
+    opt.read(true);
     #[cfg(unix)]
     opt.preserve_mode(true).preserve_owner(true);
     opt.open(file)
 }
+
+/// Recursively sum the size of all files in a directory.
+pub fn dir_size(path: &Path) -> io::Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            size += dir_size(&entry.path())?;
+        } else if file_type.is_file() {
+            size += entry.metadata()?.len();
+        }
+    }
+    Ok(size)
+}
+
+/// Statistics of the filesystem containing `path`, see `statvfs(3)`.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub fn statvfs(path: &Path) -> io::Result<libc::statvfs> {
+    use std::ffi::CString;
+    use std::mem::MaybeUninit;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    let res = unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { stat.assume_init() })
+}
@@ -306,6 +306,7 @@ pub mod encryptedfs;
 pub mod expire_value;
 pub mod fs_util;
 pub mod mount;
+pub mod storage;
 pub mod stream_util;
 pub(crate) mod test_common;
 
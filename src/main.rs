@@ -207,6 +207,17 @@ fn get_cli_args() -> ArgMatches {
                     .value_name("DATA_DIR")
                     .help("Where to store the encrypted data"),
             )
+    ).subcommand(
+        Command::new("migrate")
+            .about("Migrate a data dir created by versions that didn't keep the format version to the current format")
+            .arg(
+                Arg::new("data-dir")
+                    .long("data-dir")
+                    .short('d')
+                    .required(true)
+                    .value_name("DATA_DIR")
+                    .help("Where the encrypted data is stored"),
+            )
     )
         .get_matches()
 }
@@ -225,6 +236,7 @@ async fn async_main() -> Result<()> {
     match matches.subcommand() {
         Some(("change-password", matches)) => run_change_password(cipher, matches).await?,
         Some(("mount", matches)) => run_mount(cipher, matches).await?,
+        Some(("migrate", matches)) => run_migrate(cipher, matches).await?,
         None => {
             error!("No subcommand provided");
             return Err(ExitStatusError::Failure(1).into());
@@ -277,6 +289,35 @@ async fn run_change_password(cipher: Cipher, matches: &ArgMatches) -> Result<()>
     Ok(())
 }
 
+async fn run_migrate(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
+    let data_dir: String = matches.get_one::<String>("data-dir").unwrap().to_string();
+
+    // read password from stdin
+    print!("Enter password: ");
+    io::stdout().flush().unwrap();
+    let password = SecretString::new(read_password().unwrap());
+    println!("Migrating...");
+    EncryptedFs::migrate(Path::new(&data_dir), password, cipher)
+        .await
+        .map_err(|err| {
+            match err {
+                FsError::InvalidPassword => {
+                    println!("Invalid password");
+                }
+                FsError::InvalidDataDirStructure => {
+                    println!("Invalid structure of data directory");
+                }
+                _ => {
+                    error!(err = %err);
+                }
+            }
+            ExitStatusError::Failure(1)
+        })?;
+    println!("Migrated successfully");
+
+    Ok(())
+}
+
 async fn run_mount(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
     let mountpoint: String = matches
         .get_one::<String>("mount-point")
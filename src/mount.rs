@@ -22,6 +22,9 @@ use macos::MountHandleInnerImpl;
 #[cfg(target_os = "macos")]
 use macos::MountPointImpl;
 
+#[cfg(all(test, feature = "fuse-tests", target_os = "linux"))]
+mod test;
+
 #[cfg(target_os = "windows")]
 mod windows;
 #[cfg(target_os = "windows")]
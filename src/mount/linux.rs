@@ -23,30 +23,21 @@ use fuse3::raw::{Filesystem, MountHandle, Request, Session};
 use fuse3::{Errno, Inode, MountOptions, Result, SetAttr, Timestamp};
 use futures_util::stream::Iter;
 use futures_util::{stream, FutureExt};
-use libc::{EACCES, EEXIST, EFBIG, EIO, EISDIR, ENAMETOOLONG, ENOENT, ENOTDIR, ENOTEMPTY, EPERM};
+use libc::{EACCES, EEXIST, EFBIG, EIO, EISDIR, ENAMETOOLONG, ENOENT, ENOTDIR, EPERM};
+use secrecy::zeroize::Zeroizing;
 use secrecy::{ExposeSecret, SecretString};
-use tracing::{debug, error, instrument, trace, warn};
+use tracing::{debug, error, instrument, trace};
 use tracing::{info, Level};
 
 use crate::crypto::Cipher;
 use crate::encryptedfs::{
-    CreateFileAttr, EncryptedFs, FileAttr, FileType, FsError, FsResult, PasswordProvider,
-    SetFileAttr,
+    check_access, AllocateMode, CreateFileAttr, EncryptedFs, FileAttr, FileType, FsError, FsResult,
+    OpenFlags, PasswordProvider, RenameFlags, SetFileAttr,
 };
 use crate::mount;
 use crate::mount::{MountHandleInner, MountPoint};
 
 const TTL: Duration = Duration::from_secs(1);
-const STATFS: ReplyStatFs = ReplyStatFs {
-    blocks: 1,
-    bfree: 0,
-    bavail: 0,
-    files: 1,
-    ffree: 0,
-    bsize: 4096,
-    namelen: u32::MAX,
-    frsize: 0,
-};
 
 const FMODE_EXEC: i32 = 0x20;
 
@@ -64,11 +55,7 @@ impl Iterator for DirectoryEntryIterator {
     fn next(&mut self) -> Option<Self::Item> {
         match self.0.next() {
             Some(Ok(entry)) => {
-                let kind = if entry.kind == FileType::Directory {
-                    fuse3::raw::prelude::FileType::Directory
-                } else {
-                    fuse3::raw::prelude::FileType::RegularFile
-                };
+                let kind = entry.kind.into();
                 self.1 += 1;
                 Some(Ok(DirectoryEntry {
                     inode: entry.ino,
@@ -99,11 +86,7 @@ impl Iterator for DirectoryEntryPlusIterator {
     fn next(&mut self) -> Option<Self::Item> {
         match self.0.next() {
             Some(Ok(entry)) => {
-                let kind = if entry.kind == FileType::Directory {
-                    fuse3::raw::prelude::FileType::Directory
-                } else {
-                    fuse3::raw::prelude::FileType::RegularFile
-                };
+                let kind = entry.kind.into();
                 self.1 += 1;
                 Some(Ok(DirectoryEntryPlus {
                     inode: entry.ino,
@@ -180,6 +163,7 @@ impl EncryptedFsFuse3 {
         &self,
         parent: u64,
         mut mode: u32,
+        rdev: u32,
         req: &Request,
         name: &OsStr,
         read: bool,
@@ -208,12 +192,14 @@ impl EncryptedFsFuse3 {
             mode &= !(libc::S_ISUID | libc::S_ISGID);
         }
 
-        let kind = as_file_kind(mode);
+        let kind = as_file_kind(mode)?;
         let mut attr = if kind == FileType::Directory {
             dir_attr()
         } else {
             file_attr()
         };
+        attr.kind = kind;
+        attr.rdev = rdev;
         attr.perm = self.creation_mode(mode);
         attr.uid = req.uid;
         attr.gid = creation_gid(&parent_attr, req.gid);
@@ -231,7 +217,6 @@ impl EncryptedFsFuse3 {
             .map_err(|err| {
                 error!(err = %err);
                 match err {
-                    FsError::AlreadyExists => EEXIST,
                     FsError::Io { source, .. } => {
                         if source.to_string().to_lowercase().contains("too long") {
                             ENAMETOOLONG
@@ -239,11 +224,180 @@ impl EncryptedFsFuse3 {
                             EIO
                         }
                     }
-                    _ => EIO,
+                    err => err.to_errno(),
                 }
             })?;
         Ok((fh, attr))
     }
+
+    async fn do_rename(
+        &self,
+        req: Request,
+        parent: Inode,
+        name: &OsStr,
+        new_parent: Inode,
+        new_name: &OsStr,
+        flags: RenameFlags,
+    ) -> Result<()> {
+        let Ok(Some(attr)) = self
+            .get_fs()
+            .find_by_name(
+                parent,
+                &SecretString::from_str(name.to_str().unwrap()).unwrap(),
+            )
+            .await
+        else {
+            error!(
+                parent,
+                name = name.to_str().unwrap(),
+                new_name = new_name.to_str().unwrap()
+            );
+            return Err(ENOENT.into());
+        };
+
+        let Ok(parent_attr) = self.get_fs().get_attr(parent).await else {
+            error!(parent, "parent not found");
+            return Err(ENOENT.into());
+        };
+
+        if !check_access(
+            parent_attr.uid,
+            parent_attr.gid,
+            parent_attr.perm,
+            req.uid,
+            req.gid,
+            libc::W_OK,
+        ) {
+            return Err(EACCES.into());
+        }
+
+        // "Sticky bit" handling
+        #[allow(clippy::cast_possible_truncation)]
+        if parent_attr.perm & libc::S_ISVTX as u16 != 0
+            && req.uid != 0
+            && req.uid != parent_attr.uid
+            && req.uid != attr.uid
+        {
+            return Err(EACCES.into());
+        }
+
+        let Ok(new_parent_attr) = self.get_fs().get_attr(new_parent).await else {
+            error!(new_parent, "not found");
+            return Err(ENOENT.into());
+        };
+
+        if !check_access(
+            new_parent_attr.uid,
+            new_parent_attr.gid,
+            new_parent_attr.perm,
+            req.uid,
+            req.gid,
+            libc::W_OK,
+        ) {
+            return Err(EACCES.into());
+        }
+
+        // "Sticky bit" handling in new_parent
+        #[allow(clippy::cast_possible_truncation)]
+        if new_parent_attr.perm & libc::S_ISVTX as u16 != 0 {
+            if let Ok(Some(new_attrs)) = self
+                .get_fs()
+                .find_by_name(
+                    new_parent,
+                    &SecretString::from_str(new_name.to_str().unwrap()).unwrap(),
+                )
+                .await
+            {
+                if req.uid != 0 && req.uid != new_parent_attr.uid && req.uid != new_attrs.uid {
+                    return Err(EACCES.into());
+                }
+            }
+        }
+
+        // Only move an existing directory to a new parent, if we have write access to it,
+        // because that will change the ".." link in it
+        if attr.kind == FileType::Directory
+            && parent != new_parent
+            && !check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, libc::W_OK)
+        {
+            return Err(EACCES.into());
+        }
+
+        self.get_fs()
+            .rename_with(
+                parent,
+                &SecretString::from_str(name.to_str().unwrap()).unwrap(),
+                new_parent,
+                &SecretString::from_str(new_name.to_str().unwrap()).unwrap(),
+                flags,
+            )
+            .await
+            .map_err(|err| err.to_errno().into())
+    }
+
+    async fn do_exchange(
+        &self,
+        req: Request,
+        parent: Inode,
+        name: &OsStr,
+        new_parent: Inode,
+        new_name: &OsStr,
+    ) -> Result<()> {
+        let name = SecretString::from_str(name.to_str().unwrap()).unwrap();
+        let new_name = SecretString::from_str(new_name.to_str().unwrap()).unwrap();
+        let (Ok(Some(attr)), Ok(Some(new_attr))) = (
+            self.get_fs().find_by_name(parent, &name).await,
+            self.get_fs().find_by_name(new_parent, &new_name).await,
+        ) else {
+            return Err(ENOENT.into());
+        };
+
+        // both entries are replaced, so the same checks as rename apply in both parents
+        for (dir, entry_attr) in [(parent, &attr), (new_parent, &new_attr)] {
+            let Ok(dir_attr) = self.get_fs().get_attr(dir).await else {
+                error!(dir, "not found");
+                return Err(ENOENT.into());
+            };
+            if !check_access(
+                dir_attr.uid,
+                dir_attr.gid,
+                dir_attr.perm,
+                req.uid,
+                req.gid,
+                libc::W_OK,
+            ) {
+                return Err(EACCES.into());
+            }
+            // "Sticky bit" handling
+            #[allow(clippy::cast_possible_truncation)]
+            if dir_attr.perm & libc::S_ISVTX as u16 != 0
+                && req.uid != 0
+                && req.uid != dir_attr.uid
+                && req.uid != entry_attr.uid
+            {
+                return Err(EACCES.into());
+            }
+            // moving a directory to another parent changes the ".." link in it
+            if entry_attr.kind == FileType::Directory
+                && parent != new_parent
+                && !check_access(
+                    entry_attr.uid,
+                    entry_attr.gid,
+                    entry_attr.perm,
+                    req.uid,
+                    req.gid,
+                    libc::W_OK,
+                )
+            {
+                return Err(EACCES.into());
+            }
+        }
+
+        self.get_fs()
+            .exchange(parent, &name, new_parent, &new_name)
+            .await
+            .map_err(|err| err.to_errno().into())
+    }
 }
 
 #[allow(clippy::cast_possible_truncation)]
@@ -264,11 +418,7 @@ impl From<FileAttr> for fuse3::raw::prelude::FileAttr {
             atime: from.atime.into(),
             mtime: from.mtime.into(),
             ctime: from.ctime.into(),
-            kind: if from.kind == FileType::Directory {
-                fuse3::raw::prelude::FileType::Directory
-            } else {
-                fuse3::raw::prelude::FileType::RegularFile
-            },
+            kind: from.kind.into(),
             perm: from.perm,
             nlink: from.nlink,
             uid: from.uid,
@@ -279,6 +429,19 @@ impl From<FileAttr> for fuse3::raw::prelude::FileAttr {
     }
 }
 
+impl From<FileType> for fuse3::raw::prelude::FileType {
+    fn from(kind: FileType) -> Self {
+        match kind {
+            FileType::Directory => Self::Directory,
+            FileType::RegularFile => Self::RegularFile,
+            FileType::Symlink => Self::Symlink,
+            FileType::NamedPipe => Self::NamedPipe,
+            FileType::CharDevice => Self::CharDevice,
+            FileType::BlockDevice => Self::BlockDevice,
+        }
+    }
+}
+
 impl Filesystem for EncryptedFsFuse3 {
     #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::INFO))]
     async fn init(&self, req: Request) -> Result<ReplyInit> {
@@ -408,7 +571,7 @@ impl Filesystem for EncryptedFsFuse3 {
             }
             set_attr2 = set_attr2.with_atime(SystemTime::now());
             self.get_fs()
-                .set_attr(inode, set_attr2)
+                .update_attr(inode, set_attr2)
                 .await
                 .map_err(|err| {
                     error!(err = %err);
@@ -428,13 +591,13 @@ impl Filesystem for EncryptedFsFuse3 {
         if set_attr.uid.is_some() || set_attr.gid.is_some() {
             debug!(?set_attr.uid, ?set_attr.gid, "chown");
             let mut set_attr2 = SetFileAttr::default();
-            if let Some(gid) = set_attr2.gid {
+            if let Some(gid) = set_attr.gid {
                 // Non-root users can only change gid to a group they're in
                 if req.uid != 0 && !get_groups(req.pid).contains(&gid) {
                     return Err(EPERM.into());
                 }
             }
-            if let Some(uid) = set_attr2.uid {
+            if let Some(uid) = set_attr.uid {
                 if req.uid != 0
                     // but no-op changes by the owner are not an error
                     && !(uid == attr.uid && req.uid == attr.uid)
@@ -443,7 +606,7 @@ impl Filesystem for EncryptedFsFuse3 {
                 }
             }
             // Only owner may change the group
-            if set_attr2.gid.is_some() && req.uid != 0 && req.uid != attr.uid {
+            if set_attr.gid.is_some() && req.uid != 0 && req.uid != attr.uid {
                 return Err(EPERM.into());
             }
 
@@ -453,13 +616,13 @@ impl Filesystem for EncryptedFsFuse3 {
                 set_attr2 = set_attr2.with_perm(clear_suid_sgid(attr.perm));
             }
 
-            if let Some(uid) = set_attr2.uid {
+            if let Some(uid) = set_attr.uid {
                 set_attr2 = set_attr2.with_uid(uid);
                 // Clear SETUID on owner change
                 let perm = *set_attr2.perm.as_ref().unwrap();
                 set_attr2 = set_attr2.with_perm(perm & !(libc::S_ISUID as u16));
             }
-            if let Some(gid) = set_attr2.gid {
+            if let Some(gid) = set_attr.gid {
                 set_attr2 = set_attr2.with_gid(gid);
                 // Clear SETGID unless user is root
                 if req.uid != 0 {
@@ -469,7 +632,7 @@ impl Filesystem for EncryptedFsFuse3 {
             }
             set_attr2 = set_attr2.with_atime(SystemTime::now());
             self.get_fs()
-                .set_attr(inode, set_attr2)
+                .update_attr(inode, set_attr2)
                 .await
                 .map_err(|err| {
                     error!(err = %err);
@@ -489,10 +652,6 @@ impl Filesystem for EncryptedFsFuse3 {
         if let Some(size) = set_attr.size {
             debug!(size, "truncate");
 
-            self.get_fs().set_len(inode, size).await.map_err(|err| {
-                error!(err = %err);
-                Errno::from(EIO)
-            })?;
             set_attr2 = set_attr2.with_size(size);
 
             // Clear SETUID & SETGID on truncate
@@ -526,7 +685,7 @@ impl Filesystem for EncryptedFsFuse3 {
         }
 
         self.get_fs()
-            .set_attr(inode, set_attr2)
+            .update_attr(inode, set_attr2)
             .await
             .map_err(|err| {
                 error!(err = %err);
@@ -544,6 +703,78 @@ impl Filesystem for EncryptedFsFuse3 {
         })
     }
 
+    #[instrument(skip(self), err(level = Level::WARN))]
+    async fn readlink(&self, req: Request, inode: Inode) -> Result<ReplyData> {
+        trace!("");
+
+        match self.get_fs().read_link(inode).await {
+            Ok(target) => Ok(ReplyData {
+                data: Bytes::copy_from_slice(target.expose_secret().as_bytes()),
+            }),
+            Err(FsError::InvalidInodeType) => Err(libc::EINVAL.into()),
+            Err(err) => {
+                error!(err = %err);
+                Err(ENOENT.into())
+            }
+        }
+    }
+
+    #[instrument(skip(self, name, link), fields(name = name.to_str().unwrap()), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    async fn symlink(
+        &self,
+        req: Request,
+        parent: Inode,
+        name: &OsStr,
+        link: &OsStr,
+    ) -> Result<ReplyEntry> {
+        trace!("");
+
+        let parent_attr = match self.get_fs().get_attr(parent).await {
+            Err(err) => {
+                error!(err = %err);
+                return Err(ENOENT.into());
+            }
+            Ok(parent_attr) => parent_attr,
+        };
+
+        if !check_access(
+            parent_attr.uid,
+            parent_attr.gid,
+            parent_attr.perm,
+            req.uid,
+            req.gid,
+            libc::W_OK,
+        ) {
+            return Err(EACCES.into());
+        }
+
+        let mut attr = symlink_attr();
+        attr.uid = req.uid;
+        attr.gid = creation_gid(&parent_attr, req.gid);
+
+        let attr = self
+            .get_fs()
+            .create_symlink(
+                parent,
+                &SecretString::from_str(name.to_str().unwrap()).unwrap(),
+                &SecretString::from_str(link.to_str().unwrap()).unwrap(),
+                attr,
+            )
+            .await
+            .map_err(|err| {
+                error!(err = %err);
+                match err {
+                    FsError::AlreadyExists => EEXIST,
+                    _ => EIO,
+                }
+            })?;
+        Ok(ReplyEntry {
+            ttl: TTL,
+            attr: attr.into(),
+            generation: 0,
+        })
+    }
+
     #[instrument(skip(self, name), fields(name = name.to_str().unwrap()), err(level = Level::WARN), ret(level = Level::DEBUG))]
     async fn mknod(
         &self,
@@ -556,18 +787,12 @@ impl Filesystem for EncryptedFsFuse3 {
         trace!("");
         debug!("mode={mode:o}");
 
-        let file_type = mode & libc::S_IFMT;
-
-        if file_type != libc::S_IFREG
-            // && file_type != libc::S_IFLNK as u32
-            && file_type != libc::S_IFDIR
-        {
-            // TODO
-            warn!("implementation is incomplete. Only supports regular files and directories. Got mode={mode:o}");
-            return Err(libc::ENOSYS.into());
+        if mode & libc::S_IFMT == libc::S_IFLNK {
+            // symlinks are created with `symlink`, they need a target
+            return Err(libc::EINVAL.into());
         }
 
-        self.create_nod(parent, mode, &req, name, false, false)
+        self.create_nod(parent, mode, rdev, &req, name, false, false)
             .await
             .map_err(|err| {
                 error!(err = %err);
@@ -649,6 +874,59 @@ impl Filesystem for EncryptedFsFuse3 {
         })
     }
 
+    #[instrument(skip(self, new_name), fields(new_name = new_name.to_str().unwrap()), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    async fn link(
+        &self,
+        req: Request,
+        inode: Inode,
+        new_parent: Inode,
+        new_name: &OsStr,
+    ) -> Result<ReplyEntry> {
+        trace!("");
+
+        let parent_attr = match self.get_fs().get_attr(new_parent).await {
+            Err(err) => {
+                error!(err = %err);
+                return Err(ENOENT.into());
+            }
+            Ok(attr) => attr,
+        };
+
+        if !check_access(
+            parent_attr.uid,
+            parent_attr.gid,
+            parent_attr.perm,
+            req.uid,
+            req.gid,
+            libc::W_OK,
+        ) {
+            return Err(EACCES.into());
+        }
+
+        let attr = self
+            .get_fs()
+            .link(
+                inode,
+                new_parent,
+                &SecretString::from_str(new_name.to_str().unwrap()).unwrap(),
+            )
+            .await
+            .map_err(|err| {
+                error!(err = %err);
+                match err {
+                    FsError::AlreadyExists => EEXIST,
+                    FsError::InodeNotFound(_) => ENOENT,
+                    FsError::InvalidInodeType => EPERM,
+                    _ => EIO,
+                }
+            })?;
+        Ok(ReplyEntry {
+            ttl: TTL,
+            attr: attr.into(),
+            generation: 0,
+        })
+    }
+
     #[instrument(skip(self, name), fields(name = name.to_str().unwrap()), err(level = Level::WARN), ret(level = Level::DEBUG))]
     async fn unlink(&self, req: Request, parent: Inode, name: &OsStr) -> Result<()> {
         trace!("");
@@ -771,7 +1049,7 @@ impl Filesystem for EncryptedFsFuse3 {
         {
             error!(err = %err);
             return match err {
-                FsError::NotEmpty => Err(EISDIR.into()),
+                FsError::NotEmpty(_) => Err(EISDIR.into()),
                 _ => Err(EIO.into()),
             };
         }
@@ -790,104 +1068,42 @@ impl Filesystem for EncryptedFsFuse3 {
     ) -> Result<()> {
         trace!("");
 
-        let Ok(Some(attr)) = self
-            .get_fs()
-            .find_by_name(
-                parent,
-                &SecretString::from_str(name.to_str().unwrap()).unwrap(),
-            )
-            .await
-        else {
-            error!(
-                parent,
-                name = name.to_str().unwrap(),
-                new_name = new_name.to_str().unwrap()
-            );
-            return Err(ENOENT.into());
-        };
-
-        let Ok(parent_attr) = self.get_fs().get_attr(parent).await else {
-            error!(parent, "parent not found");
-            return Err(ENOENT.into());
-        };
-
-        if !check_access(
-            parent_attr.uid,
-            parent_attr.gid,
-            parent_attr.perm,
-            req.uid,
-            req.gid,
-            libc::W_OK,
-        ) {
-            return Err(EACCES.into());
-        }
-
-        // "Sticky bit" handling
-        #[allow(clippy::cast_possible_truncation)]
-        if parent_attr.perm & libc::S_ISVTX as u16 != 0
-            && req.uid != 0
-            && req.uid != parent_attr.uid
-            && req.uid != attr.uid
-        {
-            return Err(EACCES.into());
-        }
-
-        let Ok(new_parent_attr) = self.get_fs().get_attr(new_parent).await else {
-            error!(new_parent, "not found");
-            return Err(ENOENT.into());
-        };
+        self.do_rename(
+            req,
+            parent,
+            name,
+            new_parent,
+            new_name,
+            RenameFlags::default(),
+        )
+        .await
+    }
 
-        if !check_access(
-            new_parent_attr.uid,
-            new_parent_attr.gid,
-            new_parent_attr.perm,
-            req.uid,
-            req.gid,
-            libc::W_OK,
-        ) {
-            return Err(EACCES.into());
-        }
+    #[instrument(skip(self, name, new_name), fields(name = name.to_str().unwrap(), new_name = new_name.to_str().unwrap()), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    async fn rename2(
+        &self,
+        req: Request,
+        parent: Inode,
+        name: &OsStr,
+        new_parent: Inode,
+        new_name: &OsStr,
+        flags: u32,
+    ) -> Result<()> {
+        trace!("");
 
-        // "Sticky bit" handling in new_parent
-        #[allow(clippy::cast_possible_truncation)]
-        if new_parent_attr.perm & libc::S_ISVTX as u16 != 0 {
-            if let Ok(Some(new_attrs)) = self
-                .get_fs()
-                .find_by_name(
-                    new_parent,
-                    &SecretString::from_str(new_name.to_str().unwrap()).unwrap(),
-                )
-                .await
-            {
-                if req.uid != 0 && req.uid != new_parent_attr.uid && req.uid != new_attrs.uid {
-                    return Err(EACCES.into());
-                }
+        let rename_flags = match flags {
+            0 => RenameFlags::default(),
+            libc::RENAME_NOREPLACE => RenameFlags::default().with_no_replace(true),
+            libc::RENAME_EXCHANGE => {
+                return self
+                    .do_exchange(req, parent, name, new_parent, new_name)
+                    .await;
             }
-        }
-
-        // Only move an existing directory to a new parent, if we have write access to it,
-        // because that will change the ".." link in it
-        if attr.kind == FileType::Directory
-            && parent != new_parent
-            && !check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, libc::W_OK)
-        {
-            return Err(EACCES.into());
-        }
-
-        match self
-            .get_fs()
-            .rename(
-                parent,
-                &SecretString::from_str(name.to_str().unwrap()).unwrap(),
-                new_parent,
-                &SecretString::from_str(new_name.to_str().unwrap()).unwrap(),
-            )
+            // whiteout is not supported
+            _ => return Err(libc::EINVAL.into()),
+        };
+        self.do_rename(req, parent, name, new_parent, new_name, rename_flags)
             .await
-        {
-            Ok(()) => Ok(()),
-            Err(FsError::NotEmpty) => Err(ENOTEMPTY.into()),
-            _ => Err(ENOENT.into()),
-        }
     }
 
     #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
@@ -926,16 +1142,16 @@ impl Filesystem for EncryptedFsFuse3 {
         })?;
         //
         if check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, access_mask) {
-            if truncate {
-                self.get_fs().set_len(attr.ino, 0).await.map_err(|err| {
-                    error!(err = %err);
-                    EIO
-                })?;
-            }
             let open_flags = if self.direct_io { FOPEN_DIRECT_IO } else { 0 };
             let fh = self
                 .get_fs()
-                .open(inode, read, write)
+                .open_with(
+                    inode,
+                    OpenFlags::default()
+                        .with_read(read)
+                        .with_write(write)
+                        .with_truncate(truncate),
+                )
                 .await
                 .map_err(|err| {
                     error!(err = %err);
@@ -961,7 +1177,7 @@ impl Filesystem for EncryptedFsFuse3 {
     ) -> Result<ReplyData> {
         trace!("");
 
-        let mut buf = vec![0; size as usize];
+        let mut buf = Zeroizing::new(vec![0; size as usize]);
         match self.get_fs().read(inode, offset, &mut buf, fh).await {
             Err(err) => {
                 error!(err = %err);
@@ -995,6 +1211,7 @@ impl Filesystem for EncryptedFsFuse3 {
                 error!(err = %err);
                 match err {
                     FsError::MaxFilesizeExceeded(_) => EFBIG,
+                    FsError::QuotaExceeded => libc::EDQUOT,
                     _ => EIO,
                 }
             })?;
@@ -1008,8 +1225,23 @@ impl Filesystem for EncryptedFsFuse3 {
     #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
     async fn statfs(&self, req: Request, inode: u64) -> Result<ReplyStatFs> {
         trace!("");
-        warn!("implementation is a stub");
-        Ok(STATFS)
+
+        match self.get_fs().statfs().await {
+            Ok(stat) => Ok(ReplyStatFs {
+                blocks: stat.blocks,
+                bfree: stat.bfree,
+                bavail: stat.bavail,
+                files: stat.files,
+                ffree: stat.ffree,
+                bsize: stat.bsize,
+                namelen: stat.namelen,
+                frsize: stat.frsize,
+            }),
+            Err(err) => {
+                error!(err = %err);
+                Err(EIO.into())
+            }
+        }
     }
 
     #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
@@ -1071,6 +1303,45 @@ impl Filesystem for EncryptedFsFuse3 {
         Ok(())
     }
 
+    #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    async fn fsync(&self, req: Request, inode: Inode, fh: u64, datasync: bool) -> Result<()> {
+        trace!("");
+
+        if let Err(err) = self.get_fs().fsync(fh, datasync).await {
+            error!(err = %err, fh);
+            return Err(EIO.into());
+        }
+
+        Ok(())
+    }
+
+    #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    #[allow(clippy::cast_possible_wrap)]
+    async fn fallocate(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        offset: u64,
+        length: u64,
+        mode: u32,
+    ) -> Result<()> {
+        trace!("");
+
+        let mode = match mode as i32 {
+            0 => AllocateMode::Default,
+            libc::FALLOC_FL_KEEP_SIZE => AllocateMode::KeepSize,
+            // punching holes and the other modes are not supported
+            _ => return Err(libc::EOPNOTSUPP.into()),
+        };
+        if let Err(err) = self.get_fs().allocate(inode, offset, length, mode).await {
+            error!(err = %err, inode);
+            return Err(err.to_errno().into());
+        }
+
+        Ok(())
+    }
+
     #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
     #[allow(clippy::cast_possible_wrap)]
     async fn opendir(&self, req: Request, inode: Inode, flags: u32) -> Result<ReplyOpen> {
@@ -1101,9 +1372,17 @@ impl Filesystem for EncryptedFsFuse3 {
         };
 
         if check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, access_mask) {
+            // the handle keeps the entries, so `readdir` offsets are stable between calls
+            let fh = match self.get_fs().opendir(inode).await {
+                Err(err) => {
+                    error!(err = %err);
+                    return Err(err.to_errno().into());
+                }
+                Ok(fh) => fh,
+            };
             let open_flags = if self.direct_io { FOPEN_DIRECT_IO } else { 0 };
             Ok(ReplyOpen {
-                fh: 0, // we don't use handles for directories
+                fh,
                 flags: open_flags,
             })
         } else {
@@ -1123,6 +1402,23 @@ impl Filesystem for EncryptedFsFuse3 {
     ) -> Result<ReplyDirectory<Self::DirEntryStream<'_>>> {
         trace!("");
 
+        if fh != 0 {
+            // list from the entries we took on `opendir`
+            #[allow(clippy::cast_sign_loss)]
+            let iter = match self.get_fs().readdir(fh, offset as u64).await {
+                Err(err) => {
+                    error!(err = %err);
+                    return Err(EIO.into());
+                }
+                Ok(iter) => iter,
+            };
+            #[allow(clippy::cast_sign_loss)]
+            let iter = DirectoryEntryIterator(iter, offset as u64);
+            return Ok(ReplyDirectory {
+                entries: stream::iter(iter.skip(0)),
+            });
+        }
+
         #[allow(clippy::cast_sign_loss)]
         let iter = match self.get_fs().read_dir(inode).await {
             Err(err) => {
@@ -1144,6 +1440,13 @@ impl Filesystem for EncryptedFsFuse3 {
     async fn releasedir(&self, req: Request, inode: Inode, fh: u64, flags: u32) -> Result<()> {
         trace!("");
 
+        if fh != 0 {
+            if let Err(err) = self.get_fs().releasedir(fh).await {
+                error!(err = %err, fh);
+                return Err(EIO.into());
+            }
+        }
+
         Ok(())
     }
 
@@ -1151,17 +1454,12 @@ impl Filesystem for EncryptedFsFuse3 {
     async fn access(&self, req: Request, inode: u64, mask: u32) -> Result<()> {
         trace!("");
 
-        self.get_fs().get_attr(inode).await.map_or_else(
-            |_| Err(ENOENT.into()),
-            |attr| {
-                #[allow(clippy::cast_possible_wrap)]
-                if check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, mask as i32) {
-                    Ok(())
-                } else {
-                    Err(EACCES.into())
-                }
-            },
-        )
+        #[allow(clippy::cast_possible_wrap)]
+        let mask = mask as i32;
+        self.get_fs()
+            .access(inode, req.uid, req.gid, mask)
+            .await
+            .map_err(|err| err.to_errno().into())
     }
 
     #[instrument(skip(self, name), fields(name = name.to_str().unwrap()), err(level = Level::WARN), ret(level = Level::DEBUG))]
@@ -1187,7 +1485,7 @@ impl Filesystem for EncryptedFsFuse3 {
         };
 
         let (handle, attr) = self
-            .create_nod(parent, mode, &req, name, read, write)
+            .create_nod(parent, mode, 0, &req, name, read, write)
             .await
             .map_err(|err| {
                 error!(err = %err);
@@ -1299,17 +1597,26 @@ const fn clear_suid_sgid(mut perm: u16) -> u16 {
     perm
 }
 
-fn as_file_kind(mut mode: u32) -> FileType {
+/// Sockets are not supported, other unknown types are invalid.
+fn as_file_kind(mut mode: u32) -> std::result::Result<FileType, c_int> {
     mode &= libc::S_IFMT;
 
     if mode == libc::S_IFREG {
-        FileType::RegularFile
-        // } else if mode == libc::S_IFLNK as u32 {
-        //     return FileType::Symlink;
+        Ok(FileType::RegularFile)
+    } else if mode == libc::S_IFLNK {
+        Ok(FileType::Symlink)
     } else if mode == libc::S_IFDIR {
-        FileType::Directory
+        Ok(FileType::Directory)
+    } else if mode == libc::S_IFIFO {
+        Ok(FileType::NamedPipe)
+    } else if mode == libc::S_IFCHR {
+        Ok(FileType::CharDevice)
+    } else if mode == libc::S_IFBLK {
+        Ok(FileType::BlockDevice)
+    } else if mode == libc::S_IFSOCK {
+        Err(libc::EOPNOTSUPP)
     } else {
-        unimplemented!("{mode}");
+        Err(libc::EINVAL)
     }
 }
 
@@ -1335,39 +1642,15 @@ const fn file_attr() -> CreateFileAttr {
     }
 }
 
-fn check_access(
-    #[allow(clippy::similar_names)] file_uid: u32,
-    #[allow(clippy::similar_names)] file_gid: u32,
-    file_mode: u16,
-    uid: u32,
-    gid: u32,
-    mut access_mask: i32,
-) -> bool {
-    // F_OK tests for existence of file
-    if access_mask == libc::F_OK {
-        return true;
-    }
-    let file_mode = i32::from(file_mode);
-
-    // root is allowed to read & write anything
-    if uid == 0 {
-        // root only allowed to exec if one of the X bits is set
-        access_mask &= libc::X_OK;
-        access_mask -= access_mask & (file_mode >> 6);
-        access_mask -= access_mask & (file_mode >> 3);
-        access_mask -= access_mask & file_mode;
-        return access_mask == 0;
-    }
-
-    if uid == file_uid {
-        access_mask -= access_mask & (file_mode >> 6);
-    } else if gid == file_gid {
-        access_mask -= access_mask & (file_mode >> 3);
-    } else {
-        access_mask -= access_mask & file_mode;
+const fn symlink_attr() -> CreateFileAttr {
+    CreateFileAttr {
+        kind: FileType::Symlink,
+        perm: 0o777,
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+        flags: 0,
     }
-
-    access_mask == 0
 }
 
 #[allow(clippy::cast_sign_loss)]
@@ -0,0 +1,71 @@
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use secrecy::SecretString;
+use tempfile::tempdir;
+
+use crate::crypto::Cipher;
+use crate::encryptedfs::PasswordProvider;
+use crate::mount::{create_mount_point, MountHandle, MountPoint};
+
+struct TestPasswordProvider {}
+impl PasswordProvider for TestPasswordProvider {
+    fn get_password(&self) -> Option<SecretString> {
+        Some(SecretString::from_str("password").unwrap())
+    }
+}
+
+async fn mount(mount_dir: &Path, data_dir: &Path) -> MountHandle {
+    create_mount_point(
+        mount_dir,
+        data_dir,
+        Box::new(TestPasswordProvider {}),
+        Cipher::ChaCha20Poly1305,
+        false,
+        false,
+        false,
+        false,
+    )
+    .mount()
+    .await
+    .unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_mount_write_read() {
+    let mount_dir = tempdir().unwrap();
+    let data_dir = tempdir().unwrap();
+
+    let handle = mount(mount_dir.path(), data_dir.path()).await;
+    let path = mount_dir.path().join("test-file");
+    // access it through the OS, in a blocking thread so we don't block the runtime serving the requests
+    let (content, names) = tokio::task::spawn_blocking({
+        let path = path.clone();
+        let dir = mount_dir.path().to_path_buf();
+        move || {
+            fs::create_dir(dir.join("test-dir")).unwrap();
+            fs::write(&path, b"hello from the OS").unwrap();
+            let content = fs::read(&path).unwrap();
+            let mut names: Vec<String> = fs::read_dir(&dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+                .collect();
+            names.sort();
+            (content, names)
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(b"hello from the OS".as_slice(), content);
+    assert_eq!(vec!["test-dir", "test-file"], names);
+    handle.umount().await.unwrap();
+
+    // data survives remount
+    let handle = mount(mount_dir.path(), data_dir.path()).await;
+    let content = tokio::task::spawn_blocking(move || fs::read(path).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(b"hello from the OS".as_slice(), content);
+    handle.umount().await.unwrap();
+}
@@ -0,0 +1,828 @@
+//! Where [`crate::encryptedfs::EncryptedFs`] keeps its files.
+//!
+//! [`FsStorage`] stores them in a directory on the real filesystem, [`MemoryStorage`] keeps them
+//! in memory, so tests can run without touching the disk.\
+//! Paths are relative to the root of the storage.
+#![allow(clippy::module_name_repetitions)]
+
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::{fs, io};
+
+use atomic_write_file::AtomicWriteFile;
+
+use crate::fs_util;
+
+#[cfg(test)]
+mod test;
+
+/// File opened from a [`Storage`].
+pub trait StorageFile: Read + Write + Seek + Send + Sync {
+    /// Make sure the content and the metadata reached the storage, like [`File::sync_all`].
+    #[allow(clippy::missing_errors_doc)]
+    fn sync_all(&self) -> io::Result<()>;
+
+    /// Like [`StorageFile::sync_all`], but only the metadata needed to read the content back.
+    #[allow(clippy::missing_errors_doc)]
+    fn sync_data(&self) -> io::Result<()>;
+
+    #[allow(clippy::missing_errors_doc)]
+    fn size(&self) -> io::Result<u64>;
+
+    /// Truncate or extend the file with zeros, the position is not changed.
+    #[allow(clippy::missing_errors_doc)]
+    fn set_len(&self, size: u64) -> io::Result<()>;
+}
+
+/// File opened with [`Storage::open_atomic`].
+pub trait AtomicStorageFile: StorageFile {
+    /// Replace the file with what was written, in one step.
+    ///
+    /// If it's dropped without commit, the file is left as it was.
+    #[allow(clippy::missing_errors_doc)]
+    fn commit(self: Box<Self>) -> io::Result<()>;
+}
+
+/// Free space of a [`Storage`], `0` for the values it can't tell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StorageSpace {
+    /// Free bytes
+    pub free: u64,
+    /// Free bytes available to unprivileged users
+    pub avail: u64,
+    /// How many more files can be created
+    pub files_free: u64,
+    /// Max length of file names
+    pub name_max: u32,
+}
+
+/// The operations [`crate::encryptedfs::EncryptedFs`] needs from where it keeps its files.
+///
+/// They behave like the ones from [`std::fs`], and return the same [`io::ErrorKind`] on errors.
+#[allow(clippy::missing_errors_doc)]
+pub trait Storage: Send + Sync {
+    /// Open an existing file for reading only.
+    fn open(&self, path: &Path) -> io::Result<Box<dyn StorageFile>>;
+
+    /// Open an existing file for read and write.
+    fn open_rw(&self, path: &Path) -> io::Result<Box<dyn StorageFile>>;
+
+    /// Create a file for read and write, truncating it if it exists.
+    fn create(&self, path: &Path) -> io::Result<Box<dyn StorageFile>>;
+
+    /// Open a file for appending, creating it if it doesn't exist.
+    fn append(&self, path: &Path) -> io::Result<Box<dyn StorageFile>>;
+
+    /// Open a new, empty, file for read and write, that replaces the one at `path` only on
+    /// [`AtomicStorageFile::commit`].
+    ///
+    /// Readers see either the old content or the whole new one, also if we crash while writing.
+    fn open_atomic(&self, path: &Path) -> io::Result<Box<dyn AtomicStorageFile>>;
+
+    /// Read the whole content of a file.
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let mut buf = vec![];
+        self.open(path)?.read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Replace the content of a file, creating it if needed.
+    fn write(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        let mut file = self.create(path)?;
+        file.write_all(data)?;
+        file.flush()
+    }
+
+    /// Rename a file or directory, replacing `to` if it's a file.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    /// Remove a directory with everything in it.
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// Create a directory, the parent must exist.
+    fn create_dir(&self, path: &Path) -> io::Result<()>;
+
+    /// Create a directory and all the missing parents.
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// Names of the entries in a directory, in no particular order.
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<OsString>>;
+
+    fn is_file(&self, path: &Path) -> bool;
+
+    fn is_dir(&self, path: &Path) -> bool;
+
+    fn exists(&self, path: &Path) -> bool {
+        self.is_file(path) || self.is_dir(path)
+    }
+
+    /// Size of a file.
+    fn file_size(&self, path: &Path) -> io::Result<u64> {
+        self.open(path)?.size()
+    }
+
+    /// Sum of the size of all files in a directory, recursively.
+    fn dir_size(&self, path: &Path) -> io::Result<u64> {
+        let mut size = 0;
+        for name in self.read_dir(path)? {
+            let path = path.join(name);
+            if self.is_dir(&path) {
+                size += self.dir_size(&path)?;
+            } else {
+                size += self.file_size(&path)?;
+            }
+        }
+        Ok(size)
+    }
+
+    /// Make sure the created, removed and renamed entries of a directory reached the storage.
+    fn sync_dir(&self, path: &Path) -> io::Result<()>;
+
+    fn space(&self) -> io::Result<StorageSpace>;
+
+    /// Lock `path`, creating it if needed, while the returned guard is kept.
+    ///
+    /// Many can hold a `shared` lock at the same time, but not together with an exclusive one,
+    /// also from other processes. Fails with [`io::ErrorKind::WouldBlock`] if it can't be locked now.
+    fn try_lock(&self, path: &Path, shared: bool) -> io::Result<Box<dyn Send + Sync>>;
+}
+
+impl StorageFile for File {
+    fn sync_all(&self) -> io::Result<()> {
+        Self::sync_all(self)
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        Self::sync_data(self)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn set_len(&self, size: u64) -> io::Result<()> {
+        Self::set_len(self, size)
+    }
+}
+
+impl StorageFile for AtomicWriteFile {
+    fn sync_all(&self) -> io::Result<()> {
+        self.as_file().sync_all()
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        self.as_file().sync_data()
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.as_file().metadata()?.len())
+    }
+
+    fn set_len(&self, size: u64) -> io::Result<()> {
+        self.as_file().set_len(size)
+    }
+}
+
+impl AtomicStorageFile for AtomicWriteFile {
+    fn commit(self: Box<Self>) -> io::Result<()> {
+        Self::commit(*self)
+    }
+}
+
+/// Keeps the files in `root` on the real filesystem.
+pub struct FsStorage {
+    root: PathBuf,
+}
+
+impl FsStorage {
+    #[must_use]
+    pub const fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    fn path(&self, path: &Path) -> PathBuf {
+        // joining "" adds a trailing `/`, which fails if the root is a file
+        if path.as_os_str().is_empty() {
+            return self.root.clone();
+        }
+        self.root.join(path)
+    }
+}
+
+impl Storage for FsStorage {
+    fn open(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        Ok(Box::new(File::open(self.path(path))?))
+    }
+
+    fn open_rw(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(self.path(path))?;
+        Ok(Box::new(file))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(self.path(path))?;
+        Ok(Box::new(file))
+    }
+
+    fn append(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path(path))?;
+        Ok(Box::new(file))
+    }
+
+    fn open_atomic(&self, path: &Path) -> io::Result<Box<dyn AtomicStorageFile>> {
+        Ok(Box::new(fs_util::open_atomic_write(&self.path(path))?))
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(self.path(path))
+    }
+
+    fn write(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        let mut file = File::create(self.path(path))?;
+        file.write_all(data)?;
+        file.sync_all()
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(self.path(from), self.path(to))
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(self.path(path))
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::remove_dir_all(self.path(path))
+    }
+
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir(self.path(path))
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(self.path(path))
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<OsString>> {
+        fs::read_dir(self.path(path))?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect()
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        self.path(path).is_file()
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.path(path).is_dir()
+    }
+
+    fn file_size(&self, path: &Path) -> io::Result<u64> {
+        Ok(fs::metadata(self.path(path))?.len())
+    }
+
+    fn dir_size(&self, path: &Path) -> io::Result<u64> {
+        fs_util::dir_size(&self.path(path))
+    }
+
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        File::open(self.path(path))?.sync_all()
+    }
+
+    #[allow(clippy::unnecessary_cast)]
+    #[allow(clippy::cast_possible_truncation)]
+    fn space(&self) -> io::Result<StorageSpace> {
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        {
+            let stat = fs_util::statvfs(&self.root)?;
+            let frsize = stat.f_frsize as u64;
+            Ok(StorageSpace {
+                free: stat.f_bfree as u64 * frsize,
+                avail: stat.f_bavail as u64 * frsize,
+                files_free: stat.f_ffree as u64,
+                name_max: stat.f_namemax as u32,
+            })
+        }
+        #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+        Ok(StorageSpace {
+            free: 0,
+            avail: 0,
+            files_free: 0,
+            name_max: 255,
+        })
+    }
+
+    fn try_lock(&self, path: &Path, shared: bool) -> io::Result<Box<dyn Send + Sync>> {
+        let path = self.path(path);
+        // locking doesn't need write access, so it works also on read-only media
+        let file = match File::open(&path) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)?,
+            res => res?,
+        };
+        let res = if shared {
+            file.try_lock_shared()
+        } else {
+            file.try_lock()
+        };
+        // it's released when the file is closed, also on crash
+        match res {
+            Ok(()) => Ok(Box::new(file)),
+            Err(fs::TryLockError::WouldBlock) => Err(io::ErrorKind::WouldBlock.into()),
+            Err(fs::TryLockError::Error(err)) => Err(err),
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Node {
+    File(Arc<Mutex<Vec<u8>>>),
+    Dir,
+}
+
+type Nodes = Arc<Mutex<BTreeMap<PathBuf, Node>>>;
+
+/// Keeps the files in memory, everything is lost when it's dropped.
+pub struct MemoryStorage {
+    // the root is the empty path and always exists
+    nodes: Nodes,
+    // how many hold the lock on each path, -1 if it's held exclusively
+    locks: Arc<Mutex<HashMap<PathBuf, isize>>>,
+}
+
+impl Default for MemoryStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryStorage {
+    #[must_use]
+    pub fn new() -> Self {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(PathBuf::new(), Node::Dir);
+        Self {
+            nodes: Arc::new(Mutex::new(nodes)),
+            locks: Arc::default(),
+        }
+    }
+
+    fn nodes(&self) -> MutexGuard<'_, BTreeMap<PathBuf, Node>> {
+        self.nodes.lock().expect("cannot obtain lock")
+    }
+
+    /// Make the path relative to the root, so `a/./b` and `/a/b` are the same as `a/b`.
+    fn normalize(path: &Path) -> io::Result<PathBuf> {
+        let mut res = PathBuf::new();
+        for component in path.components() {
+            match component {
+                Component::Normal(name) => res.push(name),
+                Component::CurDir | Component::RootDir => {}
+                Component::ParentDir | Component::Prefix(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "path must be inside the storage",
+                    ))
+                }
+            }
+        }
+        Ok(res)
+    }
+
+    fn check_parent(nodes: &BTreeMap<PathBuf, Node>, path: &Path) -> io::Result<()> {
+        match path.parent().map(|parent| nodes.get(parent)) {
+            Some(Some(Node::Dir)) => Ok(()),
+            Some(Some(Node::File(_))) => Err(io::ErrorKind::NotADirectory.into()),
+            // the root has no parent, but it's a directory
+            None => Err(io::ErrorKind::IsADirectory.into()),
+            Some(None) => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    /// The node at `path` and all the ones under it.
+    fn subtree(nodes: &BTreeMap<PathBuf, Node>, path: &Path) -> Vec<PathBuf> {
+        nodes
+            .keys()
+            .filter(|key| key.starts_with(path))
+            .cloned()
+            .collect()
+    }
+
+    fn open_with(&self, path: &Path, mode: Mode) -> io::Result<MemoryFile> {
+        let path = Self::normalize(path)?;
+        match self.nodes().get(&path) {
+            Some(Node::File(data)) => Ok(MemoryFile::new(data.clone(), mode)),
+            Some(Node::Dir) => Err(io::ErrorKind::IsADirectory.into()),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn open(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        Ok(Box::new(self.open_with(path, Mode::Read)?))
+    }
+
+    fn open_rw(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        Ok(Box::new(self.open_with(path, Mode::ReadWrite)?))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        let path = Self::normalize(path)?;
+        let mut nodes = self.nodes();
+        Self::check_parent(&nodes, &path)?;
+        let data = match nodes.get(&path) {
+            Some(Node::File(data)) => {
+                data.lock().expect("cannot obtain lock").clear();
+                data.clone()
+            }
+            Some(Node::Dir) => return Err(io::ErrorKind::IsADirectory.into()),
+            None => {
+                let data = Arc::new(Mutex::new(vec![]));
+                nodes.insert(path, Node::File(data.clone()));
+                data
+            }
+        };
+        Ok(Box::new(MemoryFile::new(data, Mode::ReadWrite)))
+    }
+
+    fn append(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        if !self.is_file(path) {
+            drop(self.create(path)?);
+        }
+        Ok(Box::new(self.open_with(path, Mode::Append)?))
+    }
+
+    fn open_atomic(&self, path: &Path) -> io::Result<Box<dyn AtomicStorageFile>> {
+        let path = Self::normalize(path)?;
+        let nodes = self.nodes();
+        Self::check_parent(&nodes, &path)?;
+        if matches!(nodes.get(&path), Some(Node::Dir)) {
+            return Err(io::ErrorKind::IsADirectory.into());
+        }
+        Ok(Box::new(MemoryAtomicFile {
+            file: MemoryFile::new(Arc::default(), Mode::ReadWrite),
+            path,
+            nodes: self.nodes.clone(),
+        }))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let from = Self::normalize(from)?;
+        let to = Self::normalize(to)?;
+        let mut nodes = self.nodes();
+        let node = nodes.get(&from).ok_or(io::ErrorKind::NotFound)?.clone();
+        Self::check_parent(&nodes, &to)?;
+        if from == to {
+            return Ok(());
+        }
+        match (&node, nodes.get(&to)) {
+            (Node::File(_), Some(Node::Dir)) => return Err(io::ErrorKind::IsADirectory.into()),
+            (Node::Dir, Some(Node::File(_))) => return Err(io::ErrorKind::NotADirectory.into()),
+            (Node::Dir, Some(Node::Dir)) if Self::subtree(&nodes, &to).len() > 1 => {
+                return Err(io::ErrorKind::DirectoryNotEmpty.into());
+            }
+            _ => {}
+        }
+        if to.starts_with(&from) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cannot move a directory inside itself",
+            ));
+        }
+        for key in Self::subtree(&nodes, &from) {
+            let node = nodes.remove(&key).unwrap();
+            let new_key = to.join(key.strip_prefix(&from).unwrap());
+            nodes.insert(new_key, node);
+        }
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let path = Self::normalize(path)?;
+        let mut nodes = self.nodes();
+        match nodes.get(&path) {
+            Some(Node::File(_)) => {
+                nodes.remove(&path);
+                Ok(())
+            }
+            Some(Node::Dir) => Err(io::ErrorKind::IsADirectory.into()),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        let path = Self::normalize(path)?;
+        let mut nodes = self.nodes();
+        match nodes.get(&path) {
+            Some(Node::Dir) => {
+                for key in Self::subtree(&nodes, &path) {
+                    nodes.remove(&key);
+                }
+                // we never remove the root
+                nodes.insert(PathBuf::new(), Node::Dir);
+                Ok(())
+            }
+            Some(Node::File(_)) => Err(io::ErrorKind::NotADirectory.into()),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        let path = Self::normalize(path)?;
+        let mut nodes = self.nodes();
+        if nodes.contains_key(&path) {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+        Self::check_parent(&nodes, &path)?;
+        nodes.insert(path, Node::Dir);
+        Ok(())
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let path = Self::normalize(path)?;
+        let mut nodes = self.nodes();
+        for dir in path.ancestors() {
+            match nodes.get(dir) {
+                Some(Node::File(_)) => return Err(io::ErrorKind::AlreadyExists.into()),
+                Some(Node::Dir) => break,
+                None => {}
+            }
+        }
+        for dir in path.ancestors() {
+            nodes.entry(dir.to_path_buf()).or_insert(Node::Dir);
+        }
+        Ok(())
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<OsString>> {
+        let path = Self::normalize(path)?;
+        let nodes = self.nodes();
+        match nodes.get(&path) {
+            Some(Node::Dir) => Ok(nodes
+                .keys()
+                .filter(|key| key.parent() == Some(path.as_path()))
+                .filter_map(|key| key.file_name().map(ToOwned::to_owned))
+                .collect()),
+            Some(Node::File(_)) => Err(io::ErrorKind::NotADirectory.into()),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        Self::normalize(path)
+            .is_ok_and(|path| matches!(self.nodes().get(&path), Some(Node::File(_))))
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        Self::normalize(path).is_ok_and(|path| matches!(self.nodes().get(&path), Some(Node::Dir)))
+    }
+
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        if self.is_dir(path) {
+            Ok(())
+        } else {
+            Err(io::ErrorKind::NotFound.into())
+        }
+    }
+
+    fn space(&self) -> io::Result<StorageSpace> {
+        // there is no limit besides the memory, we don't report it
+        Ok(StorageSpace {
+            free: 0,
+            avail: 0,
+            files_free: 0,
+            name_max: 255,
+        })
+    }
+
+    fn try_lock(&self, path: &Path, shared: bool) -> io::Result<Box<dyn Send + Sync>> {
+        if !self.is_file(path) {
+            drop(self.create(path)?);
+        }
+        let path = Self::normalize(path)?;
+        let mut locks = self.locks.lock().expect("cannot obtain lock");
+        let holders = locks.entry(path.clone()).or_default();
+        match (*holders, shared) {
+            (0, false) => *holders = -1,
+            (0.., true) => *holders += 1,
+            _ => return Err(io::ErrorKind::WouldBlock.into()),
+        }
+        Ok(Box::new(MemoryLock {
+            locks: self.locks.clone(),
+            path,
+        }))
+    }
+}
+
+/// Lock from [`MemoryStorage::try_lock`], released on drop.
+struct MemoryLock {
+    locks: Arc<Mutex<HashMap<PathBuf, isize>>>,
+    path: PathBuf,
+}
+
+impl Drop for MemoryLock {
+    fn drop(&mut self) {
+        let mut locks = self.locks.lock().expect("cannot obtain lock");
+        if let Some(holders) = locks.get_mut(&self.path) {
+            if *holders > 1 {
+                *holders -= 1;
+            } else {
+                locks.remove(&self.path);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Read,
+    ReadWrite,
+    /// Writes always go to the end.
+    Append,
+}
+
+/// File from [`MemoryStorage`], the content is shared with the other handles of the same file,
+/// like with files on disk.
+struct MemoryFile {
+    data: Arc<Mutex<Vec<u8>>>,
+    pos: u64,
+    mode: Mode,
+}
+
+impl MemoryFile {
+    const fn new(data: Arc<Mutex<Vec<u8>>>, mode: Mode) -> Self {
+        Self { data, pos: 0, mode }
+    }
+
+    fn data(&self) -> MutexGuard<'_, Vec<u8>> {
+        self.data.lock().expect("cannot obtain lock")
+    }
+}
+
+impl Read for MemoryFile {
+    #[allow(clippy::cast_possible_truncation)]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.mode == Mode::Append {
+            return Err(io::ErrorKind::PermissionDenied.into());
+        }
+        let data = self.data();
+        let pos = (self.pos as usize).min(data.len());
+        let len = buf.len().min(data.len() - pos);
+        buf[..len].copy_from_slice(&data[pos..pos + len]);
+        drop(data);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for MemoryFile {
+    #[allow(clippy::cast_possible_truncation)]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.mode == Mode::Read {
+            return Err(io::ErrorKind::PermissionDenied.into());
+        }
+        let mut data = self.data();
+        let pos = if self.mode == Mode::Append {
+            data.len()
+        } else {
+            self.pos as usize
+        };
+        let end = pos + buf.len();
+        if data.len() < end {
+            // writing after the end fills the gap with zeros, like a sparse file
+            data.resize(end, 0);
+        }
+        data[pos..end].copy_from_slice(buf);
+        drop(data);
+        self.pos = end as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemoryFile {
+    #[allow(clippy::cast_possible_wrap)]
+    #[allow(clippy::cast_sign_loss)]
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(offset) => (self.data().len() as u64).checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        self.pos = new_pos.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+
+impl StorageFile for MemoryFile {
+    fn sync_all(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.data().len() as u64)
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    fn set_len(&self, size: u64) -> io::Result<()> {
+        if self.mode == Mode::Read {
+            return Err(io::ErrorKind::PermissionDenied.into());
+        }
+        self.data().resize(size as usize, 0);
+        Ok(())
+    }
+}
+
+/// File from [`MemoryStorage::open_atomic`], its content is put in place of the file on commit.
+struct MemoryAtomicFile {
+    file: MemoryFile,
+    path: PathBuf,
+    nodes: Nodes,
+}
+
+impl Read for MemoryAtomicFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Write for MemoryAtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for MemoryAtomicFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+impl StorageFile for MemoryAtomicFile {
+    fn sync_all(&self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        self.file.size()
+    }
+
+    fn set_len(&self, size: u64) -> io::Result<()> {
+        self.file.set_len(size)
+    }
+}
+
+impl AtomicStorageFile for MemoryAtomicFile {
+    fn commit(self: Box<Self>) -> io::Result<()> {
+        let Self { file, path, nodes } = *self;
+        let mut nodes = nodes.lock().expect("cannot obtain lock");
+        MemoryStorage::check_parent(&nodes, &path)?;
+        if matches!(nodes.get(&path), Some(Node::Dir)) {
+            return Err(io::ErrorKind::IsADirectory.into());
+        }
+        // like a rename over the file, the handles already opened keep the old content
+        nodes.insert(path, Node::File(file.data));
+        Ok(())
+    }
+}
@@ -0,0 +1,165 @@
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::storage::{FsStorage, MemoryStorage, Storage};
+use crate::test_common::TESTS_DATA_DIR;
+
+/// Run `f` against both backends, so we know they behave the same.
+fn run_test(key: &str, f: impl Fn(&dyn Storage)) {
+    let data_dir = TESTS_DATA_DIR.join(key);
+    let _ = fs::remove_dir_all(&data_dir);
+    fs::create_dir_all(&data_dir).unwrap();
+    f(&FsStorage::new(data_dir.clone()));
+    fs::remove_dir_all(data_dir).unwrap();
+
+    f(&MemoryStorage::new());
+}
+
+fn read_dir_sorted(storage: &dyn Storage, path: &str) -> Vec<OsString> {
+    let mut names = storage.read_dir(Path::new(path)).unwrap();
+    names.sort();
+    names
+}
+
+fn kind<T>(res: io::Result<T>) -> io::ErrorKind {
+    res.err().unwrap().kind()
+}
+
+#[test]
+fn test_create_open() {
+    run_test("test_storage_create_open", |storage| {
+        let path = Path::new("file");
+        assert_eq!(io::ErrorKind::NotFound, kind(storage.open(path)));
+        assert!(!storage.exists(path));
+
+        let mut file = storage.create(path).unwrap();
+        file.write_all(b"hello").unwrap();
+        file.flush().unwrap();
+        assert!(storage.is_file(path));
+        assert!(!storage.is_dir(path));
+
+        // another handle sees the same content
+        let mut file2 = storage.open(path).unwrap();
+        let mut buf = String::new();
+        file2.read_to_string(&mut buf).unwrap();
+        assert_eq!("hello", buf);
+
+        // write in the middle and past the end
+        file.seek(SeekFrom::Start(1)).unwrap();
+        file.write_all(b"EL").unwrap();
+        file.seek(SeekFrom::End(2)).unwrap();
+        file.write_all(b"!").unwrap();
+        file.flush().unwrap();
+        assert_eq!(b"hELlo\0\0!".to_vec(), storage.read(path).unwrap());
+        assert_eq!(8, file.seek(SeekFrom::Current(0)).unwrap());
+        assert_eq!(
+            io::ErrorKind::InvalidInput,
+            kind(file.seek(SeekFrom::Current(-9)))
+        );
+
+        // create truncates
+        drop(storage.create(path).unwrap());
+        assert!(storage.read(path).unwrap().is_empty());
+
+        // parent must exist
+        assert_eq!(
+            io::ErrorKind::NotFound,
+            kind(storage.create(Path::new("missing/file")))
+        );
+    });
+}
+
+#[test]
+fn test_read_write() {
+    run_test("test_storage_read_write", |storage| {
+        let path = Path::new("file");
+        storage.write(path, b"first").unwrap();
+        assert_eq!(b"first".to_vec(), storage.read(path).unwrap());
+        storage.write(path, b"2nd").unwrap();
+        assert_eq!(b"2nd".to_vec(), storage.read(path).unwrap());
+        assert_eq!(
+            io::ErrorKind::NotFound,
+            kind(storage.read(Path::new("missing")))
+        );
+    });
+}
+
+#[test]
+fn test_dirs() {
+    run_test("test_storage_dirs", |storage| {
+        storage.create_dir_all(Path::new("a/b/c")).unwrap();
+        // already existing is fine
+        storage.create_dir_all(Path::new("a/b")).unwrap();
+        assert!(storage.is_dir(Path::new("a")));
+        assert!(storage.is_dir(Path::new("a/b/c")));
+        storage.write(Path::new("a/file"), b"data").unwrap();
+        storage.write(Path::new("a/b/file"), b"data").unwrap();
+
+        assert_eq!(
+            vec![OsString::from("b"), OsString::from("file")],
+            read_dir_sorted(storage, "a")
+        );
+        assert!(read_dir_sorted(storage, "a/b/c").is_empty());
+        assert_eq!(
+            io::ErrorKind::NotFound,
+            kind(storage.read_dir(Path::new("missing")))
+        );
+
+        // remove_file doesn't remove directories
+        assert!(storage.remove_file(Path::new("a/b")).is_err());
+        storage.remove_file(Path::new("a/file")).unwrap();
+        assert!(!storage.exists(Path::new("a/file")));
+        assert_eq!(
+            io::ErrorKind::NotFound,
+            kind(storage.remove_file(Path::new("a/file")))
+        );
+
+        storage.remove_dir_all(Path::new("a/b")).unwrap();
+        assert!(!storage.exists(Path::new("a/b")));
+        assert!(!storage.exists(Path::new("a/b/file")));
+        assert!(read_dir_sorted(storage, "a").is_empty());
+        assert_eq!(
+            io::ErrorKind::NotFound,
+            kind(storage.remove_dir_all(Path::new("a/b")))
+        );
+    });
+}
+
+#[test]
+fn test_rename() {
+    run_test("test_storage_rename", |storage| {
+        storage.write(Path::new("file"), b"data").unwrap();
+        storage.write(Path::new("other"), b"other").unwrap();
+
+        // replaces the destination file
+        storage
+            .rename(Path::new("file"), Path::new("other"))
+            .unwrap();
+        assert!(!storage.exists(Path::new("file")));
+        assert_eq!(b"data".to_vec(), storage.read(Path::new("other")).unwrap());
+
+        // directories are moved with their content
+        storage.create_dir_all(Path::new("dir/sub")).unwrap();
+        storage.write(Path::new("dir/sub/file"), b"nested").unwrap();
+        storage.create_dir_all(Path::new("dst")).unwrap();
+        storage
+            .rename(Path::new("dir"), Path::new("dst/dir"))
+            .unwrap();
+        assert!(!storage.exists(Path::new("dir")));
+        assert_eq!(
+            b"nested".to_vec(),
+            storage.read(Path::new("dst/dir/sub/file")).unwrap()
+        );
+
+        assert_eq!(
+            io::ErrorKind::NotFound,
+            kind(storage.rename(Path::new("missing"), Path::new("x")))
+        );
+        assert_eq!(
+            io::ErrorKind::NotFound,
+            kind(storage.rename(Path::new("other"), Path::new("missing/x")))
+        );
+    });
+}
@@ -3,13 +3,14 @@ use std::io;
 use std::io::{Read, Write};
 
 use num_format::{Locale, ToFormattedString};
+use secrecy::zeroize::Zeroizing;
 use tracing::{debug, error, instrument, warn};
 
 #[cfg(test)]
-const BUF_SIZE: usize = 256 * 1024;
+pub(crate) const BUF_SIZE: usize = 256 * 1024;
 // 256 KB buffer, smaller for tests because they all run in parallel
 #[cfg(not(test))]
-const BUF_SIZE: usize = 1024 * 1024; // 1 MB buffer
+pub(crate) const BUF_SIZE: usize = 1024 * 1024; // 1 MB buffer
 
 #[instrument(skip(r, len), fields(len = len.to_formatted_string( & Locale::en)))]
 pub fn seek_forward_exact(r: &mut impl Read, len: u64) -> io::Result<()> {
@@ -25,7 +26,8 @@ pub fn seek_forward<R: Read>(r: &mut R, len: u64, stop_on_eof: bool) -> io::Resu
         return Ok(0);
     }
 
-    let mut buffer = vec![0; BUF_SIZE];
+    // it might hold plaintext
+    let mut buffer = Zeroizing::new(vec![0; BUF_SIZE]);
     let mut pos = 0_u64;
     loop {
         #[allow(clippy::cast_possible_truncation)]
@@ -74,7 +76,8 @@ pub fn copy(r: &mut impl Read, w: &mut impl Write, len: u64, stop_on_eof: bool)
     if len == 0 {
         return Ok(0);
     }
-    let mut buffer = vec![0; BUF_SIZE];
+    // it might hold plaintext
+    let mut buffer = Zeroizing::new(vec![0; BUF_SIZE]);
     let mut read_pos = 0_u64;
     loop {
         #[allow(clippy::cast_possible_truncation)]
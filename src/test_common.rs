@@ -11,7 +11,8 @@ use thread_local::ThreadLocal;
 use tokio::sync::Mutex;
 
 use crate::crypto::Cipher;
-use crate::encryptedfs::{CreateFileAttr, EncryptedFs, FileType, PasswordProvider};
+use crate::encryptedfs::{CreateFileAttr, EncryptedFs, FileType, FsOptions, PasswordProvider};
+use crate::storage::MemoryStorage;
 
 #[allow(dead_code)]
 pub static TESTS_DATA_DIR: LazyLock<PathBuf> = LazyLock::new(|| {
@@ -43,11 +44,22 @@ pub struct TestSetup {
     pub key: &'static str,
 }
 
+/// Where the [`EncryptedFs`] of a test keeps its files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TestStorage {
+    /// In [`TESTS_DATA_DIR`]
+    Fs,
+    /// In a [`MemoryStorage`]
+    Memory,
+}
+
 pub struct SetupResult {
     #[allow(dead_code)]
     pub fs: Option<Arc<EncryptedFs>>,
     #[allow(dead_code)]
     setup: TestSetup,
+    #[allow(dead_code)]
+    storage: TestStorage,
 }
 
 #[allow(dead_code)]
@@ -58,23 +70,36 @@ impl PasswordProvider for PasswordProviderImpl {
     }
 }
 #[allow(dead_code)]
-async fn setup(setup: TestSetup) -> SetupResult {
-    let path = TESTS_DATA_DIR.join(setup.key);
-    let data_dir_str = path.to_str().unwrap();
-    let _ = fs::remove_dir_all(data_dir_str);
-    let _ = fs::create_dir_all(data_dir_str);
-
-    let fs = EncryptedFs::new(
-        Path::new(data_dir_str).to_path_buf(),
-        Box::new(PasswordProviderImpl {}),
-        Cipher::ChaCha20Poly1305,
-    )
-    .await
-    .unwrap();
+async fn setup(setup: TestSetup, storage: TestStorage) -> SetupResult {
+    let fs = match storage {
+        TestStorage::Fs => {
+            let path = TESTS_DATA_DIR.join(setup.key);
+            let data_dir_str = path.to_str().unwrap();
+            let _ = fs::remove_dir_all(data_dir_str);
+            let _ = fs::create_dir_all(data_dir_str);
+
+            EncryptedFs::new(
+                Path::new(data_dir_str).to_path_buf(),
+                Box::new(PasswordProviderImpl {}),
+                Cipher::ChaCha20Poly1305,
+            )
+            .await
+            .unwrap()
+        }
+        TestStorage::Memory => EncryptedFs::new_with_storage(
+            Arc::new(MemoryStorage::new()),
+            Box::new(PasswordProviderImpl {}),
+            Cipher::ChaCha20Poly1305,
+            FsOptions::default(),
+        )
+        .await
+        .unwrap(),
+    };
 
     SetupResult {
         fs: Some(fs),
         setup,
+        storage,
     }
 }
 
@@ -82,9 +107,12 @@ async fn setup(setup: TestSetup) -> SetupResult {
 async fn teardown() -> Result<(), io::Error> {
     let s = SETUP_RESULT.get_or(|| Mutex::new(None));
     let s = s.lock().await;
-    let path = TESTS_DATA_DIR.join(s.as_ref().unwrap().setup.key);
-    let data_dir_str = path.to_str().unwrap();
-    fs::remove_dir_all(data_dir_str)?;
+    let s = s.as_ref().unwrap();
+    if s.storage == TestStorage::Fs {
+        let path = TESTS_DATA_DIR.join(s.setup.key);
+        let data_dir_str = path.to_str().unwrap();
+        fs::remove_dir_all(data_dir_str)?;
+    }
 
     Ok(())
 }
@@ -92,13 +120,35 @@ async fn teardown() -> Result<(), io::Error> {
 #[allow(dead_code)]
 #[allow(clippy::future_not_send)]
 pub async fn run_test<T>(init: TestSetup, t: T)
+where
+    T: Future,
+{
+    run_test_in(init, TestStorage::Fs, t).await;
+}
+
+/// Like [`run_test`], but runs the test twice, first with the files in [`TESTS_DATA_DIR`] and
+/// then with them in a [`MemoryStorage`].
+#[allow(dead_code)]
+#[allow(clippy::future_not_send)]
+pub async fn run_test_with_storages<F, T>(init: TestSetup, t: F)
+where
+    F: Fn() -> T,
+    T: Future,
+{
+    for storage in [TestStorage::Fs, TestStorage::Memory] {
+        run_test_in(init.clone(), storage, t()).await;
+    }
+}
+
+#[allow(clippy::future_not_send)]
+async fn run_test_in<T>(init: TestSetup, storage: TestStorage, t: T)
 where
     T: Future,
 {
     {
         let s = SETUP_RESULT.get_or(|| Mutex::new(None));
         let mut s = s.lock().await;
-        *s = Some(setup(init).await);
+        *s = Some(setup(init, storage).await);
     }
     t.await;
     teardown().await.unwrap();
@@ -171,7 +221,7 @@ pub async fn read_exact(fs: &EncryptedFs, ino: u64, offset: u64, buf: &mut [u8],
 }
 
 #[allow(dead_code)]
-pub fn bench<F: Future + Send + Sync>(key: &'static str, worker_threads: usize, f: F) {
+pub fn bench<F: Future + Send>(key: &'static str, worker_threads: usize, f: F) {
     block_on(
         async {
             run_test(TestSetup { key }, f).await;